@@ -0,0 +1,507 @@
+use crate::fits::{
+    calculate_statistics, median_in_place, sample_pixels, sigma_clipped_stats, FitsImage,
+    HeaderCard,
+};
+use crate::renderer::{sample_at, Interpolation};
+use serde::{Deserialize, Serialize};
+
+/// Cells with fewer valid pixels than this share are left out of the mesh
+const MIN_CELL_COVERAGE: f32 = 0.5;
+
+/// Cells further than this many sigmas from the fitted surface (galaxies,
+/// nebulae, bright stars' halos) are rejected before the final fit
+const CELL_REJECTION_SIGMA: f32 = 2.5;
+
+/// Surface fitted through the mesh of sky levels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SurfaceModel {
+    /// Smooth polynomial in x and y, for gradients from light pollution
+    #[default]
+    Polynomial,
+    /// Cubic spline through the cell levels, follows more local variation
+    /// such as vignetting or the moon's glow
+    Spline,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BackgroundParams {
+    pub model: SurfaceModel,
+    /// Polynomial degree, 1 (a tilted plane) to 4
+    pub degree: usize,
+    /// Mesh cells across the longer side
+    pub grid: usize,
+}
+
+impl Default for BackgroundParams {
+    fn default() -> Self {
+        Self {
+            model: SurfaceModel::Polynomial,
+            degree: 2,
+            grid: 16,
+        }
+    }
+}
+
+/// What to show of the background model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundView {
+    /// The image as loaded
+    #[default]
+    Original,
+    /// The background model itself
+    Model,
+    /// Image minus the model, for additive gradients (light pollution)
+    Subtracted,
+    /// Image divided by the model, for multiplicative ones (vignetting)
+    Divided,
+}
+
+/// How strongly the sky varies across a frame
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct GradientStats {
+    /// Range of the model (2nd to 98th percentile) over its median, percent
+    pub strength_percent: f32,
+    /// The same range in background noise sigmas
+    pub strength_sigma: f32,
+    /// Direction the sky gets brighter, degrees counter-clockwise from +x
+    pub angle_deg: f32,
+    /// Mesh cells the model was fitted to, after rejection
+    pub cells_used: usize,
+}
+
+/// A fitted background
+pub struct Background {
+    pub model: Vec<f32>,
+    pub width: usize,
+    pub height: usize,
+    pub stats: GradientStats,
+    /// Median of the model, kept as the sky level when correcting
+    pub level: f32,
+}
+
+/// One mesh cell: its centre in pixels and sky level
+#[derive(Clone, Copy)]
+struct Cell {
+    x: f32,
+    y: f32,
+    level: f32,
+}
+
+impl Background {
+    /// Fit a background to `data`: sigma-clipped medians of a grid of cells,
+    /// cells far from a first surface rejected, then the surface fitted again
+    pub fn fit(data: &[f32], width: usize, height: usize, params: &BackgroundParams) -> Self {
+        let cell_size = (width.max(height) / params.grid.max(2)).max(8);
+        let (columns, rows) = (width.div_ceil(cell_size), height.div_ceil(cell_size));
+
+        let mut cells: Vec<Option<Cell>> = Vec::with_capacity(columns * rows);
+        let mut values = Vec::with_capacity(cell_size * cell_size);
+        for row in 0..rows {
+            for column in 0..columns {
+                let (x0, y0) = (column * cell_size, row * cell_size);
+                let (x1, y1) = ((x0 + cell_size).min(width), (y0 + cell_size).min(height));
+                values.clear();
+                for y in y0..y1 {
+                    values.extend(
+                        data[y * width + x0..y * width + x1]
+                            .iter()
+                            .filter(|v| v.is_finite()),
+                    );
+                }
+                let covered = values.len() as f32 / ((x1 - x0) * (y1 - y0)) as f32;
+                cells.push((covered >= MIN_CELL_COVERAGE).then(|| Cell {
+                    x: (x0 + x1) as f32 / 2.0,
+                    y: (y0 + y1) as f32 / 2.0,
+                    level: sigma_clipped_stats(&values, 3.0, 5).0,
+                }));
+            }
+        }
+
+        // Reject cells that stand out from a first fit of the same degree
+        let degree = params.degree.clamp(1, 4);
+        let fit = |cells: &[Option<Cell>]| {
+            let used: Vec<Cell> = cells.iter().flatten().copied().collect();
+            Polynomial::fit(&used, degree, width, height)
+        };
+        let mut polynomial = fit(&cells);
+        for _ in 0..2 {
+            let Some(first) = &polynomial else { break };
+            let mut residuals: Vec<f32> = cells
+                .iter()
+                .flatten()
+                .map(|c| (c.level - first.eval(c.x, c.y)).abs())
+                .collect();
+            let spread = 1.4826 * median_in_place(&mut residuals);
+            if spread <= 0.0 {
+                break;
+            }
+            for cell in &mut cells {
+                if cell.is_some_and(|c| {
+                    (c.level - first.eval(c.x, c.y)).abs() > CELL_REJECTION_SIGMA * spread
+                }) {
+                    *cell = None;
+                }
+            }
+            polynomial = fit(&cells);
+        }
+        let cells_used = cells.iter().flatten().count();
+
+        let model: Vec<f32> = match (params.model, &polynomial) {
+            (_, None) => {
+                let level = sigma_clipped_stats(&sample_pixels(data, 200_000), 3.0, 5).0;
+                vec![level; width * height]
+            }
+            (SurfaceModel::Polynomial, Some(polynomial)) => (0..width * height)
+                .map(|i| polynomial.eval((i % width) as f32 + 0.5, (i / width) as f32 + 0.5))
+                .collect(),
+            (SurfaceModel::Spline, Some(polynomial)) => {
+                // The mesh is interpolated like an image with cell-sized
+                // pixels. Rejected cells, and a border of cells around the
+                // mesh so the edges follow the trend, take the polynomial's
+                // level.
+                let (mesh_columns, mesh_rows) = (columns + 2, rows + 2);
+                let mesh: Vec<f32> = (0..mesh_columns * mesh_rows)
+                    .map(|i| {
+                        let (column, row) = (i % mesh_columns, i / mesh_columns);
+                        let inside = (1..=columns).contains(&column) && (1..=rows).contains(&row);
+                        match inside
+                            .then(|| cells[(row - 1) * columns + column - 1])
+                            .flatten()
+                        {
+                            Some(cell) => cell.level,
+                            None => polynomial.eval(
+                                (column as f32 - 0.5) * cell_size as f32,
+                                (row as f32 - 0.5) * cell_size as f32,
+                            ),
+                        }
+                    })
+                    .collect();
+                (0..width * height)
+                    .map(|i| {
+                        let (x, y) = ((i % width) as f32 + 0.5, (i / width) as f32 + 0.5);
+                        sample_at(
+                            &mesh,
+                            mesh_columns as u32,
+                            mesh_rows as u32,
+                            x / cell_size as f32 + 1.0,
+                            y / cell_size as f32 + 1.0,
+                            Interpolation::Bicubic,
+                        )
+                    })
+                    .collect()
+            }
+        };
+
+        // Noise of what is left once the gradient is gone
+        let residuals: Vec<f32> = data.iter().zip(&model).map(|(v, m)| v - m).collect();
+        let (_, noise) = sigma_clipped_stats(&sample_pixels(&residuals, 200_000), 3.0, 5);
+
+        let mut sorted = sample_pixels(&model, 200_000);
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f32| sorted[((sorted.len() - 1) as f32 * p) as usize];
+        let (low, level, high) = (percentile(0.02), percentile(0.5), percentile(0.98));
+        let (gx, gy) = polynomial.as_ref().map_or((0.0, 0.0), |p| p.slope());
+
+        Background {
+            stats: GradientStats {
+                strength_percent: if level.abs() > f32::EPSILON {
+                    (high - low) / level.abs() * 100.0
+                } else {
+                    0.0
+                },
+                strength_sigma: if noise > 0.0 {
+                    (high - low) / noise
+                } else {
+                    0.0
+                },
+                // Rows go down the screen, so flip y for counter-clockwise
+                angle_deg: (-gy).atan2(gx).to_degrees(),
+                cells_used,
+            },
+            model,
+            width,
+            height,
+            level,
+        }
+    }
+
+    /// `image` as `view` shows it, with the sky level kept so the stretch
+    /// still fits
+    pub fn apply(&self, image: &FitsImage, view: BackgroundView) -> FitsImage {
+        let data: Vec<f32> = match view {
+            BackgroundView::Original => image.data.clone(),
+            BackgroundView::Model => self.model.clone(),
+            BackgroundView::Subtracted => image
+                .data
+                .iter()
+                .zip(&self.model)
+                .map(|(v, m)| v - m + self.level)
+                .collect(),
+            BackgroundView::Divided => image
+                .data
+                .iter()
+                .zip(&self.model)
+                .map(|(v, m)| if *m > 0.0 { v / m * self.level } else { *v })
+                .collect(),
+        };
+
+        let mut header = image.header.clone();
+        if view != BackgroundView::Original {
+            header.set(HeaderCard::number(
+                "BKGGRAD",
+                self.stats.strength_percent as f64,
+                "Background gradient (% of sky)",
+            ));
+            header.push(HeaderCard::history(&format!("Background {:?}", view)));
+        }
+        FitsImage {
+            path: image.path.clone(),
+            stats: calculate_statistics(&data),
+            data,
+            width: image.width,
+            height: image.height,
+            header,
+            wcs: image.wcs.clone(),
+        }
+    }
+}
+
+/// Terms of a degree 4 polynomial in two variables
+const MAX_TERMS: usize = 15;
+
+/// Polynomial surface in coordinates scaled to -1..1 across the image
+struct Polynomial {
+    degree: usize,
+    coefficients: Vec<f64>,
+    /// Pixel to scaled coordinates: u = (x - cx) / s
+    centre: (f64, f64),
+    scale: f64,
+}
+
+impl Polynomial {
+    fn fit(cells: &[Cell], degree: usize, width: usize, height: usize) -> Option<Self> {
+        // Lower the degree until there are enough cells for it
+        let degree = (1..=degree)
+            .rev()
+            .find(|&d| cells.len() >= 2 * term_count(d))?;
+        let mut polynomial = Polynomial {
+            degree,
+            coefficients: Vec::new(),
+            centre: (width as f64 / 2.0, height as f64 / 2.0),
+            scale: width.max(height) as f64 / 2.0,
+        };
+
+        let n = term_count(degree);
+        let mut normal = vec![vec![0.0; n]; n];
+        let mut rhs = vec![0.0; n];
+        for cell in cells {
+            let terms = polynomial.terms(cell.x, cell.y);
+            let terms = &terms[..n];
+            for i in 0..n {
+                for j in 0..n {
+                    normal[i][j] += terms[i] * terms[j];
+                }
+                rhs[i] += terms[i] * cell.level as f64;
+            }
+        }
+        polynomial.coefficients = solve(normal, rhs)?;
+        Some(polynomial)
+    }
+
+    /// u^i v^j for i + j <= degree, the rest zero
+    fn terms(&self, x: f32, y: f32) -> [f64; MAX_TERMS] {
+        let u = (x as f64 - self.centre.0) / self.scale;
+        let v = (y as f64 - self.centre.1) / self.scale;
+        let mut terms = [0.0; MAX_TERMS];
+        let mut index = 0;
+        for total in 0..=self.degree {
+            for j in 0..=total {
+                terms[index] = u.powi((total - j) as i32) * v.powi(j as i32);
+                index += 1;
+            }
+        }
+        terms
+    }
+
+    fn eval(&self, x: f32, y: f32) -> f32 {
+        self.terms(x, y)
+            .iter()
+            .zip(&self.coefficients)
+            .map(|(t, c)| t * c)
+            .sum::<f64>() as f32
+    }
+
+    /// Linear terms: change in x and y at the centre of the image
+    fn slope(&self) -> (f32, f32) {
+        (self.coefficients[1] as f32, self.coefficients[2] as f32)
+    }
+}
+
+fn term_count(degree: usize) -> usize {
+    (degree + 1) * (degree + 2) / 2
+}
+
+/// Gaussian elimination with partial pivoting
+fn solve(mut matrix: Vec<Vec<f64>>, mut rhs: Vec<f64>) -> Option<Vec<f64>> {
+    let n = rhs.len();
+    for column in 0..n {
+        let pivot = (column..n)
+            .max_by(|&a, &b| matrix[a][column].abs().total_cmp(&matrix[b][column].abs()))?;
+        if matrix[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        rhs.swap(column, pivot);
+
+        let (upper, lower) = matrix.split_at_mut(column + 1);
+        let pivot_row = &upper[column];
+        for (offset, row) in lower.iter_mut().enumerate() {
+            let factor = row[column] / pivot_row[column];
+            for (value, pivot) in row[column..].iter_mut().zip(&pivot_row[column..]) {
+                *value -= factor * pivot;
+            }
+            rhs[column + 1 + offset] -= factor * rhs[column];
+        }
+    }
+
+    let mut solution = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (rhs[row] - sum) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registration::Lcg;
+    use std::f32::consts::PI;
+
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 96;
+
+    /// Sky level at a pixel position
+    type Sky = fn(f32, f32) -> f32;
+
+    /// `sky` at each pixel centre, with up to 1 of noise
+    fn frame(sky: impl Fn(f32, f32) -> f32) -> Vec<f32> {
+        let mut rng = Lcg(12345);
+        (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let noise = (rng.fraction() as f32 - 0.5) * 2.0;
+                let (x, y) = ((i % WIDTH) as f32 + 0.5, (i / WIDTH) as f32 + 0.5);
+                sky(x, y) + noise
+            })
+            .collect()
+    }
+
+    /// Largest difference between the model and `sky`, `margin` pixels in
+    /// from the edges
+    fn worst_error(background: &Background, sky: impl Fn(f32, f32) -> f32, margin: usize) -> f32 {
+        background
+            .model
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| {
+                (margin..WIDTH - margin).contains(&(i % WIDTH))
+                    && (margin..HEIGHT - margin).contains(&(i / WIDTH))
+            })
+            .map(|(i, m)| {
+                let (x, y) = ((i % WIDTH) as f32 + 0.5, (i / WIDTH) as f32 + 0.5);
+                (m - sky(x, y)).abs()
+            })
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn polynomial_follows_a_gradient() {
+        let sky = |x: f32, y: f32| 1000.0 + 0.5 * x - 0.2 * y + 0.002 * x * y;
+        let background = Background::fit(&frame(sky), WIDTH, HEIGHT, &Default::default());
+        let error = worst_error(&background, sky, 0);
+        assert!(error < 0.5, "model off by {}", error);
+    }
+
+    #[test]
+    fn gradient_points_where_the_sky_brightens() {
+        // Brighter towards +x: 0°, towards the top of the screen: 90°
+        let cases: [(Sky, f32); 3] = [
+            (|x, _| 1000.0 + x, 0.0),
+            (|_, y| 1000.0 - y, 90.0),
+            (|x, y| 1000.0 - x + y, -135.0),
+        ];
+        for (sky, angle) in cases {
+            let stats = Background::fit(&frame(sky), WIDTH, HEIGHT, &Default::default()).stats;
+            assert!(
+                (stats.angle_deg - angle).abs() < 0.5,
+                "angle {} instead of {}",
+                stats.angle_deg,
+                angle
+            );
+        }
+    }
+
+    #[test]
+    fn strength_is_the_model_range() {
+        let sky = |x: f32, _: f32| 1000.0 + x;
+        let stats = Background::fit(&frame(sky), WIDTH, HEIGHT, &Default::default()).stats;
+        // 2nd to 98th percentile of 1000..1128 is about 123 over a level of
+        // about 1064
+        assert!(
+            (stats.strength_percent - 11.5).abs() < 0.3,
+            "strength {}%",
+            stats.strength_percent
+        );
+        // Uniform noise of +-1 has a MAD of 0.5, or 0.74 sigma
+        assert!(
+            (stats.strength_sigma - 123.0 / 0.74).abs() < 10.0,
+            "strength {} sigma",
+            stats.strength_sigma
+        );
+
+        let flat = Background::fit(&frame(|_, _| 1000.0), WIDTH, HEIGHT, &Default::default());
+        assert!(flat.stats.strength_percent < 0.1);
+    }
+
+    #[test]
+    fn bright_objects_are_rejected() {
+        let sky = |x: f32, y: f32| 1000.0 + 0.5 * x - 0.2 * y;
+        // A galaxy over a few cells would pull the surface up around it
+        let mut data = frame(sky);
+        for (i, value) in data.iter_mut().enumerate() {
+            let (dx, dy) = ((i % WIDTH) as f32 - 40.0, (i / WIDTH) as f32 - 50.0);
+            *value += 500.0 * (-(dx * dx + dy * dy) / (2.0 * 4.0 * 4.0)).exp();
+        }
+
+        let background = Background::fit(&data, WIDTH, HEIGHT, &Default::default());
+        let error = worst_error(&background, sky, 0);
+        assert!(error < 0.5, "model off by {}", error);
+        assert!(background.stats.cells_used < 16 * 12);
+    }
+
+    #[test]
+    fn spline_follows_what_the_polynomial_cannot() {
+        // Two bumps across the frame, beyond a degree 2 polynomial
+        let sky = |x: f32, _: f32| 1000.0 + 20.0 * (x / WIDTH as f32 * 4.0 * PI).cos();
+        let data = frame(sky);
+
+        let polynomial = Background::fit(&data, WIDTH, HEIGHT, &Default::default());
+        let spline = Background::fit(
+            &data,
+            WIDTH,
+            HEIGHT,
+            &BackgroundParams {
+                model: SurfaceModel::Spline,
+                ..Default::default()
+            },
+        );
+        // The outer cells lean on the polynomial border of the mesh
+        let margin = WIDTH / 16;
+        let polynomial = worst_error(&polynomial, sky, margin);
+        let spline = worst_error(&spline, sky, margin);
+        assert!(polynomial > 10.0, "polynomial off by {}", polynomial);
+        assert!(spline < 2.5, "spline off by {}", spline);
+    }
+}
@@ -0,0 +1,921 @@
+use crate::cosmetic::{CosmeticParams, DefectMap};
+use crate::fits::{
+    calculate_statistics, derived_path, load_fits_f32, load_fits_header, median_in_place,
+    sample_pixels, sigma_clipped_stats_with, write_fits_f32, ClipBuffers, FitsHeader, FitsImage,
+    HeaderCard,
+};
+use crate::stacking::{read_raw, FrameFiles, BAND_VALUES};
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Kind of calibration (or light) frame, from IMAGETYP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameType {
+    Bias,
+    Dark,
+    Flat,
+    Light,
+}
+
+impl FrameType {
+    /// Parse IMAGETYP, accepting the spellings of common capture programs
+    /// ("Bias Frame", "ZERO", "Dark Frame", "FLAT", "Light Frame", "object")
+    pub fn from_header(header: &FitsHeader) -> Option<Self> {
+        let kind = header.get("IMAGETYP")?.to_ascii_lowercase();
+        if kind.contains("bias") || kind.contains("zero") || kind.contains("offset") {
+            Some(FrameType::Bias)
+        } else if kind.contains("dark") {
+            Some(FrameType::Dark)
+        } else if kind.contains("flat") {
+            Some(FrameType::Flat)
+        } else if kind.contains("light") || kind.contains("object") || kind.contains("science") {
+            Some(FrameType::Light)
+        } else {
+            None
+        }
+    }
+
+    /// IMAGETYP of the master made from frames of this kind
+    fn master_name(self) -> &'static str {
+        match self {
+            FrameType::Bias => "Master Bias",
+            FrameType::Dark => "Master Dark",
+            FrameType::Flat => "Master Flat",
+            FrameType::Light => "Master Light",
+        }
+    }
+}
+
+/// How the frames are combined pixel by pixel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CombineMethod {
+    Average,
+    Median,
+    /// Average of the values within kappa sigma of the median, drops cosmic
+    /// rays, satellites and the odd hot frame
+    #[default]
+    SigmaClip,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CombineParams {
+    pub method: CombineMethod,
+    /// Rejection threshold in sigmas, for sigma clipping
+    pub kappa: f32,
+    /// Clipping passes per pixel
+    pub iterations: usize,
+}
+
+impl Default for CombineParams {
+    fn default() -> Self {
+        Self {
+            method: CombineMethod::SigmaClip,
+            kappa: 3.0,
+            iterations: 3,
+        }
+    }
+}
+
+/// Calibration files that are combined into one master: the same kind, and
+/// for darks the same exposure and temperature, for flats the same filter
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct MasterGroup {
+    pub kind: FrameType,
+    /// EXPTIME of darks in milliseconds, so groups compare exactly
+    pub exposure_ms: Option<i64>,
+    /// CCD-TEMP of darks rounded to whole degrees
+    pub temperature: Option<i64>,
+    /// FILTER of flats
+    pub filter: Option<String>,
+    pub paths: Vec<String>,
+}
+
+impl MasterGroup {
+    /// File name of the master, e.g. `master_dark_300s_-10C.fits`
+    pub fn file_name(&self) -> String {
+        let mut name = format!("master_{:?}", self.kind).to_lowercase();
+        if let Some(exposure) = self.exposure_ms {
+            name.push_str(&format!("_{}s", exposure as f64 / 1000.0));
+        }
+        if let Some(temperature) = self.temperature {
+            name.push_str(&format!("_{}C", temperature));
+        }
+        if let Some(filter) = &self.filter {
+            let filter: String = filter
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            name.push_str(&format!("_{}", filter));
+        }
+        name + ".fits"
+    }
+}
+
+/// Sort calibration files into master groups by their headers, bias first,
+/// then darks and flats (the order they are built in). Lights and files
+/// without a usable IMAGETYP are returned separately.
+pub fn group_frames(paths: &[String]) -> Result<(Vec<MasterGroup>, Vec<String>)> {
+    let mut groups: Vec<MasterGroup> = Vec::new();
+    let mut skipped = Vec::new();
+
+    for path in paths {
+        let header = load_fits_header(path).with_context(|| format!("Failed to read {}", path))?;
+        let kind = match FrameType::from_header(&header) {
+            Some(kind) if kind != FrameType::Light => kind,
+            _ => {
+                skipped.push(path.clone());
+                continue;
+            }
+        };
+
+        let exposure_ms = (kind == FrameType::Dark)
+            .then(|| exposure_time(&header))
+            .flatten()
+            .map(|seconds| (seconds * 1000.0).round() as i64);
+        let temperature = (kind == FrameType::Dark)
+            .then(|| header.get_f64("CCD-TEMP"))
+            .flatten()
+            .map(|celsius| celsius.round() as i64);
+        let filter = (kind == FrameType::Flat)
+            .then(|| header.get("FILTER").map(str::to_string))
+            .flatten();
+
+        match groups.iter_mut().find(|g| {
+            g.kind == kind
+                && g.exposure_ms == exposure_ms
+                && g.temperature == temperature
+                && g.filter == filter
+        }) {
+            Some(group) => group.paths.push(path.clone()),
+            None => groups.push(MasterGroup {
+                kind,
+                exposure_ms,
+                temperature,
+                filter,
+                paths: vec![path.clone()],
+            }),
+        }
+    }
+
+    groups.sort();
+    Ok((groups, skipped))
+}
+
+/// Combine the frames of `group` into a master. Darks and flats have
+/// `bias` subtracted first when given; flats are then each scaled to a
+/// median of 1 so different exposures combine, and the master is
+/// normalised to a median of 1 as well.
+pub fn build_master(
+    group: &MasterGroup,
+    params: &CombineParams,
+    bias: Option<&FitsImage>,
+) -> Result<FitsImage> {
+    ensure!(!group.paths.is_empty(), "No frames to combine");
+
+    // Prepared frames wait on disk and are combined a band of rows at a
+    // time, so memory doesn't grow with the number of frames
+    let mut files = FrameFiles::new("master");
+    let mut frames = Vec::with_capacity(group.paths.len());
+    let mut size = None;
+    for path in &group.paths {
+        let mut image = load_fits_f32(path).with_context(|| format!("Failed to load {}", path))?;
+        let (width, height) = *size.get_or_insert((image.width, image.height));
+        ensure!(
+            image.width == width && image.height == height,
+            "{} is {}x{}, other frames are {}x{}",
+            path,
+            image.width,
+            image.height,
+            width,
+            height
+        );
+
+        if let (Some(bias), FrameType::Dark | FrameType::Flat) = (bias, group.kind) {
+            subtract_master(&mut image, bias, 1.0)?;
+        }
+        if group.kind == FrameType::Flat {
+            let scale = flat_level(&image.data)?;
+            image.data.iter_mut().for_each(|v| *v /= scale);
+        }
+        files.push(&image.data)?;
+        frames.push((path.clone(), image.header));
+    }
+
+    let (width, height) = size.context("No frames to combine")?;
+    let mut data = combine(&files.paths, width, height, params, BAND_VALUES)?;
+    if group.kind == FrameType::Flat {
+        let scale = flat_level(&data)?;
+        data.iter_mut().for_each(|v| *v /= scale);
+    }
+
+    let header = master_header(group, params, &frames, bias);
+    Ok(FitsImage {
+        path: String::new(),
+        stats: calculate_statistics(&data),
+        data,
+        width,
+        height,
+        header,
+        wcs: None,
+    })
+}
+
+/// Write `master` into `directory` under the group's file name, returning
+/// the path
+pub fn save_master(master: &mut FitsImage, group: &MasterGroup, directory: &str) -> Result<String> {
+    let path = Path::new(directory)
+        .join(group.file_name())
+        .to_string_lossy()
+        .into_owned();
+    write_fits_f32(
+        &path,
+        &master.data,
+        master.width,
+        master.height,
+        &master.header,
+    )?;
+    master.path = path.clone();
+    Ok(path)
+}
+
+/// Darks within this many degrees of the light's CCD-TEMP count as matching
+const DARK_TEMPERATURE_TOLERANCE: f64 = 2.0;
+
+/// Flat pixels below this (of the normalised level) are left uncorrected
+/// rather than blowing up dust shadows and vignetted corners
+const MIN_FLAT_LEVEL: f32 = 0.05;
+
+/// A master dark with what is needed to match it to a light
+struct MasterDark {
+    image: FitsImage,
+    exposure: Option<f64>,
+    temperature: Option<f64>,
+    /// Bias removed, so only the thermal signal is left and it scales with
+    /// exposure
+    thermal: bool,
+}
+
+/// Master frames to calibrate lights with
+#[derive(Default)]
+pub struct CalibrationSet {
+    bias: Option<FitsImage>,
+    darks: Vec<MasterDark>,
+    /// Normalised to a median of 1
+    flats: Vec<FitsImage>,
+    /// Hot pixels and bad columns of the longest dark
+    defects: Option<DefectMap>,
+}
+
+impl CalibrationSet {
+    /// Load masters (e.g. from `build_master`), sorted by IMAGETYP. Darks
+    /// still containing the bias have it removed when a bias is given, flats
+    /// are normalised.
+    pub fn load(paths: &[String]) -> Result<Self> {
+        let mut set = CalibrationSet::default();
+        let mut darks = Vec::new();
+        for path in paths {
+            let mut image =
+                load_fits_f32(path).with_context(|| format!("Failed to load {}", path))?;
+            match FrameType::from_header(&image.header) {
+                Some(FrameType::Bias) => {
+                    ensure!(set.bias.is_none(), "More than one master bias");
+                    set.bias = Some(image);
+                }
+                Some(FrameType::Dark) => darks.push(image),
+                Some(FrameType::Flat) => {
+                    let level = flat_level(&image.data)?;
+                    image.data.iter_mut().for_each(|v| *v /= level);
+                    set.flats.push(image);
+                }
+                _ => bail!("{} is not a master bias, dark or flat (IMAGETYP)", path),
+            }
+        }
+
+        for mut image in darks {
+            let mut thermal = image.header.get("CALBIAS").is_some();
+            if let (false, Some(bias)) = (thermal, &set.bias) {
+                subtract_master(&mut image, bias, 1.0)?;
+                thermal = true;
+            }
+            set.darks.push(MasterDark {
+                exposure: exposure_time(&image.header),
+                temperature: image.header.get_f64("CCD-TEMP"),
+                image,
+                thermal,
+            });
+        }
+
+        let longest = set.darks.iter().max_by(|a, b| {
+            a.exposure
+                .unwrap_or(0.0)
+                .total_cmp(&b.exposure.unwrap_or(0.0))
+        });
+        set.defects =
+            longest.map(|dark| DefectMap::from_dark(&dark.image, &CosmeticParams::default()));
+
+        Ok(set)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bias.is_none() && self.darks.is_empty() && self.flats.is_empty()
+    }
+
+    /// Defect map from the darks, for cosmetic correction
+    pub fn defects(&self) -> Option<&DefectMap> {
+        self.defects.as_ref()
+    }
+
+    /// File names of the loaded masters, bias first
+    pub fn file_names(&self) -> Vec<String> {
+        self.bias
+            .iter()
+            .chain(self.darks.iter().map(|d| &d.image))
+            .chain(&self.flats)
+            .map(|image| file_name(&image.path))
+            .collect()
+    }
+
+    /// Calibrate a light in place: subtract bias and the dark matching its
+    /// CCD-TEMP (scaled to its EXPTIME when the dark is bias free), then
+    /// divide by the flat of its FILTER. Returns what was applied, for the
+    /// log. Frames calibrated before, and stacks, are left as they are.
+    pub fn calibrate(&self, image: &mut FitsImage) -> Result<String> {
+        // Calibrated frames and stacks of them are left alone
+        if ["CALBIAS", "CALDARK", "CALFLAT", "NCOMBINE"]
+            .iter()
+            .any(|key| image.header.get(key).is_some())
+        {
+            return Ok("already calibrated".to_string());
+        }
+
+        let mut applied = Vec::new();
+        let exposure = exposure_time(&image.header);
+        let temperature = image.header.get_f64("CCD-TEMP");
+        let dark = self.choose_dark(exposure, temperature);
+
+        // A dark that still has the bias in it takes care of both
+        if let Some(bias) = &self.bias {
+            if dark.is_none_or(|d| d.thermal) {
+                subtract_master(image, bias, 1.0)?;
+                image.header.set(HeaderCard::text(
+                    "CALBIAS",
+                    &file_name(&bias.path),
+                    "Master bias subtracted",
+                ));
+                applied.push("bias".to_string());
+            }
+        }
+
+        if let Some(dark) = dark {
+            let scale = match (dark.thermal, exposure, dark.exposure) {
+                (true, Some(light), Some(dark)) if dark > 0.0 => light / dark,
+                _ => 1.0,
+            };
+            subtract_master(image, &dark.image, scale as f32)?;
+            image.header.set(HeaderCard::text(
+                "CALDARK",
+                &file_name(&dark.image.path),
+                "Master dark subtracted",
+            ));
+            image.header.set(HeaderCard::number(
+                "DARKSCAL",
+                scale,
+                "Dark scaled by exposure",
+            ));
+            let mut step = format!("dark x{:.3}", scale);
+            if let (Some(light), Some(dark)) = (temperature, dark.temperature) {
+                if (light - dark).abs() > DARK_TEMPERATURE_TOLERANCE {
+                    step.push_str(&format!(" (at {:.1}°C for {:.1}°C)", dark, light));
+                }
+            }
+            applied.push(step);
+        }
+
+        let filter = image.header.get("FILTER").map(str::to_string);
+        if let Some(flat) = self.choose_flat(filter.as_deref()) {
+            ensure!(
+                image.width == flat.width && image.height == flat.height,
+                "Master flat is {}x{}, frame is {}x{}",
+                flat.width,
+                flat.height,
+                image.width,
+                image.height
+            );
+            image
+                .data
+                .iter_mut()
+                .zip(&flat.data)
+                .filter(|(_, f)| **f >= MIN_FLAT_LEVEL)
+                .for_each(|(v, f)| *v /= f);
+            image.header.set(HeaderCard::text(
+                "CALFLAT",
+                &file_name(&flat.path),
+                "Divided by normalised master flat",
+            ));
+            applied.push("flat".to_string());
+        } else if !self.flats.is_empty() {
+            applied.push(format!("no flat for filter {}", filter.unwrap_or_default()));
+        }
+
+        image.stats = calculate_statistics(&image.data);
+        let summary = applied.join(", ");
+        image
+            .header
+            .push(HeaderCard::history(&format!("Calibrated: {}", summary)));
+        Ok(summary)
+    }
+
+    /// Dark closest in temperature (any within the tolerance will do), then
+    /// in exposure
+    fn choose_dark(&self, exposure: Option<f64>, temperature: Option<f64>) -> Option<&MasterDark> {
+        let score = |dark: &MasterDark| {
+            let temperature = match (temperature, dark.temperature) {
+                (Some(a), Some(b)) => ((a - b).abs() - DARK_TEMPERATURE_TOLERANCE).max(0.0),
+                _ => 0.0,
+            };
+            let exposure = match (exposure, dark.exposure) {
+                (Some(a), Some(b)) => (a - b).abs(),
+                _ => 0.0,
+            };
+            (temperature, exposure)
+        };
+        self.darks.iter().min_by(|a, b| {
+            let (a, b) = (score(a), score(b));
+            a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+        })
+    }
+
+    /// Flat of the same filter, or the only flat when filters are unknown
+    fn choose_flat(&self, filter: Option<&str>) -> Option<&FitsImage> {
+        let matching = self
+            .flats
+            .iter()
+            .find(|flat| match (filter, flat.header.get("FILTER")) {
+                (Some(a), Some(b)) => a.trim().eq_ignore_ascii_case(b.trim()),
+                _ => false,
+            });
+        match matching {
+            Some(flat) => Some(flat),
+            None if self.flats.len() == 1
+                && (filter.is_none() || self.flats[0].header.get("FILTER").is_none()) =>
+            {
+                self.flats.first()
+            }
+            None => None,
+        }
+    }
+}
+
+/// Calibrate `path` with `masters` and write it to `directory`
+/// as `<name>_cal.fits`, returning the new path
+pub fn calibrate_file(masters: &CalibrationSet, path: &str, directory: &str) -> Result<String> {
+    let mut image = load_fits_f32(path).with_context(|| format!("Failed to load {}", path))?;
+    masters.calibrate(&mut image)?;
+
+    let output = derived_path(path, directory, "_cal")?;
+    write_fits_f32(
+        &output,
+        &image.data,
+        image.width,
+        image.height,
+        &image.header,
+    )?;
+    Ok(output)
+}
+
+/// EXPTIME, or EXPOSURE as some programs write it, in seconds
+pub fn exposure_time(header: &FitsHeader) -> Option<f64> {
+    header
+        .get_f64("EXPTIME")
+        .or_else(|| header.get_f64("EXPOSURE"))
+}
+
+/// Subtract a master bias (or dark, times `scale`) of the same size from
+/// `image`
+pub fn subtract_master(image: &mut FitsImage, master: &FitsImage, scale: f32) -> Result<()> {
+    ensure!(
+        image.width == master.width && image.height == master.height,
+        "Master is {}x{}, frame is {}x{}",
+        master.width,
+        master.height,
+        image.width,
+        image.height
+    );
+    image
+        .data
+        .iter_mut()
+        .zip(&master.data)
+        .for_each(|(v, m)| *v -= m * scale);
+    Ok(())
+}
+
+/// Median of a flat, which it is divided by to normalise it
+fn flat_level(data: &[f32]) -> Result<f32> {
+    let mut sample: Vec<f32> = sample_pixels(data, 200_000)
+        .into_iter()
+        .filter(|v| v.is_finite())
+        .collect();
+    let level = median_in_place(&mut sample);
+    ensure!(level > 0.0, "Flat has no signal (median {})", level);
+    Ok(level)
+}
+
+/// Combine the raw frame files pixel by pixel, a band of rows at a time with
+/// at most `band_values` values of all frames together
+fn combine(
+    files: &[PathBuf],
+    width: usize,
+    height: usize,
+    params: &CombineParams,
+    band_values: usize,
+) -> Result<Vec<f32>> {
+    let band_rows = (band_values / (files.len() * width).max(1)).clamp(1, height.max(1));
+    let mut readers = files
+        .iter()
+        .map(File::open)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut data = Vec::with_capacity(width * height);
+    let mut bands = vec![Vec::new(); files.len()];
+    let mut values = Vec::with_capacity(files.len());
+    let mut buffers = ClipBuffers::default();
+    for start in (0..height).step_by(band_rows) {
+        let rows = band_rows.min(height - start);
+        for (reader, band) in readers.iter_mut().zip(&mut bands) {
+            read_raw(reader, start * width, rows * width, band)?;
+        }
+
+        for i in 0..rows * width {
+            values.clear();
+            values.extend(bands.iter().map(|band| band[i]).filter(|v| v.is_finite()));
+            data.push(combine_values(&mut values, params, &mut buffers));
+        }
+    }
+    Ok(data)
+}
+
+/// Combined value of one pixel's stack (reorders `values`)
+fn combine_values(values: &mut [f32], params: &CombineParams, buffers: &mut ClipBuffers) -> f32 {
+    if values.is_empty() {
+        return f32::NAN;
+    }
+    let average = |values: &[f32]| values.iter().sum::<f32>() / values.len() as f32;
+
+    match params.method {
+        CombineMethod::Average => average(values),
+        CombineMethod::Median => median_in_place(values),
+        // Too few values to tell outliers apart
+        CombineMethod::SigmaClip if values.len() < 3 => median_in_place(values),
+        CombineMethod::SigmaClip => {
+            let (median, sigma) =
+                sigma_clipped_stats_with(values, params.kappa, params.iterations, buffers);
+            if sigma == 0.0 {
+                return median;
+            }
+            let (sum, count) = values
+                .iter()
+                .filter(|v| (*v - median).abs() <= params.kappa * sigma)
+                .fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+            if count == 0 {
+                median
+            } else {
+                sum / count as f32
+            }
+        }
+    }
+}
+
+/// Header of the first of `frames` (path and header of each) with provenance: what the master is, how it was
+/// combined and from which files
+fn master_header(
+    group: &MasterGroup,
+    params: &CombineParams,
+    frames: &[(String, FitsHeader)],
+    bias: Option<&FitsImage>,
+) -> FitsHeader {
+    let mut header = frames[0].1.clone();
+    for key in [
+        "DATE-OBS", "DATE-END", "TIME-OBS", "MJD-OBS", "JD", "OBJECT",
+    ] {
+        header.remove(key);
+    }
+    header.set(HeaderCard::text(
+        "IMAGETYP",
+        group.kind.master_name(),
+        "Type of image",
+    ));
+    header.set(HeaderCard::integer(
+        "NCOMBINE",
+        frames.len() as i64,
+        "Number of frames combined",
+    ));
+    let method = match params.method {
+        CombineMethod::Average => "average".to_string(),
+        CombineMethod::Median => "median".to_string(),
+        CombineMethod::SigmaClip => format!("sigma clip {} sigma", params.kappa),
+    };
+    header.set(HeaderCard::text(
+        "COMBINE",
+        &method,
+        "How frames were combined",
+    ));
+
+    // Darks are matched to lights by temperature, keep the average
+    let temperatures: Vec<f64> = frames
+        .iter()
+        .filter_map(|(_, header)| header.get_f64("CCD-TEMP"))
+        .collect();
+    if !temperatures.is_empty() {
+        header.set(HeaderCard::number(
+            "CCD-TEMP",
+            temperatures.iter().sum::<f64>() / temperatures.len() as f64,
+            "Average sensor temperature (C)",
+        ));
+    }
+
+    if let Some(bias) = bias {
+        header.set(HeaderCard::text(
+            "CALBIAS",
+            &file_name(&bias.path),
+            "Master bias subtracted",
+        ));
+    }
+    if group.kind == FrameType::Flat {
+        header.push(HeaderCard::history(
+            "Each flat and the master normalised to median 1",
+        ));
+    }
+    for (path, _) in frames {
+        header.push(HeaderCard::history(&format!(
+            "Combined {}",
+            file_name(path)
+        )));
+    }
+    header
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fits::ImageStats;
+
+    /// 4 x 4 frame of `value` with the cards given
+    fn image(path: &str, value: f32, cards: Vec<HeaderCard>) -> FitsImage {
+        FitsImage {
+            path: path.to_string(),
+            data: vec![value; 16],
+            width: 4,
+            height: 4,
+            stats: ImageStats::default(),
+            header: FitsHeader { cards },
+            wcs: None,
+        }
+    }
+
+    fn light(value: f32, exposure: f64, temperature: f64) -> FitsImage {
+        image(
+            "light.fits",
+            value,
+            vec![
+                HeaderCard::text("IMAGETYP", "Light Frame", ""),
+                HeaderCard::number("EXPTIME", exposure, ""),
+                HeaderCard::number("CCD-TEMP", temperature, ""),
+            ],
+        )
+    }
+
+    fn dark(value: f32, exposure: f64, temperature: f64, thermal: bool) -> MasterDark {
+        MasterDark {
+            image: image(
+                &format!("dark_{}s_{}C.fits", exposure, temperature),
+                value,
+                Vec::new(),
+            ),
+            exposure: Some(exposure),
+            temperature: Some(temperature),
+            thermal,
+        }
+    }
+
+    fn flat(filter: Option<&str>) -> FitsImage {
+        let cards = filter
+            .map(|filter| vec![HeaderCard::text("FILTER", filter, "")])
+            .unwrap_or_default();
+        image(
+            &format!("flat_{}.fits", filter.unwrap_or("none")),
+            1.0,
+            cards,
+        )
+    }
+
+    fn chosen_dark(set: &CalibrationSet, exposure: f64, temperature: f64) -> String {
+        let dark = set.choose_dark(Some(exposure), Some(temperature)).unwrap();
+        file_name(&dark.image.path)
+    }
+
+    fn chosen_flat(set: &CalibrationSet, filter: Option<&str>) -> Option<String> {
+        set.choose_flat(filter).map(|flat| file_name(&flat.path))
+    }
+
+    #[test]
+    fn darks_are_named_by_exposure_and_temperature() {
+        let group = MasterGroup {
+            kind: FrameType::Dark,
+            exposure_ms: Some(300_000),
+            temperature: Some(-10),
+            filter: None,
+            paths: Vec::new(),
+        };
+        assert_eq!(group.file_name(), "master_dark_300s_-10C.fits");
+    }
+
+    #[test]
+    fn master_header_counts_frames_as_an_integer() {
+        let group = MasterGroup {
+            kind: FrameType::Dark,
+            exposure_ms: Some(300_000),
+            temperature: Some(-10),
+            filter: None,
+            paths: Vec::new(),
+        };
+        let frames: Vec<(String, FitsHeader)> = [-10.2, -9.8, -10.0]
+            .iter()
+            .map(|&t| {
+                let dark = image(
+                    "dark.fits",
+                    0.0,
+                    vec![HeaderCard::number("CCD-TEMP", t, "")],
+                );
+                (dark.path, dark.header)
+            })
+            .collect();
+        let header = master_header(&group, &CombineParams::default(), &frames, None);
+
+        assert_eq!(header.get("NCOMBINE"), Some("3"));
+        assert_eq!(header.get_i64("NCOMBINE"), Some(3));
+        assert_eq!(header.get("IMAGETYP"), Some("Master Dark"));
+        let temperature = header.get_f64("CCD-TEMP").unwrap();
+        assert!((temperature + 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bias_free_dark_is_scaled_to_the_exposure() {
+        let set = CalibrationSet {
+            bias: Some(image("bias.fits", 100.0, Vec::new())),
+            darks: vec![dark(10.0, 100.0, -10.0, true)],
+            ..Default::default()
+        };
+        let mut frame = light(200.0, 50.0, -10.0);
+        let applied = set.calibrate(&mut frame).unwrap();
+
+        assert_eq!(applied, "bias, dark x0.500");
+        assert!(frame.data.iter().all(|&v| v == 95.0));
+        assert_eq!(frame.header.get_f64("DARKSCAL"), Some(0.5));
+        assert_eq!(frame.header.get("CALBIAS"), Some("bias.fits"));
+    }
+
+    #[test]
+    fn dark_with_the_bias_in_it_replaces_the_bias() {
+        let set = CalibrationSet {
+            bias: Some(image("bias.fits", 100.0, Vec::new())),
+            darks: vec![dark(110.0, 100.0, -10.0, false)],
+            ..Default::default()
+        };
+        let mut frame = light(200.0, 50.0, -10.0);
+        let applied = set.calibrate(&mut frame).unwrap();
+
+        // Bias and dark current both scale 1, it can't be split
+        assert_eq!(applied, "dark x1.000");
+        assert!(frame.data.iter().all(|&v| v == 90.0));
+        assert_eq!(frame.header.get("CALBIAS"), None);
+    }
+
+    #[test]
+    fn dark_is_chosen_by_temperature_then_exposure() {
+        let set = CalibrationSet {
+            darks: vec![
+                dark(0.0, 300.0, -10.0, true),
+                dark(0.0, 60.0, -9.0, true),
+                dark(0.0, 300.0, 0.0, true),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(chosen_dark(&set, 300.0, -10.5), "dark_300s_-10C.fits");
+        // Both within the tolerance, the exposure decides
+        assert_eq!(chosen_dark(&set, 60.0, -10.5), "dark_60s_-9C.fits");
+        assert_eq!(chosen_dark(&set, 300.0, -8.0), "dark_300s_-10C.fits");
+        // None within it, the closest temperature wins over the exposure
+        assert_eq!(chosen_dark(&set, 60.0, 5.0), "dark_300s_0C.fits");
+    }
+
+    #[test]
+    fn dark_outside_the_tolerance_is_noted() {
+        let set = CalibrationSet {
+            darks: vec![dark(10.0, 100.0, -10.0, true)],
+            ..Default::default()
+        };
+        let mut frame = light(200.0, 100.0, 0.0);
+        let applied = set.calibrate(&mut frame).unwrap();
+        assert_eq!(applied, "dark x1.000 (at -10.0°C for 0.0°C)");
+    }
+
+    #[test]
+    fn flat_is_chosen_by_filter() {
+        let set = CalibrationSet {
+            flats: vec![flat(Some("L")), flat(Some("Ha"))],
+            ..Default::default()
+        };
+        assert_eq!(
+            chosen_flat(&set, Some(" ha")).as_deref(),
+            Some("flat_Ha.fits")
+        );
+        assert_eq!(chosen_flat(&set, Some("OIII")), None);
+        assert_eq!(chosen_flat(&set, None), None);
+    }
+
+    #[test]
+    fn single_flat_is_used_when_a_filter_is_unknown() {
+        let unnamed = CalibrationSet {
+            flats: vec![flat(None)],
+            ..Default::default()
+        };
+        assert_eq!(
+            chosen_flat(&unnamed, Some("L")).as_deref(),
+            Some("flat_none.fits")
+        );
+
+        let named = CalibrationSet {
+            flats: vec![flat(Some("L"))],
+            ..Default::default()
+        };
+        assert_eq!(chosen_flat(&named, None).as_deref(), Some("flat_L.fits"));
+        // A known, different filter is not the same flat
+        assert_eq!(chosen_flat(&named, Some("Ha")), None);
+    }
+
+    #[test]
+    fn calibrated_frames_are_left_alone() {
+        let set = CalibrationSet {
+            bias: Some(image("bias.fits", 100.0, Vec::new())),
+            ..Default::default()
+        };
+        let mut frame = light(200.0, 60.0, -10.0);
+        frame
+            .header
+            .set(HeaderCard::text("CALDARK", "dark.fits", ""));
+        assert_eq!(set.calibrate(&mut frame).unwrap(), "already calibrated");
+        assert!(frame.data.iter().all(|&v| v == 200.0));
+    }
+
+    #[test]
+    fn masters_are_the_same_across_band_boundaries() {
+        // 7 frames of 5 x 9 with noise, one of them hit by a cosmic ray
+        // in every pixel of rows 4 and 8
+        let (width, height) = (5, 9);
+        let mut files = FrameFiles::new("test-master-bands");
+        for frame in 0..7 {
+            let data: Vec<f32> = (0..width * height)
+                .map(|i| match frame {
+                    3 if [4, 8].contains(&(i / width)) => 5000.0,
+                    _ => 100.0 + ((i * 5 + frame * 3) % 7) as f32 * 0.5,
+                })
+                .collect();
+            files.push(&data).unwrap();
+        }
+
+        for method in [
+            CombineMethod::Average,
+            CombineMethod::Median,
+            CombineMethod::SigmaClip,
+        ] {
+            let params = CombineParams {
+                method,
+                ..Default::default()
+            };
+            let whole = combine(&files.paths, width, height, &params, usize::MAX).unwrap();
+            // 2 rows per band, the last band a single row
+            let banded = combine(&files.paths, width, height, &params, 2 * 7 * width).unwrap();
+            assert_eq!(whole, banded, "{:?}", method);
+            assert_eq!(banded.len(), width * height);
+
+            if method != CombineMethod::Average {
+                assert!(
+                    banded.iter().all(|v| (100.0..=103.0).contains(v)),
+                    "{:?}: {:?}",
+                    method,
+                    banded
+                );
+            }
+        }
+    }
+}
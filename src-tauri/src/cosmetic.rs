@@ -0,0 +1,491 @@
+use crate::fits::{
+    calculate_statistics, derived_path, load_fits_f32, median_in_place, sample_pixels,
+    sigma_clipped_stats, write_fits_f32, FitsImage, HeaderCard,
+};
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+
+/// The brightest pixel beside a star's peak carries at least this share of
+/// it above the background (about 0.4 at 1.8 pixels FWHM), a hot pixel's
+/// neighbours carry much less
+const MAX_NEIGHBOUR_SHARE: f32 = 0.3;
+
+/// Where the defects to correct come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DefectSource {
+    /// Found in each frame against its local median
+    #[default]
+    Detect,
+    /// From the master dark of the loaded calibration
+    Dark,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CosmeticParams {
+    /// Pixels this many noise sigmas above their neighbours' median are hot
+    pub hot_sigma: f32,
+    /// Pixels this many noise sigmas below their neighbours' median are cold
+    pub cold_sigma: f32,
+    /// Columns whose offset from their neighbours stands out this many
+    /// sigmas among all columns are bad
+    pub column_sigma: f32,
+}
+
+impl Default for CosmeticParams {
+    fn default() -> Self {
+        Self {
+            hot_sigma: 6.0,
+            cold_sigma: 6.0,
+            column_sigma: 5.0,
+        }
+    }
+}
+
+/// Bad pixels and columns of a sensor
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DefectMap {
+    pub width: usize,
+    pub height: usize,
+    /// Pixel indices (y * width + x)
+    pub hot: Vec<usize>,
+    pub cold: Vec<usize>,
+    pub columns: Vec<usize>,
+}
+
+impl DefectMap {
+    /// Find isolated hot and cold pixels, and bad columns, in a light frame
+    pub fn detect(data: &[f32], width: usize, height: usize, params: &CosmeticParams) -> Self {
+        let (background, sigma) = noise(data);
+        let mut map = DefectMap {
+            width,
+            height,
+            columns: bad_columns(data, width, height, sigma, params),
+            ..Default::default()
+        };
+        if sigma <= 0.0 || width < 3 || height < 3 {
+            return map;
+        }
+
+        let mut neighbours = [0.0f32; 8];
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                if map.columns.binary_search(&x).is_ok() {
+                    continue;
+                }
+                let value = data[y * width + x];
+                if !value.is_finite() {
+                    continue;
+                }
+
+                let mut count = 0;
+                let mut brightest_side = f32::MIN;
+                let mut faintest = f32::MAX;
+                for (dx, dy) in NEIGHBOURS {
+                    let v = data[(y as isize + dy) as usize * width + (x as isize + dx) as usize];
+                    if v.is_finite() {
+                        neighbours[count] = v;
+                        count += 1;
+                        faintest = faintest.min(v);
+                        if dx == 0 || dy == 0 {
+                            brightest_side = brightest_side.max(v);
+                        }
+                    }
+                }
+                if count < 4 {
+                    continue;
+                }
+                let local = median_in_place(&mut neighbours[..count]);
+
+                // Even a sharp star spreads into the pixels beside its peak,
+                // a hot pixel does not. A cold pixel is below all of its
+                // neighbours, unlike the wing of a star.
+                let excess = value - local;
+                if excess > params.hot_sigma * sigma
+                    && brightest_side - background < MAX_NEIGHBOUR_SHARE * (value - background)
+                {
+                    map.hot.push(y * width + x);
+                } else if -excess > params.cold_sigma * sigma && value < faintest {
+                    map.cold.push(y * width + x);
+                }
+            }
+        }
+        map
+    }
+
+    /// Hot pixels and columns of a master dark, which shows them without
+    /// stars in the way
+    pub fn from_dark(dark: &FitsImage, params: &CosmeticParams) -> Self {
+        let (background, sigma) = noise(&dark.data);
+        let hot = if sigma > 0.0 {
+            dark.data
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| **v > background + params.hot_sigma * sigma)
+                .map(|(i, _)| i)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        DefectMap {
+            width: dark.width,
+            height: dark.height,
+            hot,
+            cold: Vec::new(),
+            columns: bad_columns(&dark.data, dark.width, dark.height, sigma, params),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hot.is_empty() && self.cold.is_empty() && self.columns.is_empty()
+    }
+
+    /// Replace the defects of `image` with their good neighbours: columns by
+    /// the average of the nearest good columns, pixels by the median of the
+    /// good pixels around them. Returns a summary for the log.
+    pub fn correct(&self, image: &mut FitsImage) -> String {
+        let (width, height) = (image.width, image.height);
+        if width != self.width || height != self.height {
+            return format!(
+                "defect map is {}x{}, frame is {}x{}, not corrected",
+                self.width, self.height, width, height
+            );
+        }
+
+        let mut bad = vec![false; width * height];
+        for &x in &self.columns {
+            (0..height).for_each(|y| bad[y * width + x] = true);
+        }
+        for &i in self.hot.iter().chain(&self.cold) {
+            bad[i] = true;
+        }
+
+        let data = &mut image.data;
+        let is_bad_column = |x: usize| self.columns.binary_search(&x).is_ok();
+        for &x in &self.columns {
+            let left = (0..x).rev().find(|&c| !is_bad_column(c));
+            let right = (x + 1..width).find(|&c| !is_bad_column(c));
+            for y in 0..height {
+                let row = y * width;
+                data[row + x] = match (left, right) {
+                    (Some(l), Some(r)) => (data[row + l] + data[row + r]) / 2.0,
+                    (Some(c), None) | (None, Some(c)) => data[row + c],
+                    (None, None) => data[row + x],
+                };
+            }
+        }
+
+        let mut good = Vec::with_capacity(24);
+        for &i in self.hot.iter().chain(&self.cold) {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            good.clear();
+            for radius in 1..=2isize {
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let (nx, ny) = (x + dx, y + dy);
+                        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                            continue;
+                        }
+                        let n = ny as usize * width + nx as usize;
+                        if !bad[n] && data[n].is_finite() {
+                            good.push(data[n]);
+                        }
+                    }
+                }
+                // Widen only when the nearest ring is all defects
+                if !good.is_empty() {
+                    break;
+                }
+            }
+            if !good.is_empty() {
+                data[i] = median_in_place(&mut good);
+            }
+        }
+
+        image.stats = calculate_statistics(&image.data);
+        image.header.set(HeaderCard::integer(
+            "HOTPIX",
+            self.hot.len() as i64,
+            "Hot pixels corrected",
+        ));
+        image.header.set(HeaderCard::integer(
+            "COLDPIX",
+            self.cold.len() as i64,
+            "Cold pixels corrected",
+        ));
+        image.header.set(HeaderCard::integer(
+            "BADCOLS",
+            self.columns.len() as i64,
+            "Bad columns corrected",
+        ));
+        let summary = format!(
+            "{} hot, {} cold pixels, {} columns",
+            self.hot.len(),
+            self.cold.len(),
+            self.columns.len()
+        );
+        image.header.push(HeaderCard::history(&format!(
+            "Cosmetic correction: {}",
+            summary
+        )));
+        summary
+    }
+}
+
+/// Correct `image` with `defects`, or those detected in it. Returns a
+/// summary for the log.
+pub fn correct_image(image: &mut FitsImage, defects: Option<&DefectMap>) -> String {
+    match defects {
+        Some(defects) => defects.correct(image),
+        None => DefectMap::detect(
+            &image.data,
+            image.width,
+            image.height,
+            &CosmeticParams::default(),
+        )
+        .correct(image),
+    }
+}
+
+/// Correct `path` with `defects` (or those detected in it) and write it to
+/// `directory` as `<name>_cc.fits`, returning the new path
+pub fn correct_file(path: &str, directory: &str, defects: Option<&DefectMap>) -> Result<String> {
+    let mut image = load_fits_f32(path).with_context(|| format!("Failed to load {}", path))?;
+    correct_image(&mut image, defects);
+
+    let output = derived_path(path, directory, "_cc")?;
+    write_fits_f32(
+        &output,
+        &image.data,
+        image.width,
+        image.height,
+        &image.header,
+    )?;
+    Ok(output)
+}
+
+const NEIGHBOURS: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// Sigma-clipped background and noise
+fn noise(data: &[f32]) -> (f32, f32) {
+    sigma_clipped_stats(&sample_pixels(data, 200_000), 3.0, 5)
+}
+
+/// Columns offset from the columns beside them. Each column's median
+/// difference to its neighbours is compared with those of all columns, and
+/// must also be a sizeable part of the pixel noise `sigma`.
+fn bad_columns(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    sigma: f32,
+    params: &CosmeticParams,
+) -> Vec<usize> {
+    if width < 3 || height == 0 {
+        return Vec::new();
+    }
+
+    let mut differences = Vec::with_capacity(height);
+    let mut beside = Vec::with_capacity(4);
+    let offsets: Vec<f32> = (0..width)
+        .map(|x| {
+            // Two columns either side, so one bad column doesn't make its
+            // neighbours look bad too
+            let columns: Vec<usize> = [x.wrapping_sub(2), x.wrapping_sub(1), x + 1, x + 2]
+                .into_iter()
+                .filter(|&c| c < width)
+                .collect();
+            differences.clear();
+            for y in 0..height {
+                let row = y * width;
+                beside.clear();
+                beside.extend(
+                    columns
+                        .iter()
+                        .map(|&c| data[row + c])
+                        .filter(|v| v.is_finite()),
+                );
+                let value = data[row + x];
+                if value.is_finite() && !beside.is_empty() {
+                    differences.push(value - median_in_place(&mut beside));
+                }
+            }
+            median_in_place(&mut differences)
+        })
+        .collect();
+
+    let (centre, spread) = sigma_clipped_stats(&offsets, 3.0, 5);
+    if spread <= 0.0 {
+        return Vec::new();
+    }
+    offsets
+        .iter()
+        .enumerate()
+        .filter(|(_, offset)| {
+            let deviation = (**offset - centre).abs();
+            deviation > params.column_sigma * spread && deviation > 0.25 * sigma
+        })
+        .map(|(x, _)| x)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fits::{FitsHeader, ImageStats};
+    use crate::registration::Lcg;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 64;
+
+    /// Background of 100 with up to 2 of noise
+    fn noisy_frame() -> Vec<f32> {
+        let mut rng = Lcg(12345);
+        (0..WIDTH * HEIGHT)
+            .map(|_| 100.0 + (rng.fraction() as f32 - 0.5) * 4.0)
+            .collect()
+    }
+
+    fn image(data: Vec<f32>, width: usize, height: usize) -> FitsImage {
+        FitsImage {
+            path: String::new(),
+            data,
+            width,
+            height,
+            stats: ImageStats::default(),
+            header: FitsHeader::default(),
+            wcs: None,
+        }
+    }
+
+    #[test]
+    fn hot_pixel_is_found_but_sharp_star_is_not() {
+        let mut data = noisy_frame();
+        data[20 * WIDTH + 20] = 400.0;
+        data[30 * WIDTH + 10] = 20.0;
+        // 1.9 pixels FWHM, as sharp as stars get, peaking as high as the
+        // hot pixel
+        for dy in -3..=3i32 {
+            for dx in -3..=3i32 {
+                let r2 = (dx * dx + dy * dy) as f32;
+                let i = (40 + dy) as usize * WIDTH + (40 + dx) as usize;
+                data[i] += 300.0 * (-r2 / (2.0 * 0.8 * 0.8)).exp();
+            }
+        }
+
+        let map = DefectMap::detect(&data, WIDTH, HEIGHT, &CosmeticParams::default());
+        assert_eq!(map.hot, [20 * WIDTH + 20]);
+        assert_eq!(map.cold, [30 * WIDTH + 10]);
+        assert!(map.columns.is_empty());
+    }
+
+    #[test]
+    fn bad_column_is_found_without_its_neighbours() {
+        let mut data = noisy_frame();
+        (0..HEIGHT).for_each(|y| data[y * WIDTH + 30] += 20.0);
+        (0..HEIGHT).for_each(|y| data[y * WIDTH + 50] -= 20.0);
+
+        let map = DefectMap::detect(&data, WIDTH, HEIGHT, &CosmeticParams::default());
+        assert_eq!(map.columns, [30, 50]);
+        // Bright along the whole column, but not hot pixels
+        assert!(map.hot.is_empty() && map.cold.is_empty());
+    }
+
+    #[test]
+    fn adjacent_bad_columns_take_the_nearest_good_ones() {
+        // Rows of 0, 10, 20, ... with columns 2 and 3 stuck
+        let (width, height) = (6, 3);
+        let mut data: Vec<f32> = (0..width * height)
+            .map(|i| (i % width) as f32 * 10.0)
+            .collect();
+        (0..height).for_each(|y| {
+            data[y * width + 2] = 999.0;
+            data[y * width + 3] = 999.0;
+        });
+        let map = DefectMap {
+            width,
+            height,
+            columns: vec![2, 3],
+            ..Default::default()
+        };
+        let mut frame = image(data, width, height);
+        map.correct(&mut frame);
+
+        for row in frame.data.chunks(width) {
+            assert_eq!(row, [0.0, 10.0, 25.0, 25.0, 40.0, 50.0]);
+        }
+    }
+
+    #[test]
+    fn edge_column_takes_its_only_neighbour() {
+        let (width, height) = (4, 2);
+        let data = vec![999.0, 10.0, 20.0, 30.0, 999.0, 11.0, 21.0, 31.0];
+        let map = DefectMap {
+            width,
+            height,
+            columns: vec![0],
+            ..Default::default()
+        };
+        let mut frame = image(data, width, height);
+        map.correct(&mut frame);
+        assert_eq!(frame.data, [10.0, 10.0, 20.0, 30.0, 11.0, 11.0, 21.0, 31.0]);
+    }
+
+    #[test]
+    fn pixels_take_the_median_of_their_good_neighbours() {
+        let (width, height) = (7, 7);
+        let mut data: Vec<f32> = (0..width * height).map(|i| i as f32).collect();
+        // A hot pixel beside a cold one: neither counts for the other
+        data[3 * width + 3] = 5000.0;
+        data[3 * width + 4] = -5000.0;
+        let map = DefectMap {
+            width,
+            height,
+            hot: vec![3 * width + 3],
+            cold: vec![3 * width + 4],
+            ..Default::default()
+        };
+        let mut frame = image(data, width, height);
+        map.correct(&mut frame);
+
+        // Good pixels around (3, 3) are 16, 17, 18, 23, 30, 31 and 32, the
+        // cold one is left out
+        assert_eq!(frame.data[3 * width + 3], 23.0);
+        assert_eq!(frame.data[3 * width + 4], 26.0);
+        assert_eq!(frame.header.get_i64("HOTPIX"), Some(1));
+        assert_eq!(frame.header.get_i64("COLDPIX"), Some(1));
+        assert_eq!(frame.header.get_i64("BADCOLS"), Some(0));
+    }
+
+    #[test]
+    fn clusters_are_filled_from_the_wider_ring() {
+        let (width, height) = (7, 7);
+        let mut data = vec![100.0; width * height];
+        let mut hot = Vec::new();
+        for y in 2..5 {
+            for x in 2..5 {
+                data[y * width + x] = 5000.0;
+                hot.push(y * width + x);
+            }
+        }
+        let map = DefectMap {
+            width,
+            height,
+            hot,
+            ..Default::default()
+        };
+        let mut frame = image(data, width, height);
+        map.correct(&mut frame);
+        assert!(frame.data.iter().all(|&v| v == 100.0));
+    }
+}
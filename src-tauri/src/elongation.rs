@@ -0,0 +1,316 @@
+use crate::fits::median_in_place;
+use crate::renderer::OverlayLine;
+use crate::stars::Star;
+use serde::{Deserialize, Serialize};
+
+/// Eccentricities at or below this show green on the overlay, at or above
+/// `TRAILED` red. Near 0.5 stars start to look oval.
+const ROUND: f32 = 0.3;
+const TRAILED: f32 = 0.7;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ElongationParams {
+    /// Cells across the longer side of the frame
+    pub grid: usize,
+    /// Cells with fewer stars are left empty
+    pub min_stars: usize,
+}
+
+impl Default for ElongationParams {
+    fn default() -> Self {
+        Self {
+            grid: 6,
+            min_stars: 3,
+        }
+    }
+}
+
+/// Star shapes in one cell of the field
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ElongationCell {
+    /// Pixel bounds, x0..x1 and y0..y1
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+    pub stars: usize,
+    /// Median eccentricity of the cell's stars
+    pub eccentricity: f32,
+    /// Mean long axis direction, degrees from +x towards +y (0 to 180)
+    pub angle_deg: f32,
+}
+
+/// How elongated the stars of a frame are, and in which direction.
+/// Tracking and guiding errors trail every star the same way; tilt and field
+/// curvature elongate stars differently across the field, leaving some of it
+/// round.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ElongationReport {
+    pub width: usize,
+    pub height: usize,
+    pub stars: usize,
+    /// Median eccentricity over the frame
+    pub eccentricity: f32,
+    /// Median long over short axis
+    pub elongation: f32,
+    /// Dominant long axis direction, degrees from +x towards +y (0 to 180)
+    pub angle_deg: f32,
+    /// How well the stars agree on that direction: 1 when all point the same
+    /// way, 0 when directions are random
+    pub coherence: f32,
+    pub cells: Vec<ElongationCell>,
+}
+
+/// Measure the star shapes of a `width` x `height` frame, overall and per
+/// cell of the field
+pub fn measure(
+    stars: &[Star],
+    width: usize,
+    height: usize,
+    params: &ElongationParams,
+) -> ElongationReport {
+    let mut report = ElongationReport {
+        width,
+        height,
+        stars: stars.len(),
+        ..Default::default()
+    };
+    if stars.is_empty() {
+        return report;
+    }
+
+    let mut eccentricities: Vec<f32> = stars.iter().map(|s| s.eccentricity as f32).collect();
+    report.eccentricity = median_in_place(&mut eccentricities);
+    report.elongation = 1.0 / (1.0 - report.eccentricity.powi(2)).max(1e-6).sqrt();
+    (report.angle_deg, report.coherence) = mean_direction(stars.iter());
+
+    // Square cells, as many as fit across the longer side
+    let cell_size = (width.max(height) as f64 / params.grid.max(1) as f64).ceil() as usize;
+    let cell_size = cell_size.max(1);
+    let (columns, rows) = (width.div_ceil(cell_size), height.div_ceil(cell_size));
+    let mut members: Vec<Vec<&Star>> = vec![Vec::new(); columns * rows];
+    for star in stars {
+        let column = (star.x.max(0.0) as usize / cell_size).min(columns - 1);
+        let row = (star.y.max(0.0) as usize / cell_size).min(rows - 1);
+        members[row * columns + column].push(star);
+    }
+
+    for (index, cell_stars) in members.iter().enumerate() {
+        if cell_stars.len() < params.min_stars.max(1) {
+            continue;
+        }
+        let (column, row) = (index % columns, index / columns);
+        let mut eccentricities: Vec<f32> =
+            cell_stars.iter().map(|s| s.eccentricity as f32).collect();
+        let (angle_deg, _) = mean_direction(cell_stars.iter().copied());
+        report.cells.push(ElongationCell {
+            x0: column * cell_size,
+            y0: row * cell_size,
+            x1: ((column + 1) * cell_size).min(width),
+            y1: ((row + 1) * cell_size).min(height),
+            stars: cell_stars.len(),
+            eccentricity: median_in_place(&mut eccentricities),
+            angle_deg,
+        });
+    }
+    report
+}
+
+/// Mean long axis direction of `stars` and how well they agree on it.
+/// Directions are averaged as doubled angles, since an axis at 179° points
+/// the same way as one at 1°, weighted by eccentricity so round stars (whose
+/// direction is noise) count little.
+fn mean_direction<'a>(stars: impl Iterator<Item = &'a Star>) -> (f32, f32) {
+    let (mut sum_cos, mut sum_sin, mut sum_weight) = (0.0f64, 0.0f64, 0.0f64);
+    for star in stars {
+        let weight = star.eccentricity * star.eccentricity;
+        let doubled = (2.0 * star.angle_deg).to_radians();
+        sum_cos += weight * doubled.cos();
+        sum_sin += weight * doubled.sin();
+        sum_weight += weight;
+    }
+    if sum_weight <= 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let angle = (0.5 * sum_sin.atan2(sum_cos))
+        .to_degrees()
+        .rem_euclid(180.0);
+    let coherence = sum_cos.hypot(sum_sin) / sum_weight;
+    (angle as f32, coherence as f32)
+}
+
+impl ElongationReport {
+    /// Heat map of the cells: each outlined and crossed by a whisker along
+    /// its stars' long axis, both coloured from green (round) to red
+    /// (trailed), the whisker longer the more elongated the stars
+    pub fn overlay(&self) -> Vec<OverlayLine> {
+        let mut lines = Vec::new();
+        for cell in &self.cells {
+            let heat = ((cell.eccentricity - ROUND) / (TRAILED - ROUND)).clamp(0.0, 1.0);
+            let color = [heat, 1.0 - heat, 0.2, 0.9];
+
+            let (x0, y0, x1, y1) = (
+                cell.x0 as f32,
+                cell.y0 as f32,
+                cell.x1 as f32,
+                cell.y1 as f32,
+            );
+            // Inset so neighbouring outlines stay apart
+            let inset = 0.04 * (x1 - x0).min(y1 - y0);
+            lines.push(OverlayLine {
+                points: vec![
+                    [x0 + inset, y0 + inset],
+                    [x1 - inset, y0 + inset],
+                    [x1 - inset, y1 - inset],
+                    [x0 + inset, y1 - inset],
+                    [x0 + inset, y0 + inset],
+                ],
+                color: [color[0], color[1], color[2], 0.35],
+            });
+
+            let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+            let half = 0.45 * (x1 - x0).min(y1 - y0) * cell.eccentricity;
+            let (sin, cos) = cell.angle_deg.to_radians().sin_cos();
+            lines.push(OverlayLine {
+                points: vec![
+                    [cx - half * cos, cy - half * sin],
+                    [cx + half * cos, cy + half * sin],
+                ],
+                color,
+            });
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn star(x: f64, y: f64, eccentricity: f64, angle_deg: f64) -> Star {
+        Star {
+            x,
+            y,
+            flux: 1000.0,
+            peak: 100.0,
+            fwhm: 3.0,
+            eccentricity,
+            angle_deg,
+        }
+    }
+
+    /// Angle between two axes, 0 to 90 degrees
+    fn axis_difference(a: f32, b: f32) -> f32 {
+        let d = (a - b).rem_euclid(180.0);
+        d.min(180.0 - d)
+    }
+
+    #[test]
+    fn opposite_angles_are_the_same_axis() {
+        let stars = [star(0.0, 0.0, 0.8, 0.0), star(0.0, 0.0, 0.8, 180.0)];
+        let (angle, coherence) = mean_direction(stars.iter());
+        assert!(axis_difference(angle, 0.0) < 1e-4, "angle {}", angle);
+        assert!((coherence - 1.0).abs() < 1e-6);
+
+        // Either side of the wrap around averages to it, not to 90°
+        let stars = [star(0.0, 0.0, 0.8, 10.0), star(0.0, 0.0, 0.8, 170.0)];
+        let (angle, coherence) = mean_direction(stars.iter());
+        assert!(axis_difference(angle, 0.0) < 1e-4, "angle {}", angle);
+        assert!((coherence - 20f32.to_radians().cos()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn perpendicular_trails_cancel() {
+        let stars = [star(0.0, 0.0, 0.8, 30.0), star(0.0, 0.0, 0.8, 120.0)];
+        let (_, coherence) = mean_direction(stars.iter());
+        assert!(coherence < 1e-6, "coherence {}", coherence);
+    }
+
+    #[test]
+    fn round_stars_hardly_count() {
+        let stars = [
+            star(0.0, 0.0, 0.9, 45.0),
+            star(0.0, 0.0, 0.1, 135.0),
+            star(0.0, 0.0, 0.1, 135.0),
+        ];
+        let (angle, _) = mean_direction(stars.iter());
+        assert!(axis_difference(angle, 45.0) < 1e-3, "angle {}", angle);
+        assert_eq!(
+            mean_direction([star(0.0, 0.0, 0.0, 60.0)].iter()),
+            (0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn cells_hold_the_median_of_their_stars() {
+        // 600 x 300 in 100 pixel cells: 6 columns, 3 rows
+        let mut stars = vec![
+            // Top left, trailed at 30°
+            star(10.0, 10.0, 0.8, 30.0),
+            star(50.0, 40.0, 0.8, 30.0),
+            star(90.0, 90.0, 0.8, 30.0),
+            // Next cell along, too few to show
+            star(150.0, 50.0, 0.5, 0.0),
+            star(160.0, 50.0, 0.5, 0.0),
+        ];
+        // Bottom right, round but for one; the last star sits on the edge
+        for (x, eccentricity) in [(510.0, 0.1), (550.0, 0.2), (590.0, 0.3), (600.0, 0.9)] {
+            stars.push(star(x, 250.0, eccentricity, 90.0));
+        }
+
+        let report = measure(&stars, 600, 300, &ElongationParams::default());
+        assert_eq!(report.stars, 9);
+        assert_eq!(report.cells.len(), 2);
+
+        let top_left = &report.cells[0];
+        assert_eq!(
+            (top_left.x0, top_left.y0, top_left.x1, top_left.y1),
+            (0, 0, 100, 100)
+        );
+        assert_eq!(top_left.stars, 3);
+        assert!((top_left.eccentricity - 0.8).abs() < 1e-6);
+        assert!(axis_difference(top_left.angle_deg, 30.0) < 1e-3);
+
+        let bottom_right = &report.cells[1];
+        assert_eq!(
+            (
+                bottom_right.x0,
+                bottom_right.y0,
+                bottom_right.x1,
+                bottom_right.y1
+            ),
+            (500, 200, 600, 300)
+        );
+        assert_eq!(bottom_right.stars, 4);
+        assert!((bottom_right.eccentricity - 0.25).abs() < 1e-6);
+        assert!(axis_difference(bottom_right.angle_deg, 90.0) < 1e-3);
+    }
+
+    #[test]
+    fn overlay_colours_cells_by_eccentricity() {
+        let cell = |eccentricity: f32| ElongationCell {
+            x0: 0,
+            y0: 0,
+            x1: 100,
+            y1: 100,
+            stars: 5,
+            eccentricity,
+            angle_deg: 0.0,
+        };
+        let report = ElongationReport {
+            cells: vec![cell(0.2), cell(0.9)],
+            ..Default::default()
+        };
+        let lines = report.overlay();
+        // An outline and a whisker per cell
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].color[..2], [0.0, 1.0]);
+        assert_eq!(lines[3].color[..2], [1.0, 0.0]);
+
+        // The whisker runs along the cell's axis, longer when more trailed
+        let length = |line: &OverlayLine| line.points[1][0] - line.points[0][0];
+        assert!(lines[1].points.iter().all(|p| p[1] == 50.0));
+        assert!(length(&lines[3]) > 4.0 * length(&lines[1]));
+    }
+}
@@ -1,6 +1,14 @@
+use crate::wcs::Wcs;
 use anyhow::{ensure, Result};
-use fitsio::{hdu::HduInfo, FitsFile};
+use fitsio::{
+    hdu::HduInfo,
+    images::{ImageDescription, ImageType},
+    FitsFile,
+};
 use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageStats {
@@ -12,11 +20,111 @@ pub struct ImageStats {
     pub histogram: Vec<u32>, // 256 bins
 }
 
+/// Placeholder stats for when no image is loaded
+impl Default for ImageStats {
+    fn default() -> Self {
+        Self {
+            min: 0.0,
+            max: 0.0,
+            mean: 0.0,
+            stddev: 0.0,
+            median: 0.0,
+            histogram: vec![0; 256],
+        }
+    }
+}
+
+/// A single header card as stored in the file (value kept in its raw FITS form)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderCard {
+    pub key: String,
+    pub value: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FitsHeader {
+    pub cards: Vec<HeaderCard>,
+}
+
+impl FitsHeader {
+    /// Value of a keyword with string quotes stripped
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let card = self
+            .cards
+            .iter()
+            .find(|c| c.key.eq_ignore_ascii_case(key))?;
+        let value = card.value.trim();
+        Some(
+            value
+                .strip_prefix('\'')
+                .and_then(|v| v.strip_suffix('\''))
+                .map(str::trim_end)
+                .unwrap_or(value),
+        )
+    }
+
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        // FITS allows Fortran-style 'D' exponents
+        self.get(key)?.replace(['D', 'd'], "E").parse().ok()
+    }
+
+    pub fn get_i64(&self, key: &str) -> Option<i64> {
+        self.get(key)?.parse().ok()
+    }
+
+    /// Replace the card with the same key, or append it
+    pub fn set(&mut self, card: HeaderCard) {
+        match self
+            .cards
+            .iter_mut()
+            .find(|c| c.key.eq_ignore_ascii_case(&card.key))
+        {
+            Some(existing) => *existing = card,
+            None => self.cards.push(card),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.cards.retain(|c| !c.key.eq_ignore_ascii_case(key));
+    }
+
+    /// Append a card even if the key exists, for HISTORY and COMMENT
+    pub fn push(&mut self, card: HeaderCard) {
+        self.cards.push(card);
+    }
+
+    /// ADU level at which the sensor (or the integer data type) saturates.
+    /// Uses SATURATE when present, otherwise the largest value BITPIX can hold
+    /// after BSCALE/BZERO. Floating point data has no implicit limit.
+    pub fn saturation_level(&self) -> Option<f32> {
+        if let Some(level) = self.get_f64("SATURATE") {
+            return Some(level as f32);
+        }
+
+        let bzero = self.get_f64("BZERO").unwrap_or(0.0);
+        let bscale = self.get_f64("BSCALE").unwrap_or(1.0);
+        let raw_max = match self.get_i64("BITPIX")? {
+            8 => u8::MAX as f64,
+            16 => i16::MAX as f64,
+            32 => i32::MAX as f64,
+            _ => return None,
+        };
+
+        Some((raw_max * bscale + bzero) as f32)
+    }
+}
+
 pub struct FitsImage {
+    /// File the image was read from
+    pub path: String,
     pub data: Vec<f32>,
     pub width: usize,
     pub height: usize,
     pub stats: ImageStats,
+    pub header: FitsHeader,
+    /// Celestial coordinates from the header, for plate-solved frames
+    pub wcs: Option<Wcs>,
 }
 
 pub fn load_fits_f32(path: &str) -> Result<FitsImage> {
@@ -34,19 +142,208 @@ pub fn load_fits_f32(path: &str) -> Result<FitsImage> {
     let (h, w) = (shape[0] as usize, shape[1] as usize);
 
     let data: Vec<f32> = hdu.read_image(&mut f)?;
+    let header = read_header(&mut f)?;
 
     // Calculate statistics
     let stats = calculate_statistics(&data);
+    let wcs = Wcs::from_header(&header);
 
     Ok(FitsImage {
+        path: path.to_string(),
         data,
         width: w,
         height: h,
         stats,
+        header,
+        wcs,
     })
 }
 
-fn calculate_statistics(data: &[f32]) -> ImageStats {
+/// Header of the primary HDU without reading the pixels, e.g. to sort files
+/// by IMAGETYP
+pub fn load_fits_header(path: &str) -> Result<FitsHeader> {
+    let mut f = FitsFile::open(path)?;
+    f.primary_hdu()?;
+    read_header(&mut f)
+}
+
+/// Write `data` as a 32-bit float image to `path` (replacing the file), with
+/// the cards of `header`. Keys describing the data layout are taken from the
+/// new image instead.
+pub fn write_fits_f32(
+    path: &str,
+    data: &[f32],
+    width: usize,
+    height: usize,
+    header: &FitsHeader,
+) -> Result<()> {
+    // Written by cfitsio for the new image, or stale once the data changed
+    const STRUCTURE_KEYS: &[&str] = &[
+        "SIMPLE", "BITPIX", "NAXIS", "NAXIS1", "NAXIS2", "EXTEND", "BZERO", "BSCALE", "BLANK",
+        "DATAMIN", "DATAMAX", "CHECKSUM", "DATASUM", "END",
+    ];
+    ensure!(
+        data.len() == width * height,
+        "image data does not match {}x{}",
+        width,
+        height
+    );
+
+    let description = ImageDescription {
+        data_type: ImageType::Float,
+        dimensions: &[height, width],
+    };
+    let mut f = FitsFile::create(path)
+        .with_custom_primary(&description)
+        .overwrite()
+        .open()?;
+    let hdu = f.primary_hdu()?;
+    hdu.write_image(&mut f, data)?;
+
+    let fptr = unsafe { f.as_raw() };
+    let mut status: c_int = 0;
+    for card in &header.cards {
+        if card.key.is_empty()
+            || STRUCTURE_KEYS
+                .iter()
+                .any(|key| card.key.eq_ignore_ascii_case(key))
+        {
+            continue;
+        }
+
+        let image = CString::new(card.to_card())?;
+        if card.is_commentary() {
+            unsafe {
+                fitsio::sys::ffprec(fptr, image.as_ptr(), &mut status);
+            }
+        } else {
+            let key = CString::new(card.key.as_str())?;
+            unsafe {
+                fitsio::sys::ffucrd(fptr, key.as_ptr(), image.as_ptr(), &mut status);
+            }
+        }
+        ensure!(
+            status == 0,
+            "failed to write {} (status {})",
+            card.key,
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/// Path in `directory` for a file made from `path`, named after it with
+/// `suffix`: `<name><suffix>.fits`
+pub fn derived_path(path: &str, directory: &str, suffix: &str) -> Result<String> {
+    let stem = Path::new(path)
+        .file_stem()
+        .map_or("frame".into(), |stem| stem.to_string_lossy());
+    let output = Path::new(directory)
+        .join(format!("{}{}.fits", stem, suffix))
+        .to_string_lossy()
+        .into_owned();
+    ensure!(output != path, "Output would overwrite {}", path);
+    Ok(output)
+}
+
+/// Read every card of the current HDU. fitsio only reads keys by name,
+/// so we walk the header through cfitsio directly.
+fn read_header(f: &mut FitsFile) -> Result<FitsHeader> {
+    // FLEN_CARD is 81, every field of a card fits in that
+    const FLEN_CARD: usize = 81;
+
+    let fptr = unsafe { f.as_raw() };
+    let mut status: c_int = 0;
+    let mut num_keys: c_int = 0;
+    let mut more_keys: c_int = 0;
+    unsafe {
+        fitsio::sys::ffghsp(fptr, &mut num_keys, &mut more_keys, &mut status);
+    }
+    ensure!(
+        status == 0,
+        "failed to read header size (status {})",
+        status
+    );
+
+    let mut cards = Vec::with_capacity(num_keys as usize);
+    for index in 1..=num_keys {
+        let mut key = [0 as c_char; FLEN_CARD];
+        let mut value = [0 as c_char; FLEN_CARD];
+        let mut comment = [0 as c_char; FLEN_CARD];
+        unsafe {
+            fitsio::sys::ffgkyn(
+                fptr,
+                index,
+                key.as_mut_ptr(),
+                value.as_mut_ptr(),
+                comment.as_mut_ptr(),
+                &mut status,
+            );
+        }
+        ensure!(
+            status == 0,
+            "failed to read header card {} (status {})",
+            index,
+            status
+        );
+
+        let to_string =
+            |buf: &[c_char]| unsafe { CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned() };
+        cards.push(HeaderCard {
+            key: to_string(&key),
+            value: to_string(&value),
+            comment: to_string(&comment),
+        });
+    }
+
+    Ok(FitsHeader { cards })
+}
+
+/// Write `cards` into the primary header of the file at `path` (updating
+/// existing keys) and delete the keys in `remove`
+pub fn update_header(path: &str, cards: &[HeaderCard], remove: &[&str]) -> Result<()> {
+    // cfitsio status for deleting a key that isn't there
+    const KEY_NO_EXIST: c_int = 202;
+
+    let mut f = FitsFile::edit(path)?;
+    let fptr = unsafe { f.as_raw() };
+    let mut status: c_int = 0;
+
+    for key in remove {
+        let key = CString::new(*key)?;
+        unsafe {
+            fitsio::sys::ffdkey(fptr, key.as_ptr(), &mut status);
+        }
+        if status == KEY_NO_EXIST {
+            status = 0;
+        }
+        ensure!(
+            status == 0,
+            "failed to delete {:?} (status {})",
+            key,
+            status
+        );
+    }
+
+    for card in cards {
+        let key = CString::new(card.key.as_str())?;
+        let image = CString::new(card.to_card())?;
+        unsafe {
+            fitsio::sys::ffucrd(fptr, key.as_ptr(), image.as_ptr(), &mut status);
+        }
+        ensure!(
+            status == 0,
+            "failed to write {} (status {})",
+            card.key,
+            status
+        );
+    }
+
+    Ok(())
+}
+
+pub fn calculate_statistics(data: &[f32]) -> ImageStats {
     // Filter out NaN and infinite values
     let valid_data: Vec<f32> = data.iter().copied().filter(|&x| x.is_finite()).collect();
 
@@ -126,3 +423,214 @@ pub fn calculate_auto_stretch(
 
     (sorted[low_idx], sorted[high_idx])
 }
+
+/// Median and standard deviation after iterative kappa-sigma clipping.
+/// Sigma is estimated from the median absolute deviation, so a few stars or
+/// hot pixels do not inflate it. Non-finite values are ignored.
+pub fn sigma_clipped_stats(values: &[f32], kappa: f32, iterations: usize) -> (f32, f32) {
+    sigma_clipped_stats_with(values, kappa, iterations, &mut ClipBuffers::default())
+}
+
+/// Working space of `sigma_clipped_stats_with`
+#[derive(Debug, Default)]
+pub struct ClipBuffers {
+    kept: Vec<f32>,
+    deviations: Vec<f32>,
+}
+
+/// `sigma_clipped_stats` in `buffers`, so clipping many small sets (such as
+/// one per pixel) doesn't allocate for each of them
+pub fn sigma_clipped_stats_with(
+    values: &[f32],
+    kappa: f32,
+    iterations: usize,
+    buffers: &mut ClipBuffers,
+) -> (f32, f32) {
+    let ClipBuffers { kept, deviations } = buffers;
+    kept.clear();
+    kept.extend(values.iter().copied().filter(|v| v.is_finite()));
+    if kept.is_empty() {
+        return (0.0, 0.0);
+    }
+
+    let mut median = 0.0;
+    let mut sigma = 0.0;
+    for _ in 0..=iterations {
+        median = median_in_place(kept);
+        deviations.clear();
+        deviations.extend(kept.iter().map(|v| (v - median).abs()));
+        sigma = 1.4826 * median_in_place(deviations);
+
+        let before = kept.len();
+        kept.retain(|v| (v - median).abs() <= kappa * sigma);
+        if kept.len() == before || kept.is_empty() || sigma == 0.0 {
+            break;
+        }
+    }
+
+    (median, sigma)
+}
+
+/// Median of `values` (reorders them). For an even count it is the average
+/// of the two middle values.
+pub fn median_in_place(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let (mid, odd) = (values.len() / 2, values.len() % 2 == 1);
+    let (below, median, _) = values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+    if odd || below.is_empty() {
+        return *median;
+    }
+    // The lower middle value is the largest of the lower half
+    let lower = below.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    (lower + *median) / 2.0
+}
+
+/// Every `step`-th pixel, enough for background statistics of large frames
+pub fn sample_pixels(data: &[f32], max_samples: usize) -> Vec<f32> {
+    let step = (data.len() / max_samples.max(1)).max(1);
+    data.iter().step_by(step).copied().collect()
+}
+
+impl HeaderCard {
+    /// Card with a numeric value
+    pub fn number(key: &str, value: f64, comment: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            value: format!("{:E}", value),
+            comment: comment.to_string(),
+        }
+    }
+
+    /// Card with an integer value, for counts
+    pub fn integer(key: &str, value: i64, comment: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            value: value.to_string(),
+            comment: comment.to_string(),
+        }
+    }
+
+    /// Card with a string value (quoted as FITS requires)
+    pub fn text(key: &str, value: &str, comment: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            value: format!("'{}'", value.replace('\'', "''")),
+            comment: comment.to_string(),
+        }
+    }
+
+    /// HISTORY card, e.g. to record how a file was made
+    pub fn history(text: &str) -> Self {
+        Self {
+            key: "HISTORY".to_string(),
+            value: String::new(),
+            comment: text.to_string(),
+        }
+    }
+
+    /// HISTORY and COMMENT cards have no value and may repeat
+    fn is_commentary(&self) -> bool {
+        ["HISTORY", "COMMENT"]
+            .iter()
+            .any(|key| self.key.eq_ignore_ascii_case(key))
+    }
+
+    /// The 80-column card image. A value or comment too long for it is
+    /// shortened, keeping string values quoted.
+    fn to_card(&self) -> String {
+        const WIDTH: usize = 80;
+        let fit = |text: &str, width: usize| text.chars().take(width).collect::<String>();
+
+        if self.is_commentary() {
+            return format!("{:<8}{}", self.key, fit(&self.comment, WIDTH - 8));
+        }
+        // Fixed format: strings start in column 11, numbers end in column 30
+        let card = if self.value.starts_with('\'') {
+            format!(
+                "{:<8}= {:<20}",
+                self.key,
+                quoted_to_fit(&self.value, WIDTH - 10)
+            )
+        } else {
+            format!("{:<8}= {:>20}", self.key, fit(&self.value, WIDTH - 10))
+        };
+        let room = WIDTH.saturating_sub(card.chars().count() + 3);
+        if self.comment.is_empty() || room == 0 {
+            card
+        } else {
+            format!("{} / {}", card, fit(&self.comment, room))
+        }
+    }
+}
+
+/// Quoted string `value` cut to `width` characters with its closing quote,
+/// without keeping half of an escaped quote
+fn quoted_to_fit(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        return value.to_string();
+    }
+    let mut inner: String = value
+        .chars()
+        .skip(1)
+        .take(width.saturating_sub(2))
+        .collect();
+    // Quotes inside come in pairs, an odd run at the end is a split pair
+    if inner.chars().rev().take_while(|&c| c == '\'').count() % 2 == 1 {
+        inner.pop();
+    }
+    format!("'{}'", inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_cards_keep_the_fixed_format() {
+        let card = HeaderCard::integer("NCOMBINE", 12, "Number of frames").to_card();
+        assert_eq!(card, format!("NCOMBINE= {:>20} / Number of frames", 12));
+        let card = HeaderCard::text("IMAGETYP", "Master Dark", "").to_card();
+        assert_eq!(card, "IMAGETYP= 'Master Dark'       ");
+    }
+
+    #[test]
+    fn long_string_values_stay_quoted() {
+        let long = "x".repeat(100);
+        let card = HeaderCard::text("OBJECT", &long, "Target").to_card();
+        assert_eq!(card.len(), 80);
+        assert!(card.starts_with("OBJECT  = 'xxx"));
+        assert!(card.ends_with("x'"));
+
+        // Cut in the middle of an escaped quote: the pair goes, not half
+        let quoted = format!("{}'s", "y".repeat(67));
+        let card = HeaderCard::text("OBJECT", &quoted, "").to_card();
+        assert!(card.len() <= 80);
+        assert!(card.ends_with(&format!("{}'", "y".repeat(67))), "{}", card);
+    }
+
+    #[test]
+    fn long_comments_are_cut_to_fit() {
+        let card = HeaderCard::text("FILTER", "Ha", &"c".repeat(100)).to_card();
+        assert_eq!(card.len(), 80);
+        assert!(card.starts_with("FILTER  = 'Ha'                 / ccc"));
+
+        let card = HeaderCard::history(&"h".repeat(100)).to_card();
+        assert_eq!(card, format!("HISTORY {}", "h".repeat(72)));
+    }
+
+    #[test]
+    fn clipping_with_buffers_matches_the_plain_stats() {
+        let values: Vec<f32> = (0..101)
+            .map(|i| if i % 17 == 0 { 500.0 } else { (i % 7) as f32 })
+            .collect();
+        let mut buffers = ClipBuffers::default();
+        for _ in 0..2 {
+            assert_eq!(
+                sigma_clipped_stats_with(&values, 3.0, 5, &mut buffers),
+                sigma_clipped_stats(&values, 3.0, 5)
+            );
+        }
+    }
+}
@@ -1,16 +1,162 @@
 use std::sync::{Arc, Mutex};
 use tauri::{Manager, State};
 
+pub mod background;
+pub mod calibration;
+pub mod cosmetic;
+pub mod elongation;
 pub mod fits;
-mod renderer;
+pub mod registration;
+pub mod renderer;
+pub mod session;
+pub mod solver;
+pub mod stacking;
+pub mod stars;
+pub mod wcs;
 
 // State to hold the renderer and image data
 struct AppState {
-    renderer: Arc<Mutex<renderer::FitsRenderer>>,
-    stats: Arc<Mutex<fits::ImageStats>>,
+    renderer: Arc<Mutex<renderer::Renderer>>,
+    /// Images of each pane, kept on the CPU for pixel readout
+    images: Arc<Mutex<Vec<PaneImages>>>,
+    /// Draw the RA/Dec grid and compass when the image has a WCS
+    wcs_overlay: Arc<Mutex<bool>>,
+    /// Draw the map of star elongation across the field
+    elongation_overlay: Arc<Mutex<bool>>,
+    /// Orient each image from its WCS or PIERSIDE when it is opened
+    auto_orient: Arc<Mutex<bool>>,
+    /// Last plate solving index used, with the path it was loaded from
+    plate_index: Arc<Mutex<Option<CachedIndex>>>,
+    /// Master frames lights are calibrated with
+    calibration: Arc<Mutex<Option<Arc<calibration::CalibrationSet>>>>,
+    /// Calibrate images as they are opened, to preview subs calibrated
+    calibrate_preview: Arc<Mutex<bool>>,
+    /// Correct hot pixels and bad columns of images as they are opened
+    cosmetic_preview: Arc<Mutex<Option<cosmetic::DefectSource>>>,
+    /// Background model shown or taken off images as they are opened
+    background_view: Arc<Mutex<Option<BackgroundSetting>>>,
+    /// Last session measured, for export
+    session: Arc<Mutex<Option<session::Session>>>,
     surface_format: Arc<Mutex<wgpu::TextureFormat>>,
 }
 
+type CachedIndex = (String, Arc<solver::PlateIndex>);
+
+type BackgroundSetting = (background::BackgroundParams, background::BackgroundView);
+
+/// Images loaded into one pane: a single image, or the frames of a blink
+/// sequence with the one on screen
+#[derive(Default)]
+struct PaneImages {
+    frames: Vec<fits::FitsImage>,
+    current: usize,
+    /// Frame the current one is compared against, see `set_compare`
+    reference: Option<usize>,
+    /// How each frame was registered onto the first, None when not aligned
+    registrations: Vec<Option<registration::Registration>>,
+    /// Star shapes of each frame, measured when first needed
+    elongation: Vec<Option<elongation::ElongationReport>>,
+}
+
+impl PaneImages {
+    fn current(&self) -> Option<&fits::FitsImage> {
+        self.frames.get(self.current)
+    }
+
+    fn current_mut(&mut self) -> Option<&mut fits::FitsImage> {
+        self.frames.get_mut(self.current)
+    }
+
+    /// Star shapes of the current frame, measured once so blinking with the
+    /// elongation map on stays quick
+    fn current_elongation(&mut self) -> Option<&elongation::ElongationReport> {
+        let image = self.frames.get(self.current)?;
+        self.elongation.resize(self.frames.len(), None);
+        let report = self.elongation[self.current].get_or_insert_with(|| {
+            let stars = stars::detect_stars(
+                &image.data,
+                image.width,
+                image.height,
+                &stars::DetectionParams {
+                    max_stars: 2000,
+                    ..Default::default()
+                },
+            );
+            elongation::measure(&stars, image.width, image.height, &Default::default())
+        });
+        Some(report)
+    }
+
+    /// Difference or ratio at pixel (`x`, `y`) of the current frame, as the
+    /// renderer shows it with `uniforms`. None when not comparing.
+    fn compared_value(&self, uniforms: &renderer::Uniforms, x: usize, y: usize) -> Option<f32> {
+        let (image, reference) = (self.current()?, self.frames.get(self.reference?)?);
+        if renderer::CompareMode::from_index(uniforms.compare_mode) == renderer::CompareMode::Off {
+            return None;
+        }
+        Some(compare_pixel(
+            uniforms,
+            image.data[y * image.width + x],
+            x,
+            y,
+            &reference.data,
+            reference.width,
+        ))
+    }
+}
+
+/// Combine `value`, the frame's pixel at (`x`, `y`), with the pixel of
+/// `reference` (`reference_width` wide) the renderer pairs with it under
+/// `uniforms` (nearest reference pixel)
+fn compare_pixel(
+    uniforms: &renderer::Uniforms,
+    value: f32,
+    x: usize,
+    y: usize,
+    reference: &[f32],
+    reference_width: usize,
+) -> f32 {
+    let reference_height = reference.len() / reference_width.max(1);
+    let [dx, dy] = uniforms.reference_offset;
+    let rx = (x as f32 + dx)
+        .round()
+        .clamp(0.0, reference_width as f32 - 1.0) as usize;
+    let ry = (y as f32 + dy)
+        .round()
+        .clamp(0.0, reference_height as f32 - 1.0) as usize;
+
+    let a = value - uniforms.background_offset;
+    let b = reference[ry * reference_width + rx] - uniforms.reference_background;
+    renderer::CompareMode::from_index(uniforms.compare_mode).combine(a, b)
+}
+
+/// Blink frame on screen and the file it came from, for the UI
+#[derive(serde::Serialize)]
+struct BlinkInfo {
+    #[serde(flatten)]
+    state: renderer::BlinkState,
+    path: String,
+    /// Registration of the frame on screen onto the first frame
+    registration: Option<registration::Registration>,
+}
+
+/// State of the active pane, for the UI to show after switching panes
+#[derive(serde::Serialize)]
+struct PaneInfo {
+    pane: usize,
+    layout: renderer::PaneLayout,
+    /// None while the pane is empty
+    stats: Option<fits::ImageStats>,
+    stretch_min: f32,
+    stretch_max: f32,
+    zoom: f32,
+    pan_x: f32,
+    pan_y: f32,
+    orientation: renderer::Orientation,
+    interpolation: renderer::Interpolation,
+    has_wcs: bool,
+}
+
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -19,28 +165,1087 @@ fn greet(name: &str) -> String {
 
 #[tauri::command]
 fn update_view(state: State<AppState>, zoom: f32, pan_x: f32, pan_y: f32) {
-    let renderer = state.renderer.lock().unwrap();
+    let mut renderer = state.renderer.lock().unwrap();
     renderer.update_view(zoom, pan_x, pan_y);
 }
 
 #[tauri::command]
 fn update_stretch(state: State<AppState>, min: f32, max: f32) {
-    let renderer = state.renderer.lock().unwrap();
+    let mut renderer = state.renderer.lock().unwrap();
     renderer.update_stretch(min, max);
 }
 
+#[tauri::command]
+fn set_clipping_overlay(state: State<AppState>, enabled: bool) {
+    let mut renderer = state.renderer.lock().unwrap();
+    renderer.set_clip_overlay(enabled);
+}
+
+#[tauri::command]
+fn set_pyramid_mode(state: State<AppState>, mode: renderer::PyramidMode) {
+    let mut renderer = state.renderer.lock().unwrap();
+    renderer.set_pyramid_mode(mode);
+}
+
+/// Resample the active pane's image between pixel centres
+#[tauri::command]
+fn set_interpolation(state: State<AppState>, interpolation: renderer::Interpolation) {
+    let mut renderer = state.renderer.lock().unwrap();
+    renderer.set_interpolation(interpolation);
+}
+
+/// Rotate (clockwise, degrees) and mirror the image on screen
+#[tauri::command]
+fn set_orientation(state: State<AppState>, orientation: renderer::Orientation) {
+    let mut renderer = state.renderer.lock().unwrap();
+    renderer.set_orientation(orientation);
+}
+
+/// Orient images automatically from now on. Returns the orientation applied
+/// to the current image.
+#[tauri::command]
+fn set_auto_orient(state: State<AppState>, enabled: bool) -> renderer::Orientation {
+    *state.auto_orient.lock().unwrap() = enabled;
+    apply_auto_orientation(&state)
+}
+
+/// Orient the current image if auto-orientation is on, returns the
+/// orientation in effect
+fn apply_auto_orientation(state: &AppState) -> renderer::Orientation {
+    let pane = active_pane(state);
+    let orientation = match *state.auto_orient.lock().unwrap() {
+        true => state.images.lock().unwrap()[pane]
+            .current()
+            .and_then(auto_orientation),
+        false => None,
+    };
+
+    let mut renderer = state.renderer.lock().unwrap();
+    if let Some(orientation) = orientation {
+        renderer.set_orientation(orientation);
+    }
+    renderer.uniforms().orientation()
+}
+
+/// North up and east left when the image has a WCS. Otherwise PIERSIDE,
+/// in the ASCOM SideOfPier convention, tells the two sides of a meridian
+/// flip apart: WEST is the mount west of the pier looking east (targets
+/// rising, before the flip), EAST the mount east of the pier looking west
+/// (after the flip). WEST frames are turned 180° to match the EAST ones.
+/// None when the image has neither (or an unknown pier side), so the
+/// orientation stays as the user set it.
+fn auto_orientation(image: &fits::FitsImage) -> Option<renderer::Orientation> {
+    if let Some(wcs) = &image.wcs {
+        return Some(wcs.north_up_orientation());
+    }
+
+    let side = image.header.get("PIERSIDE")?;
+    let rotation_deg = if side.eq_ignore_ascii_case("WEST") {
+        180.0
+    } else if side.eq_ignore_ascii_case("EAST") {
+        0.0
+    } else {
+        return None;
+    };
+    Some(renderer::Orientation {
+        rotation_deg,
+        ..Default::default()
+    })
+}
+
+#[tauri::command]
+fn get_renderer_backend(state: State<AppState>) -> String {
+    state.renderer.lock().unwrap().backend_name().to_string()
+}
+
+/// Raw RGBA8 frame from the software renderer; the UI draws it on a canvas
+#[tauri::command]
+fn get_software_frame(
+    state: State<AppState>,
+    width: u32,
+    height: u32,
+) -> Result<tauri::ipc::Response, String> {
+    let renderer = state.renderer.lock().unwrap();
+    match &*renderer {
+        renderer::Renderer::Software(software) => {
+            Ok(tauri::ipc::Response::new(software.render(width, height)))
+        }
+        renderer::Renderer::Gpu(_) => Err("GPU renderer is active".to_string()),
+    }
+}
+
+/// Save the current view as PNG
+#[tauri::command]
+fn export_view(
+    state: State<AppState>,
+    path: String,
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
+    let frame = state
+        .renderer
+        .lock()
+        .unwrap()
+        .render_to_rgba(width, height)
+        .map_err(|e| format!("Failed to render view: {}", e))?;
+
+    renderer::write_png(&path, &frame, width.max(1), height.max(1))
+        .map_err(|e| format!("Failed to write PNG: {}", e))
+}
+
+/// Show or hide the RA/Dec grid and compass. Returns whether the current
+/// image has a WCS to draw.
+#[tauri::command]
+fn set_wcs_overlay(state: State<AppState>, enabled: bool) -> bool {
+    *state.wcs_overlay.lock().unwrap() = enabled;
+    refresh_overlays(&state)
+}
+
+/// Show or hide the heat map of star elongation across the field
+#[tauri::command]
+fn set_elongation_overlay(state: State<AppState>, enabled: bool) {
+    *state.elongation_overlay.lock().unwrap() = enabled;
+    refresh_overlays(&state);
+}
+
+/// Star eccentricity and elongation direction of the active pane's image,
+/// overall and across the field
+#[tauri::command]
+async fn measure_elongation(
+    state: State<'_, AppState>,
+) -> Result<elongation::ElongationReport, String> {
+    let pane = active_pane(&state);
+    let mut images = state.images.lock().unwrap();
+    let report = images[pane]
+        .current_elongation()
+        .cloned()
+        .ok_or("No image loaded")?;
+    println!(
+        "🥚 {} stars: eccentricity {:.2}, elongated towards {:.0}° (coherence {:.2})",
+        report.stars, report.eccentricity, report.angle_deg, report.coherence
+    );
+    Ok(report)
+}
+
+/// Rebuild the overlay lines of every pane for its image and the toggle
+/// states. Returns whether the active pane's image has a WCS.
+fn refresh_overlays(state: &AppState) -> bool {
+    let active = active_pane(state);
+    let wcs_enabled = *state.wcs_overlay.lock().unwrap();
+    let elongation_enabled = *state.elongation_overlay.lock().unwrap();
+    let mut images = state.images.lock().unwrap();
+
+    // Measuring stars can take a while, do it before taking the renderer so
+    // drawing and view changes don't wait for it
+    let mut active_has_wcs = false;
+    let mut overlays = Vec::with_capacity(images.len());
+    for (pane, image) in images.iter_mut().enumerate() {
+        let wcs = image
+            .current()
+            .and_then(|image| image.wcs.as_ref().map(|wcs| (image, wcs)));
+        let mut lines = match (wcs_enabled, wcs) {
+            (true, Some((image, wcs))) => wcs.overlay(image.width, image.height),
+            _ => Vec::new(),
+        };
+        if pane == active {
+            active_has_wcs = wcs.is_some();
+        }
+
+        if elongation_enabled {
+            if let Some(report) = image.current_elongation() {
+                lines.extend(report.overlay());
+            }
+        }
+        overlays.push(lines);
+    }
+    drop(images);
+
+    let mut renderer = state.renderer.lock().unwrap();
+    for (pane, lines) in overlays.iter().enumerate() {
+        renderer.set_overlay(pane, lines);
+    }
+    active_has_wcs
+}
+
+/// Pane that receives new images and settings
+fn active_pane(state: &AppState) -> usize {
+    state.renderer.lock().unwrap().active_pane()
+}
+
+/// Split the window into a single, side by side, stacked or 2 x 2 layout.
+/// Images of panes that no longer fit are closed.
+#[tauri::command]
+fn set_pane_layout(state: State<AppState>, layout: renderer::PaneLayout) -> PaneInfo {
+    {
+        let mut images = state.images.lock().unwrap();
+        let mut renderer = state.renderer.lock().unwrap();
+        renderer.set_layout(layout);
+        images.resize_with(layout.pane_count(), PaneImages::default);
+    }
+    println!("🪟 Pane layout: {:?}", layout);
+
+    refresh_overlays(&state);
+    pane_info(&state)
+}
+
+/// Make `pane` the one that receives new images and settings
+#[tauri::command]
+fn set_active_pane(state: State<AppState>, pane: usize) -> PaneInfo {
+    state.renderer.lock().unwrap().set_active_pane(pane);
+    pane_info(&state)
+}
+
+/// Activate the pane under (`x`, `y`) of a `viewport_width` x
+/// `viewport_height` viewport, e.g. on click
+#[tauri::command]
+fn select_pane_at(
+    state: State<AppState>,
+    x: f32,
+    y: f32,
+    viewport_width: f32,
+    viewport_height: f32,
+) -> PaneInfo {
+    {
+        let mut renderer = state.renderer.lock().unwrap();
+        if let Some(point) = renderer.pane_at(x, y, viewport_width, viewport_height) {
+            renderer.set_active_pane(point.pane);
+        }
+    }
+    pane_info(&state)
+}
+
+/// Zoom and pan all panes together
+#[tauri::command]
+fn set_linked_view(state: State<AppState>, linked: bool) {
+    state.renderer.lock().unwrap().set_linked_view(linked);
+}
+
+#[tauri::command]
+fn get_pane_info(state: State<AppState>) -> PaneInfo {
+    pane_info(&state)
+}
+
+fn pane_info(state: &AppState) -> PaneInfo {
+    let images = state.images.lock().unwrap();
+    let renderer = state.renderer.lock().unwrap();
+    let pane = renderer.active_pane();
+    let image = images.get(pane).and_then(PaneImages::current);
+    let uniforms = renderer.uniforms();
+
+    PaneInfo {
+        pane,
+        layout: renderer.layout(),
+        stats: image.map(|image| image.stats.clone()),
+        stretch_min: uniforms.min_value,
+        stretch_max: uniforms.max_value,
+        zoom: uniforms.zoom,
+        pan_x: uniforms.pan_x,
+        pan_y: uniforms.pan_y,
+        orientation: uniforms.orientation(),
+        interpolation: renderer::Interpolation::from_index(uniforms.interpolation),
+        has_wcs: image.is_some_and(|image| image.wcs.is_some()),
+    }
+}
+
+/// Pixel under the cursor. `x`, `y` are relative to a viewport of
+/// `viewport_width` x `viewport_height` (e.g. CSS pixels of the window).
+#[tauri::command]
+fn get_pixel_info(
+    state: State<AppState>,
+    x: f32,
+    y: f32,
+    viewport_width: f32,
+    viewport_height: f32,
+) -> Option<renderer::PixelInfo> {
+    let images = state.images.lock().unwrap();
+    let renderer = state.renderer.lock().unwrap();
+
+    let point = renderer.pane_at(x, y, viewport_width, viewport_height)?;
+    let image = images.get(point.pane)?.current()?;
+    let [px, py] = point.transform.screen_to_pixel(
+        point.u,
+        point.v,
+        image.width as u32,
+        image.height as u32,
+    )?;
+    let uniforms = renderer.pane_uniforms(point.pane);
+    let (raw, normalized) =
+        match images[point.pane].compared_value(uniforms, px as usize, py as usize) {
+            Some(value) => (value, uniforms.stretch(value)),
+            None => {
+                let raw = image.data[py as usize * image.width + px as usize];
+                (raw, uniforms.normalize(raw))
+            }
+        };
+    let sky = image
+        .wcs
+        .as_ref()
+        .and_then(|wcs| wcs.pixel_to_sky(px as f64, py as f64));
+
+    Some(renderer::PixelInfo {
+        pane: point.pane,
+        x: px,
+        y: py,
+        raw,
+        normalized,
+        ra: sky.map(|(ra, _)| ra),
+        dec: sky.map(|(_, dec)| dec),
+    })
+}
+
+/// Build a quad index for plate solving from a `ra,dec,mag` CSV catalogue,
+/// for images about `field_deg` across. Returns the number of quads.
+#[tauri::command]
+async fn build_plate_index(
+    catalogue_path: String,
+    index_path: String,
+    field_deg: f64,
+) -> Result<usize, String> {
+    let catalogue = solver::read_catalogue(&catalogue_path)
+        .map_err(|e| format!("Failed to read catalogue: {}", e))?;
+    let index = solver::PlateIndex::build(&catalogue, solver::IndexParams::for_field(field_deg));
+    index
+        .save(&index_path)
+        .map_err(|e| format!("Failed to save index: {}", e))?;
+    Ok(index.quad_count())
+}
+
+/// Plate solve the active pane's image against the index at `index_path`, write
+/// the solution into the file header and show it on the sky grid
+#[tauri::command]
+async fn plate_solve(
+    state: State<'_, AppState>,
+    index_path: String,
+) -> Result<solver::PlateSolution, String> {
+    // Detect stars while holding the image, solve without it
+    let pane = active_pane(&state);
+    let (stars, hints, width, height, path) = {
+        let images = state.images.lock().unwrap();
+        let image = images[pane].current().ok_or("No image loaded")?;
+        let stars = stars::detect_stars(
+            &image.data,
+            image.width,
+            image.height,
+            &stars::DetectionParams::default(),
+        );
+        let hints = solver::SolveHints::from_header(&image.header);
+        (stars, hints, image.width, image.height, image.path.clone())
+    };
+    println!("⭐ Detected {} stars", stars.len());
+
+    let index = {
+        let mut cached = state.plate_index.lock().unwrap();
+        match &*cached {
+            Some((cached_path, index)) if *cached_path == index_path => index.clone(),
+            _ => {
+                let index = Arc::new(
+                    solver::PlateIndex::load(&index_path)
+                        .map_err(|e| format!("Failed to load index: {}", e))?,
+                );
+                *cached = Some((index_path, index.clone()));
+                index
+            }
+        }
+    };
+
+    let (wcs, solution) = solver::solve(&index, &stars, width, height, &hints)
+        .map_err(|e| format!("Plate solving failed: {}", e))?;
+
+    let cards = wcs.to_header_cards();
+    let superseded = fits::load_fits_header(&path)
+        .map(|header| wcs::Wcs::superseded_keys(&header))
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let superseded: Vec<&str> = superseded.iter().map(String::as_str).collect();
+    fits::update_header(&path, &cards, &superseded)
+        .map_err(|e| format!("Failed to write WCS to {}: {}", path, e))?;
+
+    // Same change in memory, unless another image was opened meanwhile
+    if let Some(image) = state.images.lock().unwrap()[pane].current_mut() {
+        if image.path == path {
+            image
+                .header
+                .cards
+                .retain(|card| !wcs::Wcs::is_superseded_key(&card.key));
+            for card in cards {
+                image.header.set(card);
+            }
+            image.wcs = Some(wcs);
+        }
+    }
+    refresh_overlays(&state);
+    apply_auto_orientation(&state);
+
+    Ok(solution)
+}
+
+/// A master calibration frame written by `build_masters`
+#[derive(serde::Serialize)]
+struct MasterInfo {
+    kind: calibration::FrameType,
+    path: String,
+    frames: usize,
+    median: f32,
+}
+
+/// Sort `paths` by IMAGETYP and combine them into master bias, dark (one
+/// per exposure and temperature) and flat (one per filter) frames, written
+/// to `output_dir`. Darks and flats are bias subtracted when there are bias
+/// frames.
+#[tauri::command]
+async fn build_masters(
+    paths: Vec<String>,
+    method: calibration::CombineMethod,
+    output_dir: String,
+) -> Result<Vec<MasterInfo>, String> {
+    let (groups, skipped) =
+        calibration::group_frames(&paths).map_err(|e| format!("Failed to sort frames: {}", e))?;
+    for path in &skipped {
+        println!("⚠️ Skipped {}: not a bias, dark or flat", path);
+    }
+    if groups.is_empty() {
+        return Err("No bias, dark or flat frames (IMAGETYP) selected".to_string());
+    }
+
+    let params = calibration::CombineParams {
+        method,
+        ..Default::default()
+    };
+    let mut bias = None;
+    let mut masters = Vec::new();
+    for group in &groups {
+        println!(
+            "🧮 Combining {} {:?} frames ({:?})",
+            group.paths.len(),
+            group.kind,
+            method
+        );
+        let mut master = calibration::build_master(group, &params, bias.as_ref())
+            .map_err(|e| format!("Failed to build {}: {}", group.file_name(), e))?;
+        let path = calibration::save_master(&mut master, group, &output_dir)
+            .map_err(|e| format!("Failed to write {}: {}", group.file_name(), e))?;
+        println!("💾 Saved {}", path);
+
+        masters.push(MasterInfo {
+            kind: group.kind,
+            path,
+            frames: group.paths.len(),
+            median: master.stats.median,
+        });
+        if group.kind == calibration::FrameType::Bias {
+            bias = Some(master);
+        }
+    }
+
+    Ok(masters)
+}
+
+/// Load master bias, dark and flat frames to calibrate lights with. Returns
+/// their file names.
+#[tauri::command]
+async fn load_calibration(
+    state: State<'_, AppState>,
+    paths: Vec<String>,
+) -> Result<Vec<String>, String> {
+    let masters = calibration::CalibrationSet::load(&paths)
+        .map_err(|e| format!("Failed to load masters: {}", e))?;
+    if masters.is_empty() {
+        return Err("No master frames selected".to_string());
+    }
+    let names = masters.file_names();
+    println!("🧪 Calibration masters: {}", names.join(", "));
+
+    *state.calibration.lock().unwrap() = Some(Arc::new(masters));
+    Ok(names)
+}
+
+/// Calibrate images as they are opened (or stop), reopening the active
+/// pane's images so the change shows right away
+#[tauri::command]
+async fn set_calibration_preview(
+    state: State<'_, AppState>,
+    window: tauri::WebviewWindow,
+    enabled: bool,
+) -> Result<BlinkInfo, String> {
+    if enabled && state.calibration.lock().unwrap().is_none() {
+        return Err("Load master frames first".to_string());
+    }
+    *state.calibrate_preview.lock().unwrap() = enabled;
+
+    reopen_active_pane(&state, &window)?;
+    Ok(blink_info(&state))
+}
+
+/// Correct hot pixels and bad columns of images as they are opened, found in
+/// each image or taken from the master dark (None to stop). Reopens the
+/// active pane's images so the change shows right away.
+#[tauri::command]
+async fn set_cosmetic_preview(
+    state: State<'_, AppState>,
+    window: tauri::WebviewWindow,
+    source: Option<cosmetic::DefectSource>,
+) -> Result<BlinkInfo, String> {
+    if source == Some(cosmetic::DefectSource::Dark) {
+        dark_defects(&state)?;
+    }
+    *state.cosmetic_preview.lock().unwrap() = source;
+
+    reopen_active_pane(&state, &window)?;
+    Ok(blink_info(&state))
+}
+
+/// Show the background model of images as they are opened, or take it off
+/// them, fitted with `params`. Reopens the active pane's images so the change
+/// shows right away.
+#[tauri::command]
+async fn set_background_view(
+    state: State<'_, AppState>,
+    window: tauri::WebviewWindow,
+    params: background::BackgroundParams,
+    view: background::BackgroundView,
+) -> Result<BlinkInfo, String> {
+    *state.background_view.lock().unwrap() =
+        (view != background::BackgroundView::Original).then_some((params, view));
+
+    reopen_active_pane(&state, &window)?;
+    Ok(blink_info(&state))
+}
+
+/// Gradient of each frame in the active pane, before any background model
+/// is taken off
+#[tauri::command]
+async fn measure_gradients(
+    state: State<'_, AppState>,
+    params: background::BackgroundParams,
+) -> Result<Vec<background::GradientStats>, String> {
+    // Frames shown with a background view have the model applied already,
+    // those are read again without it
+    let background_shown = state.background_view.lock().unwrap().is_some();
+    let pane = active_pane(&state);
+    let frames: Vec<_> = {
+        let images = state.images.lock().unwrap();
+        images[pane]
+            .frames
+            .iter()
+            .map(|image| {
+                let data =
+                    (!background_shown).then(|| (image.data.clone(), image.width, image.height));
+                (image.path.clone(), data)
+            })
+            .collect()
+    };
+    if frames.is_empty() {
+        return Err("No image loaded".to_string());
+    }
+
+    // Fitted without holding the images, so the viewer stays responsive
+    frames
+        .into_iter()
+        .map(|(path, data)| {
+            let (data, width, height) = match data {
+                Some(data) => data,
+                None => {
+                    let image = load_prepared(&state, &path)?;
+                    (image.data, image.width, image.height)
+                }
+            };
+            let stats = background::Background::fit(&data, width, height, &params).stats;
+            println!(
+                "🌅 {}: gradient {:.1}% ({:.1}σ) towards {:.0}°",
+                path, stats.strength_percent, stats.strength_sigma, stats.angle_deg
+            );
+            Ok(stats)
+        })
+        .collect()
+}
+
+/// Correct hot pixels and bad columns of `paths`, writing `<name>_cc.fits`
+/// files to `output_dir`. Returns the files written.
+#[tauri::command]
+async fn correct_files(
+    state: State<'_, AppState>,
+    paths: Vec<String>,
+    source: cosmetic::DefectSource,
+    output_dir: String,
+) -> Result<Vec<String>, String> {
+    let masters = match source {
+        cosmetic::DefectSource::Dark => Some(dark_defects(&state)?),
+        cosmetic::DefectSource::Detect => None,
+    };
+    let defects = masters.as_ref().and_then(|masters| masters.defects());
+
+    paths
+        .iter()
+        .map(|path| {
+            let output = cosmetic::correct_file(path, &output_dir, defects)
+                .map_err(|e| format!("Failed to correct {}: {}", path, e))?;
+            println!("💾 Saved {}", output);
+            Ok(output)
+        })
+        .collect()
+}
+
+/// Calibrate `paths` with the loaded masters, writing `<name>_cal.fits`
+/// files to `output_dir`. Returns the files written.
+#[tauri::command]
+async fn calibrate_files(
+    state: State<'_, AppState>,
+    paths: Vec<String>,
+    output_dir: String,
+) -> Result<Vec<String>, String> {
+    let masters = state
+        .calibration
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or("Load master frames first")?;
+
+    paths
+        .iter()
+        .map(|path| {
+            let output = calibration::calibrate_file(&masters, path, &output_dir)
+                .map_err(|e| format!("Failed to calibrate {}: {}", path, e))?;
+            println!("💾 Saved {}", output);
+            Ok(output)
+        })
+        .collect()
+}
+
+/// A stack written by `stack_frames`
+#[derive(serde::Serialize)]
+struct StackInfo {
+    path: String,
+    rejection_path: String,
+    frames: Vec<stacking::StackedFrame>,
+    /// Frames left out, with the reason
+    skipped: Vec<(String, String)>,
+}
+
+/// Register `paths` onto the first and stack them into `output_path`, with
+/// the rejection map next to it. With `calibrate`, frames are calibrated with
+/// the loaded masters first.
+#[tauri::command]
+async fn stack_frames(
+    state: State<'_, AppState>,
+    paths: Vec<String>,
+    method: stacking::StackMethod,
+    weighting: stacking::Weighting,
+    calibrate: bool,
+    output_path: String,
+) -> Result<StackInfo, String> {
+    let masters = if calibrate {
+        let masters = state.calibration.lock().unwrap().clone();
+        Some(masters.ok_or("Load master frames first")?)
+    } else {
+        None
+    };
+    let params = stacking::StackParams {
+        method,
+        weighting,
+        ..Default::default()
+    };
+
+    println!(
+        "🥞 Stacking {} frames ({:?}, {:?})",
+        paths.len(),
+        method,
+        weighting
+    );
+    let mut stack = stacking::stack(&paths, &params, masters.as_deref())
+        .map_err(|e| format!("Stacking failed: {}", e))?;
+    let rejection_path = stacking::save_stack(&mut stack, &output_path)
+        .map_err(|e| format!("Failed to write {}: {}", output_path, e))?;
+    println!("💾 Saved {} and {}", output_path, rejection_path);
+
+    Ok(StackInfo {
+        path: output_path,
+        rejection_path,
+        frames: stack.frames,
+        skipped: stack.skipped,
+    })
+}
+
+/// Background, noise, star count and transparency of each of `paths` in
+/// observation order, rejecting frames less transparent than
+/// `min_transparency`. With `calibrate`, frames are calibrated with the loaded
+/// masters first.
+#[tauri::command]
+async fn measure_session(
+    state: State<'_, AppState>,
+    paths: Vec<String>,
+    calibrate: bool,
+    min_transparency: f32,
+) -> Result<session::Session, String> {
+    let masters = if calibrate {
+        let masters = state.calibration.lock().unwrap().clone();
+        Some(masters.ok_or("Load master frames first")?)
+    } else {
+        None
+    };
+
+    println!("🌌 Measuring {} frames", paths.len());
+    let params = session::SessionParams { min_transparency };
+    let session = session::measure_session(&paths, masters.as_deref(), &params);
+    if session.frames.is_empty() {
+        return Err("None of the frames could be read".to_string());
+    }
+    let rejected = session.frames.iter().filter(|f| f.reject.is_some()).count();
+    println!(
+        "☁️ {} of {} frames rejected",
+        rejected,
+        session.frames.len()
+    );
+    *state.session.lock().unwrap() = Some(session.clone());
+    Ok(session)
+}
+
+/// Write the last measured session to `path` as CSV
+#[tauri::command]
+async fn export_session(state: State<'_, AppState>, path: String) -> Result<(), String> {
+    let session = state.session.lock().unwrap().clone();
+    let session = session.ok_or("Measure a session first")?;
+    session::write_csv(&session, &path).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    println!("💾 Saved {}", path);
+    Ok(())
+}
+
+/// Statistics of the active pane's image
 #[tauri::command]
 fn get_image_stats(state: State<AppState>) -> fits::ImageStats {
-    (*state.stats.lock().unwrap()).clone()
+    let pane = active_pane(&state);
+    state.images.lock().unwrap()[pane]
+        .current()
+        .map(|image| image.stats.clone())
+        .unwrap_or_default()
 }
 
 #[tauri::command]
 async fn open_single_fits_file(
     state: State<'_, AppState>,
+    window: tauri::WebviewWindow,
     path: String,
 ) -> Result<fits::ImageStats, String> {
     // Load FITS file
-    let fits_img = fits::load_fits_f32(&path).map_err(|e| format!("Failed to load FITS: {}", e))?;
+    let fits_img = load_light(&state, &path)?;
+    let new_stats = fits_img.stats.clone();
+
+    show_in_active_pane(&state, &window, vec![fits_img], &[], Vec::new())?;
+
+    Ok(new_stats)
+}
+
+/// Load `paths` into the active pane as a blink sequence, all frames kept on
+/// the GPU. With `align`, each frame is registered so its stars sit on those
+/// of the first frame, and its sky background is matched to the first frame so
+/// the shared stretch fits every frame.
+#[tauri::command]
+async fn load_blink_frames(
+    state: State<'_, AppState>,
+    window: tauri::WebviewWindow,
+    paths: Vec<String>,
+    align: bool,
+) -> Result<BlinkInfo, String> {
+    if paths.is_empty() {
+        return Err("No frames selected".to_string());
+    }
+    open_frames(&state, &window, &paths, align)?;
+    println!("🔁 Blinking {} frames", paths.len());
+
+    Ok(blink_info(&state))
+}
+
+/// Stats of the comparison and the stretch chosen for it
+#[derive(serde::Serialize)]
+struct CompareInfo {
+    stats: fits::ImageStats,
+    stretch_min: f32,
+    stretch_max: f32,
+}
+
+/// Show the active pane's frame as difference or ratio against frame
+/// `reference` of its blink sequence (or the plain frame with `off`). Stats
+/// are taken from the result and the stretch is centred on "no change".
+#[tauri::command]
+async fn set_compare(
+    state: State<'_, AppState>,
+    mode: renderer::CompareMode,
+    reference: usize,
+) -> Result<CompareInfo, String> {
+    // Switch the view over and copy the frames out, the stats are taken
+    // without holding the images or the renderer
+    let (pane, (data, width, stats), compared, uniforms) = {
+        let mut images = state.images.lock().unwrap();
+        let mut renderer = state.renderer.lock().unwrap();
+        let pane = renderer.active_pane();
+        let images = &mut images[pane];
+        let image = images.current().ok_or("No image loaded")?;
+        let frame = (image.data.clone(), image.width, image.stats.clone());
+
+        renderer
+            .set_compare(mode, reference)
+            .map_err(|e| format!("Failed to compare: {}", e))?;
+        images.reference = (mode != renderer::CompareMode::Off).then_some(reference);
+        let compared = images
+            .reference
+            .and_then(|reference| images.frames.get(reference))
+            .map(|reference| (reference.data.clone(), reference.width));
+        (pane, frame, compared, *renderer.uniforms())
+    };
+
+    let (stats, stretch_min, stretch_max) = match compared {
+        None => {
+            let (min, max) = fits::calculate_auto_stretch(&stats, &data, 0.5, 99.5);
+            (stats, min, max)
+        }
+        Some((reference_data, reference_width)) => {
+            let result: Vec<f32> = data
+                .iter()
+                .enumerate()
+                .map(|(i, &value)| {
+                    compare_pixel(
+                        &uniforms,
+                        value,
+                        i % width,
+                        i / width,
+                        &reference_data,
+                        reference_width,
+                    )
+                })
+                .collect();
+
+            // Symmetric around "no change", wide enough for the noise
+            let (_, sigma) =
+                fits::sigma_clipped_stats(&fits::sample_pixels(&result, 200_000), 3.0, 5);
+            let half_range = (5.0 * sigma).max(f32::EPSILON);
+            let neutral = mode.neutral();
+            println!(
+                "🔀 {:?} against frame {}: σ = {:.4}",
+                mode, reference, sigma
+            );
+            (
+                fits::calculate_statistics(&result),
+                neutral - half_range,
+                neutral + half_range,
+            )
+        }
+    };
+
+    // The user may have moved to another pane meanwhile
+    let mut renderer = state.renderer.lock().unwrap();
+    if renderer.active_pane() == pane {
+        renderer.update_stretch(stretch_min, stretch_max);
+    }
+
+    Ok(CompareInfo {
+        stats,
+        stretch_min,
+        stretch_max,
+    })
+}
+
+/// Put frame `frame` of the active pane's blink sequence on screen
+#[tauri::command]
+fn show_blink_frame(state: State<AppState>, frame: usize) -> BlinkInfo {
+    {
+        let mut images = state.images.lock().unwrap();
+        let mut renderer = state.renderer.lock().unwrap();
+        let pane = renderer.active_pane();
+        let frame = frame % images[pane].frames.len().max(1);
+        renderer.show_frame(frame);
+        images[pane].current = frame;
+    }
+    refresh_overlays(&state);
+
+    blink_info(&state)
+}
+
+fn blink_info(state: &AppState) -> BlinkInfo {
+    let images = state.images.lock().unwrap();
+    let renderer = state.renderer.lock().unwrap();
+    let pane = &images[renderer.active_pane()];
+
+    BlinkInfo {
+        state: renderer.blink_state(),
+        path: pane
+            .current()
+            .map(|image| image.path.clone())
+            .unwrap_or_default(),
+        registration: pane.registrations.get(pane.current).copied().flatten(),
+    }
+}
+
+/// Register `frames` onto the first and match their backgrounds to it.
+/// Frames only shifted against the first are offset on the GPU, rotated or
+/// scaled ones are replaced by their resampled image.
+fn blink_alignments(
+    frames: &mut [fits::FitsImage],
+) -> (
+    Vec<renderer::FrameAlignment>,
+    Vec<Option<registration::Registration>>,
+) {
+    let detect = |image: &fits::FitsImage| {
+        stars::detect_stars(
+            &image.data,
+            image.width,
+            image.height,
+            &stars::DetectionParams::default(),
+        )
+    };
+
+    let (reference, rest) = frames.split_first_mut().expect("no frames");
+    let reference_stars = detect(reference);
+    let params = registration::RegistrationParams::default();
+
+    let mut alignments = vec![renderer::FrameAlignment::default()];
+    let mut registrations = vec![Some(registration::Registration {
+        transform: registration::Transform::identity(),
+        matched_stars: reference_stars.len(),
+        rms_px: 0.0,
+    })];
+    for (index, image) in rest.iter_mut().enumerate().map(|(i, image)| (i + 1, image)) {
+        let background = image.stats.median - reference.stats.median;
+
+        let result = registration::register(&reference_stars, &detect(image), &params);
+        let found = match result {
+            Ok(found) => found,
+            Err(e) => {
+                println!("⚠️ Frame {}: {}, shown unaligned", index, e);
+                alignments.push(renderer::FrameAlignment {
+                    background,
+                    ..Default::default()
+                });
+                registrations.push(None);
+                continue;
+            }
+        };
+
+        let transform = found.transform;
+        println!(
+            "🎯 Frame {}: rotated {:.3}°, scale {:.5} from {} stars (rms {:.2} px)",
+            index,
+            transform.rotation_deg(),
+            transform.scale(),
+            found.matched_stars,
+            found.rms_px
+        );
+
+        // Same size and only shifted: let the GPU offset it, no resampling
+        let same_size = image.width == reference.width && image.height == reference.height;
+        if same_size && transform.is_translation(image.width, image.height, 0.5) {
+            let (dx, dy) = transform.apply(0.0, 0.0);
+            alignments.push(renderer::FrameAlignment {
+                dx: dx as f32,
+                dy: dy as f32,
+                background,
+            });
+        } else {
+            println!("🔄 Frame {}: resampled onto the first frame", index);
+            *image =
+                registration::resample(image, &found, reference, renderer::Interpolation::Bicubic);
+            alignments.push(renderer::FrameAlignment {
+                background,
+                ..Default::default()
+            });
+        }
+        registrations.push(Some(found));
+    }
+
+    (alignments, registrations)
+}
+
+/// Load `paths` into the active pane, registered onto the first with `align`
+fn open_frames(
+    state: &AppState,
+    window: &tauri::WebviewWindow,
+    paths: &[String],
+    align: bool,
+) -> Result<(), String> {
+    let mut frames = paths
+        .iter()
+        .map(|path| load_light(state, path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (alignments, registrations) = if align {
+        blink_alignments(&mut frames)
+    } else {
+        Default::default()
+    };
+    show_in_active_pane(state, window, frames, &alignments, registrations)
+}
+
+/// Read a FITS file, calibrated, cosmetically corrected and with its
+/// background model applied when previewing those
+fn load_light(state: &AppState, path: &str) -> Result<fits::FitsImage, String> {
+    let mut image = load_prepared(state, path)?;
+
+    let background = *state.background_view.lock().unwrap();
+    if let Some((params, view)) = background {
+        let model = background::Background::fit(&image.data, image.width, image.height, &params);
+        println!(
+            "🌅 Background {:?}: gradient {:.1}%",
+            view, model.stats.strength_percent
+        );
+        image = model.apply(&image, view);
+    }
+    Ok(image)
+}
+
+/// Read a FITS file, calibrated and cosmetically corrected when previewing
+/// those
+fn load_prepared(state: &AppState, path: &str) -> Result<fits::FitsImage, String> {
+    let mut image = load_fits_logged(path)?;
+
+    let masters = state.calibration.lock().unwrap().clone();
+    if let (true, Some(masters)) = (*state.calibrate_preview.lock().unwrap(), &masters) {
+        let applied = masters
+            .calibrate(&mut image)
+            .map_err(|e| format!("Failed to calibrate {}: {}", path, e))?;
+        println!("🧪 Calibrated: {}", applied);
+    }
+
+    let source = *state.cosmetic_preview.lock().unwrap();
+    if let Some(source) = source {
+        let defects = match source {
+            cosmetic::DefectSource::Dark => masters.as_ref().and_then(|m| m.defects()),
+            cosmetic::DefectSource::Detect => None,
+        };
+        println!(
+            "🩹 Corrected {}",
+            cosmetic::correct_image(&mut image, defects)
+        );
+    }
+    Ok(image)
+}
+
+/// Loaded masters, when they include a dark to take defects from
+fn dark_defects(state: &AppState) -> Result<Arc<calibration::CalibrationSet>, String> {
+    state
+        .calibration
+        .lock()
+        .unwrap()
+        .clone()
+        .filter(|masters| masters.defects().is_some())
+        .ok_or_else(|| "Load master frames with a dark first".to_string())
+}
+
+/// Load the active pane's files again, e.g. after changing how they are
+/// prepared, keeping them registered if they were
+fn reopen_active_pane(state: &AppState, window: &tauri::WebviewWindow) -> Result<(), String> {
+    let pane = active_pane(state);
+    let (paths, aligned) = {
+        let images = state.images.lock().unwrap();
+        let images = &images[pane];
+        let paths: Vec<String> = images.frames.iter().map(|f| f.path.clone()).collect();
+        (paths, !images.registrations.is_empty())
+    };
+    if paths.is_empty() {
+        return Ok(());
+    }
+    open_frames(state, window, &paths, aligned)
+}
+
+/// Read a FITS file and print its statistics
+fn load_fits_logged(path: &str) -> Result<fits::FitsImage, String> {
+    let fits_img = fits::load_fits_f32(path).map_err(|e| format!("Failed to load FITS: {}", e))?;
 
     println!("Loaded FITS: {}x{}", fits_img.width, fits_img.height);
     println!("Statistics:");
@@ -54,38 +1259,80 @@ async fn open_single_fits_file(
     );
     println!("   Median: {:.2}", fits_img.stats.median);
 
+    Ok(fits_img)
+}
+
+/// Upload `frames` to the active pane (more than one to blink through),
+/// showing the first with `alignments` applied. `registrations` are kept
+/// with the frames for the UI.
+fn show_in_active_pane(
+    state: &AppState,
+    window: &tauri::WebviewWindow,
+    frames: Vec<fits::FitsImage>,
+    alignments: &[renderer::FrameAlignment],
+    registrations: Vec<Option<registration::Registration>>,
+) -> Result<(), String> {
+    let first = &frames[0];
+
     // Calculate auto-stretch
     let (stretch_min, stretch_max) =
-        fits::calculate_auto_stretch(&fits_img.stats, &fits_img.data, 0.5, 99.5);
+        fits::calculate_auto_stretch(&first.stats, &first.data, 0.5, 99.5);
     println!("Auto-stretch: {:.2} to {:.2}", stretch_min, stretch_max);
 
-    // Update renderer with new data
-    {
+    // Update renderer with new data, in the active pane
+    let pane = {
         let mut renderer = state.renderer.lock().unwrap();
         let surface_format = *state.surface_format.lock().unwrap();
 
+        // Keep the user's stretch and navigation when browsing to the next image
+        let first_image = !renderer.has_image();
+
         // Upload new FITS data to GPU
         renderer
-            .load_fits_data(fits_img.data, fits_img.width, fits_img.height)
+            .load_fits_data(&first.data, first.width, first.height)
             .map_err(|e| format!("Failed to upload to GPU: {}", e))?;
+        for frame in &frames[1..] {
+            renderer
+                .add_frame(&frame.data, frame.width, frame.height)
+                .map_err(|e| format!("Failed to upload to GPU: {}", e))?;
+        }
 
-        // Recreate pipeline with new dimensions (assume window size hasn't changed)
-        // Note: In a real app, you'd get the actual window size here
+        // Bind the new textures (the pipeline is reused) for the current window size
+        let size = window
+            .inner_size()
+            .map_err(|e| format!("Failed to get window size: {}", e))?;
         renderer
-            .create_pipeline(surface_format, 1200, 800)
-            .map_err(|e| format!("Failed to create pipeline: {}", e))?;
+            .bind_image(
+                surface_format,
+                size.width.max(1),
+                size.height.max(1),
+                first_image,
+            )
+            .map_err(|e| format!("Failed to bind image: {}", e))?;
 
-        // Apply auto-stretch
-        renderer.update_stretch(stretch_min, stretch_max);
+        // Apply auto-stretch to the first image only
+        if first_image {
+            renderer.update_stretch(stretch_min, stretch_max);
+        }
 
-        println!("FITS data uploaded to GPU and pipeline updated");
-    }
+        // Saturation level for the clipping overlay (SATURATE or BITPIX limit)
+        renderer.set_saturation_level(first.header.saturation_level());
+        renderer.set_frame_alignments(alignments);
 
-    // Update stats in state
-    let new_stats = fits_img.stats.clone();
-    *state.stats.lock().unwrap() = fits_img.stats;
+        println!("FITS data uploaded to GPU and bound");
+        renderer.active_pane()
+    };
 
-    Ok(new_stats)
+    // Keep the images for their pane
+    state.images.lock().unwrap()[pane] = PaneImages {
+        frames,
+        registrations,
+        ..Default::default()
+    };
+    refresh_overlays(state);
+    apply_auto_orientation(state);
+
+    Ok(())
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -98,34 +1345,54 @@ pub fn run() {
             let main_window = app.get_webview_window("main").expect("main window");
 
             // Initialize WGPU renderer on the main window (returns renderer and surface format)
-            let (renderer, surface_format) = renderer::init_renderer_for_window(&main_window)?;
-
-            println!("WGPU renderer initialized, waiting for FITS file to be loaded");
-
-            // Create placeholder/empty stats
-            let placeholder_stats = fits::ImageStats {
-                min: 0.0,
-                max: 0.0,
-                mean: 0.0,
-                stddev: 0.0,
-                median: 0.0,
-                histogram: vec![0; 256],
-            };
+            let renderer::WindowRenderer {
+                renderer,
+                surface_format,
+                render_loop,
+            } = renderer::init_renderer_for_window(&main_window)?;
+
+            println!(
+                "Renderer initialized ({}), waiting for FITS file to be loaded",
+                renderer.lock().unwrap().backend_name()
+            );
 
-            // Store renderer and stats in app state (no image loaded yet)
+            // Store renderer in app state (single pane, no image loaded yet)
             app.manage(AppState {
                 renderer: renderer.clone(),
-                stats: Arc::new(Mutex::new(placeholder_stats)),
+                images: Arc::new(Mutex::new(vec![PaneImages::default()])),
+                wcs_overlay: Arc::new(Mutex::new(false)),
+                elongation_overlay: Arc::new(Mutex::new(false)),
+                auto_orient: Arc::new(Mutex::new(false)),
+                plate_index: Arc::new(Mutex::new(None)),
+                calibration: Arc::new(Mutex::new(None)),
+                calibrate_preview: Arc::new(Mutex::new(false)),
+                cosmetic_preview: Arc::new(Mutex::new(None)),
+                background_view: Arc::new(Mutex::new(None)),
+                session: Arc::new(Mutex::new(None)),
                 surface_format: Arc::new(Mutex::new(surface_format)),
             });
 
-            // Listen for window resize events to update viewport aspect
+            // Reconfigure the surface when the window is resized or moved to a
+            // screen with another scale factor, and stop the render thread
+            // before the window (and its surface) goes away
             let renderer_for_resize = renderer.clone();
-            main_window.on_window_event(move |event| {
-                if let tauri::WindowEvent::Resized(size) = event {
-                    let renderer = renderer_for_resize.lock().unwrap();
-                    renderer.update_viewport_aspect(size.width, size.height);
+            let render_loop = Mutex::new(render_loop);
+            main_window.on_window_event(move |event| match event {
+                tauri::WindowEvent::Resized(size) => {
+                    let mut renderer = renderer_for_resize.lock().unwrap();
+                    renderer.resize(size.width, size.height);
+                }
+                tauri::WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                    let mut renderer = renderer_for_resize.lock().unwrap();
+                    renderer.resize(new_inner_size.width, new_inner_size.height);
+                }
+                // Not on CloseRequested, the close can still be cancelled
+                tauri::WindowEvent::Destroyed => {
+                    if let Some(mut render_loop) = render_loop.lock().unwrap().take() {
+                        render_loop.stop();
+                    }
                 }
+                _ => {}
             });
 
             Ok(())
@@ -134,8 +1401,41 @@ pub fn run() {
             greet,
             update_view,
             update_stretch,
+            set_clipping_overlay,
+            set_pyramid_mode,
+            set_interpolation,
+            set_orientation,
+            set_auto_orient,
+            get_renderer_backend,
+            get_software_frame,
+            export_view,
+            set_wcs_overlay,
+            set_elongation_overlay,
+            measure_elongation,
+            get_pixel_info,
+            set_pane_layout,
+            set_active_pane,
+            select_pane_at,
+            set_linked_view,
+            get_pane_info,
+            build_plate_index,
+            plate_solve,
+            build_masters,
+            load_calibration,
+            set_calibration_preview,
+            calibrate_files,
+            set_cosmetic_preview,
+            correct_files,
+            set_background_view,
+            measure_gradients,
+            measure_session,
+            export_session,
+            stack_frames,
             get_image_stats,
-            open_single_fits_file
+            open_single_fits_file,
+            load_blink_frames,
+            show_blink_frame,
+            set_compare
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,613 @@
+use crate::fits::{calculate_statistics, FitsImage, HeaderCard};
+use crate::renderer::{sample_at, Interpolation};
+use crate::solver::{solve3, StarGrid};
+use crate::stars::Star;
+use crate::wcs::Wcs;
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+
+/// Triangles match when both shape ratios agree this closely
+const TRIANGLE_TOLERANCE: f64 = 0.01;
+
+/// Triangles flatter than this (shortest / longest side) are too unstable
+const MIN_TRIANGLE_RATIO: f64 = 0.1;
+
+/// Inlier counting uses at most this many stars per frame
+const MAX_VERIFY_STARS: usize = 300;
+
+/// WCS keywords that no longer describe a resampled frame
+const WCS_KEYS: &[&str] = &[
+    "CTYPE1", "CTYPE2", "CUNIT1", "CUNIT2", "CRPIX1", "CRPIX2", "CRVAL1", "CRVAL2", "CD1_1",
+    "CD1_2", "CD2_1", "CD2_2",
+];
+
+/// Affine map from reference pixel coordinates to frame pixel coordinates
+/// (0-based, like `Star`): `x' = m[0][0] x + m[0][1] y + m[0][2]` and
+/// `y' = m[1][0] x + m[1][1] y + m[1][2]`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Transform {
+    pub m: [[f64; 3]; 2],
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self {
+            m: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+        }
+    }
+
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let [r0, r1] = self.m;
+        (r0[0] * x + r0[1] * y + r0[2], r1[0] * x + r1[1] * y + r1[2])
+    }
+
+    /// Rotation from reference to frame, degrees counter-clockwise in pixel
+    /// coordinates
+    pub fn rotation_deg(&self) -> f64 {
+        self.m[1][0].atan2(self.m[0][0]).to_degrees()
+    }
+
+    /// Mean scale from reference to frame
+    pub fn scale(&self) -> f64 {
+        let [r0, r1] = self.m;
+        (r0[0] * r1[1] - r0[1] * r1[0]).abs().sqrt()
+    }
+
+    /// True when the transform moves every point of a `width` x `height`
+    /// frame by the same amount, to within `tolerance` pixels
+    pub fn is_translation(&self, width: usize, height: usize, tolerance: f64) -> bool {
+        let (dx, dy) = self.apply(0.0, 0.0);
+        [
+            (width as f64, 0.0),
+            (0.0, height as f64),
+            (width as f64, height as f64),
+        ]
+        .iter()
+        .all(|&(x, y)| {
+            let (tx, ty) = self.apply(x, y);
+            (tx - x - dx).hypot(ty - y - dy) <= tolerance
+        })
+    }
+}
+
+/// Degrees of freedom of the fitted transform
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransformModel {
+    /// Shift, rotation and uniform scale
+    #[default]
+    Similarity,
+    /// Also shear and different scales per axis, e.g. for mirrored frames or
+    /// differential refraction
+    Affine,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RegistrationParams {
+    pub model: TransformModel,
+    /// Stars (the brightest) that triangles are built from
+    pub max_stars: usize,
+    /// Each star forms triangles with pairs of this many nearest neighbours
+    pub neighbours: usize,
+    /// RANSAC hypotheses to try
+    pub iterations: usize,
+    /// A transformed reference star within this many pixels of a frame star
+    /// counts as matched
+    pub inlier_radius: f64,
+    /// Registrations with fewer matched stars fail
+    pub min_matches: usize,
+}
+
+impl Default for RegistrationParams {
+    fn default() -> Self {
+        Self {
+            model: TransformModel::Similarity,
+            max_stars: 40,
+            neighbours: 6,
+            iterations: 1000,
+            inlier_radius: 2.0,
+            min_matches: 6,
+        }
+    }
+}
+
+/// A frame registered against the reference
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Registration {
+    pub transform: Transform,
+    pub matched_stars: usize,
+    /// Residual of the matched stars, pixels
+    pub rms_px: f64,
+}
+
+/// Pairs of (reference, frame) positions
+type Pairs = Vec<([f64; 2], [f64; 2])>;
+
+struct Triangle {
+    /// Star indices, opposite the shortest, middle and longest side
+    stars: [usize; 3],
+    /// Shortest / longest and middle / longest side, unchanged by shifts,
+    /// rotations, scaling and mirroring
+    shape: [f64; 2],
+}
+
+/// Register a frame against the reference from their detected stars (both
+/// sorted brightest first, as `detect_stars` returns them).
+///
+/// Triangles of each bright star and its neighbours are matched by shape;
+/// RANSAC then draws triangle matches at random, fits the transform to their
+/// corners and keeps the one most stars agree with, refined by least squares
+/// over all of them.
+pub fn register(
+    reference: &[Star],
+    frame: &[Star],
+    params: &RegistrationParams,
+) -> Result<Registration> {
+    let positions = |stars: &[Star], n: usize| -> Vec<[f64; 2]> {
+        stars.iter().take(n).map(|s| [s.x, s.y]).collect()
+    };
+    let reference_points = positions(reference, MAX_VERIFY_STARS);
+    let frame_points = positions(frame, MAX_VERIFY_STARS);
+    ensure!(
+        reference_points.len() >= 3 && frame_points.len() >= 3,
+        "Not enough stars to register ({} and {})",
+        reference_points.len(),
+        frame_points.len()
+    );
+
+    let reference_triangles = triangles(
+        &reference_points[..params.max_stars.min(reference_points.len())],
+        params.neighbours,
+    );
+    let frame_triangles = triangles(
+        &frame_points[..params.max_stars.min(frame_points.len())],
+        params.neighbours,
+    );
+    let candidates = match_triangles(&reference_triangles, &frame_triangles);
+    ensure!(!candidates.is_empty(), "No matching star triangles");
+
+    let lookup = StarGrid::new(&frame_points, 4.0 * params.inlier_radius.max(1.0));
+    let inliers = |transform: &Transform| -> Pairs {
+        reference_points
+            .iter()
+            .filter_map(|&p| {
+                let (x, y) = transform.apply(p[0], p[1]);
+                lookup
+                    .nearest(&frame_points, [x, y], params.inlier_radius)
+                    .map(|i| (p, frame_points[i]))
+            })
+            .collect()
+    };
+
+    // Random hypotheses, all of them when there are fewer candidates
+    let mut random = Lcg(0x2545_f491_4f6c_dd1d);
+    let mut best: Option<(Transform, Pairs)> = None;
+    let good_enough = reference_points.len().min(frame_points.len()) * 4 / 5;
+    let exhaustive = candidates.len() <= params.iterations;
+    for iteration in 0..params.iterations.min(candidates.len()) {
+        let (r, f) = if exhaustive {
+            candidates[iteration]
+        } else {
+            candidates[random.next() % candidates.len()]
+        };
+
+        let corners: Pairs = (0..3)
+            .map(|i| {
+                (
+                    reference_points[reference_triangles[r].stars[i]],
+                    frame_points[frame_triangles[f].stars[i]],
+                )
+            })
+            .collect();
+        let Some(transform) = fit(&corners, params.model) else {
+            continue;
+        };
+
+        let pairs = inliers(&transform);
+        if best.as_ref().is_none_or(|(_, b)| pairs.len() > b.len()) {
+            let done = pairs.len() >= good_enough;
+            best = Some((transform, pairs));
+            if done {
+                break;
+            }
+        }
+    }
+
+    let (mut transform, mut pairs) = best.context("No transform found")?;
+    ensure!(
+        pairs.len() >= params.min_matches,
+        "Only {} stars matched",
+        pairs.len()
+    );
+
+    // Refine on all matched stars, which may pick up a few more
+    for _ in 0..3 {
+        let Some(refined) = fit(&pairs, params.model) else {
+            break;
+        };
+        let refined_pairs = inliers(&refined);
+        if refined_pairs.len() < pairs.len() {
+            break;
+        }
+        transform = refined;
+        pairs = refined_pairs;
+    }
+
+    let rms_px = (pairs
+        .iter()
+        .map(|(p, q)| {
+            let (x, y) = transform.apply(p[0], p[1]);
+            (x - q[0]).powi(2) + (y - q[1]).powi(2)
+        })
+        .sum::<f64>()
+        / pairs.len() as f64)
+        .sqrt();
+
+    Ok(Registration {
+        transform,
+        matched_stars: pairs.len(),
+        rms_px,
+    })
+}
+
+/// Resample `image` onto the pixel grid of `reference` through the
+/// registration of `image` against it. Pixels that fall outside the frame
+/// become NaN. The result takes over the reference's WCS, if any.
+pub fn resample(
+    image: &FitsImage,
+    registration: &Registration,
+    reference: &FitsImage,
+    interpolation: Interpolation,
+) -> FitsImage {
+    let (width, height) = (reference.width, reference.height);
+    let transform = registration.transform;
+
+    let mut data = vec![f32::NAN; width * height];
+    for (y, row) in data.chunks_exact_mut(width).enumerate() {
+        for (x, value) in row.iter_mut().enumerate() {
+            let (fx, fy) = transform.apply(x as f64, y as f64);
+            if fx < -0.5
+                || fy < -0.5
+                || fx > image.width as f64 - 0.5
+                || fy > image.height as f64 - 0.5
+            {
+                continue;
+            }
+            // Star positions are pixel centres, sample_at counts from pixel edges
+            *value = sample_at(
+                &image.data,
+                image.width as u32,
+                image.height as u32,
+                fx as f32 + 0.5,
+                fy as f32 + 0.5,
+                interpolation,
+            );
+        }
+    }
+
+    let mut header = image.header.clone();
+    header.cards.retain(|card| {
+        !WCS_KEYS
+            .iter()
+            .any(|key| card.key.eq_ignore_ascii_case(key))
+            && !Wcs::is_superseded_key(&card.key)
+    });
+    if let Some(wcs) = &reference.wcs {
+        for card in wcs.to_header_cards() {
+            header.set(card);
+        }
+    }
+    let reference_name = std::path::Path::new(&reference.path)
+        .file_name()
+        .map_or(reference.path.clone(), |name| {
+            name.to_string_lossy().into_owned()
+        });
+    header.set(HeaderCard::text(
+        "REGREF",
+        &reference_name,
+        "Registered onto this frame",
+    ));
+    header.set(HeaderCard::integer(
+        "REGSTARS",
+        registration.matched_stars as i64,
+        "Stars matched for registration",
+    ));
+    header.set(HeaderCard::number(
+        "REGRMS",
+        registration.rms_px,
+        "Registration residual (pixels)",
+    ));
+
+    FitsImage {
+        path: image.path.clone(),
+        stats: calculate_statistics(&data),
+        data,
+        width,
+        height,
+        header,
+        wcs: reference.wcs.clone(),
+    }
+}
+
+/// Least squares transform through (reference, frame) pairs
+fn fit(pairs: &Pairs, model: TransformModel) -> Option<Transform> {
+    match model {
+        TransformModel::Similarity => fit_similarity(pairs),
+        TransformModel::Affine => fit_affine(pairs),
+    }
+}
+
+/// `x' = a x - b y + c`, `y' = b x + a y + d`, solved about the centroids
+fn fit_similarity(pairs: &Pairs) -> Option<Transform> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean = |pick: fn(&([f64; 2], [f64; 2])) -> f64| pairs.iter().map(pick).sum::<f64>() / n;
+    let (px, py) = (mean(|p| p.0[0]), mean(|p| p.0[1]));
+    let (qx, qy) = (mean(|p| p.1[0]), mean(|p| p.1[1]));
+
+    let (mut dot, mut cross, mut norm) = (0.0, 0.0, 0.0);
+    for (p, q) in pairs {
+        let (x, y) = (p[0] - px, p[1] - py);
+        let (u, v) = (q[0] - qx, q[1] - qy);
+        dot += x * u + y * v;
+        cross += x * v - y * u;
+        norm += x * x + y * y;
+    }
+    if norm < 1e-9 {
+        return None;
+    }
+
+    let (a, b) = (dot / norm, cross / norm);
+    Some(Transform {
+        m: [
+            [a, -b, qx - (a * px - b * py)],
+            [b, a, qy - (b * px + a * py)],
+        ],
+    })
+}
+
+/// Independent least squares for each output coordinate
+fn fit_affine(pairs: &Pairs) -> Option<Transform> {
+    if pairs.len() < 3 {
+        return None;
+    }
+    let mut normal = [[0.0; 3]; 3];
+    let mut rhs_x = [0.0; 3];
+    let mut rhs_y = [0.0; 3];
+    for (p, q) in pairs {
+        let row = [p[0], p[1], 1.0];
+        for i in 0..3 {
+            for j in 0..3 {
+                normal[i][j] += row[i] * row[j];
+            }
+            rhs_x[i] += row[i] * q[0];
+            rhs_y[i] += row[i] * q[1];
+        }
+    }
+    Some(Transform {
+        m: [solve3(normal, rhs_x)?, solve3(normal, rhs_y)?],
+    })
+}
+
+/// Triangles of each point with every pair of its nearest neighbours
+fn triangles(points: &[[f64; 2]], neighbours: usize) -> Vec<Triangle> {
+    let distance =
+        |a: usize, b: usize| (points[a][0] - points[b][0]).hypot(points[a][1] - points[b][1]);
+
+    let mut seen = std::collections::HashSet::new();
+    let mut triangles = Vec::new();
+    for anchor in 0..points.len() {
+        let mut near: Vec<usize> = (0..points.len()).filter(|&i| i != anchor).collect();
+        near.sort_by(|&a, &b| distance(anchor, a).total_cmp(&distance(anchor, b)));
+        near.truncate(neighbours);
+
+        for (i, &b) in near.iter().enumerate() {
+            for &c in &near[i + 1..] {
+                let mut key = [anchor, b, c];
+                key.sort_unstable();
+                if !seen.insert(key) {
+                    continue;
+                }
+
+                // Side opposite each corner, then corners by that side
+                let [p, q, r] = key;
+                let mut corners = [
+                    (distance(q, r), p),
+                    (distance(p, r), q),
+                    (distance(p, q), r),
+                ];
+                corners.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let [(short, s0), (middle, s1), (long, s2)] = corners;
+                if long <= 0.0 || short / long < MIN_TRIANGLE_RATIO {
+                    continue;
+                }
+
+                triangles.push(Triangle {
+                    stars: [s0, s1, s2],
+                    shape: [short / long, middle / long],
+                });
+            }
+        }
+    }
+    triangles
+}
+
+/// (reference, frame) indices of triangles with the same shape
+fn match_triangles(reference: &[Triangle], frame: &[Triangle]) -> Vec<(usize, usize)> {
+    let mut order: Vec<usize> = (0..reference.len()).collect();
+    order.sort_by(|&a, &b| reference[a].shape[0].total_cmp(&reference[b].shape[0]));
+
+    let mut matches = Vec::new();
+    for (f, triangle) in frame.iter().enumerate() {
+        let [s0, s1] = triangle.shape;
+        let start = order.partition_point(|&r| reference[r].shape[0] < s0 - TRIANGLE_TOLERANCE);
+        for &r in &order[start..] {
+            if reference[r].shape[0] > s0 + TRIANGLE_TOLERANCE {
+                break;
+            }
+            if (reference[r].shape[1] - s1).abs() <= TRIANGLE_TOLERANCE {
+                matches.push((r, f));
+            }
+        }
+    }
+    matches
+}
+
+/// Small deterministic generator so registrations are repeatable, and the
+/// noise of test frames with them
+pub(crate) struct Lcg(pub(crate) u64);
+
+impl Lcg {
+    fn step(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        self.0
+    }
+
+    fn next(&mut self) -> usize {
+        (self.step() >> 33) as usize
+    }
+
+    /// Uniform in 0..1
+    #[cfg(test)]
+    pub(crate) fn fraction(&mut self) -> f64 {
+        (self.step() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn star(x: f64, y: f64, flux: f64) -> Star {
+        Star {
+            x,
+            y,
+            flux,
+            peak: flux as f32,
+            fwhm: 3.0,
+            eccentricity: 0.0,
+            angle_deg: 0.0,
+        }
+    }
+
+    /// Reference stars scattered over 1000 x 800 pixels, brightest first,
+    /// and the same stars seen through `truth` with 0.05 px of jitter: a few
+    /// fall outside the frame and a few spurious detections come in
+    fn star_fields(truth: &Transform) -> (Vec<Star>, Vec<Star>) {
+        let mut rng = Lcg(7);
+        let mut random = move || rng.fraction();
+
+        let reference: Vec<Star> = (0..120)
+            .map(|i| star(random() * 1000.0, random() * 800.0, 1e5 / (i + 1) as f64))
+            .collect();
+        let mut frame: Vec<Star> = reference
+            .iter()
+            .map(|s| {
+                let (x, y) = truth.apply(s.x, s.y);
+                star(
+                    x + (random() - 0.5) * 0.1,
+                    y + (random() - 0.5) * 0.1,
+                    s.flux,
+                )
+            })
+            .filter(|s| (0.0..1000.0).contains(&s.x) && (0.0..800.0).contains(&s.y))
+            .collect();
+        for i in 0..10 {
+            frame.push(star(
+                random() * 1000.0,
+                random() * 800.0,
+                3e4 / (i + 1) as f64,
+            ));
+        }
+        frame.sort_by(|a, b| b.flux.total_cmp(&a.flux));
+        (reference, frame)
+    }
+
+    #[test]
+    fn registers_rotated_scaled_and_shifted_stars() {
+        let (rotation, scale) = (12f64.to_radians(), 1.03);
+        let (sin, cos) = rotation.sin_cos();
+        let truth = Transform {
+            m: [
+                [scale * cos, -scale * sin, 35.0],
+                [scale * sin, scale * cos, -20.0],
+            ],
+        };
+        let (reference, frame) = star_fields(&truth);
+
+        let registration = register(&reference, &frame, &RegistrationParams::default()).unwrap();
+        let transform = registration.transform;
+        assert!((transform.rotation_deg() - 12.0).abs() < 0.01);
+        assert!((transform.scale() - scale).abs() < 1e-4);
+        // Compare where both put points across the frame, not the raw
+        // offsets, which trade off against the rotation
+        for (x, y) in [(0.0, 0.0), (1000.0, 0.0), (0.0, 800.0), (1000.0, 800.0)] {
+            let (ex, ey) = truth.apply(x, y);
+            let (tx, ty) = transform.apply(x, y);
+            assert!((tx - ex).hypot(ty - ey) < 0.1, "({}, {}) off", x, y);
+        }
+        assert!(registration.matched_stars >= 80);
+        assert!(registration.rms_px < 0.1, "rms {}", registration.rms_px);
+    }
+
+    #[test]
+    fn resample_replaces_the_wcs_and_drops_sip() {
+        let card = |key: &str, value: &str| HeaderCard {
+            key: key.to_string(),
+            value: value.to_string(),
+            comment: String::new(),
+        };
+        let mut header = crate::fits::FitsHeader::default();
+        for (key, value) in [
+            ("CTYPE1", "'RA---TAN-SIP'"),
+            ("CDELT1", "0.001"),
+            ("PC1_2", "0.1"),
+            ("A_ORDER", "2"),
+            ("A_2_0", "1E-6"),
+            ("BP_1_1", "1E-7"),
+            ("EXPTIME", "300"),
+        ] {
+            header.cards.push(card(key, value));
+        }
+        let image = |data: Vec<f32>, header, wcs| FitsImage {
+            path: "frame.fits".to_string(),
+            stats: calculate_statistics(&data),
+            data,
+            width: 4,
+            height: 3,
+            header,
+            wcs,
+        };
+        let frame = image((0..12).map(|v| v as f32).collect(), header, None);
+        let scale = 1.0 / 3600.0;
+        let reference_wcs = Wcs::new_tan([2.5, 2.0], [150.0, 20.0], [[-scale, 0.0], [0.0, scale]]);
+        let reference = image(vec![0.0; 12], Default::default(), Some(reference_wcs));
+
+        let registration = Registration {
+            transform: Transform::identity(),
+            matched_stars: 10,
+            rms_px: 0.1,
+        };
+        let resampled = resample(&frame, &registration, &reference, Interpolation::Nearest);
+
+        assert_eq!(resampled.data, frame.data);
+        let keys: Vec<&str> = resampled
+            .header
+            .cards
+            .iter()
+            .map(|c| c.key.as_str())
+            .collect();
+        for key in ["CDELT1", "PC1_2", "A_ORDER", "A_2_0", "BP_1_1"] {
+            assert!(!keys.contains(&key), "{} kept", key);
+        }
+        assert_eq!(resampled.header.get("EXPTIME"), Some("300"));
+        assert_eq!(resampled.header.get("CTYPE1"), Some("RA---TAN"));
+        assert!(keys.contains(&"CD1_1") && keys.contains(&"REGREF"));
+        assert!(Wcs::from_header(&resampled.header).is_some());
+    }
+}
@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+/// How a blink frame lines up with the first frame of the sequence, so stars
+/// stay put and the sky looks the same while cycling
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameAlignment {
+    /// Position of the frame's stars minus the reference, image pixels
+    pub dx: f32,
+    pub dy: f32,
+    /// Sky background of the frame minus the reference (ADU), taken off
+    /// before the stretch so all frames share one black and white point
+    pub background: f32,
+}
+
+/// Which frame of a pane's blink sequence is on screen
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BlinkState {
+    pub frame: usize,
+    pub frame_count: usize,
+    /// Alignment of the frame on screen
+    pub alignment: FrameAlignment,
+    /// Shown against a reference frame, see `set_compare`. Turns false when
+    /// the frames end up too far apart to compare.
+    pub comparing: bool,
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+/// Ratios against a reference value closer to zero than this are shown as 0
+const RATIO_EPSILON: f32 = 1e-6;
+
+/// How the frame on screen is combined with a reference frame of the same
+/// pane, to spot transients (difference) or flat-field problems (ratio)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompareMode {
+    #[default]
+    Off,
+    /// Frame minus reference
+    Difference,
+    /// Frame divided by reference
+    Ratio,
+}
+
+impl CompareMode {
+    /// Inverse of `as u32`, as stored in the uniforms
+    pub fn from_index(index: u32) -> Self {
+        match index {
+            1 => CompareMode::Difference,
+            2 => CompareMode::Ratio,
+            _ => CompareMode::Off,
+        }
+    }
+
+    /// Combine background matched values of the frame (`a`) and the
+    /// reference (`b`), same as `fs_main`
+    pub fn combine(self, a: f32, b: f32) -> f32 {
+        match self {
+            CompareMode::Off => a,
+            CompareMode::Difference => a - b,
+            CompareMode::Ratio if b.abs() < RATIO_EPSILON => 0.0,
+            CompareMode::Ratio => a / b,
+        }
+    }
+
+    /// Value of identical frames, shown white by the diverging colormap
+    pub fn neutral(self) -> f32 {
+        match self {
+            CompareMode::Ratio => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Blue - white - red colormap for stretched values (0..1, white at 0.5),
+/// same as `diverging` in the shader
+pub fn diverging(t: f32) -> [f32; 3] {
+    const BLUE: [f32; 3] = [0.23, 0.30, 0.75];
+    const WHITE: [f32; 3] = [0.95, 0.95, 0.95];
+    const RED: [f32; 3] = [0.71, 0.02, 0.15];
+
+    let mix = |a: [f32; 3], b: [f32; 3], f: f32| [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * f);
+    if t < 0.5 {
+        mix(BLUE, WHITE, t * 2.0)
+    } else {
+        mix(WHITE, RED, (t - 0.5) * 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difference_and_ratio_combine_the_frames() {
+        assert_eq!(CompareMode::Off.combine(7.0, 2.0), 7.0);
+        assert_eq!(CompareMode::Difference.combine(7.0, 2.0), 5.0);
+        assert_eq!(CompareMode::Difference.combine(2.0, 7.0), -5.0);
+        assert_eq!(CompareMode::Ratio.combine(7.0, 2.0), 3.5);
+        assert_eq!(CompareMode::Ratio.combine(-3.0, 2.0), -1.5);
+    }
+
+    #[test]
+    fn identical_frames_give_the_neutral_value() {
+        for mode in [CompareMode::Difference, CompareMode::Ratio] {
+            for value in [0.5, 120.0, -8.0] {
+                assert_eq!(mode.combine(value, value), mode.neutral(), "{:?}", mode);
+            }
+        }
+        assert_eq!(diverging(0.5), [0.95, 0.95, 0.95]);
+    }
+
+    #[test]
+    fn ratio_against_zero_is_zero() {
+        for divisor in [0.0, -0.0, RATIO_EPSILON / 2.0, -RATIO_EPSILON / 2.0] {
+            assert_eq!(CompareMode::Ratio.combine(5.0, divisor), 0.0);
+        }
+        assert_eq!(CompareMode::Ratio.combine(0.0, 0.0), 0.0);
+        assert!(CompareMode::Ratio
+            .combine(5.0, RATIO_EPSILON * 2.0)
+            .is_finite());
+    }
+
+    #[test]
+    fn blank_pixels_stay_blank() {
+        // NaN marks missing data, it must not turn into a plausible value
+        for mode in [CompareMode::Difference, CompareMode::Ratio] {
+            assert!(mode.combine(f32::NAN, 2.0).is_nan(), "{:?}", mode);
+            assert!(mode.combine(2.0, f32::NAN).is_nan(), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn mode_index_round_trips() {
+        for mode in [
+            CompareMode::Off,
+            CompareMode::Difference,
+            CompareMode::Ratio,
+        ] {
+            assert_eq!(CompareMode::from_index(mode as u32), mode);
+        }
+        assert_eq!(CompareMode::from_index(9), CompareMode::Off);
+    }
+}
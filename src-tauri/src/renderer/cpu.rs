@@ -0,0 +1,674 @@
+use super::compare::{diverging, CompareMode};
+use super::interpolation::{self, Interpolation};
+use super::overlay::{draw_lines, line_vertices, OverlayLine, OverlayVertex};
+use super::pyramid::{build_levels, level_for_footprint, PyramidLevel, PyramidMode};
+use super::{BlinkState, FrameAlignment, Orientation, PaneLayout, Uniforms, ViewTransform};
+use anyhow::*;
+use std::thread;
+
+/// An image loaded into a pane, see `Frame` in the GPU renderer
+struct SoftwareFrame {
+    /// Image pyramid, level 0 is the full resolution data
+    levels: Vec<PyramidLevel>,
+    width: u32,
+    height: u32,
+    alignment: FrameAlignment,
+}
+
+impl SoftwareFrame {
+    fn new(data: &[f32], w: usize, h: usize, mode: PyramidMode) -> Result<Self> {
+        ensure!(data.len() == w * h, "image data does not match {}x{}", w, h);
+
+        let (width, height) = (w as u32, h as u32);
+        let base = PyramidLevel {
+            data: data.to_vec(),
+            width,
+            height,
+        };
+        Ok(Self {
+            levels: build_levels(base, mode),
+            width,
+            height,
+            alignment: FrameAlignment::default(),
+        })
+    }
+}
+
+/// An image shown in one pane, see `Pane` in the GPU renderer
+#[derive(Default)]
+struct SoftwarePane {
+    frames: Vec<SoftwareFrame>,
+    /// Index into `frames` of the one on screen
+    frame: usize,
+    /// Frame the one on screen is compared against
+    reference: Option<usize>,
+    uniforms: Uniforms,
+    /// Lines drawn over the image, same line list the GPU path uploads
+    overlay: Vec<OverlayVertex>,
+}
+
+impl SoftwarePane {
+    fn has_image(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    fn current(&self) -> Option<&SoftwareFrame> {
+        self.frames.get(self.frame)
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.current()
+            .map_or((0, 0), |frame| (frame.width, frame.height))
+    }
+
+    fn show_frame(&mut self, index: usize) {
+        let Some(frame) = self.frames.get(index) else {
+            return;
+        };
+        self.uniforms.aspect_ratio = frame.width as f32 / frame.height as f32;
+        self.uniforms
+            .set_alignment(frame.alignment, frame.width, frame.height);
+        self.frame = index;
+        self.overlay.clear();
+        self.update_compare();
+    }
+
+    fn update_compare(&mut self) {
+        let alignment = |index: usize| self.frames[index].alignment;
+        let (mode, reference) = match self.reference {
+            Some(reference) => (
+                CompareMode::from_index(self.uniforms.compare_mode),
+                alignment(reference),
+            ),
+            None => (CompareMode::Off, FrameAlignment::default()),
+        };
+        let current = alignment(self.frame);
+        self.uniforms.set_compare(mode, current, reference);
+    }
+}
+
+/// CPU implementation of the `shader.wgsl` pipeline, used when no GPU
+/// adapter is available. Produces RGBA8 frames that the UI draws itself.
+pub struct SoftwareRenderer {
+    /// One per cell of `layout`, always at least one
+    panes: Vec<SoftwarePane>,
+    active: usize,
+    layout: PaneLayout,
+    linked_view: bool,
+    pyramid_mode: PyramidMode,
+}
+
+impl Default for SoftwareRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoftwareRenderer {
+    /// Pixel format of the frames returned by `render`
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+    /// Same colour the GPU path clears to before an image is loaded
+    const EMPTY_COLOR: [f32; 4] = [0.2, 0.5, 0.8, 1.0];
+
+    pub fn new() -> Self {
+        Self {
+            panes: vec![SoftwarePane::default()],
+            active: 0,
+            layout: PaneLayout::default(),
+            linked_view: false,
+            pyramid_mode: PyramidMode::default(),
+        }
+    }
+
+    fn pane(&self) -> &SoftwarePane {
+        &self.panes[self.active]
+    }
+
+    fn pane_mut(&mut self) -> &mut SoftwarePane {
+        &mut self.panes[self.active]
+    }
+
+    /// Load into the active pane, replacing everything it showed
+    pub fn load_fits_data(&mut self, data: &[f32], w: usize, h: usize) -> Result<()> {
+        let frame = SoftwareFrame::new(data, w, h, self.pyramid_mode)?;
+        let pane = self.pane_mut();
+        pane.frames = vec![frame];
+        pane.reference = None;
+        pane.show_frame(0);
+
+        Ok(())
+    }
+
+    /// See `FitsRenderer::add_frame`
+    pub fn add_frame(&mut self, data: &[f32], w: usize, h: usize) -> Result<()> {
+        ensure!(self.pane().has_image(), "No first frame loaded");
+        let frame = SoftwareFrame::new(data, w, h, self.pyramid_mode)?;
+        self.pane_mut().frames.push(frame);
+
+        Ok(())
+    }
+
+    pub fn set_frame_alignments(&mut self, alignments: &[FrameAlignment]) {
+        let pane = self.pane_mut();
+        for (frame, alignment) in pane.frames.iter_mut().zip(alignments) {
+            frame.alignment = *alignment;
+        }
+        pane.show_frame(pane.frame);
+    }
+
+    pub fn show_frame(&mut self, index: usize) {
+        self.pane_mut().show_frame(index);
+    }
+
+    /// See `FitsRenderer::set_compare`
+    pub fn set_compare(&mut self, mode: CompareMode, reference: usize) -> Result<()> {
+        let pane = self.pane_mut();
+        ensure!(reference < pane.frames.len(), "No frame {}", reference);
+        ensure!(
+            pane.size() == (pane.frames[reference].width, pane.frames[reference].height),
+            "Frames to compare differ in size"
+        );
+
+        pane.reference = (mode != CompareMode::Off).then_some(reference);
+        pane.uniforms.compare_mode = mode as u32;
+        pane.update_compare();
+        Ok(())
+    }
+
+    pub fn blink_state(&self) -> BlinkState {
+        let pane = self.pane();
+        BlinkState {
+            frame: pane.frame,
+            frame_count: pane.frames.len(),
+            alignment: pane
+                .current()
+                .map(|frame| frame.alignment)
+                .unwrap_or_default(),
+            comparing: pane.reference.is_some(),
+        }
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.pane_mut().uniforms.interpolation = interpolation as u32;
+    }
+
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.pane_mut().uniforms.set_orientation(orientation);
+    }
+
+    pub fn set_pyramid_mode(&mut self, mode: PyramidMode) {
+        if self.pyramid_mode == mode {
+            return;
+        }
+
+        self.pyramid_mode = mode;
+        for frame in self.panes.iter_mut().flat_map(|pane| &mut pane.frames) {
+            let base = frame.levels.swap_remove(0);
+            frame.levels = build_levels(base, mode);
+        }
+    }
+
+    pub fn set_overlay(&mut self, pane: usize, lines: &[OverlayLine]) {
+        if let Some(pane) = self.panes.get_mut(pane) {
+            let (width, height) = pane.size();
+            pane.overlay = line_vertices(lines, width, height);
+        }
+    }
+
+    /// Uniforms of the active pane
+    pub fn uniforms(&self) -> &Uniforms {
+        &self.pane().uniforms
+    }
+
+    pub fn pane_uniforms(&self, pane: usize) -> &Uniforms {
+        &self.panes[pane].uniforms
+    }
+
+    pub fn has_image(&self) -> bool {
+        self.pane().has_image()
+    }
+
+    pub fn layout(&self) -> PaneLayout {
+        self.layout
+    }
+
+    /// See `FitsRenderer::set_layout`
+    pub fn set_layout(&mut self, layout: PaneLayout) {
+        let count = layout.pane_count();
+        self.panes.truncate(count);
+        while self.panes.len() < count {
+            let mut pane = SoftwarePane::default();
+            pane.uniforms.clip_overlay = self.panes[0].uniforms.clip_overlay;
+            pane.uniforms.interpolation = self.panes[0].uniforms.interpolation;
+            self.panes.push(pane);
+        }
+        self.layout = layout;
+        self.active = self.active.min(count - 1);
+    }
+
+    pub fn active_pane(&self) -> usize {
+        self.active
+    }
+
+    pub fn set_active_pane(&mut self, pane: usize) {
+        self.active = pane.min(self.panes.len() - 1);
+    }
+
+    pub fn set_linked_view(&mut self, linked: bool) {
+        self.linked_view = linked;
+        if linked {
+            let view = self.pane().uniforms;
+            for pane in &mut self.panes {
+                pane.uniforms.follow_view(&view);
+            }
+        }
+    }
+
+    /// Counterpart of `FitsRenderer::bind_image`: set up the uniforms for the
+    /// image newly loaded into the active pane, keeping stretch and view
+    /// unless `reset_view`
+    pub fn bind_image(&mut self, viewport_width: u32, viewport_height: u32, reset_view: bool) {
+        let rect = self
+            .layout
+            .rects(viewport_width as f32, viewport_height as f32)[self.active];
+        let linked = self
+            .panes
+            .iter()
+            .enumerate()
+            .find(|(index, pane)| *index != self.active && pane.has_image())
+            .map(|(_, pane)| pane.uniforms)
+            .filter(|_| self.linked_view);
+
+        let pane = self.pane_mut();
+        let (width, height) = pane.size();
+        let image_aspect = width as f32 / height as f32;
+        pane.uniforms
+            .prepare_for_image(image_aspect, rect.aspect(), reset_view);
+        if let (true, Some(other)) = (reset_view, linked) {
+            pane.uniforms.follow_view(&other);
+        }
+    }
+
+    pub fn update_stretch(&mut self, min: f32, max: f32) {
+        let uniforms = &mut self.pane_mut().uniforms;
+        uniforms.min_value = min;
+        uniforms.max_value = max;
+    }
+
+    pub fn update_view(&mut self, zoom: f32, pan_x: f32, pan_y: f32) {
+        for (index, pane) in self.panes.iter_mut().enumerate() {
+            if self.linked_view || index == self.active {
+                pane.uniforms.zoom = zoom;
+                pane.uniforms.pan_x = pan_x;
+                pane.uniforms.pan_y = pan_y;
+            }
+        }
+    }
+
+    pub fn update_viewport_aspect(&mut self, viewport_width: u32, viewport_height: u32) {
+        let rects = self
+            .layout
+            .rects(viewport_width as f32, viewport_height as f32);
+        for (pane, rect) in self.panes.iter_mut().zip(rects) {
+            pane.uniforms.viewport_aspect = rect.aspect();
+        }
+    }
+
+    pub fn set_clip_overlay(&mut self, enabled: bool) {
+        for pane in &mut self.panes {
+            pane.uniforms.clip_overlay = if enabled { 1.0 } else { 0.0 };
+        }
+    }
+
+    pub fn set_saturation_level(&mut self, level: Option<f32>) {
+        self.pane_mut().uniforms.saturation_level = level.unwrap_or(f32::MAX);
+    }
+
+    /// Render a `viewport_width` x `viewport_height` RGBA8 frame (row-major,
+    /// top row first) with every pane in its cell
+    pub fn render(&self, viewport_width: u32, viewport_height: u32) -> Vec<u8> {
+        let (vw, vh) = (
+            viewport_width.max(1) as usize,
+            viewport_height.max(1) as usize,
+        );
+
+        // Nothing loaded yet: plain clear like the GPU path without a pipeline
+        if !self.panes.iter().any(SoftwarePane::has_image) {
+            let color = Self::EMPTY_COLOR.map(|c| (c * 255.0).round() as u8);
+            return color.repeat(vw * vh);
+        }
+
+        // Opaque black between and around panes without an image
+        let mut frame = [0, 0, 0, 255].repeat(vw * vh);
+
+        let rects = self.layout.rects(vw as f32, vh as f32);
+        for (pane, rect) in self.panes.iter().zip(rects) {
+            let Some(image) = pane.current() else {
+                continue;
+            };
+            if rect.is_empty() {
+                continue;
+            }
+
+            let (x0, y0) = (rect.x as usize, rect.y as usize);
+            let (pw, ph) = (rect.width as usize, rect.height as usize);
+            let pixels = Self::render_pane(pane, image, pw, ph);
+            for (row, line) in pixels.chunks_exact(pw * 4).enumerate() {
+                let start = ((y0 + row) * vw + x0) * 4;
+                frame[start..start + pw * 4].copy_from_slice(line);
+            }
+        }
+
+        frame
+    }
+
+    /// One pane showing `image` as a `pw` x `ph` RGBA8 frame
+    fn render_pane(pane: &SoftwarePane, image: &SoftwareFrame, pw: usize, ph: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; pw * ph * 4];
+
+        // The frame always covers the full pane, like the viewport does
+        let mut uniforms = pane.uniforms;
+        uniforms.viewport_aspect = pw as f32 / ph as f32;
+
+        // Pyramid level, the analytic version of the derivatives in fs_main
+        let transform = ViewTransform::new(&uniforms, pw as f32, ph as f32);
+        let footprint = transform.footprint(image.height, pw as f32, ph as f32);
+        let level_index = level_for_footprint(footprint, image.levels.len() as u32);
+        let level = image.levels.get(level_index as usize);
+        let full = image.levels.first().filter(|_| level_index > 0);
+
+        // Same level of the reference frame and where it sits, in texture
+        // coordinates (see reference_coords in fs_main)
+        let reference = pane.reference.and_then(|reference| {
+            let [dx, dy] = uniforms.reference_offset;
+            let offset = [dx / image.width as f32, dy / image.height as f32];
+            pane.frames[reference]
+                .levels
+                .get(level_index as usize)
+                .map(|level| (level, offset))
+        });
+
+        // Split rows between the available cores
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let rows_per_chunk = ph.div_ceil(threads);
+
+        thread::scope(|scope| {
+            for (chunk_index, chunk) in frame.chunks_mut(rows_per_chunk * pw * 4).enumerate() {
+                let (uniforms, transform, reference) = (&uniforms, &transform, reference);
+                scope.spawn(move || {
+                    let first_row = chunk_index * rows_per_chunk;
+                    for (i, pixel) in chunk.chunks_exact_mut(4).enumerate() {
+                        let x = i % pw;
+                        let y = first_row + i / pw;
+
+                        // Fragment centre, same as the interpolated tex_coords in vs_main
+                        let u = (x as f32 + 0.5) / pw as f32;
+                        let v = (y as f32 + 0.5) / ph as f32;
+
+                        let color = Self::shade(level, full, reference, uniforms, transform, u, v);
+                        for (out, c) in pixel.iter_mut().zip(color) {
+                            *out = (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+                        }
+                    }
+                });
+            }
+        });
+
+        draw_lines(&mut frame, pw, ph, &transform, &pane.overlay);
+
+        frame
+    }
+
+    /// Rust port of `fs_main`. `full` is the full resolution level when
+    /// `level` is a coarser one, the clipping overlay tests its pixels.
+    fn shade(
+        level: Option<&PyramidLevel>,
+        full: Option<&PyramidLevel>,
+        reference: Option<(&PyramidLevel, [f32; 2])>,
+        uniforms: &Uniforms,
+        transform: &ViewTransform,
+        u: f32,
+        v: f32,
+    ) -> [f32; 4] {
+        let Some(level) = level else {
+            return Self::EMPTY_COLOR;
+        };
+
+        // Undo aspect correction, zoom, pan and orientation
+        let [tx, ty] = transform.screen_to_texture(u, v);
+
+        if !(0.0..=1.0).contains(&tx) || !(0.0..=1.0).contains(&ty) {
+            return [0.0, 0.0, 0.0, 1.0];
+        }
+
+        // Same resampling as sample_image in the shader
+        let mode = Interpolation::from_index(uniforms.interpolation);
+        let raw_value = interpolation::sample(level, tx, ty, mode);
+
+        let value = raw_value - uniforms.background_offset;
+
+        let compare = CompareMode::from_index(uniforms.compare_mode);
+        if let (CompareMode::Difference | CompareMode::Ratio, Some((reference, [ox, oy]))) =
+            (compare, reference)
+        {
+            let reference_value = interpolation::sample(reference, tx + ox, ty + oy, mode)
+                - uniforms.reference_background;
+            let result = compare.combine(value, reference_value);
+            let adjusted =
+                (uniforms.stretch(result) - 0.5) * uniforms.contrast + 0.5 + uniforms.brightness;
+            let [r, g, b] = diverging(adjusted.clamp(0.0, 1.0));
+            return [r, g, b, 1.0];
+        }
+
+        if uniforms.clip_overlay > 0.5 {
+            let full_value =
+                full.map_or(raw_value, |full| interpolation::sample(full, tx, ty, mode));
+            if full_value >= uniforms.saturation_level {
+                return [1.0, 0.85, 0.0, 1.0];
+            }
+            if full_value - uniforms.background_offset > uniforms.max_value {
+                return [1.0, 0.0, 0.0, 1.0];
+            }
+            if full_value - uniforms.background_offset < uniforms.min_value {
+                return [0.0, 0.3, 1.0, 1.0];
+            }
+        }
+
+        let normalized = uniforms.normalize(raw_value);
+        let adjusted = (normalized - 0.5) * uniforms.contrast + 0.5 + uniforms.brightness;
+        let value = adjusted.clamp(0.0, 1.0);
+
+        [value, value, value, 1.0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::headless::tests::headless;
+    use crate::renderer::FitsRenderer;
+
+    const VIEWPORT: (u32, u32) = (320, 200);
+
+    /// Both renderers showing the same 97 x 61 image, sizes that split into
+    /// neither tiles nor pyramid levels evenly
+    fn renderers() -> Option<(FitsRenderer, SoftwareRenderer)> {
+        let mut gpu = headless()?;
+        let (w, h) = (97, 61);
+        let data: Vec<f32> = (0..w * h)
+            .map(|i| ((i * 7919) % 1000) as f32 + (i % w) as f32)
+            .collect();
+        gpu.load_fits_data(&data, w, h).unwrap();
+        gpu.bind_image(
+            wgpu::TextureFormat::Rgba8Unorm,
+            VIEWPORT.0,
+            VIEWPORT.1,
+            true,
+        )
+        .unwrap();
+        let mut cpu = SoftwareRenderer::new();
+        cpu.load_fits_data(&data, w, h).unwrap();
+        cpu.bind_image(VIEWPORT.0, VIEWPORT.1, true);
+        Some((gpu, cpu))
+    }
+
+    /// Same view, stretch and clipping for both
+    fn show(
+        gpu: &mut FitsRenderer,
+        cpu: &mut SoftwareRenderer,
+        zoom: f32,
+        [pan_x, pan_y]: [f32; 2],
+        clip: bool,
+    ) {
+        gpu.update_view(zoom, pan_x, pan_y);
+        cpu.update_view(zoom, pan_x, pan_y);
+        gpu.update_stretch(100.0, 900.0);
+        cpu.update_stretch(100.0, 900.0);
+        gpu.set_clip_overlay(clip);
+        cpu.set_clip_overlay(clip);
+        gpu.set_saturation_level(Some(1050.0));
+        cpu.set_saturation_level(Some(1050.0));
+    }
+
+    /// Largest difference of any channel of any pixel
+    fn max_difference(gpu: &FitsRenderer, cpu: &SoftwareRenderer) -> u8 {
+        let a = gpu.render_to_rgba(VIEWPORT.0, VIEWPORT.1).unwrap();
+        let b = cpu.render(VIEWPORT.0, VIEWPORT.1);
+        assert_eq!(a.len(), b.len());
+        a.iter().zip(&b).map(|(a, b)| a.abs_diff(*b)).max().unwrap()
+    }
+
+    #[test]
+    fn matches_gpu_across_views() {
+        let Some((mut gpu, mut cpu)) = renderers() else {
+            return;
+        };
+        // 1:1, magnified and panned, clipped, and minified onto pyramid levels
+        for (zoom, pan_x, pan_y, clip) in [
+            (1.0, 0.0, 0.0, false),
+            (2.3, 0.1, -0.2, false),
+            (0.7, 0.0, 0.0, true),
+            (0.2, 0.0, 0.0, false),
+            (0.05, 0.1, 0.0, false),
+        ] {
+            show(&mut gpu, &mut cpu, zoom, [pan_x, pan_y], clip);
+            let difference = max_difference(&gpu, &cpu);
+            assert!(difference <= 1, "zoom {}: off by {}", zoom, difference);
+        }
+    }
+
+    #[test]
+    fn matches_gpu_with_each_interpolation_and_orientation() {
+        let Some((mut gpu, mut cpu)) = renderers() else {
+            return;
+        };
+        let orientation = Orientation {
+            rotation_deg: 30.0,
+            flip_horizontal: true,
+            flip_vertical: false,
+        };
+        gpu.set_orientation(orientation);
+        cpu.set_orientation(orientation);
+        for interpolation in [
+            Interpolation::Nearest,
+            Interpolation::Bilinear,
+            Interpolation::Bicubic,
+            Interpolation::Lanczos,
+        ] {
+            gpu.set_interpolation(interpolation);
+            cpu.set_interpolation(interpolation);
+            show(&mut gpu, &mut cpu, 3.1, [0.05, 0.02], false);
+            let difference = max_difference(&gpu, &cpu);
+            assert!(
+                difference <= 1,
+                "{:?}: off by {}",
+                interpolation,
+                difference
+            );
+        }
+    }
+
+    #[test]
+    fn saturated_pixels_survive_mean_pyramid() {
+        // 5 x 5 saturated patch straddling the 4 x 4 blocks of pyramid level
+        // 2, which averages it below the saturation level everywhere
+        let size = 400;
+        let mut data = vec![100.0f32; size * size];
+        for y in 201..206 {
+            for x in 201..206 {
+                data[y * size + x] = 1000.0;
+            }
+        }
+        let yellow = |frame: &[u8]| {
+            frame
+                .chunks_exact(4)
+                .filter(|p| p[0] == 255 && p[1] == 217 && p[2] == 0)
+                .count()
+        };
+
+        // Shown 4 times smaller, so on level 2
+        let mut cpu = SoftwareRenderer::new();
+        cpu.set_pyramid_mode(PyramidMode::Mean);
+        cpu.load_fits_data(&data, size, size).unwrap();
+        cpu.bind_image(100, 100, true);
+        cpu.update_stretch(0.0, 5000.0);
+        cpu.set_clip_overlay(true);
+        cpu.set_saturation_level(Some(1000.0));
+        assert!(yellow(&cpu.render(100, 100)) > 0);
+
+        let Some(mut gpu) = headless() else {
+            return;
+        };
+        gpu.set_pyramid_mode(PyramidMode::Mean);
+        gpu.load_fits_data(&data, size, size).unwrap();
+        gpu.bind_image(wgpu::TextureFormat::Rgba8Unorm, 100, 100, true)
+            .unwrap();
+        gpu.update_stretch(0.0, 5000.0);
+        gpu.set_clip_overlay(true);
+        gpu.set_saturation_level(Some(1000.0));
+        assert!(yellow(&gpu.render_to_rgba(100, 100).unwrap()) > 0);
+    }
+
+    #[test]
+    fn panes_fit_a_viewport_narrower_than_the_layout() {
+        let (w, h) = (8, 6);
+        let data: Vec<f32> = (0..w * h).map(|i| i as f32).collect();
+        for layout in [PaneLayout::Split, PaneLayout::Stacked, PaneLayout::Grid] {
+            let mut cpu = SoftwareRenderer::new();
+            cpu.set_layout(layout);
+            for pane in 0..layout.pane_count() {
+                cpu.set_active_pane(pane);
+                cpu.load_fits_data(&data, w, h).unwrap();
+                cpu.bind_image(1, 1, true);
+            }
+            for (width, height) in [(1, 1), (1, 5), (5, 1), (3, 3)] {
+                let rects = layout.rects(width as f32, height as f32);
+                assert!(
+                    rects
+                        .iter()
+                        .all(|r| r.x + r.width <= width as f32 && r.y + r.height <= height as f32),
+                    "{:?} at {}x{}: {:?}",
+                    layout,
+                    width,
+                    height,
+                    rects
+                );
+                let frame = cpu.render(width, height);
+                assert_eq!(frame.len(), (width * height * 4) as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolation_is_set_per_pane() {
+        let mut cpu = SoftwareRenderer::new();
+        cpu.set_layout(PaneLayout::Split);
+        cpu.set_active_pane(1);
+        cpu.set_interpolation(Interpolation::Lanczos);
+
+        let mode = |pane| Interpolation::from_index(cpu.pane_uniforms(pane).interpolation);
+        assert_eq!(mode(0), Interpolation::Nearest);
+        assert_eq!(mode(1), Interpolation::Lanczos);
+    }
+}
@@ -0,0 +1,323 @@
+use super::{request_device, FitsRenderer};
+use anyhow::*;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+
+impl FitsRenderer {
+    /// Create a renderer without a window. With `force_fallback_adapter` wgpu
+    /// picks its software adapter (WARP, llvmpipe, ...), so this also works on
+    /// CI machines without a GPU.
+    pub fn new_headless(force_fallback_adapter: bool) -> Result<Self> {
+        let instance = wgpu::Instance::default();
+
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter,
+            compatible_surface: None,
+        }))
+        .context("No adapter found for headless rendering")?;
+
+        println!("🧪 Headless renderer on '{}'", adapter.get_info().name);
+
+        let (device, queue) = request_device(&adapter)?;
+        Ok(Self::new(Arc::new(device), Arc::new(queue)))
+    }
+
+    /// Render the current view at `width` x `height` as the window would show
+    /// it at that size: the panes are laid out for the export size and go
+    /// back to the window's layout afterwards.
+    pub fn export_rgba(&mut self, width: u32, height: u32) -> Result<Vec<u8>> {
+        let (window_width, window_height) = self.viewport;
+        self.update_viewport_aspect(width, height);
+        let frame = self.render_to_rgba(width, height);
+        self.update_viewport_aspect(window_width, window_height);
+        frame
+    }
+
+    /// Render the current image into an offscreen texture and read it back as
+    /// RGBA8 (row-major, top row first). The texture uses the format the
+    /// pipeline was built for, so the result matches what the surface shows.
+    /// The viewport aspect is not changed, see `export_rgba` for rendering at
+    /// a different size than the window.
+    pub fn render_to_rgba(&self, width: u32, height: u32) -> Result<Vec<u8>> {
+        let format = self
+            .pipeline
+            .as_ref()
+            .map_or(wgpu::TextureFormat::Rgba8Unorm, |p| p.format);
+        let swap_red_blue = match format {
+            wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => false,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+            other => bail!("Cannot read back {:?} as RGBA8", other),
+        };
+
+        let size = wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        };
+
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Target"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // Rows in the readback buffer must be 256-byte aligned
+        let unpadded_bytes_per_row = size.width * 4;
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(alignment) * alignment;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Offscreen Readback"),
+            size: padded_bytes_per_row as u64 * size.height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("offscreen-encoder"),
+            });
+        self.encode_render_pass(&mut encoder, &view, size.width, size.height);
+        encoder.copy_texture_to_buffer(
+            wgpu::TexelCopyTextureInfo {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(size.height),
+                },
+            },
+            size,
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        // Wait for the copy and map the buffer
+        let slice = buffer.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::PollType::wait_indefinitely())?;
+        receiver.recv()??;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * size.height) as usize);
+        {
+            let mapped = slice.get_mapped_range();
+            for row in mapped.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+
+        if swap_red_blue {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        Ok(pixels)
+    }
+}
+
+/// Write an RGBA8 frame as PNG
+pub fn write_png(path: impl AsRef<Path>, rgba: &[u8], width: u32, height: u32) -> Result<()> {
+    ensure!(
+        rgba.len() == width as usize * height as usize * 4,
+        "frame does not match {}x{}",
+        width,
+        height
+    );
+
+    let file = std::fs::File::create(path)?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgba)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::renderer::{CompareMode, FrameAlignment};
+
+    /// Set (e.g. on CI) to fail the GPU tests when there is no adapter
+    /// instead of skipping them.
+    const REQUIRE_GPU_ENV: &str = "RAPIDFITS_REQUIRE_GPU_TESTS";
+
+    /// A hardware adapter when there is one, wgpu's software adapter
+    /// otherwise. Without either the test is skipped, or fails when
+    /// `RAPIDFITS_REQUIRE_GPU_TESTS` is set.
+    pub(crate) fn headless() -> Option<FitsRenderer> {
+        match FitsRenderer::new_headless(false).or_else(|_| FitsRenderer::new_headless(true)) {
+            Result::Ok(renderer) => Some(renderer),
+            Result::Err(e) if std::env::var_os(REQUIRE_GPU_ENV).is_some() => {
+                panic!(
+                    "{} is set but there is no wgpu adapter: {:#}",
+                    REQUIRE_GPU_ENV, e
+                )
+            }
+            Result::Err(_) => {
+                eprintln!("no wgpu adapter, skipping headless render test");
+                None
+            }
+        }
+    }
+
+    fn gray(frame: &[u8], width: u32, x: u32, y: u32) -> u8 {
+        frame[((y * width + x) * 4) as usize]
+    }
+
+    #[test]
+    fn renders_image_pixel_for_pixel() {
+        let Some(mut renderer) = headless() else {
+            return;
+        };
+        // 8 x 4 ramp from 0 to 992, shown 1:1 with a 0 to 1023 stretch
+        let data: Vec<f32> = (0..32).map(|i| i as f32 * 32.0).collect();
+        renderer.load_fits_data(&data, 8, 4).unwrap();
+        renderer
+            .bind_image(wgpu::TextureFormat::Rgba8Unorm, 8, 4, true)
+            .unwrap();
+        renderer.update_stretch(0.0, 1023.0);
+
+        let frame = renderer.render_to_rgba(8, 4).unwrap();
+        for (i, (pixel, value)) in frame.chunks_exact(4).zip(&data).enumerate() {
+            let expected = (value / 1023.0 * 255.0).round() as i32;
+            assert!(
+                (pixel[0] as i32 - expected).abs() <= 1 && pixel[0] == pixel[1],
+                "pixel {} is {:?}, expected gray {}",
+                i,
+                pixel,
+                expected
+            );
+            assert_eq!(pixel[3], 255);
+        }
+    }
+
+    #[test]
+    fn export_lays_panes_out_for_its_size() {
+        let Some(mut renderer) = headless() else {
+            return;
+        };
+        // Square image in a wide window, exported as a tall frame
+        renderer
+            .load_fits_data(&vec![500.0; 100 * 100], 100, 100)
+            .unwrap();
+        renderer
+            .bind_image(wgpu::TextureFormat::Rgba8Unorm, 300, 100, true)
+            .unwrap();
+        renderer.update_stretch(0.0, 1000.0);
+
+        let frame = renderer.export_rgba(100, 200).unwrap();
+        // The image keeps its aspect: a 100 x 100 square between black bars
+        for y in [5, 45, 155, 195] {
+            assert_eq!(gray(&frame, 100, 50, y), 0, "row {} should be empty", y);
+        }
+        for y in [55, 100, 145] {
+            assert!(
+                gray(&frame, 100, 50, y).abs_diff(128) <= 1,
+                "row {} should show the image",
+                y
+            );
+        }
+        assert_eq!(renderer.viewport, (300, 100));
+    }
+
+    #[test]
+    fn tiled_compare_stays_within_the_apron() {
+        let Some(mut renderer) = headless() else {
+            return;
+        };
+        // 200 x 150 in 64 px tiles, two frames
+        renderer.set_max_tile_size(64);
+        let data: Vec<f32> = (0..200 * 150).map(|i| (i % 200) as f32).collect();
+        renderer.load_fits_data(&data, 200, 150).unwrap();
+        renderer.add_frame(&data, 200, 150).unwrap();
+        renderer
+            .bind_image(wgpu::TextureFormat::Rgba8Unorm, 200, 150, true)
+            .unwrap();
+        let shifted = |dx| {
+            [
+                FrameAlignment::default(),
+                FrameAlignment {
+                    dx,
+                    ..Default::default()
+                },
+            ]
+        };
+
+        renderer.show_frame(1);
+
+        renderer.set_frame_alignments(&shifted(10.0));
+        assert!(renderer.set_compare(CompareMode::Difference, 0).is_err());
+        assert_eq!(renderer.panes[0].uniforms.compare_mode, 0);
+
+        renderer.set_frame_alignments(&shifted(3.0));
+        renderer.set_compare(CompareMode::Difference, 0).unwrap();
+        assert_eq!(
+            renderer.panes[0].uniforms.compare_mode,
+            CompareMode::Difference as u32
+        );
+
+        // Moving the frames apart while comparing stops the comparison
+        renderer.set_frame_alignments(&shifted(10.0));
+        assert_eq!(renderer.panes[0].reference, None);
+        assert_eq!(renderer.panes[0].uniforms.compare_mode, 0);
+    }
+
+    #[test]
+    fn tiled_image_renders_like_a_single_texture() {
+        let Some(mut single) = headless() else {
+            return;
+        };
+        let Some(mut tiled) = headless() else {
+            return;
+        };
+        tiled.set_max_tile_size(64);
+        let (w, h) = (150, 100);
+        let data: Vec<f32> = (0..w * h)
+            .map(|i| ((i * 7919) % 1000) as f32 + (i % w) as f32)
+            .collect();
+        for renderer in [&mut single, &mut tiled] {
+            renderer.load_fits_data(&data, w, h).unwrap();
+            renderer
+                .bind_image(wgpu::TextureFormat::Rgba8Unorm, 300, 200, true)
+                .unwrap();
+            renderer.set_interpolation(crate::renderer::Interpolation::Lanczos);
+            renderer.update_stretch(100.0, 900.0);
+        }
+        assert!(tiled.panes[0].frames[0].tiles.len() > 1);
+
+        // Magnified, 1:1 and shrunk to the deepest level the 64 texel tiles
+        // keep, where the filters reach furthest into the aprons
+        for zoom in [2.3, 1.0, 0.25, 0.2] {
+            single.update_view(zoom, 0.05, -0.1);
+            tiled.update_view(zoom, 0.05, -0.1);
+            let a = single.render_to_rgba(300, 200).unwrap();
+            let b = tiled.render_to_rgba(300, 200).unwrap();
+            let difference = a.iter().zip(&b).map(|(a, b)| a.abs_diff(*b)).max();
+            assert!(
+                difference <= Some(1),
+                "zoom {}: off by {:?}",
+                zoom,
+                difference
+            );
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use super::pyramid::PyramidLevel;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+/// How the image is resampled between pixel centres.
+/// The discriminants are the values `shader.wgsl` switches on.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    /// Blocky, shows the real pixels
+    #[default]
+    Nearest = 0,
+    Bilinear = 1,
+    /// Catmull-Rom cubic
+    Bicubic = 2,
+    /// Lanczos with a = 3, sharpest but may ring around bright stars
+    Lanczos = 3,
+}
+
+impl Interpolation {
+    /// Inverse of `as u32`, unknown values fall back to nearest like the shader
+    pub fn from_index(index: u32) -> Self {
+        match index {
+            1 => Interpolation::Bilinear,
+            2 => Interpolation::Bicubic,
+            3 => Interpolation::Lanczos,
+            _ => Interpolation::Nearest,
+        }
+    }
+}
+
+fn cubic_weight(x: f32) -> f32 {
+    let a = x.abs();
+    if a < 1.0 {
+        (1.5 * a - 2.5) * a * a + 1.0
+    } else if a < 2.0 {
+        ((-0.5 * a + 2.5) * a - 4.0) * a + 2.0
+    } else {
+        0.0
+    }
+}
+
+fn lanczos_weight(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    if x.abs() >= 3.0 {
+        return 0.0;
+    }
+    let px = PI * x;
+    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+}
+
+fn texel(data: &[f32], width: u32, height: u32, x: i32, y: i32) -> f32 {
+    let x = x.clamp(0, width as i32 - 1) as usize;
+    let y = y.clamp(0, height as i32 - 1) as usize;
+    data[y * width as usize + x]
+}
+
+/// Sample `level` at texture coordinates `tx`, `ty` (0..1), mirroring
+/// `sample_image` in `shader.wgsl`
+pub fn sample(level: &PyramidLevel, tx: f32, ty: f32, mode: Interpolation) -> f32 {
+    let (width, height) = (level.width, level.height);
+    let (x, y) = (tx * width as f32, ty * height as f32);
+    sample_at(&level.data, width, height, x, y, mode)
+}
+
+/// Sample a `width` x `height` image at `x`, `y` in pixels (pixel `i` covers
+/// `i..i + 1`, so its centre is at `i + 0.5`)
+pub fn sample_at(
+    data: &[f32],
+    width: u32,
+    height: u32,
+    x: f32,
+    y: f32,
+    mode: Interpolation,
+) -> f32 {
+    let texel = |x: i32, y: i32| texel(data, width, height, x, y);
+
+    let (weight, radius): (fn(f32) -> f32, i32) = match mode {
+        Interpolation::Nearest => return texel(x.floor() as i32, y.floor() as i32),
+        Interpolation::Bilinear => {
+            let (px, py) = (x - 0.5, y - 0.5);
+            let (bx, by) = (px.floor(), py.floor());
+            let (fx, fy) = (px - bx, py - by);
+            let (ix, iy) = (bx as i32, by as i32);
+
+            let top = texel(ix, iy) * (1.0 - fx) + texel(ix + 1, iy) * fx;
+            let bottom = texel(ix, iy + 1) * (1.0 - fx) + texel(ix + 1, iy + 1) * fx;
+            return top * (1.0 - fy) + bottom * fy;
+        }
+        Interpolation::Bicubic => (cubic_weight, 2),
+        Interpolation::Lanczos => (lanczos_weight, 3),
+    };
+
+    let (px, py) = (x - 0.5, y - 0.5);
+    let (bx, by) = (px.floor(), py.floor());
+    let (fx, fy) = (px - bx, py - by);
+    let (ix, iy) = (bx as i32, by as i32);
+
+    let mut sum = 0.0;
+    let mut weight_sum = 0.0;
+    for j in 1 - radius..=radius {
+        let wy = weight(fy - j as f32);
+        for i in 1 - radius..=radius {
+            let w = weight(fx - i as f32) * wy;
+            sum += w * texel(ix + i, iy + j);
+            weight_sum += w;
+        }
+    }
+
+    sum / weight_sum
+}
@@ -1,299 +1,1249 @@
 use anyhow::*;
 use std::result::Result::{Err as StdErr, Ok as StdOk};
 use std::sync::{Arc, Mutex};
-use std::{thread, time::Duration};
 use tauri::WebviewWindow;
 use wgpu::rwh::{HasDisplayHandle, HasWindowHandle};
 use wgpu::util::DeviceExt;
 
+mod blink;
+mod compare;
+mod cpu;
+mod headless;
+mod interpolation;
+mod overlay;
+mod panes;
+mod pipeline;
+mod pyramid;
+mod render_loop;
+mod surface;
+mod tiles;
+mod view;
+
+pub use blink::{BlinkState, FrameAlignment};
+pub use compare::CompareMode;
+pub use cpu::SoftwareRenderer;
+pub use headless::write_png;
+pub use interpolation::{sample_at, Interpolation};
+pub use overlay::OverlayLine;
+use overlay::{line_vertices, OverlayVertex};
+use panes::PaneRect;
+pub use panes::{PaneLayout, PanePoint};
+use pipeline::FitsPipeline;
+pub use pyramid::PyramidMode;
+use pyramid::{mip_level_count, PyramidBuilder};
+pub use render_loop::{RedrawSignal, RenderLoop};
+use surface::WindowSurface;
+use tiles::{max_reference_offset, plan_tiles, Tile};
+pub use view::{Orientation, PixelInfo, ViewTransform};
+
+/// Environment variable that forces the software renderer (`software` or `cpu`)
+const RENDERER_ENV: &str = "RAPIDFITS_RENDERER";
+
+/// Environment variable that caps the tile size (pixels), to try tiling on
+/// GPUs whose textures are large enough for any image
+const MAX_TILE_ENV: &str = "RAPIDFITS_MAX_TILE";
+
+/// Pause before retrying a frame the swapchain could not provide, doubled
+/// for every failure in a row up to `MAX_RETRY_DELAY`
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(16);
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Uniforms shared by `shader.wgsl` and the software renderer.
+/// Field order and size must match the WGSL `Uniforms` struct.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Uniforms {
+    pub min_value: f32,
+    pub max_value: f32,
+    pub brightness: f32,
+    pub contrast: f32,
+    pub zoom: f32,
+    pub pan_x: f32,
+    pub pan_y: f32,
+    pub aspect_ratio: f32,
+    pub viewport_aspect: f32,
+    pub clip_overlay: f32,
+    pub saturation_level: f32,
+    /// `Interpolation` as u32
+    pub interpolation: u32,
+    /// Clockwise rotation on screen, radians
+    pub rotation: f32,
+    /// 1.0 = mirrored left/right, applied before the rotation
+    pub flip_x: f32,
+    /// 1.0 = mirrored top/bottom, applied before the rotation
+    pub flip_y: f32,
+    /// Subtracted from every value before the stretch, see `FrameAlignment`
+    pub background_offset: f32,
+    /// Image displacement in texture coordinates, see `FrameAlignment`
+    pub shift: [f32; 2],
+    /// `CompareMode` as u32
+    pub compare_mode: u32,
+    /// Background offset of the reference frame
+    pub reference_background: f32,
+    /// Reference frame pixel minus frame pixel showing the same star
+    pub reference_offset: [f32; 2],
+    pub _padding: [f32; 2],
+}
+
+impl Default for Uniforms {
+    fn default() -> Self {
+        Self {
+            min_value: 0.0,
+            max_value: 65535.0,
+            brightness: 0.0,
+            contrast: 1.0,
+            zoom: 1.0, // 1.0 = fit to screen
+            pan_x: 0.0,
+            pan_y: 0.0,
+            aspect_ratio: 1.0,
+            viewport_aspect: 1.0,
+            clip_overlay: 0.0,
+            saturation_level: f32::MAX,
+            interpolation: Interpolation::Nearest as u32,
+            rotation: 0.0,
+            flip_x: 0.0,
+            flip_y: 0.0,
+            background_offset: 0.0,
+            shift: [0.0; 2],
+            compare_mode: CompareMode::Off as u32,
+            reference_background: 0.0,
+            reference_offset: [0.0; 2],
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+impl Uniforms {
+    /// Reset stretch and navigation for a new image, keeping display toggles
+    fn reset_for_image(&mut self, image_aspect: f32, viewport_aspect: f32) {
+        *self = Self {
+            aspect_ratio: image_aspect,
+            viewport_aspect,
+            clip_overlay: self.clip_overlay,
+            saturation_level: self.saturation_level,
+            interpolation: self.interpolation,
+            rotation: self.rotation,
+            flip_x: self.flip_x,
+            flip_y: self.flip_y,
+            ..Self::default()
+        };
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        Orientation {
+            rotation_deg: self.rotation.to_degrees(),
+            flip_horizontal: self.flip_x > 0.5,
+            flip_vertical: self.flip_y > 0.5,
+        }
+    }
+
+    fn set_orientation(&mut self, orientation: Orientation) {
+        self.rotation = orientation.rotation_deg.rem_euclid(360.0).to_radians();
+        self.flip_x = if orientation.flip_horizontal {
+            1.0
+        } else {
+            0.0
+        };
+        self.flip_y = if orientation.flip_vertical { 1.0 } else { 0.0 };
+    }
+
+    /// Line a `width` x `height` frame up with the reference frame
+    fn set_alignment(&mut self, alignment: FrameAlignment, width: u32, height: u32) {
+        self.shift = [
+            alignment.dx / width.max(1) as f32,
+            alignment.dy / height.max(1) as f32,
+        ];
+        self.background_offset = alignment.background;
+    }
+
+    /// Take zoom and pan from `other`, for linked panes
+    fn follow_view(&mut self, other: &Uniforms) {
+        self.zoom = other.zoom;
+        self.pan_x = other.pan_x;
+        self.pan_y = other.pan_y;
+    }
+
+    /// Map a raw value through the stretch: 0 at the black point, 1 at the
+    /// white point (not clamped)
+    pub fn normalize(&self, raw_value: f32) -> f32 {
+        self.stretch(raw_value - self.background_offset)
+    }
+
+    /// Like `normalize` for a value that needs no background matching, e.g.
+    /// a difference or ratio
+    pub fn stretch(&self, value: f32) -> f32 {
+        (value - self.min_value) / (self.max_value - self.min_value)
+    }
+
+    /// Compare the frame (`alignment`) against a reference frame
+    /// (`reference`), or stop comparing with `CompareMode::Off`
+    fn set_compare(
+        &mut self,
+        mode: CompareMode,
+        alignment: FrameAlignment,
+        reference: FrameAlignment,
+    ) {
+        self.compare_mode = mode as u32;
+        self.reference_offset = [reference.dx - alignment.dx, reference.dy - alignment.dy];
+        self.reference_background = reference.background;
+    }
+
+    /// Prepare for a new image: either reset everything (see `reset_for_image`)
+    /// or keep stretch and navigation and only follow the new aspect ratios
+    fn prepare_for_image(&mut self, image_aspect: f32, viewport_aspect: f32, reset_view: bool) {
+        if reset_view {
+            self.reset_for_image(image_aspect, viewport_aspect);
+        } else {
+            self.aspect_ratio = image_aspect;
+            self.viewport_aspect = viewport_aspect;
+        }
+    }
+}
+
+/// An image uploaded to a pane
+struct Frame {
+    tiles: Vec<Tile>,
+    width: u32,
+    height: u32,
+    /// Mip levels the tile aprons are sized for, see `plan_tiles`
+    levels: u32,
+    alignment: FrameAlignment,
+}
+
+/// An image shown in one pane of the window, with its own stretch and view.
+/// A pane can hold several frames to blink through, all kept on the GPU.
+struct Pane {
+    frames: Vec<Frame>,
+    /// Index into `frames` of the one on screen
+    frame: usize,
+    /// Frame the one on screen is compared against, see `set_compare`
+    reference: Option<usize>,
+    uniforms: Uniforms,
+    uniform_buffer: wgpu::Buffer,
+    /// Lines drawn over the image (line list) and their GPU copy
+    overlay: Vec<OverlayVertex>,
+    overlay_buffer: Option<wgpu::Buffer>,
+    overlay_bind_group: Option<wgpu::BindGroup>,
+}
+
+impl Pane {
+    fn new(device: &wgpu::Device) -> Self {
+        Self {
+            frames: Vec::new(),
+            frame: 0,
+            reference: None,
+            uniforms: Uniforms::default(),
+            uniform_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Uniform Buffer"),
+                size: std::mem::size_of::<Uniforms>() as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            overlay: Vec::new(),
+            overlay_buffer: None,
+            overlay_bind_group: None,
+        }
+    }
+
+    fn has_image(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    fn current(&self) -> Option<&Frame> {
+        self.frames.get(self.frame)
+    }
+
+    /// Size of the frame on screen (0 x 0 when empty)
+    fn size(&self) -> (u32, u32) {
+        self.current()
+            .map_or((0, 0), |frame| (frame.width, frame.height))
+    }
+
+    /// Put frame `index` on screen, the overlay belonged to the previous one
+    fn show_frame(&mut self, index: usize) {
+        let Some(frame) = self.frames.get(index) else {
+            return;
+        };
+        self.uniforms.aspect_ratio = frame.width as f32 / frame.height as f32;
+        self.uniforms
+            .set_alignment(frame.alignment, frame.width, frame.height);
+        self.frame = index;
+        self.overlay.clear();
+        self.overlay_buffer = None;
+        self.update_compare();
+    }
+
+    /// Follow the alignment of the frame on screen and of the reference
+    fn update_compare(&mut self) {
+        let alignment = |index: usize| self.frames[index].alignment;
+        let (mode, reference) = match self.reference {
+            Some(reference) => (
+                CompareMode::from_index(self.uniforms.compare_mode),
+                alignment(reference),
+            ),
+            None => (CompareMode::Off, FrameAlignment::default()),
+        };
+        let current = alignment(self.frame);
+        if !self.reference_within_apron() {
+            println!(
+                "⚠️  Frames {} and {} are too far apart to compare this tiled image, comparison stopped",
+                self.frame,
+                self.reference.unwrap_or_default()
+            );
+            self.reference = None;
+            self.uniforms
+                .set_compare(CompareMode::Off, current, FrameAlignment::default());
+            return;
+        }
+        self.uniforms.set_compare(mode, current, reference);
+    }
+
+    /// Each tile of a tiled frame is compared against the same tile of the
+    /// reference, whose texture reaches only its apron past the core. False
+    /// when the reference sits further away than that.
+    fn reference_within_apron(&self) -> bool {
+        let (Some(reference), Some(current)) = (self.reference, self.current()) else {
+            return true;
+        };
+        let reference = self.frames[reference].alignment;
+        let (dx, dy) = (
+            reference.dx - current.alignment.dx,
+            reference.dy - current.alignment.dy,
+        );
+        current.tiles.len() <= 1
+            || dx.abs().max(dy.abs()) <= max_reference_offset(current.levels) as f32
+    }
+}
+
 pub struct FitsRenderer {
     device: Arc<wgpu::Device>,
     queue: Arc<wgpu::Queue>,
-    texture: Option<Arc<wgpu::Texture>>,
-    width: u32,
-    height: u32,
+    /// One per cell of `layout`, always at least one
+    panes: Vec<Pane>,
+    /// Pane that new images, stretch and orientation changes go to
+    active: usize,
+    layout: PaneLayout,
+    /// Zoom and pan apply to every pane
+    linked_view: bool,
+    /// Size of the window the panes are laid out in (physical pixels)
+    viewport: (u32, u32),
+    /// Largest texture side we upload, from the device limits
+    max_tile_size: u32,
+    /// Compute pipelines for the mip pyramid, created on first upload
+    pyramid: Option<PyramidBuilder>,
+    pyramid_mode: PyramidMode,
 
-    pipeline: Option<wgpu::RenderPipeline>,
-    bind_group: Option<wgpu::BindGroup>,
-    uniform_buffer: Option<wgpu::Buffer>,
+    /// Built on first use and kept while the target format stays the same
+    pipeline: Option<FitsPipeline>,
+    /// Raised whenever the next frame would look different
+    redraw: RedrawSignal,
+    /// Physical size the window surface should have, the render thread
+    /// reconfigures the swapchain before its next frame when it differs
+    surface_size: (u32, u32),
 }
 
 impl FitsRenderer {
     pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Self {
+        let max_tile_size = device.limits().max_texture_dimension_2d;
+        let panes = vec![Pane::new(&device)];
         Self {
             device,
             queue,
-            texture: None,
-            width: 0,
-            height: 0,
+            panes,
+            active: 0,
+            layout: PaneLayout::default(),
+            linked_view: false,
+            viewport: (1, 1),
+            max_tile_size,
+            pyramid: None,
+            pyramid_mode: PyramidMode::default(),
             pipeline: None,
-            bind_group: None,
-            uniform_buffer: None,
+            redraw: RedrawSignal::default(),
+            surface_size: (0, 0),
+        }
+    }
+
+    /// Follow a window resize or DPI change (physical pixels). The swapchain
+    /// itself belongs to the render thread, which picks the new size up.
+    pub fn resize_surface(&mut self, width: u32, height: u32) {
+        self.surface_size = (width, height);
+        if width > 0 && height > 0 {
+            self.update_viewport_aspect(width, height);
         }
+        self.redraw.request();
     }
-    pub fn create_pipeline(
+
+    /// Draw the current frame into `frame`, acquired from the window surface
+    /// by the render thread, which presents it afterwards
+    fn render_to_frame(&mut self, frame: &wgpu::SurfaceTexture) {
+        let view = frame.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("surface-view"),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            format: None,
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            mip_level_count: None,
+            base_array_layer: 0,
+            array_layer_count: None,
+            usage: Some(wgpu::TextureUsages::RENDER_ATTACHMENT),
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("render-encoder"),
+            });
+        let (width, height) = (frame.texture.width(), frame.texture.height());
+        self.encode_render_pass(&mut encoder, &view, width, height);
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    /// Signal raised by every change that needs a new frame
+    pub fn redraw_signal(&self) -> RedrawSignal {
+        self.redraw.clone()
+    }
+
+    /// Show the image uploaded to the active pane: builds the render pipeline
+    /// the first time a `surface_format` is used and otherwise only swaps the
+    /// tile bind groups. With `reset_view` stretch, zoom and pan go back to
+    /// their defaults (or follow the other panes when the view is linked),
+    /// otherwise the user's current settings carry over to the new image.
+    pub fn bind_image(
         &mut self,
         surface_format: wgpu::TextureFormat,
         viewport_width: u32,
         viewport_height: u32,
+        reset_view: bool,
     ) -> Result<()> {
-        // 1. Load WGSL shader
-        let shader_source = include_str!("shader.wgsl");
-        let shader_module = self
-            .device
-            .create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("FITS Shader"),
-                source: wgpu::ShaderSource::Wgsl(shader_source.into()),
-            });
+        // You must have a texture already loaded
+        ensure!(self.pane().has_image(), "Texture not yet loaded");
 
-        // 2. Create uniform buffer (min, max, brightness, contrast, zoom, pan_x, pan_y, aspect_ratio, viewport_aspect, padding)
-        let image_aspect = self.width as f32 / self.height as f32;
-        let viewport_aspect = viewport_width as f32 / viewport_height as f32;
+        // 1. Reuse the pipeline unless the target format changed, in which
+        // case every pane needs new bind groups
+        if self.pipeline.as_ref().map(|p| p.format) != Some(surface_format) {
+            self.pipeline = Some(FitsPipeline::new(&self.device, surface_format));
+            for index in 0..self.panes.len() {
+                self.bind_pane(index);
+            }
+        } else {
+            self.bind_pane(self.active);
+        }
 
+        // 2. Update the uniforms for the new image (see `Uniforms`)
+        self.viewport = (viewport_width.max(1), viewport_height.max(1));
+        let rect = self.pane_rects()[self.active];
+        let (width, height) = self.pane().size();
+        let image_aspect = width as f32 / height as f32;
+
+        println!("📐 Image aspect: {} ({}x{})", image_aspect, width, height);
         println!(
-            "📐 Image aspect: {} ({}x{})",
-            image_aspect, self.width, self.height
-        );
-        println!(
-            "📐 Viewport aspect: {} ({}x{})",
-            viewport_aspect, viewport_width, viewport_height
+            "📐 Pane aspect: {} ({}x{})",
+            rect.aspect(),
+            rect.width,
+            rect.height
         );
 
-        let uniform_data = [
-            0.0f32,          // min_value
-            65535.0f32,      // max_value
-            0.0f32,          // brightness
-            1.0f32,          // contrast
-            1.0f32,          // zoom (1.0 = fit to screen)
-            0.0f32,          // pan_x
-            0.0f32,          // pan_y
-            image_aspect,    // aspect_ratio of image
-            viewport_aspect, // viewport_aspect (actual window dimensions)
-            0.0f32,          // padding1
-            0.0f32,          // padding2
-            0.0f32,          // padding3
-        ];
-        let uniform_buffer = self
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Uniform Buffer"),
-                contents: bytemuck::cast_slice(&uniform_data),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        let linked = self.linked_view.then(|| self.linked_uniforms()).flatten();
+        let uniforms = &mut self.panes[self.active].uniforms;
+        uniforms.prepare_for_image(image_aspect, rect.aspect(), reset_view);
+        if let (true, Some(other)) = (reset_view, linked) {
+            uniforms.follow_view(&other);
+        }
+
+        self.write_uniforms();
+
+        Ok(())
+    }
+
+    /// Tile and overlay bind groups of one pane for the current pipeline
+    fn bind_pane(&mut self, index: usize) {
+        let Some(pipeline) = &self.pipeline else {
+            return;
+        };
+        let pane = &self.panes[index];
+        // Tile i of every frame reads tile i of the reference, or itself
+        let bind_groups: Vec<Vec<wgpu::BindGroup>> = pane
+            .frames
+            .iter()
+            .map(|frame| {
+                frame
+                    .tiles
+                    .iter()
+                    .enumerate()
+                    .map(|(i, tile)| {
+                        let reference = pane
+                            .reference
+                            .and_then(|reference| pane.frames[reference].tiles.get(i))
+                            .unwrap_or(tile);
+                        pipeline.bind_tile(
+                            &self.device,
+                            &tile.texture,
+                            &reference.texture,
+                            &pane.uniform_buffer,
+                            &tile.uniform_buffer,
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let pane = &mut self.panes[index];
+        for (frame, groups) in pane.frames.iter_mut().zip(bind_groups) {
+            for (tile, group) in frame.tiles.iter_mut().zip(groups) {
+                tile.bind_group = Some(group);
+            }
+        }
+        pane.overlay_bind_group = Some(pipeline.bind_overlay(&self.device, &pane.uniform_buffer));
+    }
+
+    /// Record the frame into `encoder` for a `width` x `height` target: every
+    /// pane with an image in its cell when a pipeline exists, otherwise a
+    /// plain clear
+    fn encode_render_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        width: u32,
+        height: u32,
+    ) {
+        // Check if we have a pipeline to render with
+        if let Some(FitsPipeline {
+            pipeline,
+            overlay_pipeline,
+            ..
+        }) = &self.pipeline
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("fits-render-pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                    depth_slice: None,
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
             });
 
-        // 3. Create texture sampler (Nearest for R32Float since it's not filterable)
-        let sampler = self.device.create_sampler(&wgpu::SamplerDescriptor {
-            label: Some("FITS Sampler"),
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+            let rects = self.layout.rects(width as f32, height as f32);
+            for (pane, rect) in self.panes.iter().zip(rects) {
+                let Some(frame) = pane.current() else {
+                    continue;
+                };
+                if rect.is_empty() {
+                    continue;
+                }
+                rpass.set_viewport(rect.x, rect.y, rect.width, rect.height, 0.0, 1.0);
 
-        // You must have a texture already loaded
-        let texture = self.texture.as_ref().context("Texture not yet loaded")?;
-        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                // Render the FITS image, one quad per tile
+                rpass.set_pipeline(pipeline);
+                for tile in &frame.tiles {
+                    if let Some(bind_group) = &tile.bind_group {
+                        rpass.set_bind_group(0, bind_group, &[]);
+                        rpass.draw(0..6, 0..1); // Two triangles per tile
+                    }
+                }
 
-        // 4. Bind group layout for texture + sampler + uniform buffer
-        let bind_group_layout =
-            self.device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: Some("FITS Bind Group Layout"),
-                    entries: &[
-                        // Texture binding
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::FRAGMENT,
-                            ty: wgpu::BindingType::Texture {
-                                multisampled: false,
-                                view_dimension: wgpu::TextureViewDimension::D2,
-                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                            },
-                            count: None,
-                        },
-                        // Sampler binding
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: wgpu::ShaderStages::FRAGMENT,
-                            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
-                            count: None,
-                        },
-                        // Uniform buffer binding
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: wgpu::ShaderStages::FRAGMENT,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                    ],
-                });
-
-        // 5. Create the bind group
-        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("FITS Bind Group"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
+                // Overlay lines on top
+                if let (Some(buffer), Some(bind_group)) =
+                    (&pane.overlay_buffer, &pane.overlay_bind_group)
+                {
+                    rpass.set_pipeline(overlay_pipeline);
+                    rpass.set_bind_group(0, bind_group, &[]);
+                    rpass.set_vertex_buffer(0, buffer.slice(..));
+                    rpass.draw(0..pane.overlay.len() as u32, 0..1);
+                }
+            }
+        } else {
+            // No pipeline yet, just clear to blue
+            let _rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("clear-pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color {
+                            r: 0.2,
+                            g: 0.5,
+                            b: 0.8,
+                            a: 1.0,
+                        }),
+                        store: wgpu::StoreOp::Store,
+                    },
+                    depth_slice: None,
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+        }
+    }
+
+    /// Upload the uniforms of every pane to the GPU and schedule a redraw
+    fn write_uniforms(&self) {
+        for pane in &self.panes {
+            self.queue
+                .write_buffer(&pane.uniform_buffer, 0, bytemuck::bytes_of(&pane.uniforms));
+        }
+        self.redraw.request();
+    }
+
+    fn pane(&self) -> &Pane {
+        &self.panes[self.active]
+    }
+
+    fn pane_mut(&mut self) -> &mut Pane {
+        &mut self.panes[self.active]
+    }
+
+    fn pane_rects(&self) -> Vec<PaneRect> {
+        let (width, height) = self.viewport;
+        self.layout.rects(width as f32, height as f32)
+    }
+
+    /// Uniforms of another pane showing an image, to follow with a linked view
+    fn linked_uniforms(&self) -> Option<Uniforms> {
+        self.panes
+            .iter()
+            .enumerate()
+            .find(|(index, pane)| *index != self.active && pane.has_image())
+            .map(|(_, pane)| pane.uniforms)
+    }
+
+    /// Update stretch min/max values of the active pane
+    pub fn update_stretch(&mut self, min: f32, max: f32) {
+        let uniforms = &mut self.pane_mut().uniforms;
+        uniforms.min_value = min;
+        uniforms.max_value = max;
+        self.write_uniforms();
+    }
+
+    /// Update pan and zoom controls of the active pane, or of all panes when
+    /// the view is linked
+    pub fn update_view(&mut self, zoom: f32, pan_x: f32, pan_y: f32) {
+        for (index, pane) in self.panes.iter_mut().enumerate() {
+            if self.linked_view || index == self.active {
+                pane.uniforms.zoom = zoom;
+                pane.uniforms.pan_x = pan_x;
+                pane.uniforms.pan_y = pan_y;
+            }
+        }
+        self.write_uniforms();
+    }
+
+    /// Lay the panes out in a `viewport_width` x `viewport_height` window
+    pub fn update_viewport_aspect(&mut self, viewport_width: u32, viewport_height: u32) {
+        self.viewport = (viewport_width.max(1), viewport_height.max(1));
+        for (pane, rect) in self.panes.iter_mut().zip(
+            self.layout
+                .rects(self.viewport.0 as f32, self.viewport.1 as f32),
+        ) {
+            pane.uniforms.viewport_aspect = rect.aspect();
+        }
+        self.write_uniforms();
+    }
+
+    /// Toggle the highlight/shadow clipping overlay (all panes)
+    pub fn set_clip_overlay(&mut self, enabled: bool) {
+        for pane in &mut self.panes {
+            pane.uniforms.clip_overlay = if enabled { 1.0 } else { 0.0 };
+        }
+        self.write_uniforms();
+    }
+
+    /// Set the ADU level above which pixels of the active pane are marked as
+    /// saturated (pass `None` when the level is unknown, e.g. for float data)
+    pub fn set_saturation_level(&mut self, level: Option<f32>) {
+        self.pane_mut().uniforms.saturation_level = level.unwrap_or(f32::MAX);
+        self.write_uniforms();
+    }
+
+    /// Cap the tile size below the device limit (useful to exercise tiling
+    /// with small images)
+    pub fn set_max_tile_size(&mut self, size: u32) {
+        self.max_tile_size = size.clamp(1, self.device.limits().max_texture_dimension_2d);
+    }
+
+    /// Upload the image to the active pane, replacing everything it showed
+    pub fn load_fits_data(&mut self, data: &[f32], w: usize, h: usize) -> Result<()> {
+        let frame = self.upload_frame(data, w, h)?;
+
+        // The overlay, alignment and comparison belonged to the previous image
+        let pane = self.pane_mut();
+        pane.frames = vec![frame];
+        pane.reference = None;
+        pane.show_frame(0);
+
+        self.build_pyramids(Some((self.active, 0)));
+
+        Ok(())
+    }
+
+    /// Upload another frame to blink through into the active pane, after the
+    /// one from `load_fits_data`. Call `bind_image` once all are added.
+    pub fn add_frame(&mut self, data: &[f32], w: usize, h: usize) -> Result<()> {
+        ensure!(self.pane().has_image(), "No first frame loaded");
+        let frame = self.upload_frame(data, w, h)?;
+
+        let pane = self.pane_mut();
+        pane.frames.push(frame);
+        let index = pane.frames.len() - 1;
+
+        self.build_pyramids(Some((self.active, index)));
+
+        Ok(())
+    }
+
+    /// Line the frames of the active pane up with the first one, in order
+    pub fn set_frame_alignments(&mut self, alignments: &[FrameAlignment]) {
+        let pane = self.pane_mut();
+        for (frame, alignment) in pane.frames.iter_mut().zip(alignments) {
+            frame.alignment = *alignment;
+        }
+        pane.show_frame(pane.frame);
+        self.write_uniforms();
+    }
+
+    /// Show the active pane's frame as difference or ratio against frame
+    /// `reference` of the same pane, which must have the same size
+    pub fn set_compare(&mut self, mode: CompareMode, reference: usize) -> Result<()> {
+        let pane = self.pane_mut();
+        ensure!(reference < pane.frames.len(), "No frame {}", reference);
+        ensure!(
+            pane.size() == (pane.frames[reference].width, pane.frames[reference].height),
+            "Frames to compare differ in size"
+        );
+
+        let previous = (pane.reference, pane.uniforms.compare_mode);
+        pane.reference = (mode != CompareMode::Off).then_some(reference);
+        pane.uniforms.compare_mode = mode as u32;
+        if !pane.reference_within_apron() {
+            (pane.reference, pane.uniforms.compare_mode) = previous;
+            bail!(
+                "Frames are more than {} px apart, too far to compare this tiled image",
+                pane.current()
+                    .map_or(0, |frame| max_reference_offset(frame.levels))
+            );
+        }
+        pane.update_compare();
+
+        self.bind_pane(self.active);
+        self.write_uniforms();
+        Ok(())
+    }
+
+    /// Put frame `index` of the active pane on screen, keeping stretch and view
+    pub fn show_frame(&mut self, index: usize) {
+        self.pane_mut().show_frame(index);
+        self.write_uniforms();
+    }
+
+    pub fn blink_state(&self) -> BlinkState {
+        let pane = self.pane();
+        BlinkState {
+            frame: pane.frame,
+            frame_count: pane.frames.len(),
+            alignment: pane
+                .current()
+                .map(|frame| frame.alignment)
+                .unwrap_or_default(),
+            comparing: pane.reference.is_some(),
+        }
+    }
+
+    /// Copy an image to GPU textures, split into tiles when it exceeds the
+    /// texture limit. The mip levels are left for `build_pyramids`.
+    fn upload_frame(&self, data: &[f32], w: usize, h: usize) -> Result<Frame> {
+        ensure!(data.len() == w * h, "image data does not match {}x{}", w, h);
+
+        let (width, height) = (w as u32, h as u32);
+        let rects = plan_tiles(width, height, self.max_tile_size);
+        let levels = rects[0].levels;
+        if rects.len() > 1 {
+            println!(
+                "🧩 {}x{} exceeds {} px, uploading {} tiles",
+                width,
+                height,
+                self.max_tile_size,
+                rects.len()
+            );
+        }
+
+        let mut tiles = Vec::with_capacity(rects.len());
+        for rect in rects {
+            let [tex_x, tex_y, tex_w, tex_h] = rect.texture;
+            let size = wgpu::Extent3d {
+                width: tex_w,
+                height: tex_h,
+                depth_or_array_layers: 1,
+            };
+
+            // Full mip chain, filled by the pyramid compute pass below (copied
+            // in from scratch textures, hence COPY_DST is all it needs)
+            let desc = wgpu::TextureDescriptor {
+                label: Some("Fits DATA Texture"),
+                size,
+                mip_level_count: rect.levels.min(mip_level_count(tex_w, tex_h)),
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            };
+
+            let texture = self.device.create_texture(&desc);
+
+            // Copy the tile's region straight out of the full image
+            self.queue.write_texture(
+                wgpu::TexelCopyTextureInfo {
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: uniform_buffer.as_entire_binding(),
+                bytemuck::cast_slice(data), // Convert &[f32] to &[u8] bytes
+                wgpu::TexelCopyBufferLayout {
+                    offset: (tex_y as u64 * width as u64 + tex_x as u64) * 4,
+                    bytes_per_row: Some(width * 4), // 4 bytes per f32
+                    rows_per_image: Some(tex_h),
                 },
-            ],
-        });
+                size,
+            );
 
-        // 6. Create the pipeline layout
-        let pipeline_layout = self
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("FITS Pipeline Layout"),
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
+            let uniform_buffer =
+                self.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Tile Uniform Buffer"),
+                        contents: bytemuck::bytes_of(&rect.uniforms(width, height)),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    });
+
+            tiles.push(Tile {
+                texture,
+                uniform_buffer,
+                bind_group: None,
             });
+        }
+
+        Ok(Frame {
+            tiles,
+            width,
+            height,
+            levels,
+            alignment: FrameAlignment::default(),
+        })
+    }
 
-        // 7. Create a fullscreen quad vertex buffer layout (no vertex buffer used)
-        let vertex_state = wgpu::VertexState {
-            module: &shader_module,
-            entry_point: Some("vs_main"),
-            buffers: &[], // fullscreen triangle via shader
-            compilation_options: Default::default(),
+    /// Replace the lines drawn over the image of `pane` (image pixel coordinates)
+    pub fn set_overlay(&mut self, pane: usize, lines: &[OverlayLine]) {
+        let Some(pane) = self.panes.get_mut(pane) else {
+            return;
         };
+        let (width, height) = pane.size();
+        pane.overlay = line_vertices(lines, width, height);
+        pane.overlay_buffer = (!pane.overlay.is_empty()).then(|| {
+            self.device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Overlay Vertex Buffer"),
+                    contents: bytemuck::cast_slice(&pane.overlay),
+                    usage: wgpu::BufferUsages::VERTEX,
+                })
+        });
+        self.redraw.request();
+    }
 
-        // 8. Create the render pipeline
-        let pipeline = self
-            .device
-            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some("FITS Render Pipeline"),
-                layout: Some(&pipeline_layout),
-                vertex: vertex_state,
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader_module,
-                    entry_point: Some("fs_main"),
-                    targets: &[Some(wgpu::ColorTargetState {
-                        format: surface_format,
-                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                        write_mask: wgpu::ColorWrites::ALL,
-                    })],
-                    compilation_options: Default::default(),
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    strip_index_format: None,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: None,
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                    unclipped_depth: false,
-                    conservative: false,
-                },
-                depth_stencil: None,
-                multisample: wgpu::MultisampleState {
-                    count: 1,
-                    mask: !0,
-                    alpha_to_coverage_enabled: false,
-                },
-                multiview: None,
-                cache: None,
-            });
+    /// Choose how the image of the active pane is resampled between pixel
+    /// centres
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.pane_mut().uniforms.interpolation = interpolation as u32;
+        self.write_uniforms();
+    }
+
+    /// Rotate and mirror the image of the active pane on screen
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.pane_mut().uniforms.set_orientation(orientation);
+        self.write_uniforms();
+    }
 
-        // 9. Store them
-        self.pipeline = Some(pipeline);
-        self.bind_group = Some(bind_group);
-        self.uniform_buffer = Some(uniform_buffer);
+    /// Split the window into panes. Panes beyond the new count are dropped
+    /// along with their images.
+    pub fn set_layout(&mut self, layout: PaneLayout) {
+        let count = layout.pane_count();
+        self.panes.truncate(count);
+        while self.panes.len() < count {
+            let mut pane = Pane::new(&self.device);
+            // New panes start with the same display settings
+            pane.uniforms.clip_overlay = self.panes[0].uniforms.clip_overlay;
+            pane.uniforms.interpolation = self.panes[0].uniforms.interpolation;
+            self.panes.push(pane);
+            self.bind_pane(self.panes.len() - 1);
+        }
 
-        Ok(())
+        self.layout = layout;
+        self.active = self.active.min(count - 1);
+        self.update_viewport_aspect(self.viewport.0, self.viewport.1);
     }
 
-    /// Update stretch min/max values
-    pub fn update_stretch(&self, min: f32, max: f32) {
-        if let Some(buffer) = &self.uniform_buffer {
-            // Update min and max values (indices 0, 1 in the uniform array)
-            let data = [min, max];
-            self.queue
-                .write_buffer(buffer, 0, bytemuck::cast_slice(&data)); // offset 0 bytes
+    /// Make `pane` receive new images and settings
+    pub fn set_active_pane(&mut self, pane: usize) {
+        self.active = pane.min(self.panes.len() - 1);
+    }
+
+    /// Link zoom and pan of all panes, starting from the active pane's view
+    pub fn set_linked_view(&mut self, linked: bool) {
+        self.linked_view = linked;
+        if linked {
+            let view = self.pane().uniforms;
+            for pane in &mut self.panes {
+                pane.uniforms.follow_view(&view);
+            }
+            self.write_uniforms();
         }
     }
 
-    /// Update pan and zoom controls
-    pub fn update_view(&self, zoom: f32, pan_x: f32, pan_y: f32) {
-        if let Some(buffer) = &self.uniform_buffer {
-            // Update only the zoom and pan values (indices 4, 5, 6 in the uniform array)
-            let data = [zoom, pan_x, pan_y];
-            self.queue
-                .write_buffer(buffer, 16, bytemuck::cast_slice(&data)); // offset 16 bytes (4 floats * 4 bytes)
+    /// Switch between mean and max reduction for the zoomed-out levels
+    pub fn set_pyramid_mode(&mut self, mode: PyramidMode) {
+        if self.pyramid_mode != mode {
+            self.pyramid_mode = mode;
+            self.build_pyramids(None);
         }
     }
 
-    /// Update viewport aspect ratio when window is resized
-    pub fn update_viewport_aspect(&self, viewport_width: u32, viewport_height: u32) {
-        if let Some(buffer) = &self.uniform_buffer {
-            let viewport_aspect = viewport_width as f32 / viewport_height as f32;
-            // viewport_aspect is at index 8 in the uniform array
-            self.queue
-                .write_buffer(buffer, 32, bytemuck::cast_slice(&[viewport_aspect]));
-            // offset 32 bytes (8 floats * 4 bytes)
+    /// Fill the mip levels from level 0 for one frame, given as (pane, frame),
+    /// or for every frame of every pane
+    fn build_pyramids(&mut self, frame: Option<(usize, usize)>) {
+        let device = &self.device;
+        let tiles: Vec<&Tile> = self
+            .panes
+            .iter()
+            .enumerate()
+            .flat_map(|(pane, p)| p.frames.iter().enumerate().map(move |(i, f)| (pane, i, f)))
+            .filter(|(pane, index, _)| frame.is_none_or(|frame| frame == (*pane, *index)))
+            .flat_map(|(_, _, frame)| &frame.tiles)
+            .collect();
+        if tiles.is_empty() {
+            return;
+        }
+
+        let builder = self
+            .pyramid
+            .get_or_insert_with(|| PyramidBuilder::new(device));
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("pyramid-encoder"),
+        });
+        for tile in tiles {
+            builder.encode(device, &mut encoder, &tile.texture, self.pyramid_mode);
         }
+        self.queue.submit(Some(encoder.finish()));
+        self.redraw.request();
     }
+}
 
-    pub fn load_fits_data(&mut self, data: Vec<f32>, w: usize, h: usize) -> Result<()> {
-        let size = wgpu::Extent3d {
-            width: w as u32,
-            height: h as u32,
-            depth_or_array_layers: 1,
-        };
+/// The active rendering backend
+pub enum Renderer {
+    Gpu(Box<FitsRenderer>),
+    Software(SoftwareRenderer),
+}
 
-        let desc = wgpu::TextureDescriptor {
-            label: Some("Fits DATA Texture"),
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::R32Float,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            view_formats: &[],
-        };
+impl Renderer {
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            Renderer::Gpu(_) => "gpu",
+            Renderer::Software(_) => "software",
+        }
+    }
 
-        let texture = self.device.create_texture(&desc);
-
-        self.queue.write_texture(
-            wgpu::TexelCopyTextureInfo {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            bytemuck::cast_slice(&data), // Convert Vec<f32> to &[u8] bytes
-            wgpu::TexelCopyBufferLayout {
-                offset: 0,
-                bytes_per_row: Some(w as u32 * 4), // 4 bytes per f32
-                rows_per_image: Some(h as u32),
-            },
-            size, // The same size you used for texture creation
-        );
+    pub fn load_fits_data(&mut self, data: &[f32], w: usize, h: usize) -> Result<()> {
+        match self {
+            Renderer::Gpu(r) => r.load_fits_data(data, w, h),
+            Renderer::Software(r) => r.load_fits_data(data, w, h),
+        }
+    }
 
-        // Store the texture
-        self.texture = Some(Arc::new(texture));
-        self.width = w as u32;
-        self.height = h as u32;
+    /// Append a frame to blink through to the active pane
+    pub fn add_frame(&mut self, data: &[f32], w: usize, h: usize) -> Result<()> {
+        match self {
+            Renderer::Gpu(r) => r.add_frame(data, w, h),
+            Renderer::Software(r) => r.add_frame(data, w, h),
+        }
+    }
 
-        Ok(())
+    pub fn set_frame_alignments(&mut self, alignments: &[FrameAlignment]) {
+        match self {
+            Renderer::Gpu(r) => r.set_frame_alignments(alignments),
+            Renderer::Software(r) => r.set_frame_alignments(alignments),
+        }
+    }
+
+    pub fn show_frame(&mut self, index: usize) {
+        match self {
+            Renderer::Gpu(r) => r.show_frame(index),
+            Renderer::Software(r) => r.show_frame(index),
+        }
+    }
+
+    pub fn set_compare(&mut self, mode: CompareMode, reference: usize) -> Result<()> {
+        match self {
+            Renderer::Gpu(r) => r.set_compare(mode, reference),
+            Renderer::Software(r) => r.set_compare(mode, reference),
+        }
+    }
+
+    /// Blink position of the active pane
+    pub fn blink_state(&self) -> BlinkState {
+        match self {
+            Renderer::Gpu(r) => r.blink_state(),
+            Renderer::Software(r) => r.blink_state(),
+        }
+    }
+
+    /// Uniforms of the active pane
+    pub fn uniforms(&self) -> &Uniforms {
+        self.pane_uniforms(self.active_pane())
     }
+
+    /// Uniforms of `pane` (must be below `layout().pane_count()`)
+    pub fn pane_uniforms(&self, pane: usize) -> &Uniforms {
+        match self {
+            Renderer::Gpu(r) => &r.panes[pane].uniforms,
+            Renderer::Software(r) => r.pane_uniforms(pane),
+        }
+    }
+
+    /// Pane under (`x`, `y`) of a `viewport_width` x `viewport_height`
+    /// viewport (any unit) and its screen <-> image mapping
+    pub fn pane_at(
+        &self,
+        x: f32,
+        y: f32,
+        viewport_width: f32,
+        viewport_height: f32,
+    ) -> Option<PanePoint> {
+        panes::locate(
+            self.layout(),
+            |pane| self.pane_uniforms(pane),
+            x,
+            y,
+            viewport_width,
+            viewport_height,
+        )
+    }
+
+    /// Replace the lines drawn over the image of `pane` (image pixel coordinates)
+    pub fn set_overlay(&mut self, pane: usize, lines: &[OverlayLine]) {
+        match self {
+            Renderer::Gpu(r) => r.set_overlay(pane, lines),
+            Renderer::Software(r) => r.set_overlay(pane, lines),
+        }
+    }
+
+    /// Whether an image has been loaded into the active pane yet
+    pub fn has_image(&self) -> bool {
+        match self {
+            Renderer::Gpu(r) => r.pane().has_image(),
+            Renderer::Software(r) => r.has_image(),
+        }
+    }
+
+    pub fn layout(&self) -> PaneLayout {
+        match self {
+            Renderer::Gpu(r) => r.layout,
+            Renderer::Software(r) => r.layout(),
+        }
+    }
+
+    pub fn set_layout(&mut self, layout: PaneLayout) {
+        match self {
+            Renderer::Gpu(r) => r.set_layout(layout),
+            Renderer::Software(r) => r.set_layout(layout),
+        }
+    }
+
+    pub fn active_pane(&self) -> usize {
+        match self {
+            Renderer::Gpu(r) => r.active,
+            Renderer::Software(r) => r.active_pane(),
+        }
+    }
+
+    pub fn set_active_pane(&mut self, pane: usize) {
+        match self {
+            Renderer::Gpu(r) => r.set_active_pane(pane),
+            Renderer::Software(r) => r.set_active_pane(pane),
+        }
+    }
+
+    pub fn set_linked_view(&mut self, linked: bool) {
+        match self {
+            Renderer::Gpu(r) => r.set_linked_view(linked),
+            Renderer::Software(r) => r.set_linked_view(linked),
+        }
+    }
+
+    /// Show the loaded image, see `FitsRenderer::bind_image`. The software
+    /// renderer has no pipeline, it only updates its uniforms the same way.
+    pub fn bind_image(
+        &mut self,
+        surface_format: wgpu::TextureFormat,
+        viewport_width: u32,
+        viewport_height: u32,
+        reset_view: bool,
+    ) -> Result<()> {
+        match self {
+            Renderer::Gpu(r) => {
+                r.bind_image(surface_format, viewport_width, viewport_height, reset_view)
+            }
+            Renderer::Software(r) => {
+                r.bind_image(viewport_width, viewport_height, reset_view);
+                Ok(())
+            }
+        }
+    }
+
+    /// Render the current view into a `width` x `height` RGBA8 frame, as the
+    /// window would show it at that size
+    pub fn render_to_rgba(&mut self, width: u32, height: u32) -> Result<Vec<u8>> {
+        match self {
+            Renderer::Gpu(r) => r.export_rgba(width, height),
+            Renderer::Software(r) => Ok(r.render(width, height)),
+        }
+    }
+
+    pub fn update_stretch(&mut self, min: f32, max: f32) {
+        match self {
+            Renderer::Gpu(r) => r.update_stretch(min, max),
+            Renderer::Software(r) => r.update_stretch(min, max),
+        }
+    }
+
+    pub fn update_view(&mut self, zoom: f32, pan_x: f32, pan_y: f32) {
+        match self {
+            Renderer::Gpu(r) => r.update_view(zoom, pan_x, pan_y),
+            Renderer::Software(r) => r.update_view(zoom, pan_x, pan_y),
+        }
+    }
+
+    pub fn update_viewport_aspect(&mut self, viewport_width: u32, viewport_height: u32) {
+        match self {
+            Renderer::Gpu(r) => r.update_viewport_aspect(viewport_width, viewport_height),
+            Renderer::Software(r) => r.update_viewport_aspect(viewport_width, viewport_height),
+        }
+    }
+
+    /// Follow a window resize or DPI change (physical pixels)
+    pub fn resize(&mut self, width: u32, height: u32) {
+        match self {
+            Renderer::Gpu(r) => r.resize_surface(width, height),
+            Renderer::Software(r) if width > 0 && height > 0 => {
+                r.update_viewport_aspect(width, height)
+            }
+            Renderer::Software(_) => {}
+        }
+    }
+
+    pub fn set_clip_overlay(&mut self, enabled: bool) {
+        match self {
+            Renderer::Gpu(r) => r.set_clip_overlay(enabled),
+            Renderer::Software(r) => r.set_clip_overlay(enabled),
+        }
+    }
+
+    pub fn set_saturation_level(&mut self, level: Option<f32>) {
+        match self {
+            Renderer::Gpu(r) => r.set_saturation_level(level),
+            Renderer::Software(r) => r.set_saturation_level(level),
+        }
+    }
+
+    pub fn set_pyramid_mode(&mut self, mode: PyramidMode) {
+        match self {
+            Renderer::Gpu(r) => r.set_pyramid_mode(mode),
+            Renderer::Software(r) => r.set_pyramid_mode(mode),
+        }
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        match self {
+            Renderer::Gpu(r) => r.set_interpolation(interpolation),
+            Renderer::Software(r) => r.set_interpolation(interpolation),
+        }
+    }
+
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        match self {
+            Renderer::Gpu(r) => r.set_orientation(orientation),
+            Renderer::Software(r) => r.set_orientation(orientation),
+        }
+    }
+}
+
+/// Everything `init_renderer_for_window` sets up for a window
+pub struct WindowRenderer {
+    pub renderer: Arc<Mutex<Renderer>>,
+    pub surface_format: wgpu::TextureFormat,
+    /// Presents frames to the window; `None` for the software renderer,
+    /// whose frames the UI pulls itself
+    pub render_loop: Option<RenderLoop>,
+}
+
+fn software_renderer_requested() -> bool {
+    std::env::var(RENDERER_ENV)
+        .map(|v| v.eq_ignore_ascii_case("software") || v.eq_ignore_ascii_case("cpu"))
+        .unwrap_or(false)
 }
 
-pub fn init_renderer_for_window(
-    window: &WebviewWindow,
-) -> Result<(Arc<Mutex<FitsRenderer>>, wgpu::TextureFormat)> {
+/// Create the renderer for the main window. Uses the GPU when an adapter is
+/// available and falls back to the software renderer otherwise (or when
+/// `RAPIDFITS_RENDERER=software` is set).
+pub fn init_renderer_for_window(window: &WebviewWindow) -> Result<WindowRenderer> {
+    if software_renderer_requested() {
+        println!("🖥️  Software renderer requested via {}", RENDERER_ENV);
+        return Ok(init_software_renderer());
+    }
+
+    match init_gpu_renderer(window) {
+        StdOk(result) => Ok(result),
+        StdErr(e) => {
+            println!(
+                "⚠️  GPU renderer unavailable ({:#}), falling back to software renderer",
+                e
+            );
+            Ok(init_software_renderer())
+        }
+    }
+}
+
+fn init_software_renderer() -> WindowRenderer {
+    let renderer = Renderer::Software(SoftwareRenderer::new());
+    println!("✅ Software renderer started");
+    WindowRenderer {
+        renderer: Arc::new(Mutex::new(renderer)),
+        surface_format: SoftwareRenderer::FORMAT,
+        render_loop: None,
+    }
+}
+
+fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue)> {
+    let (device, queue) =
+        pollster::block_on(adapter.request_device(&wgpu::wgt::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::empty(),
+            // Ask for everything the adapter offers, large frames need big textures
+            required_limits: adapter.limits(),
+            experimental_features: wgpu::ExperimentalFeatures::disabled(),
+            memory_hints: wgpu::MemoryHints::default(),
+            trace: wgpu::Trace::Off,
+        }))?;
+
+    Ok((device, queue))
+}
+
+fn init_gpu_renderer(window: &WebviewWindow) -> Result<WindowRenderer> {
     println!(
         "🚀 Initializing transparent WGPU renderer for '{}'",
         window.label()
@@ -318,154 +1268,82 @@ pub fn init_renderer_for_window(
     }))
     .context("No compatible GPU adapter found")?;
 
-    let (device, queue) =
-        pollster::block_on(adapter.request_device(&wgpu::wgt::DeviceDescriptor {
-            label: None,
-            required_features: wgpu::Features::empty(),
-            required_limits: wgpu::Limits::defaults(),
-            experimental_features: wgpu::ExperimentalFeatures::disabled(),
-            memory_hints: wgpu::MemoryHints::default(),
-            trace: wgpu::Trace::Off,
-        }))?;
+    let (device, queue) = request_device(&adapter)?;
 
-    let caps = surface.get_capabilities(&adapter);
-    let format = caps.formats[0];
     let size = window.inner_size()?;
+    let mut window_surface =
+        WindowSurface::new(surface, &adapter, &device, size.width, size.height);
+    let format = window_surface.format();
 
-    // Wrap device and queue in Arc for sharing
     let device = Arc::new(device);
-    let queue = Arc::new(queue);
-
-    // Create the renderer
-    let renderer = Arc::new(Mutex::new(FitsRenderer::new(
-        Arc::clone(&device),
-        Arc::clone(&queue),
-    )));
-
-    // Clone for the render thread
-    let renderer_clone = Arc::clone(&renderer);
-    let device_clone = Arc::clone(&device);
-    let queue_clone = Arc::clone(&queue);
-
-    // Try to find a supported alpha mode, preferring PreMultiplied for transparency
-    let alpha_mode = if caps
-        .alpha_modes
-        .contains(&wgpu::CompositeAlphaMode::PreMultiplied)
-    {
-        wgpu::CompositeAlphaMode::PreMultiplied
-    } else if caps
-        .alpha_modes
-        .contains(&wgpu::CompositeAlphaMode::PostMultiplied)
+    let mut fits_renderer = FitsRenderer::new(Arc::clone(&device), Arc::new(queue));
+    if let Some(size) = std::env::var(MAX_TILE_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
     {
-        wgpu::CompositeAlphaMode::PostMultiplied
-    } else if caps
-        .alpha_modes
-        .contains(&wgpu::CompositeAlphaMode::Inherit)
-    {
-        wgpu::CompositeAlphaMode::Inherit
-    } else {
-        println!("⚠️  No transparent alpha mode supported, using Opaque");
-        caps.alpha_modes[0]
-    };
-
-    println!("Using alpha mode: {:?}", alpha_mode);
-
-    let config = wgpu::SurfaceConfiguration {
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        format,
-        width: size.width.max(1),
-        height: size.height.max(1),
-        present_mode: wgpu::PresentMode::Fifo,
-        desired_maximum_frame_latency: 1,
-        alpha_mode,
-        view_formats: vec![],
-    };
-    surface.configure(&device_clone, &config);
-
-    // Spawn continuous rendering thread
-    thread::spawn(move || {
-        loop {
-            match surface.get_current_texture() {
-                StdOk(frame) => {
-                    let view = frame.texture.create_view(&wgpu::TextureViewDescriptor {
-                        label: Some("surface-view"),
-                        dimension: Some(wgpu::TextureViewDimension::D2),
-                        format: None,
-                        aspect: wgpu::TextureAspect::All,
-                        base_mip_level: 0,
-                        mip_level_count: None,
-                        base_array_layer: 0,
-                        array_layer_count: None,
-                        usage: Some(wgpu::TextureUsages::RENDER_ATTACHMENT),
-                    });
+        println!("🧩 Tiles capped at {} px via {}", size, MAX_TILE_ENV);
+        fits_renderer.set_max_tile_size(size);
+    }
+    fits_renderer.resize_surface(size.width, size.height);
+    let redraw = fits_renderer.redraw_signal();
+    let renderer = Arc::new(Mutex::new(Renderer::Gpu(Box::new(fits_renderer))));
 
-                    let mut encoder =
-                        device_clone.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                            label: Some("render-encoder"),
-                        });
-
-                    {
-                        let renderer = renderer_clone.lock().unwrap();
-
-                        // Check if we have a pipeline to render with
-                        if let (Some(pipeline), Some(bind_group)) =
-                            (&renderer.pipeline, &renderer.bind_group)
-                        {
-                            // Render the FITS image
-                            let mut rpass =
-                                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                                    label: Some("fits-render-pass"),
-                                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                                        view: &view,
-                                        resolve_target: None,
-                                        ops: wgpu::Operations {
-                                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                                            store: wgpu::StoreOp::Store,
-                                        },
-                                        depth_slice: None,
-                                    })],
-                                    depth_stencil_attachment: None,
-                                    occlusion_query_set: None,
-                                    timestamp_writes: None,
-                                });
-
-                            rpass.set_pipeline(pipeline);
-                            rpass.set_bind_group(0, bind_group, &[]);
-                            rpass.draw(0..3, 0..1); // Draw fullscreen triangle
-                        } else {
-                            // No pipeline yet, just clear to blue
-                            let _rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                                label: Some("clear-pass"),
-                                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                                    view: &view,
-                                    resolve_target: None,
-                                    ops: wgpu::Operations {
-                                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                                            r: 0.2,
-                                            g: 0.5,
-                                            b: 0.8,
-                                            a: 1.0,
-                                        }),
-                                        store: wgpu::StoreOp::Store,
-                                    },
-                                    depth_slice: None,
-                                })],
-                                depth_stencil_attachment: None,
-                                occlusion_query_set: None,
-                                timestamp_writes: None,
-                            });
-                        }
-                    }
+    // Draw only when the image, view, stretch or window size changed. The
+    // render thread owns the swapchain and holds the renderer only while
+    // encoding: waiting for the next frame (vsync with Fifo) and presenting
+    // happen outside the lock so commands never queue up behind them.
+    let renderer_clone = Arc::clone(&renderer);
+    let retry = redraw.clone();
+    let mut failures = 0u32;
+    let render_loop = RenderLoop::spawn(redraw, move || {
+        let size = match &*renderer_clone.lock().unwrap() {
+            Renderer::Gpu(renderer) => renderer.surface_size,
+            Renderer::Software(_) => return,
+        };
+        if size != window_surface.size() || window_surface.suspended() {
+            window_surface.resize(&device, size.0, size.1);
+        }
 
-                    queue_clone.submit(Some(encoder.finish()));
-                    frame.present();
+        let frame = match window_surface.acquire(&device) {
+            StdOk(Some(frame)) => frame,
+            // Minimized, the resize on restore asks for a frame
+            StdOk(None) => return,
+            StdErr(e) if surface::is_transient(&e) => {
+                // A skipped frame would leave a stale picture until the next
+                // change, try again after a pause that grows while it keeps
+                // failing
+                if failures == 0 {
+                    println!("⚠️  Skipped a frame ({}), retrying", e);
                 }
-                StdErr(_) => {}
+                let delay = RETRY_DELAY
+                    .saturating_mul(1 << failures.min(6))
+                    .min(MAX_RETRY_DELAY);
+                failures += 1;
+                std::thread::sleep(delay);
+                retry.request();
+                return;
             }
-            thread::sleep(Duration::from_millis(16)); // ~60 fps
+            StdErr(e) => {
+                // Retrying will not help, wait for the next change instead of
+                // spinning the render thread
+                if failures == 0 {
+                    println!("❌ Failed to acquire frame: {}", e);
+                }
+                failures += 1;
+                return;
+            }
+        };
+        failures = 0;
+        if let Renderer::Gpu(renderer) = &mut *renderer_clone.lock().unwrap() {
+            renderer.render_to_frame(&frame);
         }
+        frame.present();
     });
 
     println!("✅ WGPU transparent renderer started");
-    Ok((renderer, format))
+    Ok(WindowRenderer {
+        renderer,
+        surface_format: format,
+        render_loop: Some(render_loop),
+    })
 }
@@ -0,0 +1,86 @@
+use super::ViewTransform;
+
+/// A polyline drawn on top of the image (grids, markers, ...)
+#[derive(Debug, Clone)]
+pub struct OverlayLine {
+    /// Image pixel coordinates: pixel (i, j) spans i..i+1, j..j+1
+    pub points: Vec<[f32; 2]>,
+    /// Straight RGBA, alpha blended onto the image
+    pub color: [f32; 4],
+}
+
+/// Vertex of the overlay line list, must match `vs_overlay` in `shader.wgsl`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct OverlayVertex {
+    /// Texture coordinates of the full image (0..1)
+    pub position: [f32; 2],
+    pub color: [f32; 4],
+}
+
+impl OverlayVertex {
+    pub const ATTRIBUTES: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x4];
+
+    pub fn layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as u64,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// Flatten polylines into a line list in texture coordinates
+pub fn line_vertices(lines: &[OverlayLine], width: u32, height: u32) -> Vec<OverlayVertex> {
+    let (w, h) = (width.max(1) as f32, height.max(1) as f32);
+    lines
+        .iter()
+        .flat_map(|line| {
+            line.points.windows(2).flat_map(move |pair| {
+                pair.iter().map(move |[x, y]| OverlayVertex {
+                    position: [x / w, y / h],
+                    color: line.color,
+                })
+            })
+        })
+        .collect()
+}
+
+/// Software counterpart of the overlay pipeline: blend the line list into an
+/// RGBA8 `frame` of `width` x `height`
+pub fn draw_lines(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    transform: &ViewTransform,
+    vertices: &[OverlayVertex],
+) {
+    let to_screen = |v: &OverlayVertex| {
+        let [sx, sy] = transform.texture_to_screen(v.position[0], v.position[1]);
+        (sx * width as f32, sy * height as f32)
+    };
+
+    for pair in vertices.chunks_exact(2) {
+        let (x0, y0) = to_screen(&pair[0]);
+        let (x1, y1) = to_screen(&pair[1]);
+        let [r, g, b, a] = pair[0].color;
+
+        // One sample per screen pixel along the major axis
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0) as usize;
+        for i in 0..=steps {
+            let t = i as f32 / steps as f32;
+            let x = (x0 + (x1 - x0) * t).floor();
+            let y = (y0 + (y1 - y0) * t).floor();
+            if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+                continue;
+            }
+
+            let index = (y as usize * width + x as usize) * 4;
+            for (channel, value) in frame[index..index + 3].iter_mut().zip([r, g, b]) {
+                let blended = value * a + (*channel as f32 / 255.0) * (1.0 - a);
+                *channel = (blended.clamp(0.0, 1.0) * 255.0).round() as u8;
+            }
+        }
+    }
+}
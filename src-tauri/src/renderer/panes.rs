@@ -0,0 +1,206 @@
+use super::{Uniforms, ViewTransform};
+use serde::{Deserialize, Serialize};
+
+/// How the window is split into panes, each showing its own image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaneLayout {
+    #[default]
+    Single,
+    /// Two panes side by side
+    Split,
+    /// Two panes, one above the other
+    Stacked,
+    /// Four panes, 2 x 2
+    Grid,
+}
+
+impl PaneLayout {
+    /// Columns and rows
+    fn shape(self) -> (usize, usize) {
+        match self {
+            PaneLayout::Single => (1, 1),
+            PaneLayout::Split => (2, 1),
+            PaneLayout::Stacked => (1, 2),
+            PaneLayout::Grid => (2, 2),
+        }
+    }
+
+    pub fn pane_count(self) -> usize {
+        let (columns, rows) = self.shape();
+        columns * rows
+    }
+
+    /// Cells of a `width` x `height` viewport in pane order (row by row).
+    /// Edges are rounded so cells line up with whole pixels, and stay inside
+    /// the viewport: a viewport narrower than the columns leaves some cells
+    /// empty.
+    pub fn rects(self, width: f32, height: f32) -> Vec<PaneRect> {
+        let (columns, rows) = self.shape();
+        let edge = |size: f32, i: usize, n: usize| {
+            (size * i as f32 / n as f32)
+                .round()
+                .clamp(0.0, size.max(0.0))
+        };
+
+        (0..rows)
+            .flat_map(|row| {
+                (0..columns).map(move |column| {
+                    let (x0, x1) = (
+                        edge(width, column, columns),
+                        edge(width, column + 1, columns),
+                    );
+                    let (y0, y1) = (edge(height, row, rows), edge(height, row + 1, rows));
+                    PaneRect {
+                        x: x0,
+                        y: y0,
+                        width: x1 - x0,
+                        height: y1 - y0,
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+/// Position and size of a pane in the viewport
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaneRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl PaneRect {
+    /// Width over height, of at least a pixel each so empty cells still give
+    /// a usable aspect
+    pub fn aspect(&self) -> f32 {
+        self.width.max(1.0) / self.height.max(1.0)
+    }
+
+    /// Too small to hold a pixel, nothing is drawn in it
+    pub fn is_empty(&self) -> bool {
+        self.width < 1.0 || self.height < 1.0
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+}
+
+/// A viewport position resolved to the pane under it
+#[derive(Debug, Clone, Copy)]
+pub struct PanePoint {
+    pub pane: usize,
+    /// Screen <-> image mapping of that pane
+    pub transform: ViewTransform,
+    /// Position as a fraction of the pane (0..1, top-left origin)
+    pub u: f32,
+    pub v: f32,
+}
+
+/// Find the pane of `layout` under (`x`, `y`) in a `viewport_width` x
+/// `viewport_height` viewport (any unit). `uniforms` gives each pane's uniforms.
+pub fn locate<'a>(
+    layout: PaneLayout,
+    uniforms: impl Fn(usize) -> &'a Uniforms,
+    x: f32,
+    y: f32,
+    viewport_width: f32,
+    viewport_height: f32,
+) -> Option<PanePoint> {
+    let (pane, rect) = layout
+        .rects(viewport_width, viewport_height)
+        .into_iter()
+        .enumerate()
+        .find(|(_, rect)| rect.contains(x, y))?;
+
+    Some(PanePoint {
+        pane,
+        transform: ViewTransform::new(uniforms(pane), rect.width, rect.height),
+        u: (x - rect.x) / rect.width,
+        v: (y - rect.y) / rect.height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUTS: [PaneLayout; 4] = [
+        PaneLayout::Single,
+        PaneLayout::Split,
+        PaneLayout::Stacked,
+        PaneLayout::Grid,
+    ];
+
+    /// Number of panes covering each pixel of a `width` x `height` viewport
+    fn coverage(rects: &[PaneRect], width: usize, height: usize) -> Vec<u8> {
+        let mut covered = vec![0u8; width * height];
+        for rect in rects {
+            for y in rect.y as usize..(rect.y + rect.height) as usize {
+                for x in rect.x as usize..(rect.x + rect.width) as usize {
+                    covered[y * width + x] += 1;
+                }
+            }
+        }
+        covered
+    }
+
+    #[test]
+    fn panes_tile_the_viewport() {
+        for layout in LAYOUTS {
+            for (width, height) in [(1280, 720), (801, 599), (3, 5)] {
+                let rects = layout.rects(width as f32, height as f32);
+                assert_eq!(rects.len(), layout.pane_count());
+                for rect in &rects {
+                    assert_eq!(rect.x.fract(), 0.0);
+                    assert_eq!(rect.width.fract(), 0.0);
+                    assert!(rect.x + rect.width <= width as f32);
+                    assert!(rect.y + rect.height <= height as f32);
+                }
+                // Every pixel in exactly one pane
+                assert!(
+                    coverage(&rects, width, height).iter().all(|&n| n == 1),
+                    "{:?} at {} x {}",
+                    layout,
+                    width,
+                    height
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn narrow_viewports_leave_panes_empty() {
+        let rects = PaneLayout::Split.rects(1.0, 300.0);
+        assert_eq!(rects.iter().filter(|rect| rect.is_empty()).count(), 1);
+        assert!(coverage(&rects, 1, 300).iter().all(|&n| n == 1));
+
+        let rects = PaneLayout::Grid.rects(1.0, 1.0);
+        assert_eq!(rects.iter().filter(|rect| !rect.is_empty()).count(), 1);
+        for rect in &rects {
+            assert!(rect.x >= 0.0 && rect.x + rect.width <= 1.0);
+            assert!(rect.y >= 0.0 && rect.y + rect.height <= 1.0);
+            assert!(rect.aspect().is_finite() && rect.aspect() > 0.0);
+        }
+
+        // Nothing at all to draw in
+        assert!(PaneLayout::Single.rects(0.0, 0.0)[0].is_empty());
+    }
+
+    #[test]
+    fn positions_resolve_to_their_pane() {
+        let uniforms = Uniforms::default();
+        let locate = |x, y| locate(PaneLayout::Grid, |_| &uniforms, x, y, 800.0, 600.0);
+
+        let point = locate(600.0, 150.0).unwrap();
+        assert_eq!(point.pane, 1);
+        assert!((point.u - 0.5).abs() < 1e-6 && (point.v - 0.5).abs() < 1e-6);
+        assert_eq!(locate(0.0, 599.0).unwrap().pane, 2);
+        assert_eq!(locate(799.0, 300.0).unwrap().pane, 3);
+        assert!(locate(800.0, 10.0).is_none());
+        assert!(locate(-1.0, 10.0).is_none());
+    }
+}
@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+
+/// How texels are combined when building the zoomed-out levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PyramidMode {
+    /// Average, keeps background and noise faithful
+    #[default]
+    Mean,
+    /// Brightest texel, keeps faint stars visible at fit-to-screen
+    Max,
+}
+
+/// Number of levels down to 1x1 for a `width` x `height` texture
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Level of detail for a given number of image pixels per screen pixel.
+/// Must match the level selection in `fs_main`.
+pub fn level_for_footprint(footprint: f32, level_count: u32) -> u32 {
+    let level = (footprint.max(1.0).log2() + 0.5).floor();
+    (level as u32).min(level_count.saturating_sub(1))
+}
+
+/// CPU copy of a pyramid level, used by the software renderer
+pub struct PyramidLevel {
+    pub data: Vec<f32>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Build all levels below `base` on the CPU, mirroring `pyramid.wgsl`
+pub fn build_levels(base: PyramidLevel, mode: PyramidMode) -> Vec<PyramidLevel> {
+    let count = mip_level_count(base.width, base.height);
+    let mut levels = vec![base];
+    for _ in 1..count {
+        let next = reduce(levels.last().unwrap(), mode);
+        levels.push(next);
+    }
+    levels
+}
+
+fn reduce(src: &PyramidLevel, mode: PyramidMode) -> PyramidLevel {
+    let (sw, sh) = (src.width as usize, src.height as usize);
+    let (dw, dh) = ((sw / 2).max(1), (sh / 2).max(1));
+
+    // Same block bounds as `source_block` in the shader
+    let block = |i: usize, src_size: usize, dst_size: usize| {
+        let start = i * src_size / dst_size;
+        let end = ((i + 1) * src_size).div_ceil(dst_size).max(start + 1);
+        start..end
+    };
+
+    let mut data = Vec::with_capacity(dw * dh);
+    for y in 0..dh {
+        let rows = block(y, sh, dh);
+        for x in 0..dw {
+            let cols = block(x, sw, dw);
+            let first = src.data[rows.start * sw + cols.start];
+            let values = rows
+                .clone()
+                .flat_map(|row| &src.data[row * sw + cols.start..row * sw + cols.end])
+                .copied()
+                .filter(|v| v.is_finite());
+
+            let value = match mode {
+                PyramidMode::Mean => {
+                    let (sum, count) = values.fold((0.0f32, 0u32), |(s, c), v| (s + v, c + 1));
+                    if count > 0 {
+                        sum / count as f32
+                    } else {
+                        first
+                    }
+                }
+                PyramidMode::Max => values.fold(first, |best, v| {
+                    if !best.is_finite() || v > best {
+                        v
+                    } else {
+                        best
+                    }
+                }),
+            };
+            data.push(value);
+        }
+    }
+
+    PyramidLevel {
+        data,
+        width: dw as u32,
+        height: dh as u32,
+    }
+}
+
+/// Compute pipelines that fill the mip chain of a tile texture on the GPU
+pub struct PyramidBuilder {
+    bind_group_layout: wgpu::BindGroupLayout,
+    mean_pipeline: wgpu::ComputePipeline,
+    max_pipeline: wgpu::ComputePipeline,
+}
+
+impl PyramidBuilder {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Pyramid Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("pyramid.wgsl").into()),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Pyramid Bind Group Layout"),
+            entries: &[
+                // Level being read
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                // Level being written
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: wgpu::TextureFormat::R32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Pyramid Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let create = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Pyramid Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader_module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+
+        Self {
+            mean_pipeline: create("reduce_mean"),
+            max_pipeline: create("reduce_max"),
+            bind_group_layout,
+        }
+    }
+
+    /// Record the reduction of every level below 0 into `encoder`. Each level
+    /// is written to a scratch texture and copied into the mip chain, so we
+    /// never read and write the same texture in one dispatch (the GL backend
+    /// ignores such writes).
+    pub fn encode(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        mode: PyramidMode,
+    ) {
+        let pipeline = match mode {
+            PyramidMode::Mean => &self.mean_pipeline,
+            PyramidMode::Max => &self.max_pipeline,
+        };
+
+        for level in 1..texture.mip_level_count() {
+            let size = wgpu::Extent3d {
+                width: (texture.width() >> level).max(1),
+                height: (texture.height() >> level).max(1),
+                depth_or_array_layers: 1,
+            };
+
+            let scratch = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Pyramid Scratch Texture"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R32Float,
+                usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            });
+
+            let source = texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("Pyramid Source View"),
+                base_mip_level: level - 1,
+                mip_level_count: Some(1),
+                ..Default::default()
+            });
+            let destination = scratch.create_view(&wgpu::TextureViewDescriptor::default());
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Pyramid Bind Group"),
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&destination),
+                    },
+                ],
+            });
+
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("pyramid-pass"),
+                    timestamp_writes: None,
+                });
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups(size.width.div_ceil(8), size.height.div_ceil(8), 1);
+            }
+
+            encoder.copy_texture_to_texture(
+                wgpu::TexelCopyTextureInfo {
+                    texture: &scratch,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::TexelCopyTextureInfo {
+                    texture,
+                    mip_level: level,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                size,
+            );
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// Builds one mip level of a FITS tile from the level above it
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+
+@group(0) @binding(1)
+var destination: texture_storage_2d<r32float, write>;
+
+fn is_finite(value: f32) -> bool {
+    // NaN fails every comparison, infinities are larger than f32::MAX
+    return abs(value) <= 3.40282347e38;
+}
+
+// Source texels covered by destination texel `id` (start.xy, end.xy exclusive).
+// Odd sizes make the last block 3 texels wide, so nothing is dropped.
+fn source_block(id: vec2<u32>) -> vec4<u32> {
+    let src_size = textureDimensions(source);
+    let dst_size = textureDimensions(destination);
+    let start = (id * src_size) / dst_size;
+    let end = max(((id + 1u) * src_size + dst_size - 1u) / dst_size, start + 1u);
+    return vec4<u32>(start, end);
+}
+
+// Average of the finite texels, keeps the background level and noise honest
+@compute @workgroup_size(8, 8)
+fn reduce_mean(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dst_size = textureDimensions(destination);
+    if (id.x >= dst_size.x || id.y >= dst_size.y) {
+        return;
+    }
+
+    let block = source_block(id.xy);
+    let first = textureLoad(source, vec2<i32>(block.xy), 0).r;
+    var sum = 0.0;
+    var count = 0.0;
+    for (var y = block.y; y < block.w; y++) {
+        for (var x = block.x; x < block.z; x++) {
+            let value = textureLoad(source, vec2<i32>(i32(x), i32(y)), 0).r;
+            if (is_finite(value)) {
+                sum += value;
+                count += 1.0;
+            }
+        }
+    }
+
+    let result = select(first, sum / count, count > 0.0);
+    textureStore(destination, vec2<i32>(id.xy), vec4<f32>(result, 0.0, 0.0, 1.0));
+}
+
+// Brightest finite texel, keeps stars visible when zoomed out
+@compute @workgroup_size(8, 8)
+fn reduce_max(@builtin(global_invocation_id) id: vec3<u32>) {
+    let dst_size = textureDimensions(destination);
+    if (id.x >= dst_size.x || id.y >= dst_size.y) {
+        return;
+    }
+
+    let block = source_block(id.xy);
+    var result = textureLoad(source, vec2<i32>(block.xy), 0).r;
+    for (var y = block.y; y < block.w; y++) {
+        for (var x = block.x; x < block.z; x++) {
+            let value = textureLoad(source, vec2<i32>(i32(x), i32(y)), 0).r;
+            if (is_finite(value) && (!is_finite(result) || value > result)) {
+                result = value;
+            }
+        }
+    }
+
+    textureStore(destination, vec2<i32>(id.xy), vec4<f32>(result, 0.0, 0.0, 1.0));
+}
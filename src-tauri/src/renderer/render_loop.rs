@@ -0,0 +1,81 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+#[derive(Default)]
+struct LoopState {
+    dirty: bool,
+    shutdown: bool,
+}
+
+/// Wakes the render thread when something on screen changed. Cheap to clone,
+/// all clones share the same flag.
+#[derive(Clone, Default)]
+pub struct RedrawSignal {
+    state: Arc<(Mutex<LoopState>, Condvar)>,
+}
+
+impl RedrawSignal {
+    /// Ask for one more frame; requests made before it is drawn are merged
+    pub fn request(&self) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().dirty = true;
+        condvar.notify_one();
+    }
+
+    /// Tell the render thread to exit
+    pub fn shutdown(&self) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().shutdown = true;
+        condvar.notify_one();
+    }
+
+    /// Block until a redraw is requested. Returns false once shut down.
+    fn wait(&self) -> bool {
+        let (lock, condvar) = &*self.state;
+        let mut state = condvar
+            .wait_while(lock.lock().unwrap(), |s| !s.dirty && !s.shutdown)
+            .unwrap();
+        state.dirty = false;
+        !state.shutdown
+    }
+}
+
+/// Thread that draws a frame whenever its `RedrawSignal` fires
+pub struct RenderLoop {
+    signal: RedrawSignal,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RenderLoop {
+    /// Start the thread and draw the first frame right away
+    pub fn spawn(signal: RedrawSignal, mut draw: impl FnMut() + Send + 'static) -> Self {
+        let waiter = signal.clone();
+        signal.request();
+
+        let thread = thread::spawn(move || {
+            while waiter.wait() {
+                draw();
+            }
+            println!("🛑 Render thread stopped");
+        });
+
+        Self {
+            signal,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop the thread and wait for the frame in flight to finish
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.signal.shutdown();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for RenderLoop {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -1,13 +1,19 @@
-// Bind group 0: Texture, sampler, and uniforms (all in one group)
+// Bind group 0: Textures, uniforms and tile placement (all in one group).
+// R32Float isn't filterable, so the texture is read with textureLoad and
+// interpolated by hand.
 @group(0) @binding(0)
 var fits_texture: texture_2d<f32>;
 
+// Same tile of the frame we compare against (the tile itself when not comparing)
 @group(0) @binding(1)
-var texture_sampler: sampler;
+var reference_texture: texture_2d<f32>;
 
 @group(0) @binding(2)
 var<uniform> uniforms: Uniforms;
 
+@group(0) @binding(3)
+var<uniform> tile: TileUniforms;
+
 // Uniforms for stretching and navigation
 struct Uniforms {
     min_value: f32,
@@ -19,9 +25,28 @@ struct Uniforms {
     pan_y: f32,       // Pan offset Y (-1.0 to 1.0)
     aspect_ratio: f32, // Image aspect ratio (width/height)
     viewport_aspect: f32, // Viewport aspect ratio
-    _padding1: f32,
-    _padding2: f32,
-    _padding3: f32,
+    clip_overlay: f32,    // 1.0 = highlight clipped pixels
+    saturation_level: f32, // Raw ADU value considered saturated
+    interpolation: u32,   // 0 = nearest, 1 = bilinear, 2 = bicubic, 3 = Lanczos
+    rotation: f32,        // Clockwise on screen, radians
+    flip_x: f32,          // 1.0 = mirrored left/right (before rotating)
+    flip_y: f32,          // 1.0 = mirrored top/bottom (before rotating)
+    background_offset: f32, // Subtracted before the stretch (blink frames)
+    shift: vec2<f32>,       // Image displacement in texture coordinates (blink frames)
+    compare_mode: u32,      // 0 = off, 1 = difference, 2 = ratio against the reference
+    reference_background: f32, // Background offset of the reference frame
+    reference_offset: vec2<f32>, // Reference pixel minus frame pixel of the same star
+    _padding: vec2<f32>,
+}
+
+// Placement of the current tile, all in image pixels
+struct TileUniforms {
+    core_min: vec2<f32>,       // Top-left of the region this tile draws
+    core_max: vec2<f32>,       // Bottom-right of the region this tile draws
+    texture_origin: vec2<f32>, // Top-left of the texture (core plus apron)
+    texture_size: vec2<f32>,   // Size of the texture
+    image_size: vec2<f32>,     // Size of the full image
+    _padding: vec2<f32>,
 }
 
 // Vertex shader output
@@ -30,17 +55,158 @@ struct VertexOutput {
     @location(0) tex_coords: vec2<f32>,
 }
 
-// Vertex shader - draws fullscreen triangle
+// Bounding box of the rotated image, in units of the image height
+fn oriented_size() -> vec2<f32> {
+    let c = abs(cos(uniforms.rotation));
+    let s = abs(sin(uniforms.rotation));
+    return vec2<f32>(uniforms.aspect_ratio * c + s, uniforms.aspect_ratio * s + c);
+}
+
+// Scale applied to centred screen coordinates so the image keeps its aspect ratio
+fn aspect_scale() -> vec2<f32> {
+    let size = oriented_size();
+    let aspect = size.x / size.y;
+    // If viewport is wider than image, scale X; if taller, scale Y
+    if (uniforms.viewport_aspect > aspect) {
+        // Viewport is wider, letterbox sides
+        return vec2<f32>(uniforms.viewport_aspect / aspect, 1.0);
+    }
+    // Viewport is taller, letterbox top/bottom
+    return vec2<f32>(1.0, aspect / uniforms.viewport_aspect);
+}
+
+// Full image texture coordinates to screen coordinates (0..1, top-left origin):
+// undo the alignment shift, flip and rotate in image height units, then pan,
+// zoom and aspect correction
+fn texture_to_screen(tex_coords: vec2<f32>) -> vec2<f32> {
+    var q = (tex_coords - uniforms.shift - 0.5) * vec2<f32>(uniforms.aspect_ratio, 1.0);
+    q *= select(vec2<f32>(1.0), vec2<f32>(-1.0), vec2<bool>(uniforms.flip_x > 0.5, uniforms.flip_y > 0.5));
+    
+    let c = cos(uniforms.rotation);
+    let s = sin(uniforms.rotation);
+    let rotated = vec2<f32>(c * q.x - s * q.y, s * q.x + c * q.y) / oriented_size();
+    
+    let centered = (rotated + vec2<f32>(uniforms.pan_x, uniforms.pan_y)) * uniforms.zoom;
+    return centered / aspect_scale() + 0.5;
+}
+
+// Texel at `p`, clamped to the edge of the texture
+fn load_texel(t: texture_2d<f32>, p: vec2<i32>, dims: vec2<i32>, level: i32) -> f32 {
+    return textureLoad(t, clamp(p, vec2<i32>(0), dims - 1), level).r;
+}
+
+// Catmull-Rom cubic
+fn cubic_weight(x: f32) -> f32 {
+    let a = abs(x);
+    if (a < 1.0) {
+        return (1.5 * a - 2.5) * a * a + 1.0;
+    }
+    if (a < 2.0) {
+        return ((-0.5 * a + 2.5) * a - 4.0) * a + 2.0;
+    }
+    return 0.0;
+}
+
+// Lanczos with a = 3
+fn lanczos_weight(x: f32) -> f32 {
+    if (abs(x) < 1e-5) {
+        return 1.0;
+    }
+    if (abs(x) >= 3.0) {
+        return 0.0;
+    }
+    let px = 3.14159265 * x;
+    return 3.0 * sin(px) * sin(px / 3.0) / (px * px);
+}
+
+// Separable kernel over a (2 * radius) x (2 * radius) neighbourhood
+fn sample_kernel(t: texture_2d<f32>, pos: vec2<f32>, dims: vec2<i32>, level: i32, radius: i32) -> f32 {
+    let p = pos - 0.5;
+    let base = floor(p);
+    let f = p - base;
+    let origin = vec2<i32>(base);
+    
+    var sum = 0.0;
+    var weight_sum = 0.0;
+    for (var j = 1 - radius; j <= radius; j++) {
+        var wy = lanczos_weight(f.y - f32(j));
+        if (radius == 2) {
+            wy = cubic_weight(f.y - f32(j));
+        }
+        for (var i = 1 - radius; i <= radius; i++) {
+            var wx = lanczos_weight(f.x - f32(i));
+            if (radius == 2) {
+                wx = cubic_weight(f.x - f32(i));
+            }
+            let w = wx * wy;
+            sum += w * load_texel(t, origin + vec2<i32>(i, j), dims, level);
+            weight_sum += w;
+        }
+    }
+    return sum / weight_sum;
+}
+
+// Read tile texture `t` at `coords` (0..1) from pyramid `level`
+fn sample_image(t: texture_2d<f32>, coords: vec2<f32>, level: i32) -> f32 {
+    let dims = vec2<i32>(textureDimensions(t, level));
+    let pos = coords * vec2<f32>(dims);
+    
+    switch uniforms.interpolation {
+        case 1u: {
+            let p = pos - 0.5;
+            let base = floor(p);
+            let f = p - base;
+            let i = vec2<i32>(base);
+            let top = mix(load_texel(t, i, dims, level), load_texel(t, i + vec2<i32>(1, 0), dims, level), f.x);
+            let bottom = mix(load_texel(t, i + vec2<i32>(0, 1), dims, level), load_texel(t, i + vec2<i32>(1, 1), dims, level), f.x);
+            return mix(top, bottom, f.y);
+        }
+        case 2u: {
+            return sample_kernel(t, pos, dims, level, 2);
+        }
+        case 3u: {
+            return sample_kernel(t, pos, dims, level, 3);
+        }
+        default: {
+            return load_texel(t, vec2<i32>(floor(pos)), dims, level);
+        }
+    }
+}
+
+// Blue - white - red, white at 0.5
+fn diverging(t: f32) -> vec3<f32> {
+    let blue = vec3<f32>(0.23, 0.30, 0.75);
+    let white = vec3<f32>(0.95, 0.95, 0.95);
+    let red = vec3<f32>(0.71, 0.02, 0.15);
+    if (t < 0.5) {
+        return mix(blue, white, t * 2.0);
+    }
+    return mix(white, red, (t - 0.5) * 2.0);
+}
+
+// Vertex shader - draws one quad covering the current tile
 @vertex
 fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
     var output: VertexOutput;
     
-    // Generate fullscreen triangle positions
-    let x = f32((vertex_index << 1u) & 2u);
-    let y = f32(vertex_index & 2u);
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(0.0, 1.0),
+        vec2<f32>(0.0, 1.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+    );
+    let corner = corners[vertex_index];
     
-    output.position = vec4<f32>(x * 2.0 - 1.0, 1.0 - y * 2.0, 0.0, 1.0);
-    output.tex_coords = vec2<f32>(x, y);
+    // Tile corner in texture coordinates of the full image
+    let tex_coords = mix(tile.core_min, tile.core_max, corner) / tile.image_size;
+    
+    // Inverse of the screen -> texture mapping: undo orientation, pan, zoom and aspect correction
+    let screen = texture_to_screen(tex_coords);
+    
+    output.position = vec4<f32>(screen.x * 2.0 - 1.0, 1.0 - screen.y * 2.0, 0.0, 1.0);
+    output.tex_coords = tex_coords;
     
     return output;
 }
@@ -48,38 +214,58 @@ fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
 // Fragment shader - samples texture and applies color mapping
 @fragment
 fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
-    // Apply zoom and pan to texture coordinates
-    var tex_coords = input.tex_coords - 0.5; // Center at origin
+    // Texture coordinates of the full image, already zoomed and panned by vs_main
+    let image_pixel = input.tex_coords * tile.image_size;
     
-    // Correct aspect ratio to prevent stretching
-    // If viewport is wider than image, scale X; if taller, scale Y
-    if (uniforms.viewport_aspect > uniforms.aspect_ratio) {
-        // Viewport is wider, letterbox sides
-        tex_coords.x *= uniforms.viewport_aspect / uniforms.aspect_ratio;
-    } else {
-        // Viewport is taller, letterbox top/bottom
-        tex_coords.y *= uniforms.aspect_ratio / uniforms.viewport_aspect;
-    }
+    // Position inside this tile's texture
+    let tile_coords = (image_pixel - tile.texture_origin) / tile.texture_size;
     
-    // Apply zoom
-    tex_coords = tex_coords / uniforms.zoom;
+    // Pick the pyramid level from how many image pixels fall on one screen pixel
+    let footprint = max(length(dpdx(image_pixel)), length(dpdy(image_pixel)));
+    let level = min(i32(floor(log2(max(footprint, 1.0)) + 0.5)), i32(textureNumLevels(fits_texture)) - 1);
     
-    // Apply pan
-    tex_coords = tex_coords - vec2<f32>(uniforms.pan_x, uniforms.pan_y);
+    // Sample the FITS texture (single channel float)
+    let raw_value = sample_image(fits_texture, tile_coords, level);
     
-    // Back to texture space
-    tex_coords = tex_coords + 0.5;
+    // Same background level for every blink frame
+    let value = raw_value - uniforms.background_offset;
     
-    // Check if we're outside the texture bounds
-    if (tex_coords.x < 0.0 || tex_coords.x > 1.0 || tex_coords.y < 0.0 || tex_coords.y > 1.0) {
-        return vec4<f32>(0.0, 0.0, 0.0, 1.0); // Black outside bounds
+    // Difference or ratio against the reference frame, on a diverging colormap
+    if (uniforms.compare_mode != 0u) {
+        let reference_coords = (image_pixel + uniforms.reference_offset - tile.texture_origin) / tile.texture_size;
+        let reference = sample_image(reference_texture, reference_coords, level) - uniforms.reference_background;
+        
+        var result = value - reference;
+        if (uniforms.compare_mode == 2u) {
+            result = select(value / reference, 0.0, abs(reference) < 1e-6);
+        }
+        
+        let normalized = (result - uniforms.min_value) / (uniforms.max_value - uniforms.min_value);
+        let adjusted = (normalized - 0.5) * uniforms.contrast + 0.5 + uniforms.brightness;
+        return vec4<f32>(diverging(clamp(adjusted, 0.0, 1.0)), 1.0);
     }
     
-    // Sample the FITS texture (single channel float)
-    let raw_value = textureSample(fits_texture, texture_sampler, tex_coords).r;
+    // Clipping overlay: saturated pixels first, then highlights and shadows.
+    // Tested on the full resolution image, a mean pyramid level would
+    // average clipped pixels away.
+    if (uniforms.clip_overlay > 0.5) {
+        var full_value = raw_value;
+        if (level > 0) {
+            full_value = sample_image(fits_texture, tile_coords, 0);
+        }
+        if (full_value >= uniforms.saturation_level) {
+            return vec4<f32>(1.0, 0.85, 0.0, 1.0); // Yellow for saturated ADU
+        }
+        if (full_value - uniforms.background_offset > uniforms.max_value) {
+            return vec4<f32>(1.0, 0.0, 0.0, 1.0); // Red above white point
+        }
+        if (full_value - uniforms.background_offset < uniforms.min_value) {
+            return vec4<f32>(0.0, 0.3, 1.0, 1.0); // Blue below black point
+        }
+    }
     
     // Normalize: map [min, max] to [0, 1]
-    let normalized = (raw_value - uniforms.min_value) / (uniforms.max_value - uniforms.min_value);
+    let normalized = (value - uniforms.min_value) / (uniforms.max_value - uniforms.min_value);
     
     // Apply brightness and contrast
     let adjusted = (normalized - 0.5) * uniforms.contrast + 0.5 + uniforms.brightness;
@@ -91,4 +277,26 @@ fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
     let color = vec3<f32>(final_value, final_value, final_value);
     
     return vec4<f32>(color, 1.0);
-}
\ No newline at end of file
+}
+// Overlay lines (grids, markers), positions in image texture coordinates
+struct OverlayOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+}
+
+@vertex
+fn vs_overlay(@location(0) position: vec2<f32>, @location(1) color: vec4<f32>) -> OverlayOutput {
+    var output: OverlayOutput;
+
+    // Same mapping as vs_main
+    let screen = texture_to_screen(position);
+
+    output.position = vec4<f32>(screen.x * 2.0 - 1.0, 1.0 - screen.y * 2.0, 0.0, 1.0);
+    output.color = color;
+    return output;
+}
+
+@fragment
+fn fs_overlay(input: OverlayOutput) -> @location(0) vec4<f32> {
+    return input.color;
+}
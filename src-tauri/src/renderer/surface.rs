@@ -0,0 +1,126 @@
+use std::result::Result::{Err as StdErr, Ok as StdOk};
+
+/// The window's swapchain. Kept at the window's physical size so the image
+/// is drawn 1:1 instead of being scaled by the compositor.
+pub struct WindowSurface {
+    surface: wgpu::Surface<'static>,
+    config: wgpu::SurfaceConfiguration,
+    /// The window is minimized (zero-sized), nothing can be presented
+    suspended: bool,
+}
+
+impl WindowSurface {
+    /// Configure `surface` for `adapter` at `width` x `height` physical pixels
+    pub fn new(
+        surface: wgpu::Surface<'static>,
+        adapter: &wgpu::Adapter,
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let caps = surface.get_capabilities(adapter);
+        let format = caps.formats[0];
+
+        // Try to find a supported alpha mode, preferring PreMultiplied for transparency
+        let alpha_mode = if caps
+            .alpha_modes
+            .contains(&wgpu::CompositeAlphaMode::PreMultiplied)
+        {
+            wgpu::CompositeAlphaMode::PreMultiplied
+        } else if caps
+            .alpha_modes
+            .contains(&wgpu::CompositeAlphaMode::PostMultiplied)
+        {
+            wgpu::CompositeAlphaMode::PostMultiplied
+        } else if caps
+            .alpha_modes
+            .contains(&wgpu::CompositeAlphaMode::Inherit)
+        {
+            wgpu::CompositeAlphaMode::Inherit
+        } else {
+            println!("⚠️  No transparent alpha mode supported, using Opaque");
+            caps.alpha_modes[0]
+        };
+
+        println!("Using alpha mode: {:?}", alpha_mode);
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format,
+            width: width.max(1),
+            height: height.max(1),
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: 1,
+            alpha_mode,
+            view_formats: vec![],
+        };
+
+        let mut window_surface = Self {
+            surface,
+            config,
+            suspended: false,
+        };
+        window_surface.resize(device, width, height);
+        window_surface
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.config.format
+    }
+
+    /// Current swapchain size in physical pixels
+    pub fn size(&self) -> (u32, u32) {
+        (self.config.width, self.config.height)
+    }
+
+    /// The window is minimized, frames are skipped until it is restored
+    pub fn suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Reconfigure for a new physical size. A zero size (minimized window)
+    /// suspends presenting until the window is restored.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            self.suspended = true;
+            return;
+        }
+
+        self.suspended = false;
+        self.config.width = width;
+        self.config.height = height;
+        self.surface.configure(device, &self.config);
+    }
+
+    /// Get the next frame, reconfiguring the swapchain when it went stale.
+    /// Returns `Ok(None)` while the window is minimized, and the error when
+    /// no frame could be had, so the caller can tell a frame worth retrying
+    /// from a broken surface.
+    pub fn acquire(
+        &mut self,
+        device: &wgpu::Device,
+    ) -> Result<Option<wgpu::SurfaceTexture>, wgpu::SurfaceError> {
+        if self.suspended {
+            return StdOk(None);
+        }
+
+        match self.surface.get_current_texture() {
+            StdOk(frame) => StdOk(Some(frame)),
+            StdErr(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                // Swapchain no longer matches the window, rebuild it and try once more
+                self.surface.configure(device, &self.config);
+                self.surface.get_current_texture().map(Some)
+            }
+            StdErr(e) => StdErr(e),
+        }
+    }
+}
+
+/// Timeouts and a stale swapchain clear up by themselves, the frame is worth
+/// retrying. Anything else (out of memory, a lost device) will not.
+pub fn is_transient(error: &wgpu::SurfaceError) -> bool {
+    matches!(
+        error,
+        wgpu::SurfaceError::Timeout | wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated
+    )
+}
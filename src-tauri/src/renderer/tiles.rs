@@ -0,0 +1,181 @@
+use super::pyramid::mip_level_count;
+
+/// Extra texels kept around each tile, at every mip level, so sampling near
+/// a tile edge never needs data from a neighbouring texture
+const TILE_APRON: u32 = 4;
+
+/// Texels the widest interpolation kernel (Lanczos) reaches past the
+/// sampled point
+const KERNEL_RADIUS: u32 = 3;
+
+/// Deepest pyramid of a tiled texture. Level k halves the apron k times, so
+/// the apron in full resolution pixels doubles with every level.
+const MAX_TILE_LEVELS: u32 = 7;
+
+/// Apron in full resolution pixels for tiles with `levels` mip levels
+pub fn apron(levels: u32) -> u32 {
+    TILE_APRON << levels.saturating_sub(1)
+}
+
+/// How far (full resolution pixels) a tile may sample the same tile of
+/// another frame away from its core, what the apron leaves beside the
+/// kernel at the coarsest level
+pub fn max_reference_offset(levels: u32) -> u32 {
+    (TILE_APRON - KERNEL_RADIUS) << levels.saturating_sub(1)
+}
+
+/// Per-tile uniforms, must match `TileUniforms` in `shader.wgsl`.
+/// All values are in image pixels.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TileUniforms {
+    pub core_min: [f32; 2],
+    pub core_max: [f32; 2],
+    pub texture_origin: [f32; 2],
+    pub texture_size: [f32; 2],
+    pub image_size: [f32; 2],
+    pub _padding: [f32; 2],
+}
+
+/// Where a tile sits in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRect {
+    /// Region this tile draws: x, y, width, height
+    pub core: [u32; 4],
+    /// Region stored in the texture (core plus apron): x, y, width, height
+    pub texture: [u32; 4],
+    /// Mip levels the apron is sized for; small edge tiles may have fewer
+    pub levels: u32,
+}
+
+impl TileRect {
+    pub fn uniforms(&self, image_width: u32, image_height: u32) -> TileUniforms {
+        let [cx, cy, cw, ch] = self.core;
+        let [tx, ty, tw, th] = self.texture;
+        TileUniforms {
+            core_min: [cx as f32, cy as f32],
+            core_max: [(cx + cw) as f32, (cy + ch) as f32],
+            texture_origin: [tx as f32, ty as f32],
+            texture_size: [tw as f32, th as f32],
+            image_size: [image_width as f32, image_height as f32],
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+/// A GPU-resident piece of the image
+pub struct Tile {
+    pub texture: wgpu::Texture,
+    pub uniform_buffer: wgpu::Buffer,
+    pub bind_group: Option<wgpu::BindGroup>,
+}
+
+/// Split a `width` x `height` image into tiles whose textures (including the
+/// apron) fit into `max_dimension`. Images that fit get a single tile with
+/// the full pyramid. Tiles get as many levels (up to `MAX_TILE_LEVELS`) as
+/// leave at least half of each texture to the core, and their cores and
+/// aprons are whole pixels of the coarsest level so every level lines up
+/// with the full image's.
+pub fn plan_tiles(width: u32, height: u32, max_dimension: u32) -> Vec<TileRect> {
+    if width <= max_dimension && height <= max_dimension {
+        return vec![TileRect {
+            core: [0, 0, width, height],
+            texture: [0, 0, width, height],
+            levels: mip_level_count(width, height),
+        }];
+    }
+
+    let levels = (1..=MAX_TILE_LEVELS)
+        .rev()
+        .find(|&levels| 4 * apron(levels) <= max_dimension)
+        .unwrap_or(1);
+    let apron = apron(levels);
+    let block = 1 << (levels - 1);
+    let core_size = (max_dimension.saturating_sub(2 * apron) / block * block).max(1);
+    let mut tiles = Vec::new();
+
+    for y in (0..height).step_by(core_size as usize) {
+        for x in (0..width).step_by(core_size as usize) {
+            let core_w = core_size.min(width - x);
+            let core_h = core_size.min(height - y);
+
+            let tex_x = x.saturating_sub(apron);
+            let tex_y = y.saturating_sub(apron);
+            let tex_w = (x + core_w + apron).min(width) - tex_x;
+            let tex_h = (y + core_h + apron).min(height) - tex_y;
+
+            tiles.push(TileRect {
+                core: [x, y, core_w, core_h],
+                texture: [tex_x, tex_y, tex_w, tex_h],
+                levels,
+            });
+        }
+    }
+
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_images_get_one_tile() {
+        let tiles = plan_tiles(300, 200, 512);
+        assert_eq!(tiles.len(), 1);
+        assert_eq!(tiles[0].core, [0, 0, 300, 200]);
+        assert_eq!(tiles[0].texture, [0, 0, 300, 200]);
+        assert_eq!(tiles[0].levels, mip_level_count(300, 200));
+    }
+
+    #[test]
+    fn tiles_cover_the_image_once_with_their_aprons() {
+        // Neither side divides into the cores
+        for (width, height, max_dimension) in [(1000, 700, 256), (4099, 2053, 1024), (97, 61, 40)] {
+            let tiles = plan_tiles(width, height, max_dimension);
+            assert!(tiles.len() > 1);
+
+            let mut covered = vec![0u8; (width * height) as usize];
+            for tile in &tiles {
+                let [cx, cy, cw, ch] = tile.core;
+                let [tx, ty, tw, th] = tile.texture;
+                assert!(tw <= max_dimension && th <= max_dimension);
+                for y in cy..cy + ch {
+                    for x in cx..cx + cw {
+                        covered[(y * width + x) as usize] += 1;
+                    }
+                }
+
+                // The apron surrounds the core wherever the image goes on
+                let apron = apron(tile.levels);
+                assert_eq!(tx, cx.saturating_sub(apron));
+                assert_eq!(ty, cy.saturating_sub(apron));
+                assert_eq!(tx + tw, (cx + cw + apron).min(width));
+                assert_eq!(ty + th, (cy + ch + apron).min(height));
+
+                // Texels of the coarsest level line up with the image's
+                let block = 1 << (tile.levels - 1);
+                assert_eq!(tx % block, 0);
+                assert_eq!(ty % block, 0);
+            }
+            assert!(
+                covered.iter().all(|&count| count == 1),
+                "{}x{}",
+                width,
+                height
+            );
+        }
+    }
+
+    #[test]
+    fn apron_grows_with_the_pyramid() {
+        let tiles = plan_tiles(20000, 100, 8192);
+        assert_eq!(tiles[0].levels, MAX_TILE_LEVELS);
+        // Still 4 texels at the coarsest level, 3 of them for the kernel
+        assert_eq!(apron(MAX_TILE_LEVELS) >> (MAX_TILE_LEVELS - 1), TILE_APRON);
+        assert_eq!(max_reference_offset(MAX_TILE_LEVELS), 64);
+
+        // Small tiles make do with fewer levels
+        assert_eq!(plan_tiles(1000, 100, 64)[0].levels, 3);
+    }
+}
@@ -0,0 +1,343 @@
+use super::Uniforms;
+use serde::{Deserialize, Serialize};
+
+/// Screen <-> image mapping of the current view. Mirrors `vs_main` (image to
+/// screen) and the software renderer (screen to image), so whatever the user
+/// points at maps to the pixel that is drawn there.
+///
+/// Screen coordinates are fractions of the viewport (0..1, top-left origin),
+/// texture coordinates fractions of the image (0..1, first data row on top).
+/// In between, orientation is applied in units of the image height so
+/// rotations keep the pixels square.
+#[derive(Debug, Clone, Copy)]
+pub struct ViewTransform {
+    /// Letterbox correction, see `aspect_scale` in the shader
+    scale: [f32; 2],
+    zoom: f32,
+    pan: [f32; 2],
+    image_aspect: f32,
+    /// Bounding box of the oriented image, see `oriented_size` in the shader
+    size: [f32; 2],
+    /// Cosine and sine of the rotation
+    rotation: [f32; 2],
+    /// -1.0 on mirrored axes
+    flip: [f32; 2],
+    /// Alignment shift in texture coordinates, see `FrameAlignment`
+    shift: [f32; 2],
+}
+
+impl ViewTransform {
+    /// Transform for `uniforms` shown in a `viewport_width` x `viewport_height`
+    /// viewport (any unit, only the ratio matters)
+    pub fn new(uniforms: &Uniforms, viewport_width: f32, viewport_height: f32) -> Self {
+        let (sin, cos) = uniforms.rotation.sin_cos();
+        let aspect = uniforms.aspect_ratio;
+        let size = [
+            aspect * cos.abs() + sin.abs(),
+            aspect * sin.abs() + cos.abs(),
+        ];
+        let oriented_aspect = size[0] / size[1];
+
+        let viewport_aspect = viewport_width.max(1.0) / viewport_height.max(1.0);
+        let scale = if viewport_aspect > oriented_aspect {
+            // Viewport is wider, letterbox sides
+            [viewport_aspect / oriented_aspect, 1.0]
+        } else {
+            // Viewport is taller, letterbox top/bottom
+            [1.0, oriented_aspect / viewport_aspect]
+        };
+        let flip = |flag: f32| if flag > 0.5 { -1.0 } else { 1.0 };
+
+        Self {
+            scale,
+            zoom: uniforms.zoom,
+            pan: [uniforms.pan_x, uniforms.pan_y],
+            image_aspect: aspect,
+            size,
+            rotation: [cos, sin],
+            flip: [flip(uniforms.flip_x), flip(uniforms.flip_y)],
+            shift: uniforms.shift,
+        }
+    }
+
+    pub fn screen_to_texture(&self, u: f32, v: f32) -> [f32; 2] {
+        // Fraction of the oriented bounding box, then image height units
+        let dx = ((u - 0.5) * self.scale[0] / self.zoom - self.pan[0]) * self.size[0];
+        let dy = ((v - 0.5) * self.scale[1] / self.zoom - self.pan[1]) * self.size[1];
+
+        // Undo the rotation, then the flips
+        let [cos, sin] = self.rotation;
+        let qx = (cos * dx + sin * dy) * self.flip[0];
+        let qy = (cos * dy - sin * dx) * self.flip[1];
+
+        [
+            qx / self.image_aspect + 0.5 + self.shift[0],
+            qy + 0.5 + self.shift[1],
+        ]
+    }
+
+    pub fn texture_to_screen(&self, tx: f32, ty: f32) -> [f32; 2] {
+        let qx = (tx - self.shift[0] - 0.5) * self.image_aspect * self.flip[0];
+        let qy = (ty - self.shift[1] - 0.5) * self.flip[1];
+
+        let [cos, sin] = self.rotation;
+        let dx = (cos * qx - sin * qy) / self.size[0];
+        let dy = (sin * qx + cos * qy) / self.size[1];
+
+        [
+            (dx + self.pan[0]) * self.zoom / self.scale[0] + 0.5,
+            (dy + self.pan[1]) * self.zoom / self.scale[1] + 0.5,
+        ]
+    }
+
+    /// Image pixels covered by one screen pixel, for picking the pyramid
+    /// level (`image_height` in pixels, viewport size in screen pixels)
+    pub fn footprint(&self, image_height: u32, viewport_width: f32, viewport_height: f32) -> f32 {
+        // Rotation keeps lengths, so one step along either screen axis
+        // spans this many image heights
+        let along_x = self.size[0] * self.scale[0] / (self.zoom * viewport_width);
+        let along_y = self.size[1] * self.scale[1] / (self.zoom * viewport_height);
+        along_x.max(along_y) * image_height as f32
+    }
+
+    /// Image pixel (0-based column, row) under a screen position, or `None`
+    /// when the position is outside the image
+    pub fn screen_to_pixel(&self, u: f32, v: f32, width: u32, height: u32) -> Option<[u32; 2]> {
+        let [tx, ty] = self.screen_to_texture(u, v);
+        if !(0.0..1.0).contains(&tx) || !(0.0..1.0).contains(&ty) {
+            return None;
+        }
+        Some([
+            ((tx * width as f32) as u32).min(width - 1),
+            ((ty * height as f32) as u32).min(height - 1),
+        ])
+    }
+}
+
+/// How the image is turned on screen. The flips apply to the image as
+/// stored, before it is rotated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Orientation {
+    /// Clockwise, degrees
+    pub rotation_deg: f32,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+
+/// What the cursor points at
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PixelInfo {
+    /// Pane the cursor is over
+    pub pane: usize,
+    /// 0-based column and row in the data array
+    pub x: u32,
+    pub y: u32,
+    /// Value as stored in the file, after BSCALE/BZERO. When comparing
+    /// frames, the difference or ratio instead.
+    pub raw: f32,
+    /// Value mapped through the current stretch, 0 at the black point and
+    /// 1 at the white point (not clamped)
+    pub normalized: f32,
+    /// Sky position in degrees, when the header has a WCS
+    pub ra: Option<f64>,
+    pub dec: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniforms(aspect_ratio: f32, zoom: f32, pan: [f32; 2]) -> Uniforms {
+        Uniforms {
+            aspect_ratio,
+            zoom,
+            pan_x: pan[0],
+            pan_y: pan[1],
+            ..Default::default()
+        }
+    }
+
+    fn oriented(aspect_ratio: f32, rotation_deg: f32, flip_horizontal: bool) -> Uniforms {
+        let mut uniforms = uniforms(aspect_ratio, 1.0, [0.0, 0.0]);
+        uniforms.set_orientation(Orientation {
+            rotation_deg,
+            flip_horizontal,
+            flip_vertical: false,
+        });
+        uniforms
+    }
+
+    #[test]
+    fn screen_and_texture_round_trip() {
+        for aspect in [0.5, 1.0, 1.5] {
+            for zoom in [0.25, 1.0, 3.7] {
+                for pan in [[0.0, 0.0], [0.2, -0.1], [-0.45, 0.3]] {
+                    for (width, height) in [(800.0, 600.0), (300.0, 900.0)] {
+                        let view = ViewTransform::new(&uniforms(aspect, zoom, pan), width, height);
+                        for (u, v) in [(0.0, 0.0), (0.5, 0.5), (0.9, 0.2), (0.13, 0.77)] {
+                            let [tx, ty] = view.screen_to_texture(u, v);
+                            let [bu, bv] = view.texture_to_screen(tx, ty);
+                            assert!(
+                                (bu - u).abs() < 1e-4 && (bv - v).abs() < 1e-4,
+                                "({}, {}) came back as ({}, {}) at aspect {}, zoom {}, pan {:?}",
+                                u,
+                                v,
+                                bu,
+                                bv,
+                                aspect,
+                                zoom,
+                                pan
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fitted_image_fills_the_viewport() {
+        // Same aspect as the viewport, so the corners meet
+        let view = ViewTransform::new(&uniforms(2.0, 1.0, [0.0, 0.0]), 400.0, 200.0);
+        let [x0, y0] = view.screen_to_texture(0.0, 0.0);
+        let [x1, y1] = view.screen_to_texture(1.0, 1.0);
+        assert!(x0.abs() < 1e-6 && y0.abs() < 1e-6);
+        assert!((x1 - 1.0).abs() < 1e-6 && (y1 - 1.0).abs() < 1e-6);
+
+        // Zoomed in on the centre, panned by a quarter of the image
+        let view = ViewTransform::new(&uniforms(2.0, 2.0, [0.25, 0.0]), 400.0, 200.0);
+        let [cx, cy] = view.screen_to_texture(0.5, 0.5);
+        assert!((cx - 0.25).abs() < 1e-6 && (cy - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn footprint_counts_image_pixels_per_screen_pixel() {
+        // 2000 x 1000 image in a 1000 x 500 viewport
+        let view = ViewTransform::new(&uniforms(2.0, 1.0, [0.0, 0.0]), 1000.0, 500.0);
+        assert!((view.footprint(1000, 1000.0, 500.0) - 2.0).abs() < 1e-4);
+
+        let view = ViewTransform::new(&uniforms(2.0, 4.0, [0.3, 0.1]), 1000.0, 500.0);
+        assert!((view.footprint(1000, 1000.0, 500.0) - 0.5).abs() < 1e-4);
+
+        // Letterboxed: a square image in a wide viewport is fitted to the
+        // height, so the width doesn't matter
+        let view = ViewTransform::new(&uniforms(1.0, 1.0, [0.0, 0.0]), 1000.0, 250.0);
+        assert!((view.footprint(1000, 1000.0, 250.0) - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pixels_under_the_screen() {
+        let (width, height) = (400, 200);
+        let view = ViewTransform::new(&uniforms(2.0, 1.0, [0.0, 0.0]), 800.0, 400.0);
+
+        // Edge pixels are inside the image
+        assert_eq!(view.screen_to_pixel(0.0, 0.0, width, height), Some([0, 0]));
+        assert_eq!(
+            view.screen_to_pixel(0.9999, 0.9999, width, height),
+            Some([width - 1, height - 1])
+        );
+        assert_eq!(
+            view.screen_to_pixel(0.5, 0.5, width, height),
+            Some([200, 100])
+        );
+
+        // Just past either edge is not
+        for (u, v) in [(-0.001, 0.5), (1.0, 0.5), (0.5, -0.001), (0.5, 1.0)] {
+            assert_eq!(
+                view.screen_to_pixel(u, v, width, height),
+                None,
+                "({}, {})",
+                u,
+                v
+            );
+        }
+    }
+
+    #[test]
+    fn letterbox_bars_are_outside_the_image() {
+        // Square image in a viewport twice as wide: a quarter bar each side
+        let view = ViewTransform::new(&uniforms(1.0, 1.0, [0.0, 0.0]), 800.0, 400.0);
+        assert_eq!(view.screen_to_pixel(0.2, 0.5, 100, 100), None);
+        assert_eq!(view.screen_to_pixel(0.8, 0.5, 100, 100), None);
+        assert_eq!(view.screen_to_pixel(0.2501, 0.5, 100, 100), Some([0, 50]));
+        assert_eq!(view.screen_to_pixel(0.7499, 0.5, 100, 100), Some([99, 50]));
+    }
+
+    #[test]
+    fn orientations_show_the_expected_corner() {
+        // 4 x 3 image, viewport shaped like the turned image so its corners
+        // are the image's corners. Source pixel at the screen's top-left and
+        // top-right for each clockwise rotation, flips applied first.
+        let (width, height) = (4, 3);
+        let cases = [
+            (0.0, false, [0, 0], [3, 0]),
+            (90.0, false, [0, 2], [0, 0]),
+            (180.0, false, [3, 2], [0, 2]),
+            (270.0, false, [3, 0], [3, 2]),
+            (0.0, true, [3, 0], [0, 0]),
+            (90.0, true, [3, 2], [3, 0]),
+            (180.0, true, [0, 2], [3, 2]),
+            (270.0, true, [0, 0], [0, 2]),
+        ];
+        for (rotation, flip, top_left, top_right) in cases {
+            let (view_width, view_height) = if rotation == 90.0 || rotation == 270.0 {
+                (3.0, 4.0)
+            } else {
+                (4.0, 3.0)
+            };
+            let uniforms = oriented(width as f32 / height as f32, rotation, flip);
+            let view = ViewTransform::new(&uniforms, view_width, view_height);
+            let pixel = |u, v| view.screen_to_pixel(u, v, width, height);
+            assert_eq!(pixel(0.05, 0.05), Some(top_left), "{} {}", rotation, flip);
+            assert_eq!(pixel(0.95, 0.05), Some(top_right), "{} {}", rotation, flip);
+        }
+    }
+
+    #[test]
+    fn vertical_flip_is_a_horizontal_flip_turned_half_way() {
+        let (width, height) = (4, 3);
+        let mut flipped = oriented(width as f32 / height as f32, 0.0, false);
+        flipped.set_orientation(Orientation {
+            flip_vertical: true,
+            ..Default::default()
+        });
+        let turned = oriented(width as f32 / height as f32, 180.0, true);
+        let (a, b) = (
+            ViewTransform::new(&flipped, 4.0, 3.0),
+            ViewTransform::new(&turned, 4.0, 3.0),
+        );
+        for (u, v) in [(0.05, 0.05), (0.95, 0.05), (0.4, 0.6), (0.95, 0.95)] {
+            assert_eq!(
+                a.screen_to_pixel(u, v, width, height),
+                b.screen_to_pixel(u, v, width, height)
+            );
+        }
+        assert_eq!(a.screen_to_pixel(0.05, 0.05, width, height), Some([0, 2]));
+    }
+
+    #[test]
+    fn turned_views_round_trip() {
+        for rotation in [0.0, 30.0, 90.0, 200.0, 270.0] {
+            for flip in [false, true] {
+                let mut uniforms = oriented(1.5, rotation, flip);
+                uniforms.zoom = 2.5;
+                uniforms.pan_x = 0.1;
+                let view = ViewTransform::new(&uniforms, 640.0, 480.0);
+                for (tx, ty) in [(0.0, 0.0), (0.3, 0.8), (1.0, 0.5)] {
+                    let [u, v] = view.texture_to_screen(tx, ty);
+                    let [bx, by] = view.screen_to_texture(u, v);
+                    assert!(
+                        (bx - tx).abs() < 1e-4 && (by - ty).abs() < 1e-4,
+                        "{} {}: ({}, {}) came back as ({}, {})",
+                        rotation,
+                        flip,
+                        tx,
+                        ty,
+                        bx,
+                        by
+                    );
+                }
+            }
+        }
+    }
+}
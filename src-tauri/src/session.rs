@@ -0,0 +1,620 @@
+use crate::background::{Background, BackgroundParams};
+use crate::calibration::{exposure_time, CalibrationSet};
+use crate::elongation;
+use crate::fits::{load_fits_f32, median_in_place, sample_pixels, sigma_clipped_stats, FitsHeader};
+use crate::registration::{self, Registration, RegistrationParams};
+use crate::solver::StarGrid;
+use crate::stars::{detect_stars, DetectionParams, Star};
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Brightest unsaturated reference stars whose flux is compared
+const PHOTOMETRY_STARS: usize = 200;
+
+/// Fewest matched stars for a transparency measurement
+const MIN_PHOTOMETRY_STARS: usize = 5;
+
+/// Stars peaking above this share of the saturation level are left out of
+/// photometry
+const SATURATION_SHARE: f32 = 0.9;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SessionParams {
+    /// Frames with less transparency than this, relative to the reference,
+    /// are rejected
+    pub min_transparency: f32,
+}
+
+impl Default for SessionParams {
+    fn default() -> Self {
+        Self {
+            min_transparency: 0.7,
+        }
+    }
+}
+
+/// Sky and star metrics of one frame of a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameMetrics {
+    pub path: String,
+    /// DATE-OBS as written in the header
+    pub date_obs: Option<String>,
+    /// Start of the exposure, seconds since 1970-01-01 UTC
+    pub timestamp: Option<f64>,
+    pub exposure: Option<f64>,
+    pub filter: Option<String>,
+    /// Sigma-clipped median of the frame
+    pub background: f32,
+    /// Sigma-clipped standard deviation of the frame
+    pub noise: f32,
+    /// Stars detected above the default threshold
+    pub stars: usize,
+    /// Median star eccentricity, rising when tracking or guiding slipped
+    pub eccentricity: f32,
+    /// Dominant direction stars are elongated in, degrees from +x towards +y
+    pub elongation_angle_deg: f32,
+    /// Range of the fitted background over the sky level, percent
+    pub gradient_percent: f32,
+    /// Direction the sky gets brighter, degrees counter-clockwise from +x
+    pub gradient_angle_deg: f32,
+    /// Star flux relative to the reference frame per second of exposure,
+    /// from the median ratio over matched stars. None when too few matched.
+    pub transparency: Option<f32>,
+    /// Stars the transparency was measured on
+    pub matched_stars: usize,
+    /// Why the frame should be left out of stacking
+    pub reject: Option<String>,
+}
+
+/// Metrics of a session's frames in observation order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    /// Frames without a usable date come last, in the order given
+    pub frames: Vec<FrameMetrics>,
+    /// Frames that could not be read, with the reason
+    pub skipped: Vec<(String, String)>,
+    /// Frames transparency is measured against, the one with most stars of
+    /// each filter
+    pub references: Vec<String>,
+}
+
+/// Stars of a frame kept for photometry
+struct FrameStars {
+    stars: Vec<Star>,
+    /// Star peaks (above the background) from which stars are saturated
+    saturated_peak: f32,
+}
+
+/// Measure every frame of `paths`, calibrated with `masters` when given,
+/// order them by DATE-OBS and reject those clouds dimmed
+pub fn measure_session(
+    paths: &[String],
+    masters: Option<&CalibrationSet>,
+    params: &SessionParams,
+) -> Session {
+    let mut session = Session::default();
+    let mut measured = Vec::new();
+    for path in paths {
+        match measure_frame(path, masters) {
+            Result::Ok(frame) => measured.push(frame),
+            Err(e) => {
+                println!("⚠️ Skipping {}: {}", path, e);
+                session.skipped.push((path.clone(), e.to_string()));
+            }
+        }
+    }
+
+    // Stable, so frames without a date keep their order
+    measured.sort_by(|(a, _), (b, _)| observation_order(a, b));
+
+    // Filters pass different amounts of starlight, so each filter's frames
+    // are compared with their own reference
+    let mut filters: Vec<Option<String>> = Vec::new();
+    for (metrics, _) in &measured {
+        if !filters.contains(&metrics.filter) {
+            filters.push(metrics.filter.clone());
+        }
+    }
+    for filter in &filters {
+        let group: Vec<usize> = (0..measured.len())
+            .filter(|&i| measured[i].0.filter == *filter)
+            .collect();
+        let reference = measure_transparency(&mut measured, &group);
+        session.references.push(measured[reference].0.path.clone());
+    }
+
+    for (mut metrics, _) in measured {
+        metrics.reject = rejection(metrics.transparency, params);
+        session.frames.push(metrics);
+    }
+    session
+}
+
+/// Why a frame of `transparency` should be left out, if it should
+fn rejection(transparency: Option<f32>, params: &SessionParams) -> Option<String> {
+    match transparency {
+        None => Some("Too few stars matched the reference".to_string()),
+        Some(t) if t < params.min_transparency => Some(format!(
+            "Transparency {:.0}% below {:.0}%",
+            t * 100.0,
+            params.min_transparency * 100.0
+        )),
+        Some(_) => None,
+    }
+}
+
+/// Dated frames by their start, before frames without a date
+fn observation_order(a: &FrameMetrics, b: &FrameMetrics) -> std::cmp::Ordering {
+    match (a.timestamp, b.timestamp) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    }
+}
+
+/// Transparency of the frames `group` (indices into `frames`, not empty)
+/// relative to the one with most stars, taken to be the clearest. Returns
+/// the index of that reference.
+fn measure_transparency(frames: &mut [(FrameMetrics, FrameStars)], group: &[usize]) -> usize {
+    let reference = group
+        .iter()
+        .copied()
+        .max_by_key(|&i| frames[i].0.stars)
+        .unwrap_or(group[0]);
+    let (reference_metrics, reference_stars) = &frames[reference];
+    let reference_exposure = reference_metrics.exposure;
+    let reference_stars: Vec<Star> = reference_stars
+        .stars
+        .iter()
+        .filter(|s| s.peak < reference_stars.saturated_peak)
+        .take(PHOTOMETRY_STARS)
+        .cloned()
+        .collect();
+
+    for &index in group {
+        let (metrics, stars) = &mut frames[index];
+        let measurement = if index == reference {
+            Some((1.0, reference_stars.len()))
+        } else {
+            registration::register(&reference_stars, &stars.stars, &Default::default())
+                .ok()
+                .and_then(|found| relative_flux(&reference_stars, stars, &found))
+                .map(|(ratio, matched)| {
+                    let exposure_ratio = match (metrics.exposure, reference_exposure) {
+                        (Some(e), Some(r)) if e > 0.0 && r > 0.0 => (e / r) as f32,
+                        _ => 1.0,
+                    };
+                    (ratio / exposure_ratio, matched)
+                })
+        };
+        if let Some((transparency, matched)) = measurement {
+            metrics.transparency = Some(transparency);
+            metrics.matched_stars = matched;
+        }
+    }
+    reference
+}
+
+/// Median flux ratio of frame to reference over the unsaturated stars at the
+/// positions `found` maps the reference stars to, with the number of stars
+fn relative_flux(
+    reference: &[Star],
+    frame: &FrameStars,
+    found: &Registration,
+) -> Option<(f32, usize)> {
+    let points: Vec<[f64; 2]> = frame.stars.iter().map(|s| [s.x, s.y]).collect();
+    let radius = RegistrationParams::default().inlier_radius;
+    let grid = StarGrid::new(&points, 4.0 * radius);
+
+    let mut ratios: Vec<f32> = reference
+        .iter()
+        .filter_map(|star| {
+            let (x, y) = found.transform.apply(star.x, star.y);
+            let matched = &frame.stars[grid.nearest(&points, [x, y], radius)?];
+            (matched.peak < frame.saturated_peak && star.flux > 0.0)
+                .then(|| (matched.flux / star.flux) as f32)
+        })
+        .collect();
+    if ratios.len() < MIN_PHOTOMETRY_STARS {
+        return None;
+    }
+    let matched = ratios.len();
+    Some((median_in_place(&mut ratios), matched))
+}
+
+fn measure_frame(
+    path: &str,
+    masters: Option<&CalibrationSet>,
+) -> Result<(FrameMetrics, FrameStars)> {
+    let mut image = load_fits_f32(path).with_context(|| format!("Failed to load {}", path))?;
+    if let Some(masters) = masters {
+        masters.calibrate(&mut image)?;
+    }
+
+    let (background, noise) = sigma_clipped_stats(&sample_pixels(&image.data, 200_000), 3.0, 5);
+    let stars = detect_stars(
+        &image.data,
+        image.width,
+        image.height,
+        &DetectionParams {
+            max_stars: 100_000,
+            ..Default::default()
+        },
+    );
+
+    let shapes = elongation::measure(&stars, image.width, image.height, &Default::default());
+    let gradient = Background::fit(
+        &image.data,
+        image.width,
+        image.height,
+        &BackgroundParams::default(),
+    )
+    .stats;
+
+    let header = &image.header;
+    let saturated_peak = header
+        .saturation_level()
+        .map_or(f32::INFINITY, |level| SATURATION_SHARE * level - background);
+    let metrics = FrameMetrics {
+        path: path.to_string(),
+        date_obs: header.get("DATE-OBS").map(str::to_string),
+        timestamp: observation_time(header),
+        exposure: exposure_time(header),
+        filter: header.get("FILTER").map(str::to_string),
+        background,
+        noise,
+        stars: stars.len(),
+        eccentricity: shapes.eccentricity,
+        elongation_angle_deg: shapes.angle_deg,
+        gradient_percent: gradient.strength_percent,
+        gradient_angle_deg: gradient.angle_deg,
+        transparency: None,
+        matched_stars: 0,
+        reject: None,
+    };
+    Ok((
+        metrics,
+        FrameStars {
+            stars,
+            saturated_peak,
+        },
+    ))
+}
+
+/// Write the session as CSV, one row per frame, with minutes since the
+/// first dated frame for plotting
+pub fn write_csv(session: &Session, path: &str) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(
+        out,
+        "path,date_obs,elapsed_min,exposure,filter,background,noise,stars,eccentricity,elongation_angle,gradient,gradient_angle,transparency,reject"
+    )?;
+
+    let start = session.frames.iter().find_map(|f| f.timestamp);
+    let optional = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for frame in &session.frames {
+        let elapsed = frame.timestamp.zip(start).map(|(t, s)| (t - s) / 60.0);
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&frame.path),
+            csv_field(frame.date_obs.as_deref().unwrap_or("")),
+            optional(elapsed),
+            optional(frame.exposure),
+            csv_field(frame.filter.as_deref().unwrap_or("")),
+            frame.background,
+            frame.noise,
+            frame.stars,
+            frame.eccentricity,
+            frame.elongation_angle_deg,
+            frame.gradient_percent,
+            frame.gradient_angle_deg,
+            optional(frame.transparency.map(f64::from)),
+            csv_field(frame.reject.as_deref().unwrap_or(""))
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Quote a field holding separators or quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Start of the exposure from DATE-OBS (with TIME-OBS when the date has no
+/// time), or MJD-OBS, in seconds since 1970-01-01 UTC
+pub fn observation_time(header: &FitsHeader) -> Option<f64> {
+    if let Some(date) = header.get("DATE-OBS") {
+        let parsed = if date.contains('T') {
+            parse_iso_time(date)
+        } else {
+            let time = header.get("TIME-OBS").unwrap_or("00:00:00");
+            parse_iso_time(&format!("{}T{}", date, time))
+        };
+        if parsed.is_some() {
+            return parsed;
+        }
+    }
+
+    // MJD 40587 is 1970-01-01
+    header
+        .get_f64("MJD-OBS")
+        .map(|mjd| (mjd - 40587.0) * 86400.0)
+}
+
+/// Seconds since 1970-01-01 of `YYYY-MM-DDThh:mm:ss[.sss]`, also taking the
+/// old `DD/MM/YY` date form
+fn parse_iso_time(value: &str) -> Option<f64> {
+    let (date, time) = value.trim().split_once('T')?;
+
+    let (year, month, day) = if let [day, month, year] = date.split('/').collect::<Vec<_>>()[..] {
+        // Two digit years of the old form are 1900-1999
+        (
+            1900 + year.parse::<i64>().ok()?,
+            month.parse::<i64>().ok()?,
+            day.parse::<i64>().ok()?,
+        )
+    } else {
+        let mut parts = date.split('-');
+        (
+            parts.next()?.parse::<i64>().ok()?,
+            parts.next()?.parse::<i64>().ok()?,
+            parts.next()?.parse::<i64>().ok()?,
+        )
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let time = time.trim_end_matches('Z');
+    let mut parts = time.split(':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next().unwrap_or("0").parse().ok()?;
+    let seconds: f64 = parts.next().unwrap_or("0").parse().ok()?;
+
+    let days = days_from_civil(year, month, day) as f64;
+    Some(days * 86400.0 + hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fits::HeaderCard;
+    use crate::registration::Lcg;
+
+    /// 2000-01-01T00:00:00 UTC
+    const Y2K: f64 = 946_684_800.0;
+
+    fn header(cards: &[(&str, &str)]) -> FitsHeader {
+        FitsHeader {
+            cards: cards
+                .iter()
+                .map(|(key, value)| HeaderCard::text(key, value, ""))
+                .collect(),
+        }
+    }
+
+    fn metrics(path: &str, timestamp: Option<f64>) -> FrameMetrics {
+        FrameMetrics {
+            path: path.to_string(),
+            date_obs: None,
+            timestamp,
+            exposure: None,
+            filter: None,
+            background: 0.0,
+            noise: 0.0,
+            stars: 0,
+            eccentricity: 0.0,
+            elongation_angle_deg: 0.0,
+            gradient_percent: 0.0,
+            gradient_angle_deg: 0.0,
+            transparency: None,
+            matched_stars: 0,
+            reject: None,
+        }
+    }
+
+    #[test]
+    fn days_count_from_1970() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 1, 1), 10957);
+        // 2000 is a leap year, 1900 is not
+        assert_eq!(
+            days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28),
+            2
+        );
+        assert_eq!(
+            days_from_civil(1900, 3, 1) - days_from_civil(1900, 2, 28),
+            1
+        );
+    }
+
+    #[test]
+    fn iso_times_parse() {
+        assert_eq!(parse_iso_time("2000-01-01T00:00:00"), Some(Y2K));
+        assert_eq!(
+            parse_iso_time("2000-01-01T12:30:15.5Z"),
+            Some(Y2K + 45015.5)
+        );
+        assert_eq!(parse_iso_time(" 2000-01-01T01 "), Some(Y2K + 3600.0));
+        assert_eq!(parse_iso_time("2000-13-01T00:00:00"), None);
+        assert_eq!(parse_iso_time("2000-01-01"), None);
+        assert_eq!(parse_iso_time("yesterday"), None);
+    }
+
+    #[test]
+    fn observation_time_takes_the_old_date_form_and_time_obs() {
+        let old = header(&[("DATE-OBS", "31/12/99"), ("TIME-OBS", "23:59:59")]);
+        assert_eq!(observation_time(&old), Some(Y2K - 1.0));
+
+        let split = header(&[("DATE-OBS", "2000-01-01"), ("TIME-OBS", "06:00:00")]);
+        assert_eq!(observation_time(&split), Some(Y2K + 6.0 * 3600.0));
+
+        // A date alone starts at midnight
+        let date = header(&[("DATE-OBS", "2000-01-01")]);
+        assert_eq!(observation_time(&date), Some(Y2K));
+    }
+
+    #[test]
+    fn observation_time_falls_back_to_mjd() {
+        let mut mjd = header(&[("DATE-OBS", "sometime")]);
+        mjd.set(HeaderCard::number("MJD-OBS", 51544.5, ""));
+        assert_eq!(observation_time(&mjd), Some(Y2K + 43200.0));
+
+        assert_eq!(observation_time(&header(&[("OBJECT", "M31")])), None);
+    }
+
+    #[test]
+    fn undated_frames_come_last_in_their_order() {
+        let mut frames = [
+            metrics("undated1", None),
+            metrics("late", Some(Y2K + 60.0)),
+            metrics("undated2", None),
+            metrics("early", Some(Y2K)),
+        ];
+        frames.sort_by(observation_order);
+        let order: Vec<&str> = frames.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(order, ["early", "late", "undated1", "undated2"]);
+    }
+
+    /// 60 stars scattered over 1000 x 800 pixels, brightest first, with
+    /// peaks a tenth of their flux
+    fn reference_stars() -> Vec<Star> {
+        let mut rng = Lcg(7);
+        let mut random = move || rng.fraction();
+        (0..60)
+            .map(|i| {
+                let flux = 1e5 / (i + 1) as f64;
+                Star {
+                    x: random() * 1000.0,
+                    y: random() * 800.0,
+                    flux,
+                    peak: (flux / 10.0) as f32,
+                    fwhm: 3.0,
+                    eccentricity: 0.0,
+                    angle_deg: 0.0,
+                }
+            })
+            .collect()
+    }
+
+    /// The reference stars shifted and with their flux times `factor`
+    fn dimmed(stars: &[Star], factor: f64) -> Vec<Star> {
+        stars
+            .iter()
+            .map(|s| Star {
+                x: s.x + 12.3,
+                y: s.y - 7.1,
+                flux: s.flux * factor,
+                peak: s.peak * factor as f32,
+                ..s.clone()
+            })
+            .collect()
+    }
+
+    fn frame(
+        path: &str,
+        exposure: f64,
+        stars: Vec<Star>,
+        saturated_peak: f32,
+    ) -> (FrameMetrics, FrameStars) {
+        let mut metrics = metrics(path, None);
+        metrics.exposure = Some(exposure);
+        metrics.stars = stars.len();
+        (
+            metrics,
+            FrameStars {
+                stars,
+                saturated_peak,
+            },
+        )
+    }
+
+    #[test]
+    fn transparency_is_the_flux_ratio() {
+        let reference = reference_stars();
+        // One star fewer, so the reference is the first frame
+        let mut frames = vec![
+            frame("clear", 60.0, reference.clone(), f32::INFINITY),
+            frame("hazy", 60.0, dimmed(&reference[..59], 0.5), f32::INFINITY),
+        ];
+        assert_eq!(measure_transparency(&mut frames, &[0, 1]), 0);
+
+        assert_eq!(frames[0].0.transparency, Some(1.0));
+        let hazy = &frames[1].0;
+        assert!((hazy.transparency.unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(hazy.matched_stars, 59);
+    }
+
+    #[test]
+    fn transparency_is_per_second_of_exposure() {
+        let reference = reference_stars();
+        let mut frames = vec![
+            frame("short", 30.0, dimmed(&reference[..59], 0.5), f32::INFINITY),
+            frame("long", 60.0, reference, f32::INFINITY),
+        ];
+        assert_eq!(measure_transparency(&mut frames, &[0, 1]), 1);
+        assert!((frames[0].0.transparency.unwrap() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn saturated_stars_are_left_out() {
+        let reference = reference_stars();
+        let mut stars = dimmed(&reference[..59], 0.5);
+        // The five brightest are clipped, their flux falls short
+        stars[..5].iter_mut().for_each(|s| s.flux *= 0.6);
+        let mut frames = vec![
+            // The three brightest reference stars are saturated as well
+            frame("clear", 60.0, reference.clone(), 1e4 / 3.5),
+            frame("hazy", 60.0, stars, 5e3 / 5.5),
+        ];
+        measure_transparency(&mut frames, &[0, 1]);
+
+        assert_eq!(frames[0].0.matched_stars, 57);
+        let hazy = &frames[1].0;
+        assert!((hazy.transparency.unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(hazy.matched_stars, 54);
+    }
+
+    #[test]
+    fn frames_below_the_minimum_transparency_are_rejected() {
+        let params = SessionParams::default();
+        assert_eq!(rejection(Some(0.9), &params), None);
+        assert_eq!(rejection(Some(0.7), &params), None);
+        assert_eq!(
+            rejection(Some(0.5), &params).as_deref(),
+            Some("Transparency 50% below 70%")
+        );
+        assert!(rejection(None, &params).is_some());
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("light_001.fits"), "light_001.fits");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(
+            csv_field("Transparency \"low\""),
+            "\"Transparency \"\"low\"\"\""
+        );
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+}
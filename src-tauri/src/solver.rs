@@ -0,0 +1,1017 @@
+use crate::fits::FitsHeader;
+use crate::stars::Star;
+use crate::wcs::{deproject_tan, project_tan, Wcs};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+/// A star from the reference catalogue
+#[derive(Debug, Clone, Copy)]
+pub struct CatalogueStar {
+    pub ra: f64,
+    pub dec: f64,
+    pub mag: f32,
+}
+
+/// Read a catalogue in CSV form: `ra,dec,mag` per line, degrees. Lines that
+/// start with `#` or don't parse (e.g. a column header) are skipped.
+pub fn read_catalogue(path: &str) -> Result<Vec<CatalogueStar>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path))?;
+
+    let mut stars = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .map(str::parse::<f64>);
+        if let (Some(Ok(ra)), Some(Ok(dec)), Some(Ok(mag))) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            if (0.0..360.0).contains(&ra) && (-90.0..=90.0).contains(&dec) {
+                stars.push(CatalogueStar {
+                    ra,
+                    dec,
+                    mag: mag as f32,
+                });
+            }
+        }
+    }
+
+    ensure!(!stars.is_empty(), "no stars found in {}", path);
+    Ok(stars)
+}
+
+/// Quads are made of a star and its nearest neighbours after thinning the
+/// catalogue to an even density, so they come out about `cell_deg` across.
+/// The solver builds image quads the same way from a matching number of
+/// stars.
+#[derive(Debug, Clone, Copy)]
+pub struct IndexParams {
+    /// Side of the thinning cells in degrees, around 1/4 of the field of view
+    pub cell_deg: f64,
+    /// Brightest stars kept per cell
+    pub stars_per_cell: usize,
+    /// Neighbours of each star used to form quads
+    pub neighbours: usize,
+    /// Largest quad in degrees, about the field of view
+    pub max_quad_deg: f64,
+}
+
+impl IndexParams {
+    /// Parameters for images about `field_deg` across
+    pub fn for_field(field_deg: f64) -> Self {
+        Self {
+            cell_deg: field_deg / 4.0,
+            max_quad_deg: field_deg,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for IndexParams {
+    fn default() -> Self {
+        Self {
+            cell_deg: 0.25,
+            stars_per_cell: 6,
+            neighbours: NEIGHBOURS,
+            max_quad_deg: 1.0,
+        }
+    }
+}
+
+/// Neighbours per star used for quads, in the index and in images
+const NEIGHBOURS: usize = 6;
+
+/// Four stars and their geometric hash. The code describes the positions of
+/// C and D in the frame where A = (0, 0) and B = (1, 1), so it does not
+/// depend on position, rotation or scale.
+#[derive(Debug, Clone, Copy)]
+struct Quad {
+    /// Catalogue indices in canonical A, B, C, D order
+    stars: [u32; 4],
+    code: [f32; 4],
+    /// Distance between A and B, degrees
+    size: f32,
+}
+
+/// Quads built from a thinned catalogue, sorted by the first code component
+pub struct PlateIndex {
+    stars: Vec<CatalogueStar>,
+    /// Indices of `stars` in Dec order, to find the stars of a Dec band
+    by_dec: Vec<u32>,
+    quads: Vec<Quad>,
+    params: IndexParams,
+}
+
+const INDEX_MAGIC: &[u8; 8] = b"FITSIDX1";
+
+impl PlateIndex {
+    pub fn build(catalogue: &[CatalogueStar], params: IndexParams) -> Self {
+        let stars = thin_catalogue(catalogue, params.cell_deg, params.stars_per_cell);
+        println!(
+            "🔭 Building quad index from {} of {} catalogue stars",
+            stars.len(),
+            catalogue.len()
+        );
+
+        // Sorted by Dec so neighbours can be found in a band
+        let by_dec = dec_order(&stars);
+        let decs: Vec<f64> = by_dec.iter().map(|&i| stars[i as usize].dec).collect();
+        let vectors: Vec<[f64; 3]> = stars.iter().map(|s| unit_vector(s.ra, s.dec)).collect();
+        let max_cos = params.max_quad_deg.to_radians().cos();
+
+        let mut seen = HashSet::new();
+        let mut quads = Vec::new();
+        for (anchor, star) in stars.iter().enumerate() {
+            let low = decs.partition_point(|&d| d < star.dec - params.max_quad_deg);
+            let high = decs.partition_point(|&d| d <= star.dec + params.max_quad_deg);
+
+            let mut near: Vec<(f64, u32)> = by_dec[low..high]
+                .iter()
+                .filter(|&&i| i as usize != anchor)
+                .filter_map(|&i| {
+                    let cos = dot(vectors[anchor], vectors[i as usize]);
+                    (cos >= max_cos).then_some((-cos, i))
+                })
+                .collect();
+            near.sort_by(|a, b| a.0.total_cmp(&b.0));
+            near.truncate(params.neighbours);
+
+            for i in 0..near.len() {
+                for j in i + 1..near.len() {
+                    for k in j + 1..near.len() {
+                        let mut members = [anchor as u32, near[i].1, near[j].1, near[k].1];
+                        members.sort_unstable();
+                        if !seen.insert(members) {
+                            continue;
+                        }
+
+                        let center = [star.ra, star.dec];
+                        let points = members.map(|m| {
+                            let s = &stars[m as usize];
+                            project_tan(center, s.ra, s.dec).map(|(x, y)| [x, y])
+                        });
+                        let [Some(a), Some(b), Some(c), Some(d)] = points else {
+                            continue;
+                        };
+                        let Some((order, code, size)) = quad_code([a, b, c, d]) else {
+                            continue;
+                        };
+                        if size > params.max_quad_deg {
+                            continue;
+                        }
+
+                        quads.push(Quad {
+                            stars: order.map(|o| members[o]),
+                            code: code.map(|c| c as f32),
+                            size: size as f32,
+                        });
+                    }
+                }
+            }
+        }
+
+        quads.sort_by(|a, b| a.code[0].total_cmp(&b.code[0]));
+        println!("✅ Index has {} quads", quads.len());
+
+        Self {
+            stars,
+            by_dec,
+            quads,
+            params,
+        }
+    }
+
+    /// Stars with a Dec between `min_dec` and `max_dec` (degrees)
+    fn stars_in_band(&self, min_dec: f64, max_dec: f64) -> impl Iterator<Item = &CatalogueStar> {
+        let dec = |i: &u32| self.stars[*i as usize].dec;
+        let low = self.by_dec.partition_point(|i| dec(i) < min_dec);
+        let high = self.by_dec.partition_point(|i| dec(i) <= max_dec);
+        self.by_dec[low..high.max(low)]
+            .iter()
+            .map(|&i| &self.stars[i as usize])
+    }
+
+    pub fn quad_count(&self) -> usize {
+        self.quads.len()
+    }
+
+    /// Write the index in a small little-endian binary format
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("failed to create {}", path))?;
+        let mut out = BufWriter::new(file);
+
+        out.write_all(INDEX_MAGIC)?;
+        out.write_all(&(self.stars.len() as u32).to_le_bytes())?;
+        out.write_all(&(self.quads.len() as u32).to_le_bytes())?;
+        out.write_all(&self.params.cell_deg.to_le_bytes())?;
+        out.write_all(&(self.params.stars_per_cell as u32).to_le_bytes())?;
+        out.write_all(&(self.params.neighbours as u32).to_le_bytes())?;
+        out.write_all(&self.params.max_quad_deg.to_le_bytes())?;
+
+        for star in &self.stars {
+            out.write_all(&star.ra.to_le_bytes())?;
+            out.write_all(&star.dec.to_le_bytes())?;
+            out.write_all(&star.mag.to_le_bytes())?;
+        }
+        for quad in &self.quads {
+            for s in quad.stars {
+                out.write_all(&s.to_le_bytes())?;
+            }
+            for c in quad.code {
+                out.write_all(&c.to_le_bytes())?;
+            }
+            out.write_all(&quad.size.to_le_bytes())?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("failed to open {}", path))?;
+        let mut input = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        ensure!(
+            &magic == INDEX_MAGIC,
+            "{} is not a plate solving index",
+            path
+        );
+
+        let star_count = read_u32(&mut input)? as usize;
+        let quad_count = read_u32(&mut input)? as usize;
+        let params = IndexParams {
+            cell_deg: read_f64(&mut input)?,
+            stars_per_cell: read_u32(&mut input)? as usize,
+            neighbours: read_u32(&mut input)? as usize,
+            max_quad_deg: read_f64(&mut input)?,
+        };
+
+        let mut stars = Vec::with_capacity(star_count);
+        for _ in 0..star_count {
+            stars.push(CatalogueStar {
+                ra: read_f64(&mut input)?,
+                dec: read_f64(&mut input)?,
+                mag: read_f32(&mut input)?,
+            });
+        }
+
+        let mut quads = Vec::with_capacity(quad_count);
+        for _ in 0..quad_count {
+            let mut members = [0u32; 4];
+            for m in &mut members {
+                *m = read_u32(&mut input)?;
+                ensure!((*m as usize) < star_count, "corrupt index {}", path);
+            }
+            let mut code = [0f32; 4];
+            for c in &mut code {
+                *c = read_f32(&mut input)?;
+            }
+            quads.push(Quad {
+                stars: members,
+                code,
+                size: read_f32(&mut input)?,
+            });
+        }
+
+        println!(
+            "📂 Loaded index with {} stars and {} quads",
+            stars.len(),
+            quads.len()
+        );
+        Ok(Self {
+            by_dec: dec_order(&stars),
+            stars,
+            quads,
+            params,
+        })
+    }
+}
+
+/// Starting point for the solver, usually read from the header
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SolveHints {
+    /// Approximate field center, degrees
+    pub center: Option<(f64, f64)>,
+    /// How far from `center` the field may be, degrees
+    pub search_radius_deg: f64,
+    /// Approximate pixel scale in arcseconds per pixel
+    pub scale_arcsec: Option<f64>,
+}
+
+impl SolveHints {
+    /// Pointing from RA/DEC (or OBJCTRA/OBJCTDEC) and the scale from
+    /// FOCALLEN (mm) and XPIXSZ (µm, binning included)
+    pub fn from_header(header: &FitsHeader) -> Self {
+        let ra = header.get_f64("RA").or_else(|| {
+            header
+                .get("OBJCTRA")
+                .and_then(parse_sexagesimal)
+                .map(|h| h * 15.0)
+        });
+        let dec = header
+            .get_f64("DEC")
+            .or_else(|| header.get("OBJCTDEC").and_then(parse_sexagesimal));
+
+        let scale_arcsec = match (header.get_f64("XPIXSZ"), header.get_f64("FOCALLEN")) {
+            (Some(pixel_um), Some(focal_mm)) if pixel_um > 0.0 && focal_mm > 0.0 => {
+                Some(206.265 * pixel_um / focal_mm)
+            }
+            _ => None,
+        };
+
+        Self {
+            center: ra.zip(dec),
+            search_radius_deg: 10.0,
+            scale_arcsec,
+        }
+    }
+}
+
+/// "12 34 56.7" or "-12:34:56" to decimal units
+fn parse_sexagesimal(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let negative = value.starts_with('-');
+    let mut parts = value
+        .trim_start_matches(['+', '-'])
+        .split([' ', ':'])
+        .filter(|p| !p.is_empty())
+        .map(str::parse::<f64>);
+
+    let mut result = parts.next()?.ok()?;
+    let mut unit = 1.0;
+    for part in parts {
+        unit /= 60.0;
+        result += part.ok()? * unit;
+    }
+    Some(if negative { -result } else { result })
+}
+
+/// Result of a successful solve
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlateSolution {
+    /// Field center, degrees
+    pub ra: f64,
+    pub dec: f64,
+    pub scale_arcsec: f64,
+    /// Angle of north from the image +Y axis towards +X
+    pub north_angle_deg: f64,
+    /// Catalogue stars that landed on a detected star
+    pub matched_stars: usize,
+    pub rms_arcsec: f64,
+}
+
+/// Relative scale error accepted against the hint
+const SCALE_TOLERANCE: f64 = 0.2;
+/// Largest code distance (per component) treated as a match
+const CODE_TOLERANCE: f32 = 0.01;
+/// Numbers of brightest stars tried for image quads when the scale is unknown
+const QUAD_STAR_COUNTS: [usize; 5] = [16, 32, 64, 128, 256];
+
+/// Find the TAN WCS of an image from its detected stars (brightest first)
+pub fn solve(
+    index: &PlateIndex,
+    stars: &[Star],
+    width: usize,
+    height: usize,
+    hints: &SolveHints,
+) -> Result<(Wcs, PlateSolution)> {
+    ensure!(stars.len() >= 6, "only {} stars detected", stars.len());
+
+    let detected: Vec<[f64; 2]> = stars.iter().map(|s| [s.x, s.y]).collect();
+    let lookup = StarGrid::new(&detected, 32.0);
+    let crpix = [width as f64 / 2.0 + 0.5, height as f64 / 2.0 + 0.5];
+    let center_vector = hints.center.map(|(ra, dec)| unit_vector(ra, dec));
+    let search_cos = (hints.search_radius_deg.min(180.0)).to_radians().cos();
+
+    let mut best: Option<(Wcs, Vec<Match>)> = None;
+    let mut attempts = 0usize;
+
+    // Image quads must come from stars as dense as the index. With a known
+    // scale that count follows from the field area, otherwise try several.
+    let star_counts = match hints.scale_arcsec {
+        Some(scale) => {
+            let area = width as f64 * height as f64 * (scale / 3600.0).powi(2);
+            let per_area = index.params.stars_per_cell as f64 / index.params.cell_deg.powi(2);
+            vec![((area * per_area).round() as usize).max(NEIGHBOURS + 1)]
+        }
+        None => QUAD_STAR_COUNTS.to_vec(),
+    };
+
+    let mut quads = Vec::new();
+    let mut previous = 0;
+    for count in star_counts {
+        let count = count.min(detected.len());
+        if count > previous {
+            quads.extend(image_quads(&detected[..count]));
+            previous = count;
+        }
+    }
+
+    for image_quad in quads {
+        // With a known scale, skip quads the index can't contain
+        if let Some(hint) = hints.scale_arcsec {
+            let size_deg = image_quad.size * hint / 3600.0;
+            if size_deg > index.params.max_quad_deg * (1.0 + SCALE_TOLERANCE) {
+                continue;
+            }
+        }
+
+        let first = image_quad.code[0] as f32;
+        let start = index
+            .quads
+            .partition_point(|q| q.code[0] < first - CODE_TOLERANCE);
+
+        for quad in index.quads[start..]
+            .iter()
+            .take_while(|q| q.code[0] <= first + CODE_TOLERANCE)
+        {
+            let close = quad
+                .code
+                .iter()
+                .zip(image_quad.code)
+                .all(|(a, b)| (a - b as f32).abs() <= CODE_TOLERANCE);
+            if !close {
+                continue;
+            }
+
+            let scale = quad.size as f64 * 3600.0 / image_quad.size;
+            if let Some(hint) = hints.scale_arcsec {
+                if (scale / hint - 1.0).abs() > SCALE_TOLERANCE {
+                    continue;
+                }
+            }
+            if let Some(center) = center_vector {
+                let anchor = &index.stars[quad.stars[0] as usize];
+                if dot(center, unit_vector(anchor.ra, anchor.dec)) < search_cos {
+                    continue;
+                }
+            }
+
+            attempts += 1;
+            let pairs: Vec<Match> = (0..4)
+                .map(|i| {
+                    let s = &index.stars[quad.stars[i] as usize];
+                    (detected[image_quad.stars[i]], [s.ra, s.dec])
+                })
+                .collect();
+            let Some(wcs) = fit_tan(&pairs, crpix) else {
+                continue;
+            };
+
+            let matches = verify(index, &wcs, &detected, &lookup, width, height);
+            if matches.len() >= MIN_MATCHES
+                && best.as_ref().is_none_or(|(_, m)| matches.len() > m.len())
+            {
+                best = Some((wcs, matches));
+            }
+        }
+
+        // A solution matching this many stars won't be beaten meaningfully
+        if best
+            .as_ref()
+            .is_some_and(|(_, m)| m.len() >= 3 * MIN_MATCHES)
+        {
+            break;
+        }
+    }
+
+    let Some((mut wcs, mut matches)) = best else {
+        bail!("no solution found after {} candidate matches", attempts);
+    };
+
+    // Refine with every matched star
+    for _ in 0..3 {
+        let Some(refined) = fit_tan(&matches, crpix) else {
+            break;
+        };
+        let rematched = verify(index, &refined, &detected, &lookup, width, height);
+        if rematched.len() < matches.len() {
+            break;
+        }
+        wcs = refined;
+        matches = rematched;
+    }
+
+    let rms_px = (matches
+        .iter()
+        .filter_map(|(pixel, sky)| {
+            let (x, y) = wcs.sky_to_pixel(sky[0], sky[1])?;
+            Some((x - pixel[0]).powi(2) + (y - pixel[1]).powi(2))
+        })
+        .sum::<f64>()
+        / matches.len() as f64)
+        .sqrt();
+
+    let (ra, dec) = wcs
+        .pixel_to_sky(width as f64 / 2.0 - 0.5, height as f64 / 2.0 - 0.5)
+        .context("solution does not cover the image center")?;
+    let scale_arcsec = wcs.pixel_scale_arcsec();
+    let solution = PlateSolution {
+        ra,
+        dec,
+        scale_arcsec,
+        north_angle_deg: wcs.north_angle_deg(),
+        matched_stars: matches.len(),
+        rms_arcsec: rms_px * scale_arcsec,
+    };
+
+    println!(
+        "🎯 Solved after {} candidates: RA {:.4}° Dec {:.4}°, {:.3}\"/px, {} stars matched",
+        attempts, solution.ra, solution.dec, solution.scale_arcsec, solution.matched_stars
+    );
+    Ok((wcs, solution))
+}
+
+/// Catalogue stars must project this close to a detected star to count
+const MATCH_RADIUS_PX: f64 = 3.0;
+/// Matches needed before a candidate solution is believed
+const MIN_MATCHES: usize = 8;
+
+/// A detected star (0-based pixel) and the catalogue RA/Dec it matches
+type Match = ([f64; 2], [f64; 2]);
+
+/// Detected and catalogue stars that agree under `wcs`
+fn verify(
+    index: &PlateIndex,
+    wcs: &Wcs,
+    detected: &[[f64; 2]],
+    lookup: &StarGrid,
+    width: usize,
+    height: usize,
+) -> Vec<Match> {
+    let Some((center_ra, center_dec)) = wcs.pixel_to_sky(width as f64 / 2.0, height as f64 / 2.0)
+    else {
+        return Vec::new();
+    };
+    let radius_deg = (width as f64).hypot(height as f64) / 2.0 * wcs.pixel_scale_arcsec() / 3600.0;
+    let min_cos = radius_deg.min(90.0).to_radians().cos();
+    let center = unit_vector(center_ra, center_dec);
+
+    // Only stars in the field's Dec band can be in the field
+    let mut used = vec![false; detected.len()];
+    let mut matches = Vec::new();
+    for star in index.stars_in_band(center_dec - radius_deg, center_dec + radius_deg) {
+        if dot(center, unit_vector(star.ra, star.dec)) < min_cos {
+            continue;
+        }
+        let Some((x, y)) = wcs.sky_to_pixel(star.ra, star.dec) else {
+            continue;
+        };
+        if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 {
+            continue;
+        }
+        if let Some(i) = lookup.nearest(detected, [x, y], MATCH_RADIUS_PX) {
+            if !used[i] {
+                used[i] = true;
+                matches.push((detected[i], [star.ra, star.dec]));
+            }
+        }
+    }
+    matches
+}
+
+/// Least-squares TAN fit of (0-based pixel, RA/Dec) pairs with the
+/// reference pixel fixed at `crpix`
+fn fit_tan(pairs: &[Match], crpix: [f64; 2]) -> Option<Wcs> {
+    if pairs.len() < 3 {
+        return None;
+    }
+
+    // Start from the mean direction of the stars
+    let sum = pairs.iter().fold([0.0; 3], |acc, (_, sky)| {
+        let v = unit_vector(sky[0], sky[1]);
+        [acc[0] + v[0], acc[1] + v[1], acc[2] + v[2]]
+    });
+    let mut crval = [
+        sum[1].atan2(sum[0]).to_degrees().rem_euclid(360.0),
+        sum[2].atan2(sum[0].hypot(sum[1])).to_degrees(),
+    ];
+
+    let mut cd = [[0.0; 2]; 2];
+    for _ in 0..3 {
+        // Affine map from pixel offsets to standard coordinates:
+        // xi = a u + b v + c, eta = d u + e v + f
+        let mut normal = [[0.0; 3]; 3];
+        let mut rhs_xi = [0.0; 3];
+        let mut rhs_eta = [0.0; 3];
+        for (pixel, sky) in pairs {
+            let (xi, eta) = project_tan(crval, sky[0], sky[1])?;
+            let row = [pixel[0] + 1.0 - crpix[0], pixel[1] + 1.0 - crpix[1], 1.0];
+            for i in 0..3 {
+                for j in 0..3 {
+                    normal[i][j] += row[i] * row[j];
+                }
+                rhs_xi[i] += row[i] * xi;
+                rhs_eta[i] += row[i] * eta;
+            }
+        }
+        let [a, b, c] = solve3(normal, rhs_xi)?;
+        let [d, e, f] = solve3(normal, rhs_eta)?;
+        cd = [[a, b], [d, e]];
+
+        // Move the tangent point to where the reference pixel landed
+        let (ra, dec) = deproject_tan(crval, c, f)?;
+        crval = [ra, dec];
+        if c.hypot(f) < 1e-9 {
+            break;
+        }
+    }
+
+    let det = cd[0][0] * cd[1][1] - cd[0][1] * cd[1][0];
+    (det.abs() > 0.0 && det.is_finite()).then(|| Wcs::new_tan(crpix, crval, cd))
+}
+
+/// Solve a 3x3 linear system with Cramer's rule
+pub(crate) fn solve3(m: [[f64; 3]; 3], r: [f64; 3]) -> Option<[f64; 3]> {
+    let det3 = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let det = det3(m);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+
+    let mut result = [0.0; 3];
+    for (column, value) in result.iter_mut().enumerate() {
+        let mut replaced = m;
+        for row in 0..3 {
+            replaced[row][column] = r[row];
+        }
+        *value = det3(replaced) / det;
+    }
+    Some(result)
+}
+
+struct ImageQuad {
+    /// Indices into the detected stars, canonical A, B, C, D order
+    stars: [usize; 4],
+    code: [f64; 4],
+    /// Distance between A and B, pixels
+    size: f64,
+}
+
+/// Quads of each star with its nearest neighbours, plus their mirror images
+/// so flipped optics (odd number of reflections) still match the catalogue
+fn image_quads(points: &[[f64; 2]]) -> Vec<ImageQuad> {
+    let mut seen = HashSet::new();
+    let mut quads = Vec::new();
+    for (anchor, p) in points.iter().enumerate() {
+        let mut near: Vec<(f64, usize)> = points
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != anchor)
+            .map(|(i, q)| ((q[0] - p[0]).hypot(q[1] - p[1]), i))
+            .collect();
+        near.sort_by(|a, b| a.0.total_cmp(&b.0));
+        near.truncate(NEIGHBOURS);
+
+        for i in 0..near.len() {
+            for j in i + 1..near.len() {
+                for k in j + 1..near.len() {
+                    let mut members = [anchor, near[i].1, near[j].1, near[k].1];
+                    members.sort_unstable();
+                    if !seen.insert(members) {
+                        continue;
+                    }
+
+                    for mirror in [false, true] {
+                        let positions = members.map(|m| {
+                            let [x, y] = points[m];
+                            if mirror {
+                                [-x, y]
+                            } else {
+                                [x, y]
+                            }
+                        });
+                        if let Some((order, code, size)) = quad_code(positions) {
+                            quads.push(ImageQuad {
+                                stars: order.map(|o| members[o]),
+                                code,
+                                size,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+    quads
+}
+
+/// Canonical star order, hash code and size (A-B distance) of four points.
+/// `None` for degenerate quads whose C or D falls outside the A-B circle.
+fn quad_code(points: [[f64; 2]; 4]) -> Option<([usize; 4], [f64; 4], f64)> {
+    // A and B are the most distant pair
+    let mut pair = (0, 1);
+    let mut longest = 0.0;
+    for i in 0..4 {
+        for j in i + 1..4 {
+            let d = (points[i][0] - points[j][0]).hypot(points[i][1] - points[j][1]);
+            if d > longest {
+                longest = d;
+                pair = (i, j);
+            }
+        }
+    }
+    if longest <= 0.0 {
+        return None;
+    }
+
+    let others: Vec<usize> = (0..4).filter(|&i| i != pair.0 && i != pair.1).collect();
+    let (mut a, mut b) = pair;
+    let (mut c, mut d) = (others[0], others[1]);
+
+    // Frame with A at (0, 0) and B at (1, 1): z' = (z - A) / (B - A) * (1 + i)
+    let frame = |a: usize, b: usize, p: usize| {
+        let (dx, dy) = (points[b][0] - points[a][0], points[b][1] - points[a][1]);
+        let (px, py) = (points[p][0] - points[a][0], points[p][1] - points[a][1]);
+        let norm = dx * dx + dy * dy;
+        let (re, im) = ((px * dx + py * dy) / norm, (py * dx - px * dy) / norm);
+        [re - im, re + im]
+    };
+
+    let mut pc = frame(a, b, c);
+    let mut pd = frame(a, b, d);
+    if pc[0] + pd[0] > 1.0 {
+        std::mem::swap(&mut a, &mut b);
+        pc = frame(a, b, c);
+        pd = frame(a, b, d);
+    }
+    if pc[0] > pd[0] {
+        std::mem::swap(&mut c, &mut d);
+        std::mem::swap(&mut pc, &mut pd);
+    }
+
+    // C and D lie within the circle through A and B
+    let inside = |p: [f64; 2]| (p[0] - 0.5).powi(2) + (p[1] - 0.5).powi(2) <= 0.5;
+    if !inside(pc) || !inside(pd) {
+        return None;
+    }
+
+    Some(([a, b, c, d], [pc[0], pc[1], pd[0], pd[1]], longest))
+}
+
+/// Keep the brightest `per_cell` stars in each cell of roughly `cell_deg`
+/// square, so the index density is even across the sky
+fn thin_catalogue(
+    catalogue: &[CatalogueStar],
+    cell_deg: f64,
+    per_cell: usize,
+) -> Vec<CatalogueStar> {
+    let cell_deg = cell_deg.max(1e-3);
+    let dec_cells = (180.0 / cell_deg).ceil() as i64;
+
+    let mut cells: std::collections::HashMap<(i64, i64), Vec<CatalogueStar>> =
+        std::collections::HashMap::new();
+    for star in catalogue {
+        let dec_cell = (((star.dec + 90.0) / cell_deg) as i64).min(dec_cells - 1);
+        // Narrower RA cells away from the poles keep the area about equal
+        let band_dec = (dec_cell as f64 + 0.5) * cell_deg - 90.0;
+        let ra_cells = ((360.0 * band_dec.to_radians().cos() / cell_deg).ceil() as i64).max(1);
+        let ra_cell = ((star.ra / 360.0 * ra_cells as f64) as i64).min(ra_cells - 1);
+        cells.entry((dec_cell, ra_cell)).or_default().push(*star);
+    }
+
+    let mut stars = Vec::new();
+    for mut cell in cells.into_values() {
+        cell.sort_by(|a, b| a.mag.total_cmp(&b.mag));
+        cell.truncate(per_cell);
+        stars.extend(cell);
+    }
+    stars
+}
+
+/// Bucketed detected stars for nearest neighbour lookups
+pub(crate) struct StarGrid {
+    cell: f64,
+    columns: usize,
+    rows: usize,
+    buckets: Vec<Vec<usize>>,
+}
+
+impl StarGrid {
+    pub(crate) fn new(points: &[[f64; 2]], cell: f64) -> Self {
+        let max_x = points.iter().map(|p| p[0]).fold(0.0, f64::max);
+        let max_y = points.iter().map(|p| p[1]).fold(0.0, f64::max);
+        let columns = (max_x / cell) as usize + 1;
+        let rows = (max_y / cell) as usize + 1;
+
+        let mut buckets = vec![Vec::new(); columns * rows];
+        for (i, p) in points.iter().enumerate() {
+            let (cx, cy) = (
+                (p[0].max(0.0) / cell) as usize,
+                (p[1].max(0.0) / cell) as usize,
+            );
+            buckets[cy * columns + cx].push(i);
+        }
+
+        Self {
+            cell,
+            columns,
+            rows,
+            buckets,
+        }
+    }
+
+    /// Closest point within `radius` (at most one cell size) of `target`
+    pub(crate) fn nearest(
+        &self,
+        points: &[[f64; 2]],
+        target: [f64; 2],
+        radius: f64,
+    ) -> Option<usize> {
+        let cx = (target[0] / self.cell) as isize;
+        let cy = (target[1] / self.cell) as isize;
+
+        let mut best = None;
+        let mut best_distance = radius.min(self.cell);
+        for y in cy - 1..=cy + 1 {
+            for x in cx - 1..=cx + 1 {
+                if x < 0 || y < 0 || x as usize >= self.columns || y as usize >= self.rows {
+                    continue;
+                }
+                for &i in &self.buckets[y as usize * self.columns + x as usize] {
+                    let d = (points[i][0] - target[0]).hypot(points[i][1] - target[1]);
+                    if d <= best_distance {
+                        best_distance = d;
+                        best = Some(i);
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+/// Indices of `stars` sorted by Dec
+fn dec_order(stars: &[CatalogueStar]) -> Vec<u32> {
+    let mut by_dec: Vec<u32> = (0..stars.len() as u32).collect();
+    by_dec.sort_by(|&a, &b| stars[a as usize].dec.total_cmp(&stars[b as usize].dec));
+    by_dec
+}
+
+fn unit_vector(ra: f64, dec: f64) -> [f64; 3] {
+    let (ra, dec) = (ra.to_radians(), dec.to_radians());
+    [dec.cos() * ra.cos(), dec.cos() * ra.sin(), dec.sin()]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn read_u32(input: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(input: &mut impl Read) -> Result<f32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn read_f64(input: &mut impl Read) -> Result<f64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registration::Lcg;
+
+    /// Random catalogue around (150, 20) and a 1200 x 900 image of it through
+    /// `truth`, stars brightest first
+    fn synthetic_field(truth: &Wcs) -> (Vec<CatalogueStar>, Vec<Star>) {
+        let mut rng = Lcg(12345);
+        let mut random = || rng.fraction();
+
+        let catalogue: Vec<CatalogueStar> = (0..4000)
+            .map(|_| CatalogueStar {
+                ra: 147.0 + 6.0 * random(),
+                dec: 17.0 + 6.0 * random(),
+                mag: (8.0 + 6.0 * random()) as f32,
+            })
+            .collect();
+
+        let mut stars: Vec<Star> = catalogue
+            .iter()
+            .filter_map(|c| {
+                let (x, y) = truth.sky_to_pixel(c.ra, c.dec)?;
+                ((0.0..1200.0).contains(&x) && (0.0..900.0).contains(&y)).then(|| Star {
+                    x,
+                    y,
+                    flux: 10f64.powf(-0.4 * c.mag as f64) * 1e8,
+                    peak: 1000.0,
+                    fwhm: 2.5,
+                    eccentricity: 0.1,
+                    angle_deg: 0.0,
+                })
+            })
+            .collect();
+        stars.sort_by(|a, b| b.flux.total_cmp(&a.flux));
+        stars.truncate(150);
+        (catalogue, stars)
+    }
+
+    fn assert_solved(truth: &Wcs, wcs: &Wcs) {
+        // The solver puts CRPIX at the image centre, compare the sky there
+        let (ra, dec) = truth
+            .pixel_to_sky(wcs.crpix[0] - 1.0, wcs.crpix[1] - 1.0)
+            .unwrap();
+        let arcsec = 1.0 / 3600.0;
+        assert!(
+            (wcs.crval[0] - ra).abs() * dec.to_radians().cos() < arcsec
+                && (wcs.crval[1] - dec).abs() < arcsec,
+            "CRVAL {:?}, expected ({}, {})",
+            wcs.crval,
+            ra,
+            dec
+        );
+        for (solved, expected) in wcs.cd.iter().flatten().zip(truth.cd.iter().flatten()) {
+            assert!(
+                (solved - expected).abs() < 1e-3 * truth.pixel_scale_arcsec() / 3600.0,
+                "CD {:?}, expected {:?}",
+                wcs.cd,
+                truth.cd
+            );
+        }
+    }
+
+    fn truth() -> Wcs {
+        // 5" pixels, rotated by 30°, east left
+        let scale = 5.0 / 3600.0;
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        Wcs::new_tan(
+            [600.5, 450.5],
+            [150.2, 19.7],
+            [[-scale * cos, scale * sin], [scale * sin, scale * cos]],
+        )
+    }
+
+    #[test]
+    fn solves_synthetic_field_with_hints() {
+        let truth = truth();
+        let (catalogue, stars) = synthetic_field(&truth);
+        let index = PlateIndex::build(&catalogue, IndexParams::for_field(1.5));
+        assert!(index.quad_count() > 0);
+
+        let hints = SolveHints {
+            center: Some((150.5, 19.5)),
+            search_radius_deg: 2.0,
+            scale_arcsec: Some(5.2),
+        };
+        let (wcs, solution) = solve(&index, &stars, 1200, 900, &hints).unwrap();
+        assert_solved(&truth, &wcs);
+        assert!(solution.matched_stars >= MIN_MATCHES);
+        assert!((solution.scale_arcsec - 5.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn solves_synthetic_field_blind() {
+        let truth = truth();
+        let (catalogue, stars) = synthetic_field(&truth);
+        let index = PlateIndex::build(&catalogue, IndexParams::for_field(1.5));
+
+        let (wcs, _) = solve(&index, &stars, 1200, 900, &SolveHints::default()).unwrap();
+        assert_solved(&truth, &wcs);
+    }
+
+    #[test]
+    fn dec_band_holds_exactly_the_stars_in_it() {
+        let (catalogue, _) = synthetic_field(&truth());
+        let index = PlateIndex::build(&catalogue, IndexParams::for_field(1.5));
+
+        for (min_dec, max_dec) in [(19.0, 20.5), (16.0, 17.5), (22.9, 30.0), (25.0, 26.0)] {
+            let mut band: Vec<f64> = index
+                .stars_in_band(min_dec, max_dec)
+                .map(|star| star.dec)
+                .collect();
+            let mut expected: Vec<f64> = index
+                .stars
+                .iter()
+                .map(|star| star.dec)
+                .filter(|dec| (min_dec..=max_dec).contains(dec))
+                .collect();
+            band.sort_by(f64::total_cmp);
+            expected.sort_by(f64::total_cmp);
+            assert_eq!(band, expected);
+        }
+        assert!(index.stars_in_band(19.0, 20.5).count() < index.stars.len() / 2);
+    }
+}
@@ -0,0 +1,746 @@
+use crate::calibration::CalibrationSet;
+use crate::fits::{
+    calculate_statistics, load_fits_f32, median_in_place, sample_pixels, sigma_clipped_stats,
+    write_fits_f32, FitsImage, HeaderCard,
+};
+use crate::registration::{self, Registration, RegistrationParams, Transform};
+use crate::renderer::Interpolation;
+use crate::stars::{detect_stars, DetectionParams, Star};
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Pixel values of all frames held at once while stacking or building a
+/// master, which sets how many rows are combined per pass
+pub(crate) const BAND_VALUES: usize = 16 * 1024 * 1024;
+
+/// Brightest stars whose mean flux is the signal for SNR weighting
+const SIGNAL_STARS: usize = 20;
+
+/// Frame file sets made by this process, so stacks and masters combined at
+/// the same time get different file names
+static FRAME_FILE_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// How the values of one pixel across the frames are combined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StackMethod {
+    /// Weighted mean, no rejection
+    Mean,
+    Median,
+    /// Weighted mean of the values within kappa sigma of the median
+    #[default]
+    SigmaClip,
+    /// Sigma clipping with sigma taken from winsorized values, so outliers
+    /// do not widen the bounds that are meant to reject them; better for
+    /// smaller stacks
+    Winsorized,
+    /// Rejects values far from a line fitted through the sorted values,
+    /// best for large stacks with varying sky
+    LinearFit,
+}
+
+/// How much each frame counts in the mean
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weighting {
+    None,
+    /// Inverse variance of the background noise
+    #[default]
+    Noise,
+    /// Square of star signal over background noise
+    Snr,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StackParams {
+    pub method: StackMethod,
+    pub weighting: Weighting,
+    /// Rejection thresholds below and above, in sigmas
+    pub kappa_low: f32,
+    pub kappa_high: f32,
+    /// Rejection passes per pixel
+    pub iterations: usize,
+    /// Resampling of registered frames onto the reference
+    pub interpolation: Interpolation,
+}
+
+impl Default for StackParams {
+    fn default() -> Self {
+        Self {
+            method: StackMethod::SigmaClip,
+            weighting: Weighting::Noise,
+            kappa_low: 3.0,
+            kappa_high: 3.0,
+            iterations: 5,
+            interpolation: Interpolation::Lanczos,
+        }
+    }
+}
+
+/// A frame that went into the stack
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackedFrame {
+    pub path: String,
+    /// Relative to the reference frame
+    pub weight: f32,
+    /// Added to the frame to match the reference background
+    pub offset: f32,
+    pub noise: f32,
+    pub registration: Registration,
+}
+
+/// Result of `stack`
+pub struct Stack {
+    pub image: FitsImage,
+    /// Values rejected at each pixel, divided by the frames that covered it
+    pub rejection_map: Vec<f32>,
+    pub frames: Vec<StackedFrame>,
+    /// Paths left out because they could not be loaded, calibrated or
+    /// registered, with the reason
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Frames kept on disk while they are combined, removed when dropped
+pub(crate) struct FrameFiles {
+    pub(crate) paths: Vec<PathBuf>,
+    /// Start of the file names, unique to the process and the set
+    prefix: String,
+}
+
+impl FrameFiles {
+    /// An empty set, `kind` ("stack", "master") goes into the file names
+    pub(crate) fn new(kind: &str) -> Self {
+        let run = FRAME_FILE_RUNS.fetch_add(1, Ordering::Relaxed);
+        Self {
+            paths: Vec::new(),
+            prefix: format!("rapidfits-{}-{}-{}", kind, std::process::id(), run),
+        }
+    }
+
+    /// Write the next frame
+    pub(crate) fn push(&mut self, data: &[f32]) -> Result<()> {
+        let path = std::env::temp_dir().join(format!("{}-{}.raw", self.prefix, self.paths.len()));
+        // Listed first so a partly written file is removed as well
+        self.paths.push(path.clone());
+        write_raw(&path, data)
+    }
+}
+
+impl Drop for FrameFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Stack `paths` onto the first of them that loads. Each frame is
+/// calibrated with `masters` (when given), registered and resampled onto
+/// that reference, and its background matched to it. The resampled frames
+/// wait on disk, and are combined a band of rows at a time so memory stays
+/// bounded however many frames there are.
+pub fn stack(
+    paths: &[String],
+    params: &StackParams,
+    masters: Option<&CalibrationSet>,
+) -> Result<Stack> {
+    ensure!(paths.len() >= 2, "Select at least two frames to stack");
+
+    let load = |path: &str| -> Result<(FitsImage, Vec<Star>)> {
+        let mut image = load_fits_f32(path).with_context(|| format!("Failed to load {}", path))?;
+        if let Some(masters) = masters {
+            masters.calibrate(&mut image)?;
+        }
+        let stars = detect_stars(
+            &image.data,
+            image.width,
+            image.height,
+            &DetectionParams::default(),
+        );
+        Ok((image, stars))
+    };
+
+    let mut skipped = Vec::new();
+    let mut first = 0;
+    let (reference, reference_stars) = loop {
+        let path = paths
+            .get(first)
+            .context("None of the frames could be loaded")?;
+        match load(path) {
+            Result::Ok(loaded) => break loaded,
+            Err(e) => {
+                println!("⚠️ Skipping {}: {:#}", path, e);
+                skipped.push((path.clone(), format!("{:#}", e)));
+                first += 1;
+            }
+        }
+    };
+    let (width, height) = (reference.width, reference.height);
+    let (reference_background, reference_noise) = background(&reference.data);
+    let reference_signal = star_signal(&reference_stars);
+
+    let mut files = FrameFiles::new("stack");
+    let mut frames = Vec::new();
+    for (index, path) in paths.iter().enumerate().skip(first) {
+        let (aligned, registration, signal, noise) = if index == first {
+            let registration = Registration {
+                transform: Transform::identity(),
+                matched_stars: reference_stars.len(),
+                rms_px: 0.0,
+            };
+            (None, registration, reference_signal, reference_noise)
+        } else {
+            let found = load(path).and_then(|(image, stars)| {
+                let found = registration::register(
+                    &reference_stars,
+                    &stars,
+                    &RegistrationParams::default(),
+                )?;
+                Ok((image, stars, found))
+            });
+            match found {
+                Result::Ok((image, stars, found)) => {
+                    // Resampling smooths the pixel noise, so it is measured
+                    // on the frame as taken, like the reference's
+                    let (_, noise) = background(&image.data);
+                    let aligned =
+                        registration::resample(&image, &found, &reference, params.interpolation);
+                    (Some(aligned), found, star_signal(&stars), noise)
+                }
+                Err(e) => {
+                    println!("⚠️ Skipping {}: {:#}", path, e);
+                    skipped.push((path.clone(), format!("{:#}", e)));
+                    continue;
+                }
+            }
+        };
+        let data = aligned
+            .as_ref()
+            .map_or(&reference.data, |image| &image.data);
+
+        let (median, _) = background(data);
+        let weight = match params.weighting {
+            Weighting::None => 1.0,
+            Weighting::Noise => (reference_noise / noise).powi(2),
+            Weighting::Snr => ((signal / noise) / (reference_signal / reference_noise)).powi(2),
+        };
+        // Frames without noise or a reference without stars can't be weighed
+        let weight = if weight.is_finite() { weight } else { 1.0 };
+
+        files.push(data)?;
+
+        println!(
+            "📥 Frame {}: noise {:.2}, weight {:.3}, {} stars matched",
+            index, noise, weight, registration.matched_stars
+        );
+        frames.push(StackedFrame {
+            path: path.clone(),
+            weight,
+            offset: reference_background - median,
+            noise,
+            registration,
+        });
+    }
+    ensure!(
+        frames.len() >= 2,
+        "Only {} frame could be loaded and registered",
+        frames.len()
+    );
+
+    let (data, rejection_map) =
+        combine_frames(&files.paths, &frames, width, height, params, BAND_VALUES)?;
+
+    let mut header = reference.header.clone();
+    header.set(HeaderCard::text(
+        "IMAGETYP",
+        "Master Light",
+        "Type of image",
+    ));
+    header.set(HeaderCard::integer(
+        "NCOMBINE",
+        frames.len() as i64,
+        "Number of frames stacked",
+    ));
+    header.set(HeaderCard::text(
+        "STACKMTH",
+        &format!("{:?}", params.method),
+        "Pixel rejection",
+    ));
+    header.set(HeaderCard::text(
+        "STACKWGT",
+        &format!("{:?}", params.weighting),
+        "Frame weighting",
+    ));
+    header.set(HeaderCard::number(
+        "REJLOW",
+        params.kappa_low as f64,
+        "Lower rejection (sigma)",
+    ));
+    header.set(HeaderCard::number(
+        "REJHIGH",
+        params.kappa_high as f64,
+        "Upper rejection (sigma)",
+    ));
+    for frame in &frames {
+        let name = Path::new(&frame.path)
+            .file_name()
+            .map_or(frame.path.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        header.push(HeaderCard::history(&format!(
+            "Stacked {} weight {:.3}",
+            name, frame.weight
+        )));
+    }
+
+    Ok(Stack {
+        image: FitsImage {
+            path: String::new(),
+            stats: calculate_statistics(&data),
+            data,
+            width,
+            height,
+            header,
+            wcs: reference.wcs.clone(),
+        },
+        rejection_map,
+        frames,
+        skipped,
+    })
+}
+
+/// Write the stack to `path` and its rejection map next to it as
+/// `<name>_rejection.fits`, returning that path
+pub fn save_stack(stack: &mut Stack, path: &str) -> Result<String> {
+    stack.image.path = path.to_string();
+    let image = &stack.image;
+    write_fits_f32(path, &image.data, image.width, image.height, &image.header)?;
+
+    let mut header = image.header.clone();
+    header.set(HeaderCard::text(
+        "IMAGETYP",
+        "Rejection Map",
+        "Fraction of values rejected",
+    ));
+    let rejection_path = Path::new(path)
+        .with_file_name(format!(
+            "{}_rejection.fits",
+            Path::new(path)
+                .file_stem()
+                .map_or("stack".into(), |stem| stem.to_string_lossy())
+        ))
+        .to_string_lossy()
+        .into_owned();
+    write_fits_f32(
+        &rejection_path,
+        &stack.rejection_map,
+        image.width,
+        image.height,
+        &header,
+    )?;
+    Ok(rejection_path)
+}
+
+/// Stack the raw frame files band by band, each band at most `band_values`
+/// pixel values of all frames together
+fn combine_frames(
+    files: &[PathBuf],
+    frames: &[StackedFrame],
+    width: usize,
+    height: usize,
+    params: &StackParams,
+    band_values: usize,
+) -> Result<(Vec<f32>, Vec<f32>)> {
+    let band_rows = (band_values / (frames.len() * width).max(1)).clamp(1, height.max(1));
+    let mut readers = files
+        .iter()
+        .map(File::open)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut data = vec![f32::NAN; width * height];
+    let mut rejection_map = vec![0.0; width * height];
+    let mut bands = vec![Vec::new(); frames.len()];
+    let mut values = Vec::with_capacity(frames.len());
+    for start in (0..height).step_by(band_rows) {
+        let rows = band_rows.min(height - start);
+        for (reader, band) in readers.iter_mut().zip(&mut bands) {
+            read_raw(reader, start * width, rows * width, band)?;
+        }
+
+        for i in 0..rows * width {
+            values.clear();
+            values.extend(
+                frames
+                    .iter()
+                    .zip(&bands)
+                    .map(|(frame, band)| (band[i] + frame.offset, frame.weight))
+                    .filter(|(value, weight)| value.is_finite() && *weight > 0.0),
+            );
+            if values.is_empty() {
+                continue;
+            }
+
+            let total = values.len();
+            let rejected = reject(&mut values, params);
+            let pixel = start * width + i;
+            data[pixel] = match params.method {
+                StackMethod::Median => median(&values),
+                _ => weighted_mean(&values),
+            };
+            rejection_map[pixel] = rejected as f32 / total as f32;
+        }
+    }
+
+    Ok((data, rejection_map))
+}
+
+/// Remove outliers from one pixel's (value, weight) pairs, returning how
+/// many were removed
+fn reject(values: &mut Vec<(f32, f32)>, params: &StackParams) -> usize {
+    let total = values.len();
+    for _ in 0..params.iterations {
+        // Too few values to tell outliers apart
+        if values.len() < 3 {
+            break;
+        }
+
+        let before = values.len();
+        match params.method {
+            StackMethod::Mean | StackMethod::Median => break,
+            StackMethod::SigmaClip | StackMethod::Winsorized => {
+                let centre = median(values);
+                let sigma = if params.method == StackMethod::Winsorized {
+                    winsorized_sigma(values, centre)
+                } else {
+                    standard_deviation(values.iter().map(|v| v.0))
+                };
+                if sigma <= 0.0 {
+                    break;
+                }
+                values.retain(|&(v, _)| {
+                    v >= centre - params.kappa_low * sigma
+                        && v <= centre + params.kappa_high * sigma
+                });
+            }
+            StackMethod::LinearFit => {
+                values.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let (intercept, slope, sigma) = trimmed_fit(values);
+                let residual = |i: usize, v: f32| v - (intercept + slope * i as f32);
+                if sigma <= 0.0 {
+                    break;
+                }
+                let mut index = 0;
+                values.retain(|&(v, _)| {
+                    let r = residual(index, v);
+                    index += 1;
+                    r >= -params.kappa_low * sigma && r <= params.kappa_high * sigma
+                });
+            }
+        }
+        if values.len() == before {
+            break;
+        }
+    }
+    total - values.len()
+}
+
+/// Standard deviation of the values after repeatedly clamping them to 1.5
+/// sigma of the median (Huber's winsorization, with its 1.134 correction)
+fn winsorized_sigma(values: &[(f32, f32)], centre: f32) -> f32 {
+    let mut clamped: Vec<f32> = values.iter().map(|v| v.0).collect();
+    let mut sigma = standard_deviation(clamped.iter().copied());
+    for _ in 0..10 {
+        let (low, high) = (centre - 1.5 * sigma, centre + 1.5 * sigma);
+        clamped.iter_mut().for_each(|v| *v = v.clamp(low, high));
+        let next = 1.134 * standard_deviation(clamped.iter().copied());
+        let converged = (next - sigma).abs() <= 5e-4 * sigma;
+        sigma = next;
+        if converged {
+            break;
+        }
+    }
+    sigma
+}
+
+/// Line through sorted values, as (intercept, slope) over their index, and
+/// the noise around it: the RMS residual of the values it was fitted to.
+/// The extremes are left out of both, they are the likely outliers and
+/// would tilt the line and widen the noise. Below 5 values no line is left
+/// between the extremes: it becomes the mean level of the values without
+/// the one farthest from the median. Below 4 values there is too little
+/// left to measure noise from, and the noise comes out 0.
+fn trimmed_fit(values: &[(f32, f32)]) -> (f32, f32, f32) {
+    if values.len() < 4 {
+        return (median(values), 0.0, 0.0);
+    }
+    if values.len() < 5 {
+        let centre = median(values);
+        let mut kept: Vec<f32> = values.iter().map(|v| v.0).collect();
+        kept.sort_by(|a, b| (a - centre).abs().total_cmp(&(b - centre).abs()));
+        kept.pop();
+        let level = kept.iter().sum::<f32>() / kept.len() as f32;
+        return (level, 0.0, rms(kept.iter().map(|v| v - level), 1));
+    }
+
+    let core = &values[1..values.len() - 1];
+    let (intercept, slope) = fit_line(core, 1);
+    let residuals = core
+        .iter()
+        .enumerate()
+        .map(|(i, v)| v.0 - (intercept + slope * (i + 1) as f32));
+    (intercept, slope, rms(residuals, 2))
+}
+
+/// Root mean square of residuals from a fit with `parameters` free
+/// parameters
+fn rms(residuals: impl Iterator<Item = f32>, parameters: usize) -> f32 {
+    let (sum, count) = residuals.fold((0.0, 0usize), |(sum, count), r| (sum + r * r, count + 1));
+    (sum / count.saturating_sub(parameters).max(1) as f32).sqrt()
+}
+
+/// Least squares `value = intercept + slope * index` through sorted values,
+/// the first of them at index `first`
+fn fit_line(values: &[(f32, f32)], first: usize) -> (f32, f32) {
+    let n = values.len() as f32;
+    let mean_i = first as f32 + (n - 1.0) / 2.0;
+    let mean_v = values.iter().map(|v| v.0).sum::<f32>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (i, &(v, _)) in values.iter().enumerate() {
+        let di = (first + i) as f32 - mean_i;
+        covariance += di * (v - mean_v);
+        variance += di * di;
+    }
+    let slope = if variance > 0.0 {
+        covariance / variance
+    } else {
+        0.0
+    };
+    (mean_v - slope * mean_i, slope)
+}
+
+fn standard_deviation(values: impl Iterator<Item = f32> + Clone) -> f32 {
+    let n = values.clone().count() as f32;
+    let mean = values.clone().sum::<f32>() / n;
+    (values.map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt()
+}
+
+fn median(values: &[(f32, f32)]) -> f32 {
+    let mut sorted: Vec<f32> = values.iter().map(|v| v.0).collect();
+    median_in_place(&mut sorted)
+}
+
+fn weighted_mean(values: &[(f32, f32)]) -> f32 {
+    let (sum, weights) = values.iter().fold((0.0, 0.0), |(sum, weights), &(v, w)| {
+        (sum + v * w, weights + w)
+    });
+    sum / weights
+}
+
+/// Sigma-clipped background level and noise
+fn background(data: &[f32]) -> (f32, f32) {
+    sigma_clipped_stats(&sample_pixels(data, 200_000), 3.0, 5)
+}
+
+/// Mean flux of the brightest stars
+fn star_signal(stars: &[Star]) -> f32 {
+    let brightest = &stars[..stars.len().min(SIGNAL_STARS)];
+    if brightest.is_empty() {
+        return 0.0;
+    }
+    (brightest.iter().map(|s| s.flux).sum::<f64>() / brightest.len() as f64) as f32
+}
+
+fn write_raw(path: &Path, data: &[f32]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for value in data {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Read `count` values from value `offset` on into `band`
+pub(crate) fn read_raw(
+    file: &mut File,
+    offset: usize,
+    count: usize,
+    band: &mut Vec<f32>,
+) -> Result<()> {
+    let mut bytes = vec![0u8; count * 4];
+    file.seek(SeekFrom::Start(offset as u64 * 4))?;
+    file.read_exact(&mut bytes)?;
+    band.clear();
+    band.extend(
+        bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(method: StackMethod) -> StackParams {
+        StackParams {
+            method,
+            ..Default::default()
+        }
+    }
+
+    /// Twenty values around `level` with a little spread and one far above
+    fn with_outlier(level: f32, outlier: f32) -> Vec<(f32, f32)> {
+        let mut values: Vec<(f32, f32)> = (0..19)
+            .map(|i| (level + ((i * 37) % 11) as f32 * 0.5 - 2.5, 1.0))
+            .collect();
+        values.insert(7, (outlier, 1.0));
+        values
+    }
+
+    #[test]
+    fn mean_and_median_reject_nothing() {
+        for method in [StackMethod::Mean, StackMethod::Median] {
+            let mut values = with_outlier(100.0, 500.0);
+            assert_eq!(reject(&mut values, &params(method)), 0, "{:?}", method);
+            assert_eq!(values.len(), 20);
+        }
+    }
+
+    #[test]
+    fn median_of_an_even_stack_averages_the_middle_values() {
+        assert_eq!(median(&[(10.0, 1.0), (20.0, 1.0)]), 15.0);
+        let values = [(4.0, 1.0), (1.0, 1.0), (3.0, 1.0), (2.0, 1.0)];
+        assert_eq!(median(&values), 2.5);
+        assert_eq!(median(&values[..3]), 3.0);
+    }
+
+    #[test]
+    fn clipping_methods_reject_the_outlier_only() {
+        for method in [
+            StackMethod::SigmaClip,
+            StackMethod::Winsorized,
+            StackMethod::LinearFit,
+        ] {
+            let mut values = with_outlier(100.0, 500.0);
+            assert_eq!(reject(&mut values, &params(method)), 1, "{:?}", method);
+            assert!(values.iter().all(|v| v.0 < 200.0), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn winsorized_rejects_where_sigma_clipping_cannot() {
+        // The outlier widens plain sigma enough to survive it
+        let values = vec![
+            (100.0, 1.0),
+            (101.0, 1.0),
+            (99.0, 1.0),
+            (100.0, 1.0),
+            (200.0, 1.0),
+        ];
+        let mut clipped = values.clone();
+        assert_eq!(reject(&mut clipped, &params(StackMethod::SigmaClip)), 0);
+        let mut winsorized = values;
+        assert_eq!(reject(&mut winsorized, &params(StackMethod::Winsorized)), 1);
+    }
+
+    #[test]
+    fn linear_fit_rejects_in_small_stacks() {
+        let mut values = vec![(100.2, 1.0), (99.8, 1.0), (100.1, 1.0), (180.0, 1.0)];
+        assert_eq!(reject(&mut values, &params(StackMethod::LinearFit)), 1);
+        assert!(values.iter().all(|v| v.0 < 101.0));
+    }
+
+    #[test]
+    fn linear_fit_follows_a_spread_of_levels() {
+        // Sky varying between frames from 60 to 140 with a little noise,
+        // plus one outlier
+        let mut values: Vec<(f32, f32)> = (0..21)
+            .map(|i| {
+                let noise = ((i * 7) % 5) as f32 * 0.4 - 0.8;
+                (60.0 + ((i * 8) % 21) as f32 * 4.0 + noise, 1.0)
+            })
+            .collect();
+        values.push((400.0, 1.0));
+        assert_eq!(reject(&mut values, &params(StackMethod::LinearFit)), 1);
+        assert!(values.iter().all(|v| v.0 < 200.0));
+    }
+
+    /// Frames of `width` x `height` written as raw files, removed on drop
+    fn frame_files(frames: &[Vec<f32>], name: &str) -> FrameFiles {
+        let mut files = FrameFiles::new(&format!("test-{}", name));
+        for data in frames {
+            files.push(data).unwrap();
+        }
+        files
+    }
+
+    fn stacked(offset: f32) -> StackedFrame {
+        StackedFrame {
+            path: String::new(),
+            weight: 1.0,
+            offset,
+            noise: 1.0,
+            registration: Registration {
+                transform: Transform::identity(),
+                matched_stars: 0,
+                rms_px: 0.0,
+            },
+        }
+    }
+
+    #[test]
+    fn combine_frames_is_the_same_across_band_boundaries() {
+        // 5 frames of 7 x 10, the last one 10 ADU brighter and with a
+        // satellite trail through rows 2, 3 and 9
+        let (width, height) = (7, 10);
+        let data: Vec<Vec<f32>> = (0..5)
+            .map(|frame| {
+                (0..width * height)
+                    .map(|i| {
+                        let y = i / width;
+                        let noise = ((i * 13 + frame * 7) % 5) as f32 * 0.1;
+                        match frame {
+                            4 if [2, 3, 9].contains(&y) => 1000.0,
+                            4 => i as f32 + noise + 10.0,
+                            _ => i as f32 + noise,
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let files = frame_files(&data, "bands");
+        let frames: Vec<StackedFrame> = (0..5)
+            .map(|i| stacked(if i == 4 { -10.0 } else { 0.0 }))
+            .collect();
+        let params = StackParams {
+            method: StackMethod::Winsorized,
+            weighting: Weighting::None,
+            ..Default::default()
+        };
+
+        let whole =
+            combine_frames(&files.paths, &frames, width, height, &params, usize::MAX).unwrap();
+        // 3 rows per band: bands of 3, 3, 3 and 1 row
+        let banded =
+            combine_frames(&files.paths, &frames, width, height, &params, 3 * 5 * width).unwrap();
+        assert_eq!(whole.0, banded.0);
+        assert_eq!(whole.1, banded.1);
+
+        let (stack, rejection_map) = banded;
+        for (i, (value, rejected)) in stack.iter().zip(&rejection_map).enumerate() {
+            let trail = [2, 3, 9].contains(&(i / width));
+            assert!(
+                (value - (i as f32 + 0.2)).abs() < 0.3,
+                "pixel {}: {}",
+                i,
+                value
+            );
+            assert_eq!(*rejected, if trail { 0.2 } else { 0.0 }, "pixel {}", i);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use crate::fits::{sample_pixels, sigma_clipped_stats};
+use serde::{Deserialize, Serialize};
+
+/// A detected star
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Star {
+    /// Intensity weighted centroid, 0-based pixel coordinates
+    pub x: f64,
+    pub y: f64,
+    /// Background subtracted sum inside the measuring box
+    pub flux: f64,
+    /// Highest background subtracted pixel
+    pub peak: f32,
+    /// From the second moments, assuming a Gaussian profile
+    pub fwhm: f64,
+    /// Of the ellipse given by the second moments: 0 for a round star,
+    /// towards 1 for a trailed one
+    pub eccentricity: f64,
+    /// Direction of the ellipse's long axis, degrees from +x towards +y
+    /// (0 to 180)
+    pub angle_deg: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DetectionParams {
+    /// Detection threshold in background sigmas
+    pub threshold_sigma: f32,
+    /// Half size of the box used to measure each star
+    pub box_radius: usize,
+    /// Keep at most this many stars (the brightest)
+    pub max_stars: usize,
+}
+
+impl Default for DetectionParams {
+    fn default() -> Self {
+        Self {
+            threshold_sigma: 5.0,
+            box_radius: 5,
+            max_stars: 500,
+        }
+    }
+}
+
+/// Find stars as local maxima above a sigma-clipped background threshold.
+/// Single hot pixels are rejected by requiring neighbours above the threshold
+/// too. Returns the stars sorted by flux, brightest first.
+pub fn detect_stars(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    params: &DetectionParams,
+) -> Vec<Star> {
+    let (background, sigma) = sigma_clipped_stats(&sample_pixels(data, 200_000), 3.0, 5);
+    if sigma <= 0.0 {
+        return Vec::new();
+    }
+    let threshold = background + params.threshold_sigma * sigma;
+    let r = params.box_radius.max(2);
+
+    let at = |x: usize, y: usize| data[y * width + x];
+
+    // Local maxima with at least 3 neighbours over the threshold
+    let mut candidates = Vec::new();
+    for y in r..height.saturating_sub(r) {
+        for x in r..width.saturating_sub(r) {
+            let value = at(x, y);
+            if value.is_nan() || value <= threshold {
+                continue;
+            }
+
+            let mut is_max = true;
+            let mut bright_neighbours = 0;
+            for (dx, dy) in NEIGHBOURS {
+                let n = at((x as isize + dx) as usize, (y as isize + dy) as usize);
+                // Ties go to the first pixel in scan order
+                if n > value || (n == value && (dy < 0 || (dy == 0 && dx < 0))) {
+                    is_max = false;
+                    break;
+                }
+                if n > threshold {
+                    bright_neighbours += 1;
+                }
+            }
+            if is_max && bright_neighbours >= 3 {
+                candidates.push((value, x, y));
+            }
+        }
+    }
+
+    // Brightest first, drop maxima inside the box of a brighter star
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut stars: Vec<Star> = Vec::new();
+    for (_, cx, cy) in candidates {
+        if stars.len() >= params.max_stars * 2 {
+            break;
+        }
+        let too_close = stars
+            .iter()
+            .any(|s| (s.x - cx as f64).abs() <= r as f64 && (s.y - cy as f64).abs() <= r as f64);
+        if too_close {
+            continue;
+        }
+
+        if let Some(star) = measure(data, width, cx, cy, r, background, sigma) {
+            stars.push(star);
+        }
+    }
+
+    stars.sort_by(|a, b| b.flux.total_cmp(&a.flux));
+    stars.truncate(params.max_stars);
+    stars
+}
+
+const NEIGHBOURS: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// Largest measuring box, in multiples of the detection box
+const MAX_BOX_SCALE: usize = 4;
+
+/// Pixels this many background sigmas up, connected to the peak, make the
+/// star's shape. Below that the noise would outweigh a faint star in the
+/// second moments. Cutting an elliptical Gaussian along one of its own
+/// contours keeps its axis ratio and direction.
+const ISOPHOTE_SIGMAS: f32 = 2.0;
+
+/// Centroid, flux, FWHM and shape of the star found at (cx, cy), in a
+/// background of `sigma` noise. Measured first in the detection box, which
+/// is then grown to cover the star's long axis: a box that cuts a trail off
+/// makes it look rounder and shorter than it is.
+fn measure(
+    data: &[f32],
+    width: usize,
+    cx: usize,
+    cy: usize,
+    r: usize,
+    background: f32,
+    sigma: f32,
+) -> Option<Star> {
+    let height = data.len() / width;
+    let threshold = ISOPHOTE_SIGMAS * sigma;
+    let mut radius = r;
+    let mut m = moments(data, width, height, (cx, cy), radius, background, threshold)?;
+    for _ in 0..MAX_BOX_SCALE {
+        // 3 sigma along the long axis, the growing box keeps revealing more
+        // of a trail so this takes a few rounds
+        let wanted = ((3.0 * m.major.sqrt()).ceil() as usize).clamp(r, r * MAX_BOX_SCALE);
+        if wanted <= radius {
+            break;
+        }
+        radius = wanted;
+        let center = (m.x.round() as usize, m.y.round() as usize);
+        match moments(data, width, height, center, radius, background, threshold) {
+            Some(grown) => m = grown,
+            None => break,
+        }
+    }
+
+    // Cutting the wings off at a `level` of the peak shrinks a Gaussian's
+    // second moments by this factor, the same along both axes
+    let peak = data[cy * width + cx] - background;
+    let level = (threshold / peak) as f64;
+    let shrink = if level > 0.0 && level < 1.0 {
+        1.0 + level * level.ln() / (1.0 - level)
+    } else {
+        1.0
+    };
+    let sigma = ((m.xx + m.yy) / 2.0 / shrink).sqrt();
+    let eccentricity = if m.major > 0.0 {
+        (1.0 - m.minor / m.major).sqrt()
+    } else {
+        0.0
+    };
+    let angle_deg = (0.5 * (2.0 * m.xy).atan2(m.xx - m.yy))
+        .to_degrees()
+        .rem_euclid(180.0);
+
+    Some(Star {
+        x: m.x,
+        y: m.y,
+        flux: m.total,
+        peak,
+        fwhm: 2.3548 * sigma,
+        eccentricity,
+        angle_deg,
+    })
+}
+
+/// Background subtracted sum, centroid and second moments of a star
+struct Moments {
+    /// Sum of the whole box, negative pixels included so noise averages out
+    total: f64,
+    x: f64,
+    y: f64,
+    xx: f64,
+    yy: f64,
+    xy: f64,
+    /// Eigenvalues of the moment matrix, the squared axes of the ellipse
+    major: f64,
+    minor: f64,
+}
+
+/// Moments of the box within `radius` of `center` (clipped to the image).
+/// The star is the pixels more than `threshold` above the background that
+/// connect to `center`, so noise around it is left out.
+fn moments(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    center: (usize, usize),
+    radius: usize,
+    background: f32,
+    threshold: f32,
+) -> Option<Moments> {
+    let (x0, x1) = (
+        center.0.saturating_sub(radius),
+        (center.0 + radius).min(width - 1),
+    );
+    let (y0, y1) = (
+        center.1.saturating_sub(radius),
+        (center.1 + radius).min(height - 1),
+    );
+    let value = |x: usize, y: usize| (data[y * width + x] - background) as f64;
+
+    let total: f64 = (y0..=y1)
+        .flat_map(|y| (x0..=x1).map(move |x| value(x, y)))
+        .filter(|v| v.is_finite())
+        .sum();
+
+    // Flood fill from the centre over the pixels above the threshold
+    let box_width = x1 - x0 + 1;
+    let mut seen = vec![false; box_width * (y1 - y0 + 1)];
+    seen[(center.1 - y0) * box_width + center.0 - x0] = true;
+    let mut queue = vec![center];
+    let mut pixels = Vec::new();
+    while let Some((x, y)) = queue.pop() {
+        let v = value(x, y);
+        if !v.is_finite() || v <= threshold as f64 {
+            continue;
+        }
+        pixels.push((x as f64, y as f64, v));
+        for (dx, dy) in NEIGHBOURS {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            if nx < x0 as isize || ny < y0 as isize || nx > x1 as isize || ny > y1 as isize {
+                continue;
+            }
+            let (nx, ny) = (nx as usize, ny as usize);
+            let index = (ny - y0) * box_width + nx - x0;
+            if !seen[index] {
+                seen[index] = true;
+                queue.push((nx, ny));
+            }
+        }
+    }
+
+    let (mut sum, mut sx, mut sy) = (0.0f64, 0.0f64, 0.0f64);
+    for &(x, y, v) in &pixels {
+        sum += v;
+        sx += v * x;
+        sy += v * y;
+    }
+    if sum <= 0.0 {
+        return None;
+    }
+    let (mx, my) = (sx / sum, sy / sum);
+
+    let (mut xx, mut yy, mut xy) = (0.0f64, 0.0f64, 0.0f64);
+    for &(x, y, v) in &pixels {
+        let (dx, dy) = (x - mx, y - my);
+        xx += v * dx * dx;
+        yy += v * dy * dy;
+        xy += v * dx * dy;
+    }
+    let (xx, yy, xy) = (xx / sum, yy / sum, xy / sum);
+
+    let half_difference = (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt();
+    Some(Moments {
+        total,
+        x: mx,
+        y: my,
+        xx,
+        yy,
+        xy,
+        major: (xx + yy) / 2.0 + half_difference,
+        minor: ((xx + yy) / 2.0 - half_difference).max(0.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fits::median_in_place;
+    use crate::registration::Lcg;
+
+    /// Noise of the test frames, uniform over 4 ADU
+    const NOISE_SIGMA: f64 = 4.0 / 3.4641;
+
+    /// 200 x 200 frame of background 100 with a little noise and a star at
+    /// (100, 100): a Gaussian of `peak` and `sigma` smeared over `length`
+    /// pixels along `angle_deg`
+    fn frame(peak: f64, sigma: f64, length: f64, angle_deg: f64, seed: u64) -> Vec<f32> {
+        let (width, height) = (200, 200);
+        let (sin, cos) = angle_deg.to_radians().sin_cos();
+        let mut rng = Lcg(seed);
+        let mut data = vec![0.0f32; width * height];
+        for (i, value) in data.iter_mut().enumerate() {
+            let (x, y) = ((i % width) as f64 - 100.0, (i / width) as f64 - 100.0);
+            // Distance from the trail's segment
+            let along = (x * cos + y * sin).clamp(-length / 2.0, length / 2.0);
+            let (dx, dy) = (x - along * cos, y - along * sin);
+            let star = peak * (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp();
+            let noise = (rng.fraction() - 0.5) * 4.0;
+            *value = (100.0 + star + noise) as f32;
+        }
+        data
+    }
+
+    fn brightest(data: &[f32]) -> Star {
+        detect_stars(data, 200, 200, &DetectionParams::default())
+            .into_iter()
+            .next()
+            .expect("no star found")
+    }
+
+    #[test]
+    fn round_star_stays_round() {
+        let star = brightest(&frame(1000.0, 1.5, 0.0, 0.0, 12345));
+        assert!((star.x - 100.0).abs() < 0.05 && (star.y - 100.0).abs() < 0.05);
+        assert!((star.fwhm - 2.3548 * 1.5).abs() < 0.3, "fwhm {}", star.fwhm);
+        assert!(
+            star.eccentricity < 0.3,
+            "eccentricity {}",
+            star.eccentricity
+        );
+    }
+
+    #[test]
+    fn long_trail_is_measured_whole() {
+        // A 30 pixel trail reaches far past the 11 x 11 detection box
+        let (sigma, length) = (1.5, 30.0);
+        let star = brightest(&frame(1000.0, sigma, length, 30.0, 12345));
+
+        // Second moments of a Gaussian smeared along a segment
+        let major = sigma * sigma + length * length / 12.0;
+        let expected = (1.0 - sigma * sigma / major).sqrt();
+        assert!(
+            (star.eccentricity - expected).abs() < 0.02,
+            "eccentricity {} instead of {}",
+            star.eccentricity,
+            expected
+        );
+        assert!(
+            (star.angle_deg - 30.0).abs() < 1.0,
+            "angle {}",
+            star.angle_deg
+        );
+        assert!((star.x - 100.0).abs() < 0.5 && (star.y - 100.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn faint_round_stars_stay_round() {
+        // Peak 15 sigma up: noise would dominate moments taken over the box
+        let mut eccentricities = Vec::new();
+        for seed in 1..=15 {
+            let star = brightest(&frame(15.0 * NOISE_SIGMA, 1.5, 0.0, 0.0, seed));
+            assert!((star.x - 100.0).abs() < 0.5 && (star.y - 100.0).abs() < 0.5);
+            assert!(
+                (star.fwhm - 2.3548 * 1.5).abs() < 0.6,
+                "seed {}: fwhm {}",
+                seed,
+                star.fwhm
+            );
+            eccentricities.push(star.eccentricity as f32);
+        }
+        let median = median_in_place(&mut eccentricities);
+        assert!(median < 0.45, "median eccentricity {}", median);
+    }
+
+    #[test]
+    fn faint_trails_keep_their_direction() {
+        let (sigma, length): (f64, f64) = (1.5, 10.0);
+        let major = sigma * sigma + length * length / 12.0;
+        let expected = (1.0 - sigma * sigma / major).sqrt();
+        for seed in 1..=15 {
+            let star = brightest(&frame(15.0 * NOISE_SIGMA, sigma, length, 120.0, seed));
+            assert!(
+                (star.angle_deg - 120.0).abs() < 8.0,
+                "seed {}: angle {}",
+                seed,
+                star.angle_deg
+            );
+            assert!(
+                (star.eccentricity - expected).abs() < 0.1,
+                "seed {}: eccentricity {} instead of {}",
+                seed,
+                star.eccentricity,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,720 @@
+use crate::fits::{FitsHeader, HeaderCard};
+use crate::renderer::{Orientation, OverlayLine};
+
+/// SIP polynomial, `coeffs[p][q]` multiplies u^p v^q
+#[derive(Debug, Clone)]
+struct Polynomial {
+    coeffs: Vec<Vec<f64>>,
+}
+
+impl Polynomial {
+    /// Read `<prefix>_ORDER` and the `<prefix>_p_q` cards, missing terms are 0
+    fn from_header(header: &FitsHeader, prefix: &str) -> Option<Self> {
+        let order = header.get_i64(&format!("{}_ORDER", prefix))?.max(0) as usize;
+        let coeffs = (0..=order)
+            .map(|p| {
+                (0..=order)
+                    .map(|q| {
+                        if p + q > order {
+                            return 0.0;
+                        }
+                        header
+                            .get_f64(&format!("{}_{}_{}", prefix, p, q))
+                            .unwrap_or(0.0)
+                    })
+                    .collect()
+            })
+            .collect();
+        Some(Self { coeffs })
+    }
+
+    fn eval(&self, u: f64, v: f64) -> f64 {
+        let mut sum = 0.0;
+        let mut u_pow = 1.0;
+        for row in &self.coeffs {
+            let mut v_pow = 1.0;
+            for c in row {
+                sum += c * u_pow * v_pow;
+                v_pow *= v;
+            }
+            u_pow *= u;
+        }
+        sum
+    }
+}
+
+/// Simple Imaging Polynomial distortion (Shupe et al. 2005)
+#[derive(Debug, Clone)]
+struct Sip {
+    a: Polynomial,
+    b: Polynomial,
+    /// Inverse polynomials, optional in the convention
+    ap: Option<Polynomial>,
+    bp: Option<Polynomial>,
+}
+
+impl Sip {
+    fn forward(&self, u: f64, v: f64) -> (f64, f64) {
+        (u + self.a.eval(u, v), v + self.b.eval(u, v))
+    }
+
+    fn inverse(&self, u: f64, v: f64) -> (f64, f64) {
+        if let (Some(ap), Some(bp)) = (&self.ap, &self.bp) {
+            return (u + ap.eval(u, v), v + bp.eval(u, v));
+        }
+
+        // No AP/BP in the header: invert the forward polynomial by fixed
+        // point iteration, distortions are small so this converges quickly
+        let (mut x, mut y) = (u, v);
+        for _ in 0..20 {
+            x = u - self.a.eval(x, y);
+            y = v - self.b.eval(x, y);
+        }
+        (x, y)
+    }
+}
+
+/// Gnomonic (TAN) world coordinate system with optional SIP distortion.
+/// Pixel coordinates are 0-based data coordinates: (0, 0) is the centre of
+/// the first pixel of the first row, i.e. FITS pixel (1, 1).
+#[derive(Debug, Clone)]
+pub struct Wcs {
+    /// Reference pixel, FITS 1-based
+    pub crpix: [f64; 2],
+    /// RA/Dec of the reference pixel in degrees
+    pub crval: [f64; 2],
+    /// Linear transform from pixel offsets to intermediate coordinates (deg)
+    pub cd: [[f64; 2]; 2],
+    sip: Option<Sip>,
+}
+
+impl Wcs {
+    /// Plain TAN solution without distortion
+    pub fn new_tan(crpix: [f64; 2], crval: [f64; 2], cd: [[f64; 2]; 2]) -> Self {
+        Self {
+            crpix,
+            crval,
+            cd,
+            sip: None,
+        }
+    }
+
+    /// Parse the celestial WCS of `header`. Only RA---TAN/DEC--TAN (with or
+    /// without -SIP) is supported; anything else returns `None`.
+    pub fn from_header(header: &FitsHeader) -> Option<Self> {
+        let ctype1 = header.get("CTYPE1")?.to_ascii_uppercase();
+        let ctype2 = header.get("CTYPE2")?.to_ascii_uppercase();
+        if !ctype1.starts_with("RA---TAN") || !ctype2.starts_with("DEC--TAN") {
+            return None;
+        }
+
+        let crpix = [header.get_f64("CRPIX1")?, header.get_f64("CRPIX2")?];
+        let crval = [header.get_f64("CRVAL1")?, header.get_f64("CRVAL2")?];
+        let cd = Self::read_cd(header)?;
+
+        let sip = if ctype1.ends_with("-SIP") && ctype2.ends_with("-SIP") {
+            Some(Sip {
+                a: Polynomial::from_header(header, "A")?,
+                b: Polynomial::from_header(header, "B")?,
+                ap: Polynomial::from_header(header, "AP"),
+                bp: Polynomial::from_header(header, "BP"),
+            })
+        } else {
+            None
+        };
+
+        let det = cd[0][0] * cd[1][1] - cd[0][1] * cd[1][0];
+        if det == 0.0 || !det.is_finite() {
+            return None;
+        }
+
+        Some(Self {
+            crpix,
+            crval,
+            cd,
+            sip,
+        })
+    }
+
+    /// CD matrix from CDi_j, or PCi_j/CROTA2 combined with CDELTi
+    fn read_cd(header: &FitsHeader) -> Option<[[f64; 2]; 2]> {
+        if let Some(cd1_1) = header.get_f64("CD1_1") {
+            return Some([
+                [cd1_1, header.get_f64("CD1_2").unwrap_or(0.0)],
+                [
+                    header.get_f64("CD2_1").unwrap_or(0.0),
+                    header.get_f64("CD2_2").unwrap_or(0.0),
+                ],
+            ]);
+        }
+
+        let cdelt = [header.get_f64("CDELT1")?, header.get_f64("CDELT2")?];
+        let pc = if header.get("PC1_1").is_some() || header.get("PC2_2").is_some() {
+            [
+                [
+                    header.get_f64("PC1_1").unwrap_or(1.0),
+                    header.get_f64("PC1_2").unwrap_or(0.0),
+                ],
+                [
+                    header.get_f64("PC2_1").unwrap_or(0.0),
+                    header.get_f64("PC2_2").unwrap_or(1.0),
+                ],
+            ]
+        } else {
+            let rho = header.get_f64("CROTA2").unwrap_or(0.0).to_radians();
+            [
+                [rho.cos(), -rho.sin() * cdelt[1] / cdelt[0]],
+                [rho.sin() * cdelt[0] / cdelt[1], rho.cos()],
+            ]
+        };
+
+        Some([
+            [cdelt[0] * pc[0][0], cdelt[0] * pc[0][1]],
+            [cdelt[1] * pc[1][0], cdelt[1] * pc[1][1]],
+        ])
+    }
+
+    /// Header cards describing this solution (plain TAN, no distortion)
+    pub fn to_header_cards(&self) -> Vec<HeaderCard> {
+        vec![
+            HeaderCard::text("CTYPE1", "RA---TAN", "Gnomonic projection"),
+            HeaderCard::text("CTYPE2", "DEC--TAN", "Gnomonic projection"),
+            HeaderCard::text("CUNIT1", "deg", ""),
+            HeaderCard::text("CUNIT2", "deg", ""),
+            HeaderCard::number("EQUINOX", 2000.0, "Equinox of coordinates"),
+            HeaderCard::text("RADESYS", "ICRS", ""),
+            HeaderCard::number("CRPIX1", self.crpix[0], "Reference pixel X"),
+            HeaderCard::number("CRPIX2", self.crpix[1], "Reference pixel Y"),
+            HeaderCard::number("CRVAL1", self.crval[0], "RA of reference pixel (deg)"),
+            HeaderCard::number("CRVAL2", self.crval[1], "Dec of reference pixel (deg)"),
+            HeaderCard::number("CD1_1", self.cd[0][0], ""),
+            HeaderCard::number("CD1_2", self.cd[0][1], ""),
+            HeaderCard::number("CD2_1", self.cd[1][0], ""),
+            HeaderCard::number("CD2_2", self.cd[1][1], ""),
+        ]
+    }
+
+    /// Whether `key` conflicts with a CD matrix solution and is removed when
+    /// writing one: another form of the linear transform, or SIP distortion
+    /// (orders and every `A_p_q`, `B_p_q`, `AP_p_q`, `BP_p_q` coefficient)
+    pub fn is_superseded_key(key: &str) -> bool {
+        const LINEAR: [&str; 8] = [
+            "CDELT1", "CDELT2", "CROTA1", "CROTA2", "PC1_1", "PC1_2", "PC2_1", "PC2_2",
+        ];
+        let key = key.trim().to_ascii_uppercase();
+        if LINEAR.contains(&key.as_str()) {
+            return true;
+        }
+
+        let mut parts = key.split('_');
+        let is_number = |part: Option<&str>| {
+            part.is_some_and(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()))
+        };
+        matches!(parts.next(), Some("A" | "B" | "AP" | "BP"))
+            && match parts.next() {
+                Some("ORDER") => parts.next().is_none(),
+                p => is_number(p) && is_number(parts.next()) && parts.next().is_none(),
+            }
+    }
+
+    /// Keys of `header` that `is_superseded_key`, each once
+    pub fn superseded_keys(header: &FitsHeader) -> Vec<String> {
+        let mut keys: Vec<String> = Vec::new();
+        for card in &header.cards {
+            if Self::is_superseded_key(&card.key) && !keys.contains(&card.key) {
+                keys.push(card.key.clone());
+            }
+        }
+        keys
+    }
+
+    /// Angle of north from the image +Y axis towards +X, in degrees
+    pub fn north_angle_deg(&self) -> f64 {
+        // Pixel direction in which Dec increases: CD^-1 * (0, 1)
+        let [[a, b], [c, d]] = self.cd;
+        let det = a * d - b * c;
+        let (nu, nv) = (-b / det, a / det);
+        nu.atan2(nv).to_degrees()
+    }
+
+    /// Orientation that shows this field north up and east left
+    pub fn north_up_orientation(&self) -> Orientation {
+        // Pixel directions of north and east (y points down on screen)
+        let [[a, b], [c, d]] = self.cd;
+        let det = a * d - b * c;
+        let (mut north_x, north_y) = (-b / det, a / det);
+        let (east_x, east_y) = (d / det, -c / det);
+
+        // East must be a quarter turn counter-clockwise from north on screen,
+        // otherwise the image is mirrored
+        let flip_horizontal = north_x * east_y - north_y * east_x > 0.0;
+        if flip_horizontal {
+            north_x = -north_x;
+        }
+
+        // Turn north to point up
+        let rotation = -90.0 - north_y.atan2(north_x).to_degrees();
+        Orientation {
+            rotation_deg: rotation.rem_euclid(360.0) as f32,
+            flip_horizontal,
+            flip_vertical: false,
+        }
+    }
+
+    /// Pixel scale in arcseconds per pixel (geometric mean of both axes)
+    pub fn pixel_scale_arcsec(&self) -> f64 {
+        let det = self.cd[0][0] * self.cd[1][1] - self.cd[0][1] * self.cd[1][0];
+        det.abs().sqrt() * 3600.0
+    }
+
+    /// RA/Dec in degrees (RA in 0..360) of a 0-based pixel position
+    pub fn pixel_to_sky(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let (mut u, mut v) = (x + 1.0 - self.crpix[0], y + 1.0 - self.crpix[1]);
+        if let Some(sip) = &self.sip {
+            (u, v) = sip.forward(u, v);
+        }
+
+        // Intermediate world coordinates (standard coordinates)
+        let xi = self.cd[0][0] * u + self.cd[0][1] * v;
+        let eta = self.cd[1][0] * u + self.cd[1][1] * v;
+        deproject_tan(self.crval, xi, eta)
+    }
+
+    /// 0-based pixel position of RA/Dec (degrees). `None` for points on the
+    /// far side of the sky, which the projection cannot show.
+    pub fn sky_to_pixel(&self, ra: f64, dec: f64) -> Option<(f64, f64)> {
+        let (xi, eta) = project_tan(self.crval, ra, dec)?;
+
+        // Invert the CD matrix
+        let [[a, b], [c, d]] = self.cd;
+        let det = a * d - b * c;
+        let mut u = (d * xi - b * eta) / det;
+        let mut v = (a * eta - c * xi) / det;
+        if let Some(sip) = &self.sip {
+            (u, v) = sip.inverse(u, v);
+        }
+
+        Some((u + self.crpix[0] - 1.0, v + self.crpix[1] - 1.0))
+    }
+
+    /// RA/Dec grid and a north/east compass for a `width` x `height` image,
+    /// as lines in image pixel coordinates
+    pub fn overlay(&self, width: usize, height: usize) -> Vec<OverlayLine> {
+        const GRID_COLOR: [f32; 4] = [0.3, 0.8, 1.0, 0.6];
+        const SAMPLES: usize = 200;
+
+        let (w, h) = (width as f64, height as f64);
+        let mut lines = Vec::new();
+
+        // Sky area covered by the image. RA is unwrapped around CRVAL1 so
+        // fields crossing RA = 0 stay contiguous.
+        let ra0 = self.crval[0];
+        let unwrap = |ra: f64| (ra - ra0 + 540.0).rem_euclid(360.0) - 180.0;
+        let (mut ra_min, mut ra_max) = (f64::MAX, f64::MIN);
+        let (mut dec_min, mut dec_max) = (f64::MAX, f64::MIN);
+        for j in 0..=16 {
+            for i in 0..=16 {
+                let x = i as f64 / 16.0 * w - 0.5;
+                let y = j as f64 / 16.0 * h - 0.5;
+                if let Some((ra, dec)) = self.pixel_to_sky(x, y) {
+                    let ra = unwrap(ra);
+                    ra_min = ra_min.min(ra);
+                    ra_max = ra_max.max(ra);
+                    dec_min = dec_min.min(dec);
+                    dec_max = dec_max.max(dec);
+                }
+            }
+        }
+        if ra_min > ra_max {
+            return lines;
+        }
+
+        // A celestial pole inside the frame means every RA is visible
+        for pole in [90.0, -90.0] {
+            if let Some((x, y)) = self.sky_to_pixel(0.0, pole) {
+                if (-0.5..w - 0.5).contains(&x) && (-0.5..h - 0.5).contains(&y) {
+                    (ra_min, ra_max) = (-180.0, 180.0);
+                    dec_min = dec_min.min(pole);
+                    dec_max = dec_max.max(pole);
+                }
+            }
+        }
+
+        // Sky position to image pixel coordinates (pixel i spans i..i+1)
+        let project = |ra: f64, dec: f64| {
+            self.sky_to_pixel(ra0 + ra, dec)
+                .map(|(x, y)| [(x + 0.5) as f32, (y + 0.5) as f32])
+        };
+
+        // Lines of constant declination
+        let dec_step = nice_step((dec_max - dec_min) / 6.0);
+        for dec in grid_values(dec_min, dec_max, dec_step) {
+            let points = (0..=SAMPLES).map(|i| {
+                let ra = ra_min + (ra_max - ra_min) * i as f64 / SAMPLES as f64;
+                project(ra, dec)
+            });
+            lines.extend(clip_polyline(points, width, height, GRID_COLOR));
+        }
+
+        // Lines of constant right ascension, at round absolute RA. A degree
+        // of RA shrinks on the sky by cos(dec), so the step is widened by
+        // that (at the field centre) to space the lines like the Dec lines,
+        // with at most 12 across the field.
+        let centre_dec = self
+            .pixel_to_sky(w / 2.0 - 0.5, h / 2.0 - 0.5)
+            .map_or(self.crval[1], |(_, dec)| dec);
+        let cos_dec = centre_dec.to_radians().cos().max(1e-6);
+        let ra_step = nice_step((dec_step / cos_dec).max((ra_max - ra_min) / 12.0));
+        for ra in grid_values(ra0 + ra_min, ra0 + ra_max, ra_step) {
+            let points = (0..=SAMPLES).map(|i| {
+                let dec = dec_min + (dec_max - dec_min) * i as f64 / SAMPLES as f64;
+                project(ra - ra0, dec)
+            });
+            lines.extend(clip_polyline(points, width, height, GRID_COLOR));
+        }
+
+        lines.extend(self.compass(width, height));
+        lines
+    }
+
+    /// Arrows pointing north (red) and east (green), near the top-left corner
+    fn compass(&self, width: usize, height: usize) -> Vec<OverlayLine> {
+        const NORTH_COLOR: [f32; 4] = [1.0, 0.35, 0.35, 1.0];
+        const EAST_COLOR: [f32; 4] = [0.35, 1.0, 0.35, 1.0];
+
+        let length = 0.08 * width.min(height) as f64;
+        let origin = [1.5 * length, 1.5 * length];
+        let Some((ra, dec)) = self.pixel_to_sky(origin[0] - 0.5, origin[1] - 0.5) else {
+            return Vec::new();
+        };
+
+        // Direction of a small step on the sky, in pixels
+        let step = self.pixel_scale_arcsec() / 3600.0 * 10.0;
+        let direction = |d_ra: f64, d_dec: f64| {
+            let (x, y) = self.sky_to_pixel(ra + d_ra, dec + d_dec)?;
+            let (dx, dy) = (x + 0.5 - origin[0], y + 0.5 - origin[1]);
+            let norm = dx.hypot(dy);
+            (norm > 0.0).then(|| [dx / norm, dy / norm])
+        };
+        // Step towards the equator near the pole and flip the result
+        let north = if dec + step < 90.0 {
+            direction(0.0, step)
+        } else {
+            direction(0.0, -step).map(|[x, y]| [-x, -y])
+        };
+        let east = direction(step / dec.to_radians().cos().max(1e-6), 0.0);
+
+        let mut lines = Vec::new();
+        for (dir, glyph, color) in [(north, GLYPH_N, NORTH_COLOR), (east, GLYPH_E, EAST_COLOR)] {
+            let Some([dx, dy]) = dir else { continue };
+            let point = |along: f64, across: f64| {
+                [
+                    (origin[0] + (dx * along - dy * across) * length) as f32,
+                    (origin[1] + (dy * along + dx * across) * length) as f32,
+                ]
+            };
+
+            lines.push(OverlayLine {
+                points: vec![point(0.0, 0.0), point(1.0, 0.0)],
+                color,
+            });
+            lines.push(OverlayLine {
+                points: vec![point(0.8, -0.1), point(1.0, 0.0), point(0.8, 0.1)],
+                color,
+            });
+
+            // Letter beyond the tip, upright in image coordinates
+            let size = 0.25 * length;
+            let [cx, cy] = point(1.35, 0.0);
+            for stroke in glyph {
+                lines.push(OverlayLine {
+                    points: stroke
+                        .iter()
+                        .map(|[gx, gy]| {
+                            [
+                                cx + ((gx - 0.5) * size) as f32,
+                                cy + ((gy - 0.5) * size) as f32,
+                            ]
+                        })
+                        .collect(),
+                    color,
+                });
+            }
+        }
+        lines
+    }
+}
+
+/// Gnomonic projection of RA/Dec onto the plane tangent at `center`
+/// (all in degrees). `None` on the far hemisphere.
+pub fn project_tan(center: [f64; 2], ra: f64, dec: f64) -> Option<(f64, f64)> {
+    let (ra, dec) = (ra.to_radians(), dec.to_radians());
+    let (ra0, dec0) = (center[0].to_radians(), center[1].to_radians());
+    let d_ra = ra - ra0;
+
+    let cos_c = dec0.sin() * dec.sin() + dec0.cos() * dec.cos() * d_ra.cos();
+    if cos_c <= 1e-6 {
+        return None;
+    }
+    let xi = (dec.cos() * d_ra.sin() / cos_c).to_degrees();
+    let eta = ((dec0.cos() * dec.sin() - dec0.sin() * dec.cos() * d_ra.cos()) / cos_c).to_degrees();
+    Some((xi, eta))
+}
+
+/// Inverse of `project_tan`, RA in 0..360
+pub fn deproject_tan(center: [f64; 2], xi: f64, eta: f64) -> Option<(f64, f64)> {
+    let (xi, eta) = (xi.to_radians(), eta.to_radians());
+    let (ra0, dec0) = (center[0].to_radians(), center[1].to_radians());
+
+    let denom = dec0.cos() - eta * dec0.sin();
+    let ra = ra0 + xi.atan2(denom);
+    let dec = (dec0.sin() + eta * dec0.cos()).atan2(xi.hypot(denom));
+
+    let (ra, dec) = (ra.to_degrees().rem_euclid(360.0), dec.to_degrees());
+    (ra.is_finite() && dec.is_finite()).then_some((ra, dec))
+}
+
+/// Stroke outlines of the compass letters in a unit box, y pointing down
+const GLYPH_N: &[&[[f64; 2]]] = &[&[[0.0, 1.0], [0.0, 0.0], [1.0, 1.0], [1.0, 0.0]]];
+const GLYPH_E: &[&[[f64; 2]]] = &[
+    &[[1.0, 0.0], [0.0, 0.0], [0.0, 1.0], [1.0, 1.0]],
+    &[[0.0, 0.5], [0.7, 0.5]],
+];
+
+/// Multiples of `step` from `min` to `max`
+fn grid_values(min: f64, max: f64, step: f64) -> impl Iterator<Item = f64> {
+    let first = (min / step).ceil() as i64;
+    let last = (max / step).floor() as i64;
+    (first..=last).map(move |k| k as f64 * step)
+}
+
+/// Smallest round grid spacing (degrees) of at least `raw`
+fn nice_step(raw: f64) -> f64 {
+    const STEPS: [f64; 20] = [
+        1.0 / 3600.0,
+        2.0 / 3600.0,
+        5.0 / 3600.0,
+        10.0 / 3600.0,
+        15.0 / 3600.0,
+        30.0 / 3600.0,
+        1.0 / 60.0,
+        2.0 / 60.0,
+        5.0 / 60.0,
+        10.0 / 60.0,
+        15.0 / 60.0,
+        30.0 / 60.0,
+        1.0,
+        2.0,
+        5.0,
+        10.0,
+        15.0,
+        30.0,
+        45.0,
+        90.0,
+    ];
+    STEPS.into_iter().find(|&step| step >= raw).unwrap_or(90.0)
+}
+
+/// Split a sampled curve into the runs that lie inside the image. Missing
+/// samples (not projectable) break the curve as well.
+fn clip_polyline(
+    points: impl Iterator<Item = Option<[f32; 2]>>,
+    width: usize,
+    height: usize,
+    color: [f32; 4],
+) -> Vec<OverlayLine> {
+    let (w, h) = (width as f32, height as f32);
+    let inside = |[x, y]: [f32; 2]| (0.0..=w).contains(&x) && (0.0..=h).contains(&y);
+
+    let mut lines = Vec::new();
+    let mut current: Vec<[f32; 2]> = Vec::new();
+    let mut previous: Option<[f32; 2]> = None;
+
+    let mut finish = |current: &mut Vec<[f32; 2]>| {
+        if current.len() > 1 {
+            lines.push(OverlayLine {
+                points: std::mem::take(current),
+                color,
+            });
+        }
+        current.clear();
+    };
+
+    for point in points {
+        match (previous, point) {
+            (Some(a), Some(b)) => match clip_segment(a, b, w, h) {
+                Some((start, end)) => {
+                    if current.is_empty() {
+                        current.push(start);
+                    }
+                    current.push(end);
+                    if !inside(b) {
+                        finish(&mut current);
+                    }
+                }
+                None => finish(&mut current),
+            },
+            _ => finish(&mut current),
+        }
+        previous = point;
+    }
+    finish(&mut current);
+
+    lines
+}
+
+/// Liang-Barsky clipping of the segment a-b to 0..w x 0..h
+fn clip_segment(a: [f32; 2], b: [f32; 2], w: f32, h: f32) -> Option<([f32; 2], [f32; 2])> {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let (mut t0, mut t1) = (0.0f32, 1.0f32);
+
+    for (p, q) in [(-dx, a[0]), (dx, w - a[0]), (-dy, a[1]), (dy, h - a[1])] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let t = q / p;
+        if p < 0.0 {
+            t0 = t0.max(t);
+        } else {
+            t1 = t1.min(t);
+        }
+        if t0 > t1 {
+            return None;
+        }
+    }
+
+    Some((
+        [a[0] + t0 * dx, a[1] + t0 * dy],
+        [a[0] + t1 * dx, a[1] + t1 * dy],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2000 x 1500 pixels of 10", rotated by 20°, centred off a round RA
+    fn field(crval: [f64; 2]) -> Wcs {
+        let scale = 10.0 / 3600.0;
+        let (sin, cos) = 20f64.to_radians().sin_cos();
+        Wcs::new_tan(
+            [1000.5, 750.5],
+            crval,
+            [[-scale * cos, scale * sin], [scale * sin, scale * cos]],
+        )
+    }
+
+    fn assert_round_trip(wcs: &Wcs) {
+        for (x, y) in [(0.0, 0.0), (1999.0, 0.0), (431.7, 1204.2), (1999.0, 1499.0)] {
+            let (ra, dec) = wcs.pixel_to_sky(x, y).unwrap();
+            let (bx, by) = wcs.sky_to_pixel(ra, dec).unwrap();
+            assert!(
+                (bx - x).abs() < 1e-6 && (by - y).abs() < 1e-6,
+                "({}, {}) came back as ({}, {})",
+                x,
+                y,
+                bx,
+                by
+            );
+        }
+    }
+
+    #[test]
+    fn pixel_sky_round_trip() {
+        assert_round_trip(&field([10.3, 41.2]));
+        assert_round_trip(&field([359.9, -62.0]));
+    }
+
+    #[test]
+    fn pixel_sky_round_trip_with_sip() {
+        let mut header = FitsHeader::default();
+        let mut cards = field([83.8, -5.4]).to_header_cards();
+        cards[0] = HeaderCard::text("CTYPE1", "RA---TAN-SIP", "");
+        cards[1] = HeaderCard::text("CTYPE2", "DEC--TAN-SIP", "");
+        cards.extend([
+            HeaderCard::integer("A_ORDER", 2, ""),
+            HeaderCard::number("A_2_0", 2e-6, ""),
+            HeaderCard::number("A_1_1", -1e-6, ""),
+            HeaderCard::number("A_0_2", 5e-7, ""),
+            HeaderCard::integer("B_ORDER", 2, ""),
+            HeaderCard::number("B_2_0", -8e-7, ""),
+            HeaderCard::number("B_0_2", 1.5e-6, ""),
+        ]);
+        for card in cards {
+            header.set(card);
+        }
+
+        let wcs = Wcs::from_header(&header).unwrap();
+        assert!(wcs.sip.is_some());
+        // The distortion is large enough to matter at the corners
+        let (ra, dec) = wcs.pixel_to_sky(0.0, 0.0).unwrap();
+        let (px, py) = field([83.8, -5.4]).sky_to_pixel(ra, dec).unwrap();
+        assert!(px.hypot(py) > 1.0);
+        assert_round_trip(&wcs);
+    }
+
+    #[test]
+    fn superseded_keys_include_sip_coefficients() {
+        for key in [
+            "A_ORDER", "BP_ORDER", "A_0_2", "B_1_1", "AP_10_0", "BP_0_3", "PC1_2",
+        ] {
+            assert!(Wcs::is_superseded_key(key), "{}", key);
+        }
+        for key in [
+            "A_", "A_1", "A_1_", "A_1_2_3", "AB_1_1", "A_X_1", "CD1_1", "CRPIX1",
+        ] {
+            assert!(!Wcs::is_superseded_key(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn ra_lines_fall_on_round_values() {
+        let wcs = field([10.3, 41.2]);
+        let mut ra_lines = 0;
+        for line in wcs.overlay(2000, 1500) {
+            let sky = |[x, y]: [f32; 2]| wcs.pixel_to_sky(x as f64 - 0.5, y as f64 - 0.5).unwrap();
+            let (Some(&first), Some(&last)) = (line.points.first(), line.points.last()) else {
+                continue;
+            };
+            let ((ra_a, dec_a), (ra_b, _)) = (sky(first), sky(last));
+            if line.points.len() > 10 && (ra_a - ra_b).abs() < 1e-5 {
+                ra_lines += 1;
+                assert!(
+                    (ra_a - ra_a.round()).abs() < 1e-5,
+                    "RA line at {} (dec {})",
+                    ra_a,
+                    dec_a
+                );
+            }
+        }
+        assert!((3..=12).contains(&ra_lines), "{} RA lines", ra_lines);
+    }
+
+    #[test]
+    fn ra_step_widens_towards_the_pole() {
+        let count = |dec: f64| {
+            let wcs = field([10.3, dec]);
+            wcs.overlay(2000, 1500)
+                .iter()
+                .filter(|line| {
+                    let sky = |[x, y]: [f32; 2]| {
+                        wcs.pixel_to_sky(x as f64 - 0.5, y as f64 - 0.5).unwrap().0
+                    };
+                    line.points.len() > 10
+                        && (sky(line.points[0]) - sky(*line.points.last().unwrap())).abs() < 1e-5
+                })
+                .count()
+        };
+        // Same field size on the sky, so about as many RA lines
+        assert!(
+            count(86.0) <= count(0.0) + 2,
+            "{} vs {}",
+            count(86.0),
+            count(0.0)
+        );
+        assert!(count(90.0) <= 12);
+    }
+}
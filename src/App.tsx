@@ -1,6 +1,6 @@
-import { createSignal, onMount } from "solid-js";
+import { createSignal, onCleanup, onMount } from "solid-js";
 import { invoke } from "@tauri-apps/api/core";
-import { open } from "@tauri-apps/plugin-dialog";
+import { open, save } from "@tauri-apps/plugin-dialog";
 import "./App.css";
 
 interface ImageStats {
@@ -12,6 +12,206 @@ interface ImageStats {
   histogram: number[];
 }
 
+interface PixelInfo {
+  pane: number;
+  x: number;
+  y: number;
+  raw: number;
+  normalized: number;
+  ra: number | null;
+  dec: number | null;
+}
+
+interface PlateSolution {
+  ra: number;
+  dec: number;
+  scale_arcsec: number;
+  north_angle_deg: number;
+  matched_stars: number;
+  rms_arcsec: number;
+}
+
+interface Orientation {
+  rotation_deg: number;
+  flip_horizontal: boolean;
+  flip_vertical: boolean;
+}
+
+type Interpolation = "nearest" | "bilinear" | "bicubic" | "lanczos";
+
+interface BlinkInfo {
+  frame: number;
+  frame_count: number;
+  path: string;
+  alignment: { dx: number; dy: number; background: number };
+  comparing: boolean;
+  registration: Registration | null;
+}
+
+// Affine map from first-frame pixels to this frame's pixels
+interface Registration {
+  transform: { m: [number, number, number][] };
+  matched_stars: number;
+  rms_px: number;
+}
+
+type CompareMode = "off" | "difference" | "ratio";
+
+interface CompareInfo {
+  stats: ImageStats;
+  stretch_min: number;
+  stretch_max: number;
+}
+
+type CombineMethod = "average" | "median" | "sigmaclip";
+
+interface MasterInfo {
+  kind: "bias" | "dark" | "flat";
+  path: string;
+  frames: number;
+  median: number;
+}
+
+type DefectSource = "detect" | "dark";
+
+type StackMethod = "mean" | "median" | "sigmaclip" | "winsorized" | "linearfit";
+type Weighting = "none" | "noise" | "snr";
+
+interface StackInfo {
+  path: string;
+  rejection_path: string;
+  frames: { path: string; weight: number; noise: number }[];
+  skipped: [string, string][];
+}
+
+type SurfaceModel = "polynomial" | "spline";
+type BackgroundView = "original" | "model" | "subtracted" | "divided";
+
+interface GradientStats {
+  strength_percent: number;
+  strength_sigma: number;
+  angle_deg: number;
+  cells_used: number;
+}
+
+interface FrameMetrics {
+  path: string;
+  date_obs: string | null;
+  timestamp: number | null;
+  exposure: number | null;
+  filter: string | null;
+  background: number;
+  noise: number;
+  stars: number;
+  eccentricity: number;
+  elongation_angle_deg: number;
+  gradient_percent: number;
+  gradient_angle_deg: number;
+  transparency: number | null;
+  matched_stars: number;
+  reject: string | null;
+}
+
+interface Session {
+  frames: FrameMetrics[];
+  skipped: [string, string][];
+  references: string[];
+}
+
+// Series of the session chart, each scaled to its own range
+const SESSION_SERIES: {
+  key: "background" | "noise" | "stars" | "eccentricity" | "gradient_percent" | "transparency";
+  label: string;
+  color: string;
+}[] = [
+  { key: "background", label: "Background", color: "#24c8db" },
+  { key: "noise", label: "Noise", color: "#ffd43b" },
+  { key: "stars", label: "Stars", color: "#51cf66" },
+  { key: "eccentricity", label: "Eccentricity", color: "#ff922b" },
+  { key: "gradient_percent", label: "Gradient", color: "#f06595" },
+  { key: "transparency", label: "Transparency", color: "#cc5de8" },
+];
+
+interface ElongationCell {
+  x0: number;
+  y0: number;
+  x1: number;
+  y1: number;
+  stars: number;
+  eccentricity: number;
+  angle_deg: number;
+}
+
+interface ElongationReport {
+  width: number;
+  height: number;
+  stars: number;
+  eccentricity: number;
+  elongation: number;
+  angle_deg: number;
+  coherence: number;
+  cells: ElongationCell[];
+}
+
+// Star shapes in words: trailing the same way everywhere points at tracking,
+// shapes varying across the field at tilt or curvature
+function elongationSummary(report: ElongationReport): string {
+  const lines = [
+    `${report.stars} stars, eccentricity ${report.eccentricity.toFixed(2)} ` +
+      `(${report.elongation.toFixed(2)}:1)`,
+    `towards ${report.angle_deg.toFixed(0)}°, coherence ${report.coherence.toFixed(2)}`,
+  ];
+  const cells = report.cells.map((c) => c.eccentricity);
+  if (cells.length > 0) {
+    const min = Math.min(...cells);
+    const max = Math.max(...cells);
+    lines.push(`across the field ${min.toFixed(2)} to ${max.toFixed(2)}`);
+    if (report.eccentricity >= 0.5) {
+      lines.push(
+        report.coherence >= 0.6 && max - min < 0.2
+          ? "Trailed one way everywhere: tracking or guiding"
+          : "Varies across the field: tilt or curvature"
+      );
+    }
+  }
+  return lines.join("\n");
+}
+
+type PaneLayout = "single" | "split" | "stacked" | "grid";
+
+interface PaneInfo {
+  pane: number;
+  layout: PaneLayout;
+  stats: ImageStats | null;
+  stretch_min: number;
+  stretch_max: number;
+  zoom: number;
+  pan_x: number;
+  pan_y: number;
+  orientation: Orientation;
+  interpolation: Interpolation;
+  has_wcs: boolean;
+}
+
+// Columns and rows of each layout, panes are numbered row by row
+const PANE_SHAPES: Record<PaneLayout, [number, number]> = {
+  single: [1, 1],
+  split: [2, 1],
+  stacked: [1, 2],
+  grid: [2, 2],
+};
+
+// Rotation, scale and fit quality of a registration
+function registrationSummary(registration: Registration): string {
+  const [[a, b], [c, d]] = registration.transform.m;
+  const rotation = (Math.atan2(c, a) * 180) / Math.PI;
+  const scale = Math.sqrt(Math.abs(a * d - b * c));
+  return (
+    `${rotation.toFixed(2)}°, ×${scale.toFixed(4)}, ` +
+    `${registration.matched_stars} stars, rms ${registration.rms_px.toFixed(2)} px`
+  );
+}
+
 function App() {
   const [brightness, setBrightness] = createSignal(50);
   const [contrast, setContrast] = createSignal(50);
@@ -21,19 +221,87 @@ function App() {
   const [stats, setStats] = createSignal<ImageStats | null>(null);
   const [stretchMin, setStretchMin] = createSignal(0);
   const [stretchMax, setStretchMax] = createSignal(65535);
+  const [clipOverlay, setClipOverlay] = createSignal(false);
+  const [softwareRenderer, setSoftwareRenderer] = createSignal(false);
+  const [pyramidMode, setPyramidMode] = createSignal<"mean" | "max">("mean");
+  const [interpolation, setInterpolation] = createSignal<Interpolation>("nearest");
+  const [orientation, setOrientation] = createSignal<Orientation>({
+    rotation_deg: 0,
+    flip_horizontal: false,
+    flip_vertical: false,
+  });
+  const [autoOrient, setAutoOrient] = createSignal(false);
+  const [pixelInfo, setPixelInfo] = createSignal<PixelInfo | null>(null);
+  const [wcsOverlay, setWcsOverlay] = createSignal(false);
+  const [hasWcs, setHasWcs] = createSignal(false);
+  const [indexPath, setIndexPath] = createSignal<string | null>(null);
+  const [indexFieldDeg, setIndexFieldDeg] = createSignal(1.0);
+  const [solveStatus, setSolveStatus] = createSignal<string | null>(null);
+  const [paneLayout, setPaneLayout] = createSignal<PaneLayout>("single");
+  const [activePane, setActivePane] = createSignal(0);
+  const [linkedView, setLinkedView] = createSignal(false);
+  const [blink, setBlink] = createSignal<BlinkInfo | null>(null);
+  const [blinkAlign, setBlinkAlign] = createSignal(true);
+  const [blinkRate, setBlinkRate] = createSignal(2);
+  const [blinking, setBlinking] = createSignal(false);
+  const [compareMode, setCompareMode] = createSignal<CompareMode>("off");
+  const [compareReference, setCompareReference] = createSignal(0);
+  const [compareStatus, setCompareStatus] = createSignal<string | null>(null);
+  const [combineMethod, setCombineMethod] = createSignal<CombineMethod>("sigmaclip");
+  const [calibrationStatus, setCalibrationStatus] = createSignal<string | null>(null);
+  const [calibratePreview, setCalibratePreview] = createSignal(false);
+  const [cosmeticSource, setCosmeticSource] = createSignal<DefectSource | null>(null);
+  const [stackMethod, setStackMethod] = createSignal<StackMethod>("sigmaclip");
+  const [stackWeighting, setStackWeighting] = createSignal<Weighting>("noise");
+  const [stackCalibrate, setStackCalibrate] = createSignal(false);
+  const [stackStatus, setStackStatus] = createSignal<string | null>(null);
+  const [surfaceModel, setSurfaceModel] = createSignal<SurfaceModel>("polynomial");
+  const [surfaceDegree, setSurfaceDegree] = createSignal(2);
+  const [backgroundView, setBackgroundView] = createSignal<BackgroundView>("original");
+  const [backgroundStatus, setBackgroundStatus] = createSignal<string | null>(null);
+  const [elongationOverlay, setElongationOverlay] = createSignal(false);
+  const [elongationStatus, setElongationStatus] = createSignal<string | null>(null);
+  const [session, setSession] = createSignal<Session | null>(null);
+  const [sessionCalibrate, setSessionCalibrate] = createSignal(false);
+  const [minTransparency, setMinTransparency] = createSignal(70);
+  const [sessionStatus, setSessionStatus] = createSignal<string | null>(null);
 
   let isDragging = false;
   let lastMouseX = 0;
   let lastMouseY = 0;
   let histogramCanvas: HTMLCanvasElement | undefined;
+  let sessionCanvas: HTMLCanvasElement | undefined;
+  let softwareCanvas: HTMLCanvasElement | undefined;
+
+  // Without a GPU the backend renders on the CPU and we draw the frame ourselves
+  const refreshSoftwareFrame = async () => {
+    if (!softwareRenderer() || !softwareCanvas) return;
+
+    const width = Math.round(window.innerWidth * window.devicePixelRatio);
+    const height = Math.round(window.innerHeight * window.devicePixelRatio);
+    const frame = await invoke<ArrayBuffer>("get_software_frame", {
+      width,
+      height,
+    });
+
+    softwareCanvas.width = width;
+    softwareCanvas.height = height;
+    const ctx = softwareCanvas.getContext("2d");
+    ctx?.putImageData(
+      new ImageData(new Uint8ClampedArray(frame), width, height),
+      0,
+      0
+    );
+  };
 
   // Send view updates to Rust backend
-  const updateView = () => {
-    invoke("update_view", {
+  const updateView = async () => {
+    await invoke("update_view", {
       zoom: zoom() / 100, // Convert percentage to scale
       panX: panX(),
       panY: panY(),
     });
+    refreshSoftwareFrame();
   };
 
   // Mouse wheel for zoom
@@ -45,14 +313,35 @@ function App() {
     updateView();
   };
 
-  // Mouse drag for pan
-  const handleMouseDown = (e: MouseEvent) => {
+  // Mouse drag for pan, a click also activates the pane under the cursor
+  const handleMouseDown = async (e: MouseEvent) => {
     isDragging = true;
+    if (paneLayout() !== "single") {
+      const info = await invoke<PaneInfo>("select_pane_at", {
+        x: e.clientX,
+        y: e.clientY,
+        viewportWidth: window.innerWidth,
+        viewportHeight: window.innerHeight,
+      });
+      if (info.pane !== activePane()) applyPaneInfo(info);
+    }
     lastMouseX = e.clientX;
     lastMouseY = e.clientY;
   };
 
+  // Value under the cursor (the renderer covers the whole window)
+  const updatePixelInfo = async (e: MouseEvent) => {
+    const info = await invoke<PixelInfo | null>("get_pixel_info", {
+      x: e.clientX,
+      y: e.clientY,
+      viewportWidth: window.innerWidth,
+      viewportHeight: window.innerHeight,
+    });
+    setPixelInfo(info);
+  };
+
   const handleMouseMove = (e: MouseEvent) => {
+    updatePixelInfo(e);
     if (!isDragging) return;
 
     const deltaX = (e.clientX - lastMouseX) / window.innerWidth;
@@ -80,11 +369,122 @@ function App() {
   };
 
   // Update stretch values
-  const updateStretch = () => {
-    invoke("update_stretch", {
+  const updateStretch = async () => {
+    await invoke("update_stretch", {
       min: stretchMin(),
       max: stretchMax(),
     });
+    refreshSoftwareFrame();
+  };
+
+  // Toggle highlight/shadow clipping overlay
+  const toggleClipOverlay = async () => {
+    const enabled = !clipOverlay();
+    setClipOverlay(enabled);
+    await invoke("set_clipping_overlay", { enabled });
+    refreshSoftwareFrame();
+  };
+
+  // RA/Dec grid and compass, only drawn for plate-solved images
+  const applyWcsOverlay = async (enabled: boolean) => {
+    setWcsOverlay(enabled);
+    setHasWcs(await invoke<boolean>("set_wcs_overlay", { enabled }));
+    refreshSoftwareFrame();
+  };
+
+  // Heat map of star elongation across the field
+  const applyElongationOverlay = async (enabled: boolean) => {
+    setElongationOverlay(enabled);
+    await invoke("set_elongation_overlay", { enabled });
+    refreshSoftwareFrame();
+    if (enabled) await measureElongation();
+  };
+
+  async function measureElongation() {
+    setElongationStatus("Measuring...");
+    try {
+      const report = await invoke<ElongationReport>("measure_elongation");
+      setElongationStatus(elongationSummary(report));
+    } catch (e) {
+      setElongationStatus(String(e));
+    }
+  }
+
+  // How pixels are combined when zoomed out
+  const updatePyramidMode = async (mode: "mean" | "max") => {
+    setPyramidMode(mode);
+    await invoke("set_pyramid_mode", { mode });
+    refreshSoftwareFrame();
+  };
+
+  // How pixels are resampled between pixel centres
+  const updateInterpolation = async (mode: Interpolation) => {
+    setInterpolation(mode);
+    await invoke("set_interpolation", { interpolation: mode });
+    refreshSoftwareFrame();
+  };
+
+  // Rotate and mirror the image on screen
+  const updateOrientation = async (changes: Partial<Orientation>) => {
+    const next = { ...orientation(), ...changes };
+    next.rotation_deg = ((next.rotation_deg % 360) + 360) % 360;
+    setOrientation(next);
+    await invoke("set_orientation", { orientation: next });
+    refreshSoftwareFrame();
+  };
+
+  // North up from the WCS, or undo meridian flips from PIERSIDE
+  const applyAutoOrient = async (enabled: boolean) => {
+    setAutoOrient(enabled);
+    setOrientation(await invoke<Orientation>("set_auto_orient", { enabled }));
+    refreshSoftwareFrame();
+  };
+
+  // Show the controls of the active pane after switching panes
+  const applyPaneInfo = (info: PaneInfo) => {
+    if (info.pane !== activePane()) {
+      stopBlink();
+      setBlink(null);
+      setCompareMode("off");
+    }
+    setActivePane(info.pane);
+    setPaneLayout(info.layout);
+    setStats(info.stats);
+    setStretchMin(info.stretch_min);
+    setStretchMax(info.stretch_max);
+    setZoom(info.zoom * 100);
+    setPanX(info.pan_x);
+    setPanY(info.pan_y);
+    setOrientation(info.orientation);
+    setInterpolation(info.interpolation);
+    setHasWcs(info.has_wcs);
+    drawHistogram();
+  };
+
+  // Split the window to compare images side by side
+  const updatePaneLayout = async (layout: PaneLayout) => {
+    applyPaneInfo(await invoke<PaneInfo>("set_pane_layout", { layout }));
+    refreshSoftwareFrame();
+  };
+
+  // Zoom and pan every pane together
+  const updateLinkedView = async (linked: boolean) => {
+    setLinkedView(linked);
+    await invoke("set_linked_view", { linked });
+    refreshSoftwareFrame();
+  };
+
+  // Outline of the active pane, in percent of the window
+  const activePaneOutline = () => {
+    const [columns, rows] = PANE_SHAPES[paneLayout()];
+    const column = activePane() % columns;
+    const row = Math.floor(activePane() / columns);
+    return {
+      left: `${(column * 100) / columns}%`,
+      top: `${(row * 100) / rows}%`,
+      width: `${100 / columns}%`,
+      height: `${100 / rows}%`,
+    };
   };
 
   // Auto-stretch (percentile clipping)
@@ -166,6 +566,132 @@ function App() {
     ctx.stroke();
   };
 
+  // Horizontal position of each session frame: minutes since the first one,
+  // or its index when some frames have no DATE-OBS
+  const sessionPositions = (frames: FrameMetrics[]) => {
+    const start = frames[0]?.timestamp;
+    const dated = start != null && frames.every((f) => f.timestamp != null);
+    return frames.map((f, i) => (dated ? (f.timestamp! - start) / 60 : i));
+  };
+
+  const drawSession = () => {
+    const frames = session()?.frames;
+    if (!frames || !sessionCanvas) return;
+
+    const ctx = sessionCanvas.getContext("2d");
+    if (!ctx) return;
+
+    const width = sessionCanvas.width;
+    const height = sessionCanvas.height;
+    const margin = 6;
+
+    ctx.fillStyle = "#1a1a1a";
+    ctx.fillRect(0, 0, width, height);
+
+    const positions = sessionPositions(frames);
+    const span = Math.max(positions[positions.length - 1] - positions[0], 1e-9);
+    const x = (i: number) => margin + ((positions[i] - positions[0]) / span) * (width - 2 * margin);
+
+    // Shade rejected frames
+    ctx.fillStyle = "rgba(255, 107, 107, 0.25)";
+    frames.forEach((f, i) => f.reject && ctx.fillRect(x(i) - 2, 0, 4, height));
+
+    ctx.lineWidth = 1.5;
+    for (const series of SESSION_SERIES) {
+      // Frames without a value (transparency of unmatched frames) are skipped
+      const points = frames
+        .map((f, i) => [i, f[series.key]] as const)
+        .filter((p): p is readonly [number, number] => p[1] != null);
+      if (points.length === 0) continue;
+      const values = points.map((p) => p[1]);
+      const min = Math.min(...values);
+      const range = Math.max(Math.max(...values) - min, 1e-9);
+      const y = (v: number) => height - margin - ((v - min) / range) * (height - 2 * margin);
+
+      ctx.strokeStyle = series.color;
+      ctx.fillStyle = series.color;
+      ctx.beginPath();
+      points.forEach(([i, v], n) => (n === 0 ? ctx.moveTo(x(i), y(v)) : ctx.lineTo(x(i), y(v))));
+      ctx.stroke();
+      points.forEach(([i, v]) => ctx.fillRect(x(i) - 1.5, y(v) - 1.5, 3, 3));
+    }
+  };
+
+  // Measure a night's frames and chart them over time
+  async function measureSession() {
+    const paths = await open({
+      multiple: true,
+      directory: false,
+      filters: [{ name: "FITS", extensions: ["fits", "fit", "fts"] }],
+    });
+    if (!Array.isArray(paths) || paths.length === 0) return;
+
+    setSessionStatus("Measuring...");
+    try {
+      const measured = await invoke<Session>("measure_session", {
+        paths,
+        calibrate: sessionCalibrate(),
+        minTransparency: minTransparency() / 100,
+      });
+      setSession(measured);
+      drawSession();
+      const frames = measured.frames;
+      const positions = sessionPositions(frames);
+      const dated = frames.every((f) => f.timestamp != null);
+      const skipped = measured.skipped.length ? `, ${measured.skipped.length} skipped` : "";
+      const rejected = frames.filter((f) => f.reject).length;
+      setSessionStatus(
+        `${frames.length} frames` +
+          (dated ? ` over ${positions[positions.length - 1].toFixed(0)} min` : ", not all dated") +
+          skipped +
+          `\n${rejected} rejected`
+      );
+    } catch (e) {
+      setSessionStatus(String(e));
+    }
+  }
+
+  // Open the frame nearest to where the chart was clicked
+  async function openSessionFrame(e: MouseEvent) {
+    const frames = session()?.frames;
+    if (!frames || !sessionCanvas) return;
+
+    const rect = sessionCanvas.getBoundingClientRect();
+    const positions = sessionPositions(frames);
+    const first = positions[0];
+    const span = positions[positions.length - 1] - first;
+    const at = first + ((e.clientX - rect.left) / rect.width) * span;
+    let nearest = 0;
+    positions.forEach((p, i) => {
+      if (Math.abs(p - at) < Math.abs(positions[nearest] - at)) nearest = i;
+    });
+
+    const frame = frames[nearest];
+    setSessionStatus(
+      `${frame.path.split(/[\\/]/).pop()}: ${frame.date_obs ?? "no date"}\n` +
+        `background ${frame.background.toFixed(1)}, noise ${frame.noise.toFixed(2)}, ` +
+        `${frame.stars} stars, eccentricity ${frame.eccentricity.toFixed(2)} ` +
+        `towards ${frame.elongation_angle_deg.toFixed(0)}°, ` +
+        `gradient ${frame.gradient_percent.toFixed(1)}% towards ${frame.gradient_angle_deg.toFixed(0)}°` +
+        (frame.transparency != null
+          ? `\ntransparency ${(frame.transparency * 100).toFixed(0)}% (${frame.matched_stars} stars)`
+          : "") +
+        (frame.reject ? `\nrejected: ${frame.reject}` : "")
+    );
+    await openImage(frame.path);
+  }
+
+  async function exportSession() {
+    const path = await save({ filters: [{ name: "CSV", extensions: ["csv"] }] });
+    if (!path) return;
+    try {
+      await invoke("export_session", { path });
+      setSessionStatus(`Saved ${path}`);
+    } catch (e) {
+      setSessionStatus(String(e));
+    }
+  }
+
   async function openFileDialog() {
     const filepath = await open({
       multiple: false,
@@ -174,19 +700,377 @@ function App() {
 
     // Add this check in case the user cancels the dialog
     if (typeof filepath === "string") {
-      const newStats = await invoke<ImageStats>("open_single_fits_file", {
-        path: filepath,
+      await openImage(filepath);
+    }
+  }
+
+  async function openImage(filepath: string) {
+    stopBlink();
+    setBlink(null);
+    setCompareMode("off");
+    const newStats = await invoke<ImageStats>("open_single_fits_file", {
+      path: filepath,
+    });
+    
+    // Now this works, because setStats and drawHistogram are in the same scope!
+    setStats(newStats);
+    setStretchMin(newStats.min);
+    setStretchMax(newStats.max);
+    drawHistogram(); 
+    await applyWcsOverlay(wcsOverlay());
+    await applyAutoOrient(autoOrient());
+  }
+
+  // Quad index for plate solving from a "ra,dec,mag" CSV catalogue
+  async function buildPlateIndex() {
+    const cataloguePath = await open({
+      multiple: false,
+      directory: false,
+      filters: [{ name: "Star Catalogue", extensions: ["csv", "txt"] }],
+    });
+    if (typeof cataloguePath !== "string") return;
+    const path = await save({
+      filters: [{ name: "Plate Solving Index", extensions: ["idx"] }],
+    });
+    if (!path) return;
+
+    setSolveStatus("Building index...");
+    try {
+      const quads = await invoke<number>("build_plate_index", {
+        cataloguePath,
+        indexPath: path,
+        fieldDeg: indexFieldDeg(),
+      });
+      setIndexPath(path);
+      setSolveStatus(`Index built with ${quads} quads`);
+    } catch (e) {
+      setSolveStatus(String(e));
+    }
+  }
+
+  async function selectPlateIndex() {
+    const selected = await open({
+      multiple: false,
+      directory: false,
+      filters: [{ name: "Plate Solving Index", extensions: ["idx"] }],
+    });
+    if (typeof selected !== "string") return null;
+    setIndexPath(selected);
+    return selected;
+  }
+
+  // Solve the current image and write the WCS into its header
+  async function plateSolve() {
+    const path = indexPath() ?? (await selectPlateIndex());
+    if (!path) return;
+
+    setSolveStatus("Solving...");
+    try {
+      const solution = await invoke<PlateSolution>("plate_solve", { indexPath: path });
+      setSolveStatus(
+        `RA ${solution.ra.toFixed(4)}° Dec ${solution.dec.toFixed(4)}°, ` +
+          `${solution.scale_arcsec.toFixed(2)}"/px, ` +
+          `${solution.matched_stars} stars, RMS ${solution.rms_arcsec.toFixed(2)}"`,
+      );
+      await applyWcsOverlay(true);
+      await applyAutoOrient(autoOrient());
+    } catch (e) {
+      setSolveStatus(String(e));
+    }
+  }
+
+  // Combine bias, dark and flat files (sorted by IMAGETYP) into masters
+  async function buildMasters() {
+    const paths = await open({
+      multiple: true,
+      directory: false,
+      filters: [{ name: "FITS", extensions: ["fits", "fit", "fts"] }],
+    });
+    if (!Array.isArray(paths) || paths.length === 0) return;
+    const outputDir = await open({ multiple: false, directory: true });
+    if (typeof outputDir !== "string") return;
+
+    setCalibrationStatus("Combining...");
+    try {
+      const masters = await invoke<MasterInfo[]>("build_masters", {
+        paths,
+        method: combineMethod(),
+        outputDir,
+      });
+      setCalibrationStatus(
+        masters
+          .map(
+            (m) =>
+              `${m.path.split(/[\\/]/).pop()}: ${m.frames} frames, ` +
+              `median ${m.median.toFixed(m.kind === "flat" ? 3 : 1)}`,
+          )
+          .join("\n"),
+      );
+    } catch (e) {
+      setCalibrationStatus(String(e));
+    }
+  }
+
+  // Masters the lights are calibrated with
+  async function loadCalibration() {
+    const paths = await open({
+      multiple: true,
+      directory: false,
+      filters: [{ name: "FITS", extensions: ["fits", "fit", "fts"] }],
+    });
+    if (!Array.isArray(paths) || paths.length === 0) return;
+
+    try {
+      const names = await invoke<string[]>("load_calibration", { paths });
+      setCalibrationStatus(`Masters: ${names.join(", ")}`);
+      if (calibratePreview()) await updateCalibratePreview(true);
+    } catch (e) {
+      setCalibrationStatus(String(e));
+    }
+  }
+
+  // Show the images calibrated (or not), reopening the active pane's files
+  async function updateCalibratePreview(enabled: boolean) {
+    try {
+      stopBlink();
+      setCompareMode("off");
+      setCompareReference(0);
+      const info = await invoke<BlinkInfo>("set_calibration_preview", { enabled });
+      setCalibratePreview(enabled);
+      if (blink()) setBlink(info);
+      await loadStats();
+      drawHistogram();
+    } catch (e) {
+      setCalibratePreview(false);
+      setCalibrationStatus(String(e));
+    }
+  }
+
+  // Show images with hot pixels and bad columns corrected (null: as they are)
+  async function updateCosmeticPreview(source: DefectSource | null) {
+    try {
+      stopBlink();
+      setCompareMode("off");
+      setCompareReference(0);
+      const info = await invoke<BlinkInfo>("set_cosmetic_preview", { source });
+      setCosmeticSource(source);
+      if (blink()) setBlink(info);
+      await loadStats();
+      drawHistogram();
+    } catch (e) {
+      setCosmeticSource(null);
+      setCalibrationStatus(String(e));
+    }
+  }
+
+  // Write hot pixel corrected copies of frames
+  async function correctFiles() {
+    const paths = await open({
+      multiple: true,
+      directory: false,
+      filters: [{ name: "FITS", extensions: ["fits", "fit", "fts"] }],
+    });
+    if (!Array.isArray(paths) || paths.length === 0) return;
+    const outputDir = await open({ multiple: false, directory: true });
+    if (typeof outputDir !== "string") return;
+
+    setCalibrationStatus("Correcting...");
+    try {
+      const written = await invoke<string[]>("correct_files", {
+        paths,
+        source: cosmeticSource() ?? "detect",
+        outputDir,
+      });
+      setCalibrationStatus(`Wrote ${written.length} corrected files`);
+    } catch (e) {
+      setCalibrationStatus(String(e));
+    }
+  }
+
+  // Write calibrated copies of light frames
+  async function calibrateFiles() {
+    const paths = await open({
+      multiple: true,
+      directory: false,
+      filters: [{ name: "FITS", extensions: ["fits", "fit", "fts"] }],
+    });
+    if (!Array.isArray(paths) || paths.length === 0) return;
+    const outputDir = await open({ multiple: false, directory: true });
+    if (typeof outputDir !== "string") return;
+
+    setCalibrationStatus("Calibrating...");
+    try {
+      const written = await invoke<string[]>("calibrate_files", { paths, outputDir });
+      setCalibrationStatus(`Wrote ${written.length} calibrated files`);
+    } catch (e) {
+      setCalibrationStatus(String(e));
+    }
+  }
+
+  const backgroundParams = () => ({ model: surfaceModel(), degree: surfaceDegree(), grid: 16 });
+
+  // Show the background model, or the images with it taken off
+  async function updateBackgroundView(view: BackgroundView) {
+    try {
+      stopBlink();
+      setCompareMode("off");
+      setCompareReference(0);
+      const info = await invoke<BlinkInfo>("set_background_view", {
+        params: backgroundParams(),
+        view,
       });
-      
-      // Now this works, because setStats and drawHistogram are in the same scope!
-      setStats(newStats);
-      setStretchMin(newStats.min);
-      setStretchMax(newStats.max);
-      drawHistogram(); 
+      setBackgroundView(view);
+      if (blink()) setBlink(info);
+      await loadStats();
+      drawHistogram();
+    } catch (e) {
+      setBackgroundView("original");
+      setBackgroundStatus(String(e));
     }
   }
 
+  // Gradient strength and direction of each frame in the active pane
+  async function measureGradients() {
+    setBackgroundStatus("Measuring...");
+    try {
+      const gradients = await invoke<GradientStats[]>("measure_gradients", {
+        params: backgroundParams(),
+      });
+      setBackgroundStatus(
+        gradients
+          .map(
+            (g, i) =>
+              `${gradients.length > 1 ? `${i + 1}: ` : ""}${g.strength_percent.toFixed(1)}% ` +
+              `(${g.strength_sigma.toFixed(1)}σ) towards ${g.angle_deg.toFixed(0)}°`
+          )
+          .join("\n")
+      );
+    } catch (e) {
+      setBackgroundStatus(String(e));
+    }
+  }
+
+  // Register and stack subs (onto the first selected), then show the result
+  async function stackFrames(selected?: string[]) {
+    const paths =
+      selected ??
+      (await open({
+        multiple: true,
+        directory: false,
+        filters: [{ name: "FITS", extensions: ["fits", "fit", "fts"] }],
+      }));
+    if (!Array.isArray(paths) || paths.length === 0) return;
+    const outputPath = await save({
+      filters: [{ name: "FITS", extensions: ["fits"] }],
+    });
+    if (!outputPath) return;
+
+    setStackStatus("Stacking...");
+    try {
+      const info = await invoke<StackInfo>("stack_frames", {
+        paths,
+        method: stackMethod(),
+        weighting: stackWeighting(),
+        calibrate: stackCalibrate(),
+        outputPath,
+      });
+      const skipped = info.skipped.length ? `, ${info.skipped.length} skipped` : "";
+      setStackStatus(`Stacked ${info.frames.length} frames${skipped}`);
+      await openImage(info.path);
+    } catch (e) {
+      setStackStatus(String(e));
+    }
+  }
+
+  // Load several frames into the active pane to blink through
+  async function loadBlinkFrames() {
+    const paths = await open({
+      multiple: true,
+      directory: false,
+      filters: [{ name: "FITS", extensions: ["fits", "fit", "fts"] }],
+    });
+    if (!Array.isArray(paths) || paths.length === 0) return;
+
+    stopBlink();
+    setCompareMode("off");
+    setCompareReference(0);
+    setBlink(
+      await invoke<BlinkInfo>("load_blink_frames", { paths, align: blinkAlign() })
+    );
+    await loadStats();
+    drawHistogram();
+    await applyWcsOverlay(wcsOverlay());
+    await applyAutoOrient(autoOrient());
+  }
+
+  // Step through the blink sequence, wrapping around at the end
+  const showBlinkFrame = async (step: number) => {
+    const current = blink();
+    if (!current || current.frame_count < 2) return;
+
+    const frame = (current.frame + step + current.frame_count) % current.frame_count;
+    const info = await invoke<BlinkInfo>("show_blink_frame", { frame });
+    setBlink(info);
+    if (!info.comparing && compareMode() !== "off") {
+      setCompareMode("off");
+      setCompareStatus("Frames too far apart to compare");
+    }
+    refreshSoftwareFrame();
+  };
+
+  // Difference or ratio of the frame on screen against a reference frame
+  const updateCompare = async (mode: CompareMode, reference: number) => {
+    try {
+      const info = await invoke<CompareInfo>("set_compare", { mode, reference });
+      setCompareMode(mode);
+      setCompareReference(reference);
+      setCompareStatus(null);
+      setStats(info.stats);
+      setStretchMin(info.stretch_min);
+      setStretchMax(info.stretch_max);
+      drawHistogram();
+      refreshSoftwareFrame();
+    } catch (e) {
+      setCompareStatus(String(e));
+    }
+  };
+
+  let blinkTimer: number | undefined;
+
+  const stopBlink = () => {
+    window.clearInterval(blinkTimer);
+    blinkTimer = undefined;
+    setBlinking(false);
+  };
+
+  // Cycle at the chosen rate (frames per second)
+  const startBlink = () => {
+    stopBlink();
+    blinkTimer = window.setInterval(() => showBlinkFrame(1), 1000 / blinkRate());
+    setBlinking(true);
+  };
+
+  onCleanup(stopBlink);
+
+  // Save exactly what the viewer shows as PNG
+  async function exportView() {
+    const path = await save({
+      filters: [{ name: "PNG Image", extensions: ["png"] }],
+    });
+    if (!path) return;
+
+    await invoke("export_view", {
+      path,
+      width: Math.round(window.innerWidth * window.devicePixelRatio),
+      height: Math.round(window.innerHeight * window.devicePixelRatio),
+    });
+  }
+
   onMount(async () => {
+    const backend = await invoke<string>("get_renderer_backend");
+    setSoftwareRenderer(backend === "software");
+    window.addEventListener("resize", refreshSoftwareFrame);
+
     updateView(); // Initial view
     await loadStats(); // Load statistics
     drawHistogram(); // Draw histogram
@@ -194,12 +1078,16 @@ function App() {
 
   return (
     <div class="app-layout">
+      {softwareRenderer() && (
+        <canvas ref={softwareCanvas} class="software-canvas" />
+      )}
+
       {/* Top Toolbar */}
       <header class="toolbar">
         <h1>RapidFits - GPU FITS Viewer</h1>
         <div class="toolbar-actions">
           <button onClick={openFileDialog}>Open File</button>
-          <button>Export</button>
+          <button onClick={exportView}>Export</button>
           <button type="button">Settings</button>
         </div>
       </header>
@@ -249,6 +1137,122 @@ function App() {
               <span>{zoom()}%</span>
             </div>
 
+            <div class="property">
+              <label>Zoomed Out</label>
+              <select
+                value={pyramidMode()}
+                onChange={(e) =>
+                  updatePyramidMode(e.currentTarget.value as "mean" | "max")
+                }
+              >
+                <option value="mean">Mean (faithful)</option>
+                <option value="max">Max (keep stars)</option>
+              </select>
+            </div>
+
+            <div class="property">
+              <label>Interpolation</label>
+              <select
+                value={interpolation()}
+                onChange={(e) =>
+                  updateInterpolation(e.currentTarget.value as Interpolation)
+                }
+              >
+                <option value="nearest">Nearest</option>
+                <option value="bilinear">Bilinear</option>
+                <option value="bicubic">Bicubic</option>
+                <option value="lanczos">Lanczos</option>
+              </select>
+            </div>
+
+            <div class="property">
+              <label>Rotation</label>
+              <input
+                type="range"
+                min="0"
+                max="359"
+                value={orientation().rotation_deg}
+                onInput={(e) =>
+                  updateOrientation({ rotation_deg: Number(e.currentTarget.value) })
+                }
+              />
+              <span>{orientation().rotation_deg.toFixed(0)}°</span>
+            </div>
+
+            <div class="property">
+              <button
+                type="button"
+                onClick={() =>
+                  updateOrientation({ rotation_deg: orientation().rotation_deg - 90 })
+                }
+              >
+                ⟲ 90°
+              </button>
+              <button
+                type="button"
+                onClick={() =>
+                  updateOrientation({ rotation_deg: orientation().rotation_deg + 90 })
+                }
+              >
+                ⟳ 90°
+              </button>
+              <button
+                type="button"
+                classList={{ active: orientation().flip_horizontal }}
+                onClick={() =>
+                  updateOrientation({ flip_horizontal: !orientation().flip_horizontal })
+                }
+              >
+                Flip H
+              </button>
+              <button
+                type="button"
+                classList={{ active: orientation().flip_vertical }}
+                onClick={() =>
+                  updateOrientation({ flip_vertical: !orientation().flip_vertical })
+                }
+              >
+                Flip V
+              </button>
+            </div>
+
+            <div class="property">
+              <label>
+                <input
+                  type="checkbox"
+                  checked={autoOrient()}
+                  onChange={(e) => applyAutoOrient(e.currentTarget.checked)}
+                />
+                Auto-orient (WCS / pier side)
+              </label>
+            </div>
+
+            <div class="property">
+              <label>Panes</label>
+              <select
+                value={paneLayout()}
+                onChange={(e) =>
+                  updatePaneLayout(e.currentTarget.value as PaneLayout)
+                }
+              >
+                <option value="single">Single</option>
+                <option value="split">Side by side</option>
+                <option value="stacked">Stacked</option>
+                <option value="grid">2 x 2 grid</option>
+              </select>
+            </div>
+
+            <div class="property">
+              <label>
+                <input
+                  type="checkbox"
+                  checked={linkedView()}
+                  onChange={(e) => updateLinkedView(e.currentTarget.checked)}
+                />
+                Link zoom/pan
+              </label>
+            </div>
+
             <div class="property">
               <button
                 type="button"
@@ -332,6 +1336,374 @@ function App() {
             >
               Auto Stretch
             </button>
+
+            <button
+              type="button"
+              class="full-width"
+              classList={{ active: clipOverlay() }}
+              onClick={toggleClipOverlay}
+            >
+              {clipOverlay() ? "Hide Clipping" : "Show Clipping"}
+            </button>
+
+            <button
+              type="button"
+              class="full-width"
+              classList={{ active: wcsOverlay() }}
+              onClick={() => applyWcsOverlay(!wcsOverlay())}
+              title={hasWcs() ? undefined : "Image has no WCS (not plate solved)"}
+            >
+              {wcsOverlay() ? "Hide Sky Grid" : "Show Sky Grid"}
+            </button>
+          </div>
+
+          <div class="panel">
+            <h3>Blink</h3>
+            <div class="property">
+              <label>
+                <input
+                  type="checkbox"
+                  checked={blinkAlign()}
+                  onChange={(e) => setBlinkAlign(e.currentTarget.checked)}
+                />
+                Align on stars
+              </label>
+            </div>
+
+            <div class="property">
+              <label>Rate (fps)</label>
+              <input
+                type="number"
+                min="0.5"
+                max="30"
+                step="0.5"
+                value={blinkRate()}
+                onInput={(e) => {
+                  setBlinkRate(Math.max(0.5, Number(e.currentTarget.value)));
+                  if (blinking()) startBlink();
+                }}
+              />
+            </div>
+
+            <button type="button" class="full-width" onClick={loadBlinkFrames}>
+              Load Frames...
+            </button>
+            <div class="property">
+              <button type="button" onClick={() => showBlinkFrame(-1)}>
+                ◀
+              </button>
+              <button
+                type="button"
+                classList={{ active: blinking() }}
+                onClick={() => (blinking() ? stopBlink() : startBlink())}
+              >
+                {blinking() ? "Pause" : "Play"}
+              </button>
+              <button type="button" onClick={() => showBlinkFrame(1)}>
+                ▶
+              </button>
+            </div>
+            <div class="property">
+              <label>Compare</label>
+              <select
+                value={compareMode()}
+                disabled={(blink()?.frame_count ?? 0) < 2}
+                onChange={(e) =>
+                  updateCompare(e.currentTarget.value as CompareMode, compareReference())
+                }
+              >
+                <option value="off">Off</option>
+                <option value="difference">Difference (A − B)</option>
+                <option value="ratio">Ratio (A / B)</option>
+              </select>
+            </div>
+
+            <div class="property">
+              <label>Reference Frame (B)</label>
+              <input
+                type="number"
+                min="1"
+                max={blink()?.frame_count ?? 1}
+                value={compareReference() + 1}
+                disabled={(blink()?.frame_count ?? 0) < 2}
+                onChange={(e) =>
+                  updateCompare(compareMode(), Number(e.currentTarget.value) - 1)
+                }
+              />
+            </div>
+
+            {compareStatus() && <div class="stat">{compareStatus()}</div>}
+            {blink() && (
+              <div class="stat">
+                Frame {blink()!.frame + 1}/{blink()!.frame_count}:{" "}
+                {blink()!.path.split(/[\\/]/).pop()} (
+                {blink()!.alignment.dx.toFixed(1)},{" "}
+                {blink()!.alignment.dy.toFixed(1)} px)
+                {blink()!.registration &&
+                  blink()!.frame > 0 &&
+                  ` ${registrationSummary(blink()!.registration!)}`}
+              </div>
+            )}
+          </div>
+
+          <div class="panel">
+            <h3>Calibration</h3>
+            <div class="property">
+              <label>Combine</label>
+              <select
+                value={combineMethod()}
+                onChange={(e) => setCombineMethod(e.currentTarget.value as CombineMethod)}
+              >
+                <option value="average">Average</option>
+                <option value="median">Median</option>
+                <option value="sigmaclip">Sigma clipped</option>
+              </select>
+            </div>
+
+            <button type="button" class="full-width" onClick={buildMasters}>
+              Build Masters...
+            </button>
+            <button type="button" class="full-width" onClick={loadCalibration}>
+              Load Masters...
+            </button>
+            <div class="property">
+              <label>
+                <input
+                  type="checkbox"
+                  checked={calibratePreview()}
+                  onChange={(e) => updateCalibratePreview(e.currentTarget.checked)}
+                />
+                Preview calibrated
+              </label>
+            </div>
+            <button type="button" class="full-width" onClick={calibrateFiles}>
+              Calibrate Files...
+            </button>
+            <div class="property">
+              <label>Hot Pixels</label>
+              <select
+                value={cosmeticSource() ?? "off"}
+                onChange={(e) => {
+                  const value = e.currentTarget.value;
+                  updateCosmeticPreview(value === "off" ? null : (value as DefectSource));
+                }}
+              >
+                <option value="off">Off</option>
+                <option value="detect">Detect in frame</option>
+                <option value="dark">From master dark</option>
+              </select>
+            </div>
+            <button type="button" class="full-width" onClick={correctFiles}>
+              Correct Files...
+            </button>
+            {calibrationStatus() && (
+              <div class="stat" style={{ "white-space": "pre-line" }}>
+                {calibrationStatus()}
+              </div>
+            )}
+          </div>
+
+          <div class="panel">
+            <h3>Star Shapes</h3>
+            <button
+              type="button"
+              class="full-width"
+              classList={{ active: elongationOverlay() }}
+              onClick={() => applyElongationOverlay(!elongationOverlay())}
+            >
+              {elongationOverlay() ? "Hide Elongation Map" : "Show Elongation Map"}
+            </button>
+            <button type="button" class="full-width" onClick={measureElongation}>
+              Measure Elongation
+            </button>
+            {elongationStatus() && (
+              <div class="stat" style={{ "white-space": "pre-line" }}>
+                {elongationStatus()}
+              </div>
+            )}
+          </div>
+
+          <div class="panel">
+            <h3>Background</h3>
+            <div class="property">
+              <label>Model</label>
+              <select
+                value={surfaceModel()}
+                onChange={(e) => setSurfaceModel(e.currentTarget.value as SurfaceModel)}
+              >
+                <option value="polynomial">Polynomial</option>
+                <option value="spline">Spline</option>
+              </select>
+            </div>
+            {surfaceModel() === "polynomial" && (
+              <div class="property">
+                <label>Degree</label>
+                <input
+                  type="range"
+                  min="1"
+                  max="4"
+                  value={surfaceDegree()}
+                  onInput={(e) => setSurfaceDegree(Number(e.currentTarget.value))}
+                />
+                <span>{surfaceDegree()}</span>
+              </div>
+            )}
+            <div class="property">
+              <label>Show</label>
+              <select
+                value={backgroundView()}
+                onChange={(e) => updateBackgroundView(e.currentTarget.value as BackgroundView)}
+              >
+                <option value="original">Image</option>
+                <option value="model">Model</option>
+                <option value="subtracted">Subtracted</option>
+                <option value="divided">Divided</option>
+              </select>
+            </div>
+            <button type="button" class="full-width" onClick={measureGradients}>
+              Measure Gradient
+            </button>
+            {backgroundStatus() && (
+              <div class="stat" style={{ "white-space": "pre-line" }}>
+                {backgroundStatus()}
+              </div>
+            )}
+          </div>
+
+          <div class="panel">
+            <h3>Session</h3>
+            <div class="property">
+              <label>
+                <input
+                  type="checkbox"
+                  checked={sessionCalibrate()}
+                  onChange={(e) => setSessionCalibrate(e.currentTarget.checked)}
+                />
+                Calibrate with masters
+              </label>
+            </div>
+            <div class="property">
+              <label>Min. transparency</label>
+              <input
+                type="range"
+                min="0"
+                max="100"
+                value={minTransparency()}
+                onInput={(e) => setMinTransparency(Number(e.currentTarget.value))}
+              />
+              <span>{minTransparency()}%</span>
+            </div>
+            <button type="button" class="full-width" onClick={measureSession}>
+              Measure Session...
+            </button>
+            {session() && (
+              <>
+                <canvas
+                  ref={sessionCanvas}
+                  width="240"
+                  height="100"
+                  onClick={openSessionFrame}
+                  style={{
+                    width: "100%",
+                    border: "1px solid rgba(255,255,255,0.2)",
+                    "border-radius": "4px",
+                    cursor: "pointer",
+                  }}
+                />
+                <div class="stat">
+                  {SESSION_SERIES.map((series) => (
+                    <span style={{ color: series.color, "margin-right": "8px" }}>
+                      {series.label}
+                    </span>
+                  ))}
+                </div>
+                <button type="button" class="full-width" onClick={exportSession}>
+                  Export CSV...
+                </button>
+                <button
+                  type="button"
+                  class="full-width"
+                  onClick={() =>
+                    stackFrames(session()!.frames.filter((f) => !f.reject).map((f) => f.path))
+                  }
+                >
+                  Stack Accepted...
+                </button>
+              </>
+            )}
+            {sessionStatus() && (
+              <div class="stat" style={{ "white-space": "pre-line" }}>
+                {sessionStatus()}
+              </div>
+            )}
+          </div>
+
+          <div class="panel">
+            <h3>Stacking</h3>
+            <div class="property">
+              <label>Rejection</label>
+              <select
+                value={stackMethod()}
+                onChange={(e) => setStackMethod(e.currentTarget.value as StackMethod)}
+              >
+                <option value="mean">None (mean)</option>
+                <option value="median">Median</option>
+                <option value="sigmaclip">Sigma clip</option>
+                <option value="winsorized">Winsorized sigma clip</option>
+                <option value="linearfit">Linear fit</option>
+              </select>
+            </div>
+            <div class="property">
+              <label>Weights</label>
+              <select
+                value={stackWeighting()}
+                onChange={(e) => setStackWeighting(e.currentTarget.value as Weighting)}
+              >
+                <option value="none">Equal</option>
+                <option value="noise">Noise</option>
+                <option value="snr">SNR</option>
+              </select>
+            </div>
+            <div class="property">
+              <label>
+                <input
+                  type="checkbox"
+                  checked={stackCalibrate()}
+                  onChange={(e) => setStackCalibrate(e.currentTarget.checked)}
+                />
+                Calibrate with masters
+              </label>
+            </div>
+
+            <button type="button" class="full-width" onClick={() => stackFrames()}>
+              Stack Frames...
+            </button>
+            {stackStatus() && <div class="stat">{stackStatus()}</div>}
+          </div>
+
+          <div class="panel">
+            <h3>Plate Solving</h3>
+            <div class="property">
+              <label>Index Field (deg)</label>
+              <input
+                type="number"
+                min="0.05"
+                step="0.05"
+                value={indexFieldDeg()}
+                onInput={(e) => setIndexFieldDeg(Number(e.currentTarget.value))}
+              />
+            </div>
+
+            <button type="button" class="full-width" onClick={buildPlateIndex}>
+              Build Index...
+            </button>
+            <button type="button" class="full-width" onClick={plateSolve}>
+              Plate Solve
+            </button>
+            <button type="button" class="full-width" onClick={selectPlateIndex}>
+              Select Index...
+            </button>
+            {solveStatus() && <div class="stat">{solveStatus()}</div>}
           </div>
 
           <div class="panel">
@@ -366,11 +1738,25 @@ function App() {
           onMouseDown={handleMouseDown}
           onMouseMove={handleMouseMove}
           onMouseUp={handleMouseUp}
-          onMouseLeave={handleMouseUp}
+          onMouseLeave={() => {
+            handleMouseUp();
+            setPixelInfo(null);
+          }}
           style={{ cursor: isDragging ? "grabbing" : "grab" }}
         >
           {/* WGPU renders the full window, this area is just transparent */}
           <div class="viewer-overlay">
+            {paneLayout() !== "single" && (
+              <div
+                style={{
+                  position: "fixed",
+                  ...activePaneOutline(),
+                  "box-sizing": "border-box",
+                  border: "2px solid #24c8db",
+                  "pointer-events": "none",
+                }}
+              />
+            )}
             {/* Optional overlays, crosshairs, info text, etc. */}
             <div
               style={{
@@ -386,6 +1772,16 @@ function App() {
             >
               Zoom: {zoom().toFixed(0)}% | Pan: ({panX().toFixed(2)},{" "}
               {panY().toFixed(2)})
+              {pixelInfo() && (
+                <div>
+                  {paneLayout() !== "single" && `Pane ${pixelInfo()!.pane + 1} | `}
+                  X: {pixelInfo()!.x} Y: {pixelInfo()!.y} | Value:{" "}
+                  {pixelInfo()!.raw.toFixed(2)} (
+                  {(pixelInfo()!.normalized * 100).toFixed(1)}%)
+                  {pixelInfo()!.ra !== null &&
+                    ` | RA: ${pixelInfo()!.ra!.toFixed(5)}° Dec: ${pixelInfo()!.dec!.toFixed(5)}°`}
+                </div>
+              )}
             </div>
           </div>
         </div>